@@ -1,16 +1,14 @@
-use std::fs::File;
 use std::io;
-use std::io::BufReader;
 use std::net::ToSocketAddrs;
 use std::sync::Arc;
 
-use crate::rustls::pki_types::{self, CertificateDer, PrivateKeyDer};
+use crate::rustls::pki_types;
 use anyhow::Result;
-use rustls_pemfile::{certs, read_all};
-use simple_cypher::*;
-use tokio::io::{
-    copy, split, stdin as tokio_stdin, stdout as tokio_stdout, AsyncReadExt, AsyncWriteExt,
+use secure_channel::{
+    ra_tls_client_config, to_hex, AttestationPolicy, Channel, ClientTlsConfig, MockQuoteVerifier,
+    QuoteVerifier, Transport, MOCK_MR_ENCLAVE, RA_TLS_SERVER_NAME,
 };
+use simple_cypher::*;
 use tokio::net::TcpStream;
 use tokio_rustls::{rustls, TlsConnector};
 
@@ -21,27 +19,9 @@ async fn main() -> Result<()> {
         .next()
         .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
 
-    // let mut root_cert_store = rustls::RootCertStore::empty();
-    // let mut pem = BufReader::new(File::open("../tmp/rootCA.pem")?);
-    // for cert in rustls_pemfile::certs(&mut pem) {
-    //     root_cert_store.add(cert?).unwrap();
-    // }
-
-    // let certs = load_certs("./certificates/client.crt")?;
-    // let key = load_keys("./certificates/client.key")?;
-
-    // let config = rustls::ClientConfig::builder()
-    //     .with_root_certificates(root_cert_store)
-    //     .with_client_auth_cert(certs, key)?;
-    // let connector = TlsConnector::from(Arc::new(config));
-
-    let mut stream = TcpStream::connect(&addr).await?;
-
-    // let domain = pki_types::ServerName::try_from("127.0.0.1")
-    //     .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid dnsname"))?
-    //     .to_owned();
+    let stream = TcpStream::connect(&addr).await?;
 
-    // let mut stream = connector.connect(domain, stream).await?;
+    let mut stream = connect(stream).await?;
 
     test_crud(&mut stream).await.unwrap();
     test_find_shortest_path(&mut stream).await.unwrap();
@@ -51,23 +31,77 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn execute_query(query: CypherQuery, stream: &mut TcpStream) -> Result<Rows> {
+// The server is only trusted after its evidence matches the policy pinned by
+// `ATTESTATION_MRENCLAVE` / `ATTESTATION_MRSIGNER`. `ATTESTATION_MOCK=1` accepts the
+// mock evidence of a server without SGX, and pins the mock MRENCLAVE by default.
+fn attestation_from_env() -> Result<(Arc<dyn QuoteVerifier>, AttestationPolicy)> {
+    let mut policy = AttestationPolicy::from_env()?;
+    let verifier: Arc<dyn QuoteVerifier> = match std::env::var("ATTESTATION_MOCK").as_deref() {
+        Ok("1") => {
+            if policy.mr_enclave.is_none() && policy.mr_signer.is_none() {
+                policy.mr_enclave = Some(MOCK_MR_ENCLAVE);
+            }
+            Arc::new(MockQuoteVerifier)
+        }
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Verifying SGX quotes needs a DCAP quote verifier, set ATTESTATION_MOCK=1 to test without SGX"
+            ))
+        }
+    };
+    Ok((verifier, policy))
+}
+
+// `TRANSPORT` has to match the server: RA-TLS by default, TLS with the trust
+// anchors of `ClientTlsConfig`, or the attested handshake. A client certificate
+// from `TLS_CERT_PATH` / `TLS_KEY_PATH` is sent with either TLS transport.
+async fn connect(stream: TcpStream) -> Result<Channel> {
+    let tls_config = ClientTlsConfig::from_env()?;
+    match Transport::from_env()? {
+        Transport::RaTls => {
+            let (verifier, policy) = attestation_from_env()?;
+            let config = ra_tls_client_config(verifier, policy, tls_config.client_cert()?)?;
+            let connector = TlsConnector::from(Arc::new(config));
+            let domain = pki_types::ServerName::try_from(RA_TLS_SERVER_NAME)?.to_owned();
+            let stream = connector.connect(domain, stream).await?;
+            println!("RA-TLS handshake done");
+            Ok(Channel::plain(stream))
+        }
+        Transport::Tls => {
+            let connector = TlsConnector::from(Arc::new(tls_config.client_config()?));
+            let stream = connector.connect(tls_config.server_name()?, stream).await?;
+            Ok(Channel::plain(stream))
+        }
+        Transport::Attested => {
+            let (verifier, policy) = attestation_from_env()?;
+            let (channel, report) =
+                Channel::connect_attested(stream, verifier.as_ref(), &policy).await?;
+            println!(
+                "attested enclave MRENCLAVE {} MRSIGNER {} ISV SVN {}",
+                to_hex(&report.mr_enclave),
+                to_hex(&report.mr_signer),
+                report.isv_svn
+            );
+            Ok(channel)
+        }
+    }
+}
+
+async fn execute_query(mut query: CypherQuery, stream: &mut Channel) -> Result<Rows> {
+    if let Ok(tenant) = std::env::var("TENANT") {
+        query.tenant = Some(tenant);
+    }
     let serialized_query = query.serialize()?;
-    stream.write_u64(serialized_query.len() as u64).await?;
-    // println!("write {}", serialized_query.len());
-    stream.write_all(serialized_query.as_bytes()).await?;
+    stream.send(serialized_query.as_bytes()).await?;
     // println!("write {} bytes", serialized_query.len());
 
-    let len = stream.read_u64().await? as usize;
-    // println!("read {}", len);
-    let mut buf = vec![0u8; len];
-    stream.read_exact(&mut buf).await?;
-    // println!("read {} bytes", len);
+    let buf = stream.recv().await?;
+    // println!("read {} bytes", buf.len());
     let serialized_result = String::from_utf8(buf)?;
     Rows::deserialize(&serialized_result)
 }
 
-async fn init_test(stream: &mut TcpStream) -> Result<()> {
+async fn init_test(stream: &mut Channel) -> Result<()> {
     let query = CypherQueryBuilder::new()
         .MATCH()
         .node(Node::new(
@@ -83,7 +117,7 @@ async fn init_test(stream: &mut TcpStream) -> Result<()> {
     Ok(())
 }
 
-async fn test_crud(stream: &mut TcpStream) -> Result<()> {
+async fn test_crud(stream: &mut Channel) -> Result<()> {
     init_test(stream).await?;
 
     {
@@ -564,7 +598,7 @@ async fn test_crud(stream: &mut TcpStream) -> Result<()> {
     Ok(())
 }
 
-async fn test_find_shortest_path(stream: &mut TcpStream) -> Result<()> {
+async fn test_find_shortest_path(stream: &mut Channel) -> Result<()> {
     init_test(stream).await?;
 
     //            c --> d
@@ -641,19 +675,3 @@ async fn test_find_shortest_path(stream: &mut TcpStream) -> Result<()> {
 
     Ok(())
 }
-
-fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
-    certs(&mut BufReader::new(File::open(path)?)).collect()
-}
-
-fn load_keys(path: &str) -> Result<PrivateKeyDer<'static>> {
-    for item in read_all(&mut BufReader::new(File::open(path)?)) {
-        match item.unwrap() {
-            rustls_pemfile::Item::Pkcs1Key(key) => return Ok(key.into()),
-            rustls_pemfile::Item::Pkcs8Key(key) => return Ok(key.into()),
-            rustls_pemfile::Item::Sec1Key(key) => return Ok(key.into()),
-            _ => return Err(anyhow::anyhow!("invalid key")),
-        }
-    }
-    Err(anyhow::anyhow!("there is no key"))
-}
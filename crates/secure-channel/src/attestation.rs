@@ -0,0 +1,272 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{from_hex, to_hex};
+
+pub const REPORT_BODY_LEN: usize = 384;
+pub const REPORT_DATA_LEN: usize = 64;
+
+// offsets in sgx_report_body_t
+const MR_ENCLAVE_OFFSET: usize = 64;
+const MR_SIGNER_OFFSET: usize = 128;
+const ISV_PROD_ID_OFFSET: usize = 256;
+const ISV_SVN_OFFSET: usize = 258;
+const REPORT_DATA_OFFSET: usize = 320;
+
+// sgx_quote3_t: a 48 byte header, the report body of the enclave, then the signature data
+const QUOTE_HEADER_LEN: usize = 48;
+const QUOTE_VERSION: u16 = 3;
+
+const REPORT_DATA_CONTEXT: &str = "clique_task 2024 attestation report data";
+
+/// Measurements the mock quote provider reports, so a client in mock mode can pin them.
+pub const MOCK_MR_ENCLAVE: [u8; 32] = [0xaa; 32];
+pub const MOCK_MR_SIGNER: [u8; 32] = [0xbb; 32];
+
+/// The fields of `sgx_report_body_t` a client checks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportBody {
+    pub mr_enclave: [u8; 32],
+    pub mr_signer: [u8; 32],
+    pub isv_prod_id: u16,
+    pub isv_svn: u16,
+    pub report_data: [u8; REPORT_DATA_LEN],
+}
+
+impl ReportBody {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != REPORT_BODY_LEN {
+            return Err(anyhow::anyhow!(
+                "Invalid report body length: {}",
+                bytes.len()
+            ));
+        }
+        let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        Ok(Self {
+            mr_enclave: bytes[MR_ENCLAVE_OFFSET..MR_ENCLAVE_OFFSET + 32].try_into()?,
+            mr_signer: bytes[MR_SIGNER_OFFSET..MR_SIGNER_OFFSET + 32].try_into()?,
+            isv_prod_id: u16_at(ISV_PROD_ID_OFFSET),
+            isv_svn: u16_at(ISV_SVN_OFFSET),
+            report_data: bytes[REPORT_DATA_OFFSET..REPORT_DATA_OFFSET + REPORT_DATA_LEN]
+                .try_into()?,
+        })
+    }
+
+    /// `sgx_report_body_t` with every field not kept here zeroed.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; REPORT_BODY_LEN];
+        bytes[MR_ENCLAVE_OFFSET..MR_ENCLAVE_OFFSET + 32].copy_from_slice(&self.mr_enclave);
+        bytes[MR_SIGNER_OFFSET..MR_SIGNER_OFFSET + 32].copy_from_slice(&self.mr_signer);
+        bytes[ISV_PROD_ID_OFFSET..ISV_PROD_ID_OFFSET + 2]
+            .copy_from_slice(&self.isv_prod_id.to_le_bytes());
+        bytes[ISV_SVN_OFFSET..ISV_SVN_OFFSET + 2].copy_from_slice(&self.isv_svn.to_le_bytes());
+        bytes[REPORT_DATA_OFFSET..REPORT_DATA_OFFSET + REPORT_DATA_LEN]
+            .copy_from_slice(&self.report_data);
+        bytes
+    }
+}
+
+/// What the enclave presents to prove its identity.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Evidence {
+    /// SGX DCAP quote (version 3), signed by the quoting enclave.
+    SgxQuote(Vec<u8>),
+    /// Unsigned report body made by `MockQuoteProvider`. Anyone can forge it.
+    Mock(Vec<u8>),
+}
+
+impl Evidence {
+    /// The report body carried by the evidence, without checking any signature.
+    pub fn report_body(&self) -> Result<ReportBody> {
+        match self {
+            Evidence::SgxQuote(quote) => {
+                if quote.len() < QUOTE_HEADER_LEN + REPORT_BODY_LEN {
+                    return Err(anyhow::anyhow!("Invalid quote length: {}", quote.len()));
+                }
+                let version = u16::from_le_bytes([quote[0], quote[1]]);
+                if version != QUOTE_VERSION {
+                    return Err(anyhow::anyhow!("Unsupported quote version {}", version));
+                }
+                ReportBody::from_bytes(&quote[QUOTE_HEADER_LEN..QUOTE_HEADER_LEN + REPORT_BODY_LEN])
+            }
+            Evidence::Mock(body) => ReportBody::from_bytes(body),
+        }
+    }
+}
+
+/// Produces evidence of the running enclave over caller-chosen report data.
+pub trait QuoteProvider: Send + Sync {
+    fn quote(&self, report_data: &[u8; REPORT_DATA_LEN]) -> Result<Evidence>;
+}
+
+/// Checks the signature of evidence and returns the attested report body.
+///
+/// SGX quotes have to be checked against Intel's PCK certificate chain and
+/// collateral, e.g. by a verifier backed by the DCAP quote verification library.
+pub trait QuoteVerifier: Send + Sync {
+    fn verify(&self, evidence: &Evidence) -> Result<ReportBody>;
+}
+
+/// Reports fixed measurements without SGX, for development and tests.
+pub struct MockQuoteProvider {
+    body: ReportBody,
+}
+
+impl MockQuoteProvider {
+    pub fn new(mr_enclave: [u8; 32], mr_signer: [u8; 32], isv_svn: u16) -> Self {
+        Self {
+            body: ReportBody {
+                mr_enclave,
+                mr_signer,
+                isv_prod_id: 0,
+                isv_svn,
+                report_data: [0u8; REPORT_DATA_LEN],
+            },
+        }
+    }
+}
+
+impl Default for MockQuoteProvider {
+    fn default() -> Self {
+        Self::new(MOCK_MR_ENCLAVE, MOCK_MR_SIGNER, 0)
+    }
+}
+
+impl QuoteProvider for MockQuoteProvider {
+    fn quote(&self, report_data: &[u8; REPORT_DATA_LEN]) -> Result<Evidence> {
+        let mut body = self.body.clone();
+        body.report_data = *report_data;
+        Ok(Evidence::Mock(body.to_bytes()))
+    }
+}
+
+/// Accepts mock evidence only. Never use it against a real enclave.
+pub struct MockQuoteVerifier;
+
+impl QuoteVerifier for MockQuoteVerifier {
+    fn verify(&self, evidence: &Evidence) -> Result<ReportBody> {
+        match evidence {
+            Evidence::Mock(_) => {
+                log::warn!("accepting MOCK attestation evidence, the peer is not attested");
+                evidence.report_body()
+            }
+            Evidence::SgxQuote(_) => {
+                Err(anyhow::anyhow!("The mock verifier can't verify SGX quotes"))
+            }
+        }
+    }
+}
+
+/// The enclave identity a client is willing to talk to. At least one of
+/// MRENCLAVE and MRSIGNER has to be pinned.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AttestationPolicy {
+    pub mr_enclave: Option<[u8; 32]>,
+    pub mr_signer: Option<[u8; 32]>,
+    pub isv_prod_id: Option<u16>,
+    pub min_isv_svn: u16,
+}
+
+impl AttestationPolicy {
+    /// Reads `ATTESTATION_MRENCLAVE` and `ATTESTATION_MRSIGNER` (hex),
+    /// `ATTESTATION_ISV_PROD_ID` and `ATTESTATION_MIN_ISV_SVN`.
+    pub fn from_env() -> Result<Self> {
+        let measurement = |name: &str| -> Result<Option<[u8; 32]>> {
+            match std::env::var(name) {
+                Ok(hex) => {
+                    Ok(Some(from_hex(&hex)?.try_into().map_err(|_| {
+                        anyhow::anyhow!("{} must be 32 bytes of hex", name)
+                    })?))
+                }
+                Err(_) => Ok(None),
+            }
+        };
+        let number = |name: &str| -> Result<Option<u16>> {
+            match std::env::var(name) {
+                Ok(x) => Ok(Some(
+                    x.parse()
+                        .map_err(|_| anyhow::anyhow!("Invalid {}: {}", name, x))?,
+                )),
+                Err(_) => Ok(None),
+            }
+        };
+        Ok(Self {
+            mr_enclave: measurement("ATTESTATION_MRENCLAVE")?,
+            mr_signer: measurement("ATTESTATION_MRSIGNER")?,
+            isv_prod_id: number("ATTESTATION_ISV_PROD_ID")?,
+            min_isv_svn: number("ATTESTATION_MIN_ISV_SVN")?.unwrap_or(0),
+        })
+    }
+
+    pub fn check(&self, body: &ReportBody) -> Result<()> {
+        if self.mr_enclave.is_none() && self.mr_signer.is_none() {
+            return Err(anyhow::anyhow!(
+                "The attestation policy pins neither MRENCLAVE nor MRSIGNER"
+            ));
+        }
+        if let Some(mr_enclave) = &self.mr_enclave {
+            if mr_enclave != &body.mr_enclave {
+                return Err(anyhow::anyhow!(
+                    "Unexpected MRENCLAVE {}",
+                    to_hex(&body.mr_enclave)
+                ));
+            }
+        }
+        if let Some(mr_signer) = &self.mr_signer {
+            if mr_signer != &body.mr_signer {
+                return Err(anyhow::anyhow!(
+                    "Unexpected MRSIGNER {}",
+                    to_hex(&body.mr_signer)
+                ));
+            }
+        }
+        if let Some(isv_prod_id) = self.isv_prod_id {
+            if isv_prod_id != body.isv_prod_id {
+                return Err(anyhow::anyhow!(
+                    "Unexpected ISV product id {}",
+                    body.isv_prod_id
+                ));
+            }
+        }
+        if body.isv_svn < self.min_isv_svn {
+            return Err(anyhow::anyhow!(
+                "ISV SVN {} is older than the minimum {}",
+                body.isv_svn,
+                self.min_isv_svn
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Report data that binds `public_key` and the `nonce` of the verifier to the
+/// evidence: a length-prefixed hash of both, zero padded.
+pub fn bind_report_data(public_key: &[u8], nonce: &[u8]) -> [u8; REPORT_DATA_LEN] {
+    let mut hasher = blake3::Hasher::new_derive_key(REPORT_DATA_CONTEXT);
+    hasher.update(&(public_key.len() as u64).to_le_bytes());
+    hasher.update(public_key);
+    hasher.update(&(nonce.len() as u64).to_le_bytes());
+    hasher.update(nonce);
+    let mut report_data = [0u8; REPORT_DATA_LEN];
+    report_data[..32].copy_from_slice(hasher.finalize().as_bytes());
+    report_data
+}
+
+/// Verifies `evidence`, checks it against `policy` and that it binds `public_key`
+/// and `nonce`. Returns the attested report body.
+pub fn verify_evidence(
+    verifier: &dyn QuoteVerifier,
+    policy: &AttestationPolicy,
+    evidence: &Evidence,
+    public_key: &[u8],
+    nonce: &[u8],
+) -> Result<ReportBody> {
+    let body = verifier.verify(evidence)?;
+    policy.check(&body)?;
+    if body.report_data != bind_report_data(public_key, nonce) {
+        return Err(anyhow::anyhow!(
+            "The evidence doesn't bind the session public key"
+        ));
+    }
+    Ok(body)
+}
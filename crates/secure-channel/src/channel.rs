@@ -0,0 +1,210 @@
+use aes_gcm_siv::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng},
+    Aes256GcmSiv, Nonce,
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::attestation::{
+    bind_report_data, verify_evidence, AttestationPolicy, Evidence, QuoteProvider, QuoteVerifier,
+    ReportBody,
+};
+
+// Frames are length-prefixed, and the length is read before anything is authenticated.
+pub const MAX_FRAME_LEN: usize = 64 << 20;
+
+const CLIENT_KEY_CONTEXT: &str = "clique_task 2024 channel client to server key";
+const SERVER_KEY_CONTEXT: &str = "clique_task 2024 channel server to client key";
+
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+#[derive(Serialize, Deserialize)]
+struct ClientHello {
+    public_key: [u8; 32],
+    nonce: [u8; 32],
+}
+
+#[derive(Serialize, Deserialize)]
+struct ServerHello {
+    public_key: [u8; 32],
+    evidence: Evidence,
+}
+
+// Keys of an attested session. Frames are numbered per direction and the number is
+// the nonce, so a dropped, replayed or reordered frame fails to decrypt.
+struct Session {
+    send: Aes256GcmSiv,
+    recv: Aes256GcmSiv,
+    send_seq: u64,
+    recv_seq: u64,
+}
+
+/// A connection between client and server that carries length-prefixed frames.
+pub struct Channel {
+    stream: Box<dyn Stream>,
+    session: Option<Session>,
+}
+
+impl Channel {
+    /// Frames go over `stream` as they are, which is only safe if the stream is
+    /// already protected, e.g. by TLS.
+    pub fn plain(stream: impl Stream + 'static) -> Self {
+        Self {
+            stream: Box::new(stream),
+            session: None,
+        }
+    }
+
+    /// Client side of the attested handshake: sends an ephemeral public key and a
+    /// nonce, and accepts the server only if its evidence satisfies `policy` and
+    /// binds the server's ephemeral public key to the nonce. Returns the channel
+    /// encrypted under keys only that enclave knows, and the attested report body.
+    pub async fn connect_attested(
+        stream: impl Stream + 'static,
+        verifier: &dyn QuoteVerifier,
+        policy: &AttestationPolicy,
+    ) -> Result<(Self, ReportBody)> {
+        let mut channel = Self::plain(stream);
+
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let mut nonce = [0u8; 32];
+        OsRng.fill_bytes(&mut nonce);
+        let client_hello = serde_json::to_vec(&ClientHello {
+            public_key: PublicKey::from(&secret).to_bytes(),
+            nonce,
+        })?;
+        channel.send(&client_hello).await?;
+
+        let server_hello_bytes = channel.recv().await?;
+        let server_hello: ServerHello = serde_json::from_slice(&server_hello_bytes)?;
+        let body = verify_evidence(
+            verifier,
+            policy,
+            &server_hello.evidence,
+            &server_hello.public_key,
+            &nonce,
+        )?;
+
+        let shared = secret.diffie_hellman(&PublicKey::from(server_hello.public_key));
+        if !shared.was_contributory() {
+            return Err(anyhow::anyhow!("Invalid server public key"));
+        }
+        let transcript = transcript(&client_hello, &server_hello_bytes);
+        channel.session = Some(Session {
+            send: session_cipher(CLIENT_KEY_CONTEXT, shared.as_bytes(), &transcript),
+            recv: session_cipher(SERVER_KEY_CONTEXT, shared.as_bytes(), &transcript),
+            send_seq: 0,
+            recv_seq: 0,
+        });
+        Ok((channel, body))
+    }
+
+    /// Server side of the attested handshake, see `connect_attested`.
+    pub async fn accept_attested(
+        stream: impl Stream + 'static,
+        quote_provider: &dyn QuoteProvider,
+    ) -> Result<Self> {
+        let mut channel = Self::plain(stream);
+
+        let client_hello_bytes = channel.recv().await?;
+        let client_hello: ClientHello = serde_json::from_slice(&client_hello_bytes)?;
+
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&secret).to_bytes();
+        let evidence = quote_provider.quote(&bind_report_data(&public_key, &client_hello.nonce))?;
+        let server_hello = serde_json::to_vec(&ServerHello {
+            public_key,
+            evidence,
+        })?;
+        channel.send(&server_hello).await?;
+
+        let shared = secret.diffie_hellman(&PublicKey::from(client_hello.public_key));
+        if !shared.was_contributory() {
+            return Err(anyhow::anyhow!("Invalid client public key"));
+        }
+        let transcript = transcript(&client_hello_bytes, &server_hello);
+        channel.session = Some(Session {
+            send: session_cipher(SERVER_KEY_CONTEXT, shared.as_bytes(), &transcript),
+            recv: session_cipher(CLIENT_KEY_CONTEXT, shared.as_bytes(), &transcript),
+            send_seq: 0,
+            recv_seq: 0,
+        });
+        Ok(channel)
+    }
+
+    pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        let sealed;
+        let frame = match self.session.as_mut() {
+            Some(session) => {
+                sealed = session
+                    .send
+                    .encrypt(&seq_nonce(session.send_seq), data)
+                    .map_err(|_| anyhow::anyhow!("aes_gcm_siv encrypt failed"))?;
+                session.send_seq += 1;
+                &sealed
+            }
+            None => data,
+        };
+        if frame.len() > MAX_FRAME_LEN {
+            return Err(anyhow::anyhow!(
+                "Frame of {} bytes is too long",
+                frame.len()
+            ));
+        }
+        self.stream.write_u64(frame.len() as u64).await?;
+        self.stream.write_all(frame).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    pub async fn recv(&mut self) -> Result<Vec<u8>> {
+        let len = self.stream.read_u64().await?;
+        if len > MAX_FRAME_LEN as u64 {
+            return Err(anyhow::anyhow!("Frame of {} bytes is too long", len));
+        }
+        let mut frame = vec![0u8; len as usize];
+        self.stream.read_exact(&mut frame).await?;
+        match self.session.as_mut() {
+            Some(session) => {
+                let data = session
+                    .recv
+                    .decrypt(&seq_nonce(session.recv_seq), frame.as_slice())
+                    .map_err(|_| anyhow::anyhow!("Invalid frame"))?;
+                session.recv_seq += 1;
+                Ok(data)
+            }
+            None => Ok(frame),
+        }
+    }
+
+    pub async fn shutdown(&mut self) -> Result<()> {
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+}
+
+fn transcript(client_hello: &[u8], server_hello: &[u8]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&(client_hello.len() as u64).to_le_bytes());
+    hasher.update(client_hello);
+    hasher.update(&(server_hello.len() as u64).to_le_bytes());
+    hasher.update(server_hello);
+    *hasher.finalize().as_bytes()
+}
+
+fn session_cipher(context: &str, shared: &[u8], transcript: &[u8]) -> Aes256GcmSiv {
+    let mut hasher = blake3::Hasher::new_derive_key(context);
+    hasher.update(shared);
+    hasher.update(transcript);
+    Aes256GcmSiv::new_from_slice(hasher.finalize().as_bytes()).unwrap()
+}
+
+fn seq_nonce(seq: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&seq.to_le_bytes());
+    Nonce::from(nonce)
+}
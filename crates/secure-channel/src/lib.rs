@@ -0,0 +1,269 @@
+use anyhow::Result;
+
+mod attestation;
+mod channel;
+mod ratls;
+mod tls;
+
+pub use self::attestation::{
+    bind_report_data, verify_evidence, AttestationPolicy, Evidence, MockQuoteProvider,
+    MockQuoteVerifier, QuoteProvider, QuoteVerifier, ReportBody, MOCK_MR_ENCLAVE, MOCK_MR_SIGNER,
+    REPORT_BODY_LEN, REPORT_DATA_LEN,
+};
+pub use self::channel::{Channel, Stream, MAX_FRAME_LEN};
+pub use self::ratls::{
+    crypto_provider, generate_ra_tls_cert, ra_tls_client_config, ra_tls_server_config,
+    RaTlsServerVerifier, EVIDENCE_OID, RA_TLS_SERVER_NAME,
+};
+pub use self::tls::{
+    cert_fingerprint, load_certs, load_private_key, parse_fingerprints, ClientAuth,
+    ClientTlsConfig, PeerIdentity, ServerTlsConfig, DEFAULT_TLS_SERVER_NAME,
+};
+
+/// How client and server protect their connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// The attested handshake of `Channel` over plain TCP.
+    Attested,
+    /// TLS with an attested, self-signed server certificate.
+    RaTls,
+    /// TLS with a configured server certificate, see `ServerTlsConfig`.
+    Tls,
+}
+
+impl Transport {
+    /// Reads `TRANSPORT` (`ra-tls`, `tls` or `attested`, default `ra-tls`).
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("TRANSPORT").as_deref() {
+            Err(_) | Ok("ra-tls") => Ok(Transport::RaTls),
+            Ok("tls") => Ok(Transport::Tls),
+            Ok("attested") => Ok(Transport::Attested),
+            Ok(name) => Err(anyhow::anyhow!("Unknown TRANSPORT: {}", name)),
+        }
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    let invalid = || anyhow::anyhow!("Invalid hex: {}", hex);
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [hi, lo] => {
+                u8::from_str_radix(std::str::from_utf8(&[*hi, *lo])?, 16).map_err(|_| invalid())
+            }
+            _ => Err(invalid()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn mock_policy() -> AttestationPolicy {
+        AttestationPolicy {
+            mr_enclave: Some(MOCK_MR_ENCLAVE),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_report_body_layout() -> Result<()> {
+        let body = ReportBody {
+            mr_enclave: [1; 32],
+            mr_signer: [2; 32],
+            isv_prod_id: 3,
+            isv_svn: 4,
+            report_data: [5; REPORT_DATA_LEN],
+        };
+        let bytes = body.to_bytes();
+        assert_eq!(bytes.len(), REPORT_BODY_LEN);
+        assert_eq!(&bytes[64..96], &[1; 32]);
+        assert_eq!(&bytes[128..160], &[2; 32]);
+        assert_eq!(&bytes[256..260], &[3, 0, 4, 0]);
+        assert_eq!(ReportBody::from_bytes(&bytes)?, body);
+        Ok(())
+    }
+
+    #[test]
+    fn test_policy() -> Result<()> {
+        let body = MockQuoteProvider::default()
+            .quote(&[0; REPORT_DATA_LEN])?
+            .report_body()?;
+        mock_policy().check(&body)?;
+
+        assert!(AttestationPolicy::default().check(&body).is_err());
+        let policy = AttestationPolicy {
+            mr_signer: Some([0; 32]),
+            ..mock_policy()
+        };
+        assert!(policy.check(&body).is_err());
+        let policy = AttestationPolicy {
+            min_isv_svn: 1,
+            ..mock_policy()
+        };
+        assert!(policy.check(&body).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_evidence_binds_public_key() -> Result<()> {
+        let evidence = MockQuoteProvider::default().quote(&bind_report_data(b"key", b"nonce"))?;
+        let policy = mock_policy();
+        verify_evidence(&MockQuoteVerifier, &policy, &evidence, b"key", b"nonce")?;
+        assert!(
+            verify_evidence(&MockQuoteVerifier, &policy, &evidence, b"other", b"nonce").is_err()
+        );
+        assert!(
+            verify_evidence(&MockQuoteVerifier, &policy, &evidence, b"key", b"replay").is_err()
+        );
+        assert!(MockQuoteVerifier
+            .verify(&Evidence::SgxQuote(vec![0; 1024]))
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_attested_channel() -> Result<()> {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut channel =
+                Channel::accept_attested(server, &MockQuoteProvider::default()).await?;
+            let query = channel.recv().await?;
+            channel.send(&[query.as_slice(), b" ok"].concat()).await?;
+            Ok(()) as Result<()>
+        });
+
+        let (mut channel, body) =
+            Channel::connect_attested(client, &MockQuoteVerifier, &mock_policy()).await?;
+        assert_eq!(body.mr_enclave, MOCK_MR_ENCLAVE);
+        channel.send(b"MATCH (n) RETURN n").await?;
+        assert_eq!(channel.recv().await?, b"MATCH (n) RETURN n ok");
+        server.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_attested_channel_rejects_unexpected_enclave() -> Result<()> {
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let provider = MockQuoteProvider::new([0; 32], MOCK_MR_SIGNER, 0);
+            Channel::accept_attested(server, &provider).await
+        });
+
+        let res = Channel::connect_attested(client, &MockQuoteVerifier, &mock_policy()).await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ra_tls_cert() -> Result<()> {
+        let now = tokio_rustls::rustls::pki_types::UnixTime::now();
+        let (cert, _) = generate_ra_tls_cert(&MockQuoteProvider::default())?;
+        RaTlsServerVerifier::new(Arc::new(MockQuoteVerifier), mock_policy())
+            .verify_cert(&cert, now)?;
+
+        let policy = AttestationPolicy {
+            mr_enclave: Some([0; 32]),
+            ..Default::default()
+        };
+        let verifier = RaTlsServerVerifier::new(Arc::new(MockQuoteVerifier), policy);
+        assert!(verifier.verify_cert(&cert, now).is_err());
+
+        // evidence of one certificate doesn't vouch for the key of another
+        let (other, _) = generate_ra_tls_cert(&MockQuoteProvider::default())?;
+        let mut forged = other.to_vec();
+        let other_key = x509_parser::parse_x509_certificate(&other)?
+            .1
+            .public_key()
+            .raw
+            .to_vec();
+        let key = x509_parser::parse_x509_certificate(&cert)?
+            .1
+            .public_key()
+            .raw
+            .to_vec();
+        let at = forged
+            .windows(other_key.len())
+            .position(|x| x == other_key.as_slice())
+            .unwrap();
+        forged[at..at + key.len()].copy_from_slice(&key);
+        let verifier = RaTlsServerVerifier::new(Arc::new(MockQuoteVerifier), mock_policy());
+        assert!(verifier.verify_cert(&forged.into(), now).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_peer_identity() -> Result<()> {
+        let key = rcgen::KeyPair::generate()?;
+        let mut params = rcgen::CertificateParams::new(vec!["alice.example.com".to_string()])?;
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "alice");
+        params
+            .subject_alt_names
+            .push(rcgen::SanType::Rfc822Name("alice@example.com".try_into()?));
+        let cert = params.self_signed(&key)?;
+
+        let identity = PeerIdentity::from_cert(cert.der())?;
+        assert_eq!(identity.common_name.as_deref(), Some("alice"));
+        assert_eq!(
+            identity.alt_names,
+            vec!["alice.example.com", "alice@example.com"]
+        );
+        assert_eq!(identity.fingerprint, cert_fingerprint(cert.der()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ra_tls_channel() -> Result<()> {
+        let server_config = ra_tls_server_config(
+            &MockQuoteProvider::default(),
+            tokio_rustls::rustls::server::WebPkiClientVerifier::no_client_auth(),
+        )?;
+        let client_config = ra_tls_client_config(Arc::new(MockQuoteVerifier), mock_policy(), None)?;
+
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+            let mut channel = Channel::plain(acceptor.accept(server).await?);
+            let query = channel.recv().await?;
+            channel.send(&[query.as_slice(), b" ok"].concat()).await?;
+            Ok(()) as Result<()>
+        });
+
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+        let name = RA_TLS_SERVER_NAME.try_into()?;
+        let mut channel = Channel::plain(connector.connect(name, client).await?);
+        channel.send(b"MATCH (n) RETURN n").await?;
+        assert_eq!(channel.recv().await?, b"MATCH (n) RETURN n ok");
+        server.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ra_tls_rejects_unexpected_enclave() -> Result<()> {
+        let provider = MockQuoteProvider::new([0; 32], MOCK_MR_SIGNER, 0);
+        let server_config = ra_tls_server_config(
+            &provider,
+            tokio_rustls::rustls::server::WebPkiClientVerifier::no_client_auth(),
+        )?;
+        let client_config = ra_tls_client_config(Arc::new(MockQuoteVerifier), mock_policy(), None)?;
+
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+            acceptor.accept(server).await
+        });
+
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+        let name = RA_TLS_SERVER_NAME.try_into()?;
+        assert!(connector.connect(name, client).await.is_err());
+        Ok(())
+    }
+}
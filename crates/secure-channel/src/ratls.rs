@@ -0,0 +1,191 @@
+use anyhow::Result;
+use rcgen::{CertificateParams, CustomExtension, KeyPair};
+use tokio_rustls::rustls::{
+    self,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+    server::danger::ClientCertVerifier,
+    ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme,
+};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::attestation::{
+    bind_report_data, verify_evidence, AttestationPolicy, Evidence, QuoteProvider, QuoteVerifier,
+};
+
+use std::sync::Arc;
+
+/// Private OID of the certificate extension that carries the serialized `Evidence`.
+pub const EVIDENCE_OID: &[u64] = &[1, 3, 6, 1, 4, 1, 57264, 1, 1];
+const EVIDENCE_OID_STR: &str = "1.3.6.1.4.1.57264.1.1";
+
+// The certificate is made at startup, before any client is known, so its evidence
+// binds the public key to a fixed label instead of a client nonce.
+const RA_TLS_NONCE: &[u8] = b"clique_task ra-tls";
+
+pub const RA_TLS_SERVER_NAME: &str = "localhost";
+
+/// The crypto provider of every TLS config made here.
+pub fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Generates an ephemeral key pair and a self-signed certificate for it, with
+/// evidence from `quote_provider` over the public key in the evidence extension.
+/// The private key never leaves the enclave.
+pub fn generate_ra_tls_cert(
+    quote_provider: &dyn QuoteProvider,
+) -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    let key_pair = KeyPair::generate()?;
+    let evidence =
+        quote_provider.quote(&bind_report_data(&key_pair.public_key_der(), RA_TLS_NONCE))?;
+
+    let mut params = CertificateParams::new(vec![RA_TLS_SERVER_NAME.to_string()])?;
+    params
+        .custom_extensions
+        .push(CustomExtension::from_oid_content(
+            EVIDENCE_OID,
+            serde_json::to_vec(&evidence)?,
+        ));
+    let cert = params.self_signed(&key_pair)?;
+
+    let key = PrivatePkcs8KeyDer::from(key_pair.serialize_der());
+    Ok((cert.der().clone(), key.into()))
+}
+
+/// Server side of RA-TLS: presents a freshly generated attested certificate.
+/// Clients authenticate as in plain TLS, see `ServerTlsConfig::client_verifier`.
+pub fn ra_tls_server_config(
+    quote_provider: &dyn QuoteProvider,
+    client_verifier: Arc<dyn ClientCertVerifier>,
+) -> Result<ServerConfig> {
+    let (cert, key) = generate_ra_tls_cert(quote_provider)?;
+    Ok(ServerConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(client_verifier)
+        .with_single_cert(vec![cert], key)?)
+}
+
+/// Client side of RA-TLS: trusts the server certificate only through its evidence.
+pub fn ra_tls_client_config(
+    quote_verifier: Arc<dyn QuoteVerifier>,
+    policy: AttestationPolicy,
+    client_cert: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+) -> Result<ClientConfig> {
+    let verifier = RaTlsServerVerifier::new(quote_verifier, policy);
+    let builder = ClientConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    Ok(match client_cert {
+        Some((certs, key)) => builder.with_client_auth_cert(certs, key)?,
+        None => builder.with_no_client_auth(),
+    })
+}
+
+/// Accepts a server certificate if its evidence satisfies the policy and binds the
+/// certificate's public key, instead of checking a CA chain. The TLS handshake
+/// then proves that the server holds the private key, which only the attested
+/// enclave has.
+pub struct RaTlsServerVerifier {
+    quote_verifier: Arc<dyn QuoteVerifier>,
+    policy: AttestationPolicy,
+    provider: Arc<CryptoProvider>,
+}
+
+impl RaTlsServerVerifier {
+    pub fn new(quote_verifier: Arc<dyn QuoteVerifier>, policy: AttestationPolicy) -> Self {
+        Self {
+            quote_verifier,
+            policy,
+            provider: crypto_provider(),
+        }
+    }
+
+    /// Checks the evidence of `cert`, see `RaTlsServerVerifier`.
+    pub fn verify_cert(&self, cert: &CertificateDer<'_>, now: UnixTime) -> Result<()> {
+        let (_, cert) = X509Certificate::from_der(cert.as_ref())
+            .map_err(|e| anyhow::anyhow!("Invalid certificate: {}", e))?;
+        let now = now.as_secs() as i64;
+        if now < cert.validity().not_before.timestamp()
+            || now > cert.validity().not_after.timestamp()
+        {
+            return Err(anyhow::anyhow!(
+                "The certificate is expired or not valid yet"
+            ));
+        }
+
+        let extension = cert
+            .extensions()
+            .iter()
+            .find(|x| x.oid.to_id_string() == EVIDENCE_OID_STR)
+            .ok_or_else(|| anyhow::anyhow!("The certificate carries no attestation evidence"))?;
+        let evidence: Evidence = serde_json::from_slice(extension.value)?;
+        verify_evidence(
+            self.quote_verifier.as_ref(),
+            &self.policy,
+            &evidence,
+            cert.public_key().raw,
+            RA_TLS_NONCE,
+        )?;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for RaTlsServerVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RaTlsServerVerifier")
+            .field("policy", &self.policy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ServerCertVerifier for RaTlsServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verify_cert(end_entity, now)
+            .map_err(|e| rustls::Error::General(format!("RA-TLS: {}", e)))?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
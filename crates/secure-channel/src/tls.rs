@@ -0,0 +1,443 @@
+use anyhow::Result;
+use rustls_pemfile::{certs, read_all, Item};
+use tokio_rustls::rustls::{
+    self,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    client::WebPkiServerVerifier,
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::danger::{ClientCertVerified, ClientCertVerifier},
+    server::WebPkiClientVerifier,
+    ClientConfig, DigitallySignedStruct, DistinguishedName, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::{from_hex, ratls::crypto_provider, to_hex};
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const DEFAULT_TLS_SERVER_NAME: &str = "localhost";
+
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = certs(&mut BufReader::new(File::open(path)?)).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!(
+            "There is no certificate in {}",
+            path.display()
+        ));
+    }
+    Ok(certs)
+}
+
+pub fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    for item in read_all(&mut BufReader::new(File::open(path)?)) {
+        match item? {
+            Item::Pkcs1Key(key) => return Ok(key.into()),
+            Item::Pkcs8Key(key) => return Ok(key.into()),
+            Item::Sec1Key(key) => return Ok(key.into()),
+            _ => continue,
+        }
+    }
+    Err(anyhow::anyhow!(
+        "There is no private key in {}",
+        path.display()
+    ))
+}
+
+/// SHA-256 of the DER certificate, the value pinned in fingerprint lists.
+pub fn cert_fingerprint(cert: &CertificateDer<'_>) -> [u8; 32] {
+    ring::digest::digest(&ring::digest::SHA256, cert.as_ref())
+        .as_ref()
+        .try_into()
+        .unwrap()
+}
+
+/// Parses a comma separated list of hex SHA-256 fingerprints, colons allowed.
+pub fn parse_fingerprints(list: &str) -> Result<Vec<[u8; 32]>> {
+    list.split(',')
+        .map(|x| x.trim().replace(':', ""))
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            from_hex(&x)?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Fingerprint {} isn't 32 bytes", x))
+        })
+        .collect()
+}
+
+/// Who a verified certificate names: the common name of its subject, its DNS,
+/// email and URI subject alternative names, and its fingerprint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdentity {
+    pub common_name: Option<String>,
+    pub alt_names: Vec<String>,
+    pub fingerprint: [u8; 32],
+}
+
+impl PeerIdentity {
+    pub fn from_cert(cert: &CertificateDer<'_>) -> Result<Self> {
+        let (_, parsed) = X509Certificate::from_der(cert.as_ref())
+            .map_err(|e| anyhow::anyhow!("Invalid certificate: {}", e))?;
+        let common_name = match parsed.subject().iter_common_name().next() {
+            Some(cn) => Some(cn.as_str()?.to_string()),
+            None => None,
+        };
+        let mut alt_names = vec![];
+        if let Some(san) = parsed.subject_alternative_name()? {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(x) | GeneralName::RFC822Name(x) | GeneralName::URI(x) => {
+                        alt_names.push(x.to_string())
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(Self {
+            common_name,
+            alt_names,
+            fingerprint: cert_fingerprint(cert),
+        })
+    }
+}
+
+fn load_roots(path: &Path) -> Result<Arc<RootCertStore>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(Arc::new(roots))
+}
+
+fn check_pin(pins: &[[u8; 32]], cert: &CertificateDer<'_>) -> Result<(), rustls::Error> {
+    let fingerprint = cert_fingerprint(cert);
+    if pins.contains(&fingerprint) {
+        Ok(())
+    } else {
+        Err(rustls::Error::General(format!(
+            "Certificate fingerprint {} isn't pinned",
+            to_hex(&fingerprint)
+        )))
+    }
+}
+
+fn env_path(name: &str) -> Option<PathBuf> {
+    std::env::var_os(name).map(PathBuf::from)
+}
+
+fn env_fingerprints(name: &str) -> Result<Vec<[u8; 32]>> {
+    match std::env::var(name) {
+        Ok(list) => parse_fingerprints(&list),
+        Err(_) => Ok(vec![]),
+    }
+}
+
+/// Whether the server asks clients for a certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientAuth {
+    None,
+    Optional,
+    Required,
+}
+
+/// TLS settings of the server.
+///
+/// Client certificates are trusted if they chain to `client_ca_path`, or, in
+/// private-CA / self-signed mode, if their fingerprint is in `client_fingerprints`.
+/// With both set a certificate has to pass both checks.
+#[derive(Debug, Clone)]
+pub struct ServerTlsConfig {
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+    pub client_auth: ClientAuth,
+    pub client_ca_path: Option<PathBuf>,
+    pub client_fingerprints: Vec<[u8; 32]>,
+}
+
+impl ServerTlsConfig {
+    /// Reads `TLS_CERT_PATH`, `TLS_KEY_PATH`, `TLS_CLIENT_AUTH` (`none`,
+    /// `optional` or `required`, default `required` if a client CA or fingerprint
+    /// is set), `TLS_CLIENT_CA_PATH` and `TLS_CLIENT_FINGERPRINTS`.
+    pub fn from_env() -> Result<Self> {
+        let client_ca_path = env_path("TLS_CLIENT_CA_PATH");
+        let client_fingerprints = env_fingerprints("TLS_CLIENT_FINGERPRINTS")?;
+        let client_auth = match std::env::var("TLS_CLIENT_AUTH").as_deref() {
+            Ok("none") => ClientAuth::None,
+            Ok("optional") => ClientAuth::Optional,
+            Ok("required") => ClientAuth::Required,
+            Ok(x) => return Err(anyhow::anyhow!("Unknown TLS_CLIENT_AUTH: {}", x)),
+            Err(_) if client_ca_path.is_none() && client_fingerprints.is_empty() => {
+                ClientAuth::None
+            }
+            Err(_) => ClientAuth::Required,
+        };
+        Ok(Self {
+            cert_path: env_path("TLS_CERT_PATH"),
+            key_path: env_path("TLS_KEY_PATH"),
+            client_auth,
+            client_ca_path,
+            client_fingerprints,
+        })
+    }
+
+    pub fn client_verifier(&self) -> Result<Arc<dyn ClientCertVerifier>> {
+        if self.client_auth == ClientAuth::None {
+            return Ok(WebPkiClientVerifier::no_client_auth());
+        }
+        let inner = match &self.client_ca_path {
+            Some(path) => {
+                let builder = WebPkiClientVerifier::builder_with_provider(
+                    load_roots(path)?,
+                    crypto_provider(),
+                );
+                let builder = match self.client_auth {
+                    ClientAuth::Optional => builder.allow_unauthenticated(),
+                    _ => builder,
+                };
+                Some(builder.build()?)
+            }
+            None if self.client_fingerprints.is_empty() => {
+                return Err(anyhow::anyhow!(
+                    "Client auth needs a client CA or pinned client fingerprints"
+                ))
+            }
+            None => None,
+        };
+        Ok(Arc::new(PinnedClientCertVerifier {
+            inner,
+            pins: self.client_fingerprints.clone(),
+            mandatory: self.client_auth == ClientAuth::Required,
+            provider: crypto_provider(),
+        }))
+    }
+
+    /// The certificate and key of the server are required here, RA-TLS makes its own.
+    pub fn server_config(&self) -> Result<ServerConfig> {
+        let (Some(cert_path), Some(key_path)) = (&self.cert_path, &self.key_path) else {
+            return Err(anyhow::anyhow!("TLS needs TLS_CERT_PATH and TLS_KEY_PATH"));
+        };
+        Ok(ServerConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(self.client_verifier()?)
+            .with_single_cert(load_certs(cert_path)?, load_private_key(key_path)?)?)
+    }
+}
+
+/// TLS settings of the client.
+///
+/// The server certificate is trusted if it chains to `ca_path`, or, in
+/// private-CA / self-signed mode, if its fingerprint is in `server_fingerprints`.
+/// With both set a certificate has to pass both checks.
+#[derive(Debug, Clone)]
+pub struct ClientTlsConfig {
+    pub server_name: String,
+    pub ca_path: Option<PathBuf>,
+    pub server_fingerprints: Vec<[u8; 32]>,
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+}
+
+impl ClientTlsConfig {
+    /// Reads `TLS_SERVER_NAME` (default `localhost`), `TLS_CA_PATH`,
+    /// `TLS_SERVER_FINGERPRINTS`, and `TLS_CERT_PATH` / `TLS_KEY_PATH` of the
+    /// client certificate.
+    pub fn from_env() -> Result<Self> {
+        Ok(Self {
+            server_name: std::env::var("TLS_SERVER_NAME")
+                .unwrap_or_else(|_| DEFAULT_TLS_SERVER_NAME.to_string()),
+            ca_path: env_path("TLS_CA_PATH"),
+            server_fingerprints: env_fingerprints("TLS_SERVER_FINGERPRINTS")?,
+            cert_path: env_path("TLS_CERT_PATH"),
+            key_path: env_path("TLS_KEY_PATH"),
+        })
+    }
+
+    pub fn server_name(&self) -> Result<ServerName<'static>> {
+        Ok(ServerName::try_from(self.server_name.clone())?)
+    }
+
+    /// The client certificate chain and key, if configured.
+    pub fn client_cert(
+        &self,
+    ) -> Result<Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>> {
+        match (&self.cert_path, &self.key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                Ok(Some((load_certs(cert_path)?, load_private_key(key_path)?)))
+            }
+            (None, None) => Ok(None),
+            _ => Err(anyhow::anyhow!(
+                "A client certificate needs both TLS_CERT_PATH and TLS_KEY_PATH"
+            )),
+        }
+    }
+
+    pub fn server_verifier(&self) -> Result<Arc<dyn ServerCertVerifier>> {
+        let inner = match &self.ca_path {
+            Some(path) => Some(
+                WebPkiServerVerifier::builder_with_provider(load_roots(path)?, crypto_provider())
+                    .build()?,
+            ),
+            None if self.server_fingerprints.is_empty() => {
+                return Err(anyhow::anyhow!(
+                    "TLS needs a CA or pinned server fingerprints"
+                ))
+            }
+            None => None,
+        };
+        Ok(Arc::new(PinnedServerCertVerifier {
+            inner,
+            pins: self.server_fingerprints.clone(),
+            provider: crypto_provider(),
+        }))
+    }
+
+    pub fn client_config(&self) -> Result<ClientConfig> {
+        let builder = ClientConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(self.server_verifier()?);
+        Ok(match self.client_cert()? {
+            Some((certs, key)) => builder.with_client_auth_cert(certs, key)?,
+            None => builder.with_no_client_auth(),
+        })
+    }
+}
+
+// Checks the chain with `inner` if there is a CA, then the fingerprint if there
+// are pins. webpki rejects a self-signed certificate as end entity, so
+// self-signed peers are trusted by their pinned fingerprint alone.
+#[derive(Debug)]
+struct PinnedServerCertVerifier {
+    inner: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<[u8; 32]>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedServerCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(inner) = &self.inner {
+            inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+        if !self.pins.is_empty() {
+            check_pin(&self.pins, end_entity)?;
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+// The client side counterpart of `PinnedServerCertVerifier`.
+#[derive(Debug)]
+struct PinnedClientCertVerifier {
+    inner: Option<Arc<dyn ClientCertVerifier>>,
+    pins: Vec<[u8; 32]>,
+    mandatory: bool,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ClientCertVerifier for PinnedClientCertVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        match &self.inner {
+            Some(inner) => inner.root_hint_subjects(),
+            None => &[],
+        }
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        if let Some(inner) = &self.inner {
+            inner.verify_client_cert(end_entity, intermediates, now)?;
+        }
+        if !self.pins.is_empty() {
+            check_pin(&self.pins, end_entity)?;
+        }
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.mandatory
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
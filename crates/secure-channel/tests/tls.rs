@@ -0,0 +1,205 @@
+use anyhow::Result;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+use secure_channel::{
+    cert_fingerprint, load_certs, Channel, ClientAuth, ClientTlsConfig, ServerTlsConfig,
+    DEFAULT_TLS_SERVER_NAME,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+struct Certs {
+    dir: PathBuf,
+}
+
+impl Certs {
+    fn new(name: &str) -> Result<Self> {
+        let dir = std::env::temp_dir().join(format!(
+            "secure-channel-tls-{}-{}",
+            std::process::id(),
+            name
+        ));
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    fn write(&self, name: &str, cert: &Certificate, key: &KeyPair) -> Result<()> {
+        std::fs::write(self.path(&format!("{}.crt", name)), cert.pem())?;
+        std::fs::write(self.path(&format!("{}.key", name)), key.serialize_pem())?;
+        Ok(())
+    }
+
+    /// A private CA `ca` that issues `server` and `client`.
+    fn private_ca(name: &str) -> Result<Self> {
+        let certs = Self::new(name)?;
+        let ca_key = KeyPair::generate()?;
+        let mut params = CertificateParams::new(vec![])?;
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key)?;
+        certs.write("ca", &ca, &ca_key)?;
+
+        for (name, san) in [("server", DEFAULT_TLS_SERVER_NAME), ("client", "alice")] {
+            let key = KeyPair::generate()?;
+            let cert =
+                CertificateParams::new(vec![san.to_string()])?.signed_by(&key, &ca, &ca_key)?;
+            certs.write(name, &cert, &key)?;
+        }
+        Ok(certs)
+    }
+
+    /// Self-signed `server` and `client`, trusted by fingerprint.
+    fn self_signed(name: &str) -> Result<Self> {
+        let certs = Self::new(name)?;
+        for (name, san) in [("server", DEFAULT_TLS_SERVER_NAME), ("client", "alice")] {
+            let key = KeyPair::generate()?;
+            let cert = CertificateParams::new(vec![san.to_string()])?.self_signed(&key)?;
+            certs.write(name, &cert, &key)?;
+        }
+        Ok(certs)
+    }
+
+    fn fingerprint(&self, name: &str) -> Result<[u8; 32]> {
+        Ok(cert_fingerprint(
+            &load_certs(&self.path(&format!("{}.crt", name)))?[0],
+        ))
+    }
+
+    fn server_config(&self, client_auth: ClientAuth) -> ServerTlsConfig {
+        ServerTlsConfig {
+            cert_path: Some(self.path("server.crt")),
+            key_path: Some(self.path("server.key")),
+            client_auth,
+            client_ca_path: None,
+            client_fingerprints: vec![],
+        }
+    }
+
+    fn client_config(&self, with_cert: bool) -> ClientTlsConfig {
+        ClientTlsConfig {
+            server_name: DEFAULT_TLS_SERVER_NAME.to_string(),
+            ca_path: None,
+            server_fingerprints: vec![],
+            cert_path: with_cert.then(|| self.path("client.crt")),
+            key_path: with_cert.then(|| self.path("client.key")),
+        }
+    }
+}
+
+impl Drop for Certs {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+// Runs an echo server over TLS on a local port and sends one query from a client.
+async fn round_trip(server: &ServerTlsConfig, client: &ClientTlsConfig) -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let acceptor = TlsAcceptor::from(Arc::new(server.server_config()?));
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let mut channel = Channel::plain(acceptor.accept(stream).await?);
+        let query = channel.recv().await?;
+        channel.send(&[query.as_slice(), b" ok"].concat()).await?;
+        Ok(()) as Result<()>
+    });
+
+    let connector = TlsConnector::from(Arc::new(client.client_config()?));
+    let stream = TcpStream::connect(addr).await?;
+    let mut channel = Channel::plain(connector.connect(client.server_name()?, stream).await?);
+    channel.send(b"MATCH (n) RETURN n").await?;
+    let reply = channel.recv().await;
+    let served = server.await?;
+    assert_eq!(reply?, b"MATCH (n) RETURN n ok");
+    served
+}
+
+#[tokio::test]
+async fn test_private_ca_mutual_tls() -> Result<()> {
+    let certs = Certs::private_ca("private-ca")?;
+
+    let server = ServerTlsConfig {
+        client_ca_path: Some(certs.path("ca.crt")),
+        ..certs.server_config(ClientAuth::Required)
+    };
+    let client = ClientTlsConfig {
+        ca_path: Some(certs.path("ca.crt")),
+        ..certs.client_config(true)
+    };
+    round_trip(&server, &client).await?;
+
+    // a required client certificate can't be skipped
+    let anonymous = ClientTlsConfig {
+        ca_path: Some(certs.path("ca.crt")),
+        ..certs.client_config(false)
+    };
+    assert!(round_trip(&server, &anonymous).await.is_err());
+
+    // but can with optional client auth
+    let server = ServerTlsConfig {
+        client_ca_path: Some(certs.path("ca.crt")),
+        ..certs.server_config(ClientAuth::Optional)
+    };
+    round_trip(&server, &anonymous).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_private_ca_rejects_other_ca() -> Result<()> {
+    let certs = Certs::private_ca("ca-a")?;
+    let other = Certs::private_ca("ca-b")?;
+
+    let server = certs.server_config(ClientAuth::None);
+    let client = ClientTlsConfig {
+        ca_path: Some(other.path("ca.crt")),
+        ..certs.client_config(false)
+    };
+    assert!(round_trip(&server, &client).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_self_signed_pinned_fingerprints() -> Result<()> {
+    let certs = Certs::self_signed("self-signed")?;
+
+    let server = ServerTlsConfig {
+        client_fingerprints: vec![certs.fingerprint("client")?],
+        ..certs.server_config(ClientAuth::Required)
+    };
+    let client = ClientTlsConfig {
+        server_fingerprints: vec![certs.fingerprint("server")?],
+        ..certs.client_config(true)
+    };
+    round_trip(&server, &client).await?;
+
+    let wrong_pin = ClientTlsConfig {
+        server_fingerprints: vec![[0; 32]],
+        ..certs.client_config(true)
+    };
+    assert!(round_trip(&server, &wrong_pin).await.is_err());
+
+    let unpinned_client = ServerTlsConfig {
+        client_fingerprints: vec![[0; 32]],
+        ..certs.server_config(ClientAuth::Required)
+    };
+    assert!(round_trip(&unpinned_client, &client).await.is_err());
+    Ok(())
+}
+
+#[test]
+fn test_config_needs_trust_anchor() -> Result<()> {
+    let certs = Certs::self_signed("no-anchor")?;
+    assert!(certs.client_config(false).client_config().is_err());
+    assert!(certs
+        .server_config(ClientAuth::Required)
+        .server_config()
+        .is_err());
+    certs.server_config(ClientAuth::None).server_config()?;
+    Ok(())
+}
@@ -11,7 +11,16 @@ pub struct CypherQuery {
     pub set_list: Option<Vec<Item>>,
     pub remove_list: Option<Vec<Item>>,
     pub delete_list: Option<(Vec<Item>, bool)>,
+    pub where_list: Option<Vec<Predicate>>,
     pub find_shortest_path: bool,
+    pub union: Option<(Box<CypherQuery>, bool)>,
+    /// Explains the whole query, a part of a UNION can't be explained on its own.
+    #[serde(default)]
+    pub explain: bool,
+    /// Tenant the query runs in, `None` for the tenant of the caller. It is not
+    /// part of the query string.
+    #[serde(default)]
+    pub tenant: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -25,7 +34,10 @@ pub struct CypherQueryBuilder {
     set_list: Option<Vec<Item>>,
     remove_list: Option<Vec<Item>>,
     delete_list: Option<(Vec<Item>, bool)>,
+    where_list: Option<Vec<Predicate>>,
     find_shortest_path: bool,
+    explain: bool,
+    tenant: Option<String>,
 }
 
 #[derive(Debug)]
@@ -35,6 +47,7 @@ pub enum CRUDtype {
     Update,
     Delete,
     FindShortestPath,
+    Union,
 }
 
 impl CypherQuery {
@@ -47,7 +60,52 @@ impl CypherQuery {
         Ok(serde_json::from_str(serialized)?)
     }
 
+    // UNION / UNION ALL are appended to the end of the chain, e.g. `a.union(b).union(c)`.
+    pub fn union(self, other: CypherQuery) -> CypherQuery {
+        self.combine(other, false)
+    }
+
+    pub fn union_all(self, other: CypherQuery) -> CypherQuery {
+        self.combine(other, true)
+    }
+
+    fn combine(mut self, other: CypherQuery, all: bool) -> CypherQuery {
+        self.union = Some(match self.union.take() {
+            Some((rest, is_all)) => (Box::new(rest.combine(other, all)), is_all),
+            None => (Box::new(other), all),
+        });
+        self
+    }
+
     pub fn to_query_string(&self) -> Result<String> {
+        let mut s = self.to_single_query_string()?;
+        if self.explain {
+            s = format!("EXPLAIN {}", s);
+        }
+        match &self.union {
+            Some((other, is_all)) => {
+                self.get_type()?;
+                Ok(format!(
+                    "{} {} {}",
+                    s,
+                    if *is_all { "UNION ALL" } else { "UNION" },
+                    other.to_query_string()?
+                ))
+            }
+            None => Ok(s),
+        }
+    }
+
+    fn to_single_query_string(&self) -> Result<String> {
+        self.check_where_list()?;
+        self.check_handles()?;
+        if self.has_handle() {
+            return Err(anyhow::anyhow!(
+                "Entity handles must be resolved before building the query string: {:?}",
+                self
+            ));
+        }
+
         match (
             &self.node,
             &self.relation,
@@ -60,24 +118,22 @@ impl CypherQuery {
             &self.delete_list,
             &self.find_shortest_path,
         ) {
-            (Some(node), None, None, false, true, _, None, None, None, false) => {
-                return Ok(format!(
-                    "CREATE {} {}",
-                    node.to_query_string(),
-                    self.to_return_query_string()?
-                ));
-            }
+            (Some(node), None, None, false, true, _, None, None, None, false) => Ok(format!(
+                "CREATE {} {}",
+                node.to_query_string(),
+                self.to_return_query_string()?
+            )),
             (Some(node), Some(r), Some(next_node), false, true, _, None, None, None, false) => {
-                return Ok(format!(
+                Ok(format!(
                     "CREATE {}-{}->{} {}",
                     node.to_query_string(),
                     r.to_query_string(),
                     next_node.to_query_string(),
                     self.to_return_query_string()?,
-                ));
+                ))
             }
             (Some(node), Some(r), Some(next_node), true, true, _, None, None, None, false) => {
-                return Ok(format!(
+                Ok(format!(
                     "MATCH {}, {} CREATE ({})-{}->({}) {}",
                     node.to_query_string(),
                     next_node.to_query_string(),
@@ -88,22 +144,22 @@ impl CypherQuery {
                         .var_name()
                         .ok_or_else(|| anyhow::anyhow!("Need var_name: {:?}", node))?,
                     self.to_return_query_string()?,
-                ));
-            }
-            (Some(node), None, None, true, false, Some(_), None, None, None, false) => {
-                return Ok(format!(
-                    "MATCH {} {}",
-                    node.to_query_string(),
-                    self.to_return_query_string()?
-                ));
+                ))
             }
+            (Some(node), None, None, true, false, Some(_), None, None, None, false) => Ok(format!(
+                "MATCH {}{} {}",
+                node.to_query_string(),
+                self.to_where_query_string()?,
+                self.to_return_query_string()?
+            )),
             (Some(node), None, Some(next_node), true, false, Some(_), None, None, None, false) => {
-                return Ok(format!(
-                    "MATCH {}, {} {}",
+                Ok(format!(
+                    "MATCH {}, {}{} {}",
                     node.to_query_string(),
                     next_node.to_query_string(),
+                    self.to_where_query_string()?,
                     self.to_return_query_string()?
-                ));
+                ))
             }
             (
                 Some(node),
@@ -116,26 +172,23 @@ impl CypherQuery {
                 None,
                 None,
                 false,
-            ) => {
-                return Ok(format!(
-                    "MATCH {}-{}->{} {}",
-                    node.to_query_string(),
-                    r.to_query_string(),
-                    next_node.to_query_string(),
-                    self.to_return_query_string()?
-                ));
-            }
-            (Some(node), None, None, true, false, _, _, _, None, false) => {
-                return Ok(format!(
-                    "MATCH {} {} {} {}",
-                    node.to_query_string(),
-                    self.to_remove_query_string()?,
-                    self.to_set_query_string()?,
-                    self.to_return_query_string()?
-                ));
-            }
+            ) => Ok(format!(
+                "MATCH {}-{}->{}{} {}",
+                node.to_query_string(),
+                r.to_query_string(),
+                next_node.to_query_string(),
+                self.to_where_query_string()?,
+                self.to_return_query_string()?
+            )),
+            (Some(node), None, None, true, false, _, _, _, None, false) => Ok(format!(
+                "MATCH {} {} {} {}",
+                node.to_query_string(),
+                self.to_remove_query_string()?,
+                self.to_set_query_string()?,
+                self.to_return_query_string()?
+            )),
             (Some(node), Some(r), Some(next_node), true, false, _, _, _, None, false) => {
-                return Ok(format!(
+                Ok(format!(
                     "MATCH {}-{}->{} {} {} {}",
                     node.to_query_string(),
                     r.to_query_string(),
@@ -143,43 +196,80 @@ impl CypherQuery {
                     self.to_remove_query_string()?,
                     self.to_set_query_string()?,
                     self.to_return_query_string()?
-                ));
-            }
-            (Some(node), None, None, true, false, _, None, None, Some(_), false) => {
-                return Ok(format!(
-                    "MATCH {} {} {}",
-                    node.to_query_string(),
-                    self.to_delete_query_string()?,
-                    self.to_return_query_string()?
-                ));
+                ))
             }
+            (Some(node), None, None, true, false, _, None, None, Some(_), false) => Ok(format!(
+                "MATCH {} {} {}",
+                node.to_query_string(),
+                self.to_delete_query_string()?,
+                self.to_return_query_string()?
+            )),
             (Some(node), Some(r), Some(next_node), true, false, _, None, None, Some(_), false) => {
-                return Ok(format!(
+                Ok(format!(
                     "MATCH {}-{}->{} {} {}",
                     node.to_query_string(),
                     r.to_query_string(),
                     next_node.to_query_string(),
                     self.to_delete_query_string()?,
                     self.to_return_query_string()?
-                ));
+                ))
             }
             (Some(node), None, Some(next_node), _, false, None, None, None, None, true) => {
-                return Ok(format!(
+                Ok(format!(
                     "FIND_SHORTEST_PATH {}, {}",
                     node.to_query_string(),
                     next_node.to_query_string()
-                ));
+                ))
+            }
+            _ => Err(anyhow::anyhow!(
+                "Invalid or unsupported cypher query: {:?}",
+                self
+            )),
+        }
+    }
+
+    pub fn get_type(&self) -> Result<CRUDtype> {
+        let crud_type = self.get_single_type()?;
+        let (other, is_all) = match &self.union {
+            Some((other, is_all)) => (other, *is_all),
+            None => return Ok(crud_type),
+        };
+
+        if other.explain {
+            return Err(anyhow::anyhow!(
+                "EXPLAIN applies to a whole UNION, not to one of its parts: {:?}",
+                self
+            ));
+        }
+        if !matches!(crud_type, CRUDtype::Read) {
+            return Err(anyhow::anyhow!(
+                "UNION can only combine read queries: {:?}",
+                self
+            ));
+        }
+        match other.get_type()? {
+            CRUDtype::Read => {}
+            CRUDtype::Union if other.union.as_ref().map(|(_, x)| *x) == Some(is_all) => {}
+            CRUDtype::Union => {
+                return Err(anyhow::anyhow!(
+                    "UNION and UNION ALL can't be mixed: {:?}",
+                    self
+                ))
             }
             _ => {
                 return Err(anyhow::anyhow!(
-                    "Invalid or unsupported cypher query: {:?}",
+                    "UNION can only combine read queries: {:?}",
                     self
                 ))
             }
         }
+        Ok(CRUDtype::Union)
     }
 
-    pub fn get_type(&self) -> Result<CRUDtype> {
+    fn get_single_type(&self) -> Result<CRUDtype> {
+        self.check_where_list()?;
+        self.check_handles()?;
+
         match (
             &self.node,
             &self.relation,
@@ -226,6 +316,84 @@ impl CypherQuery {
         }
     }
 
+    // WHERE is only supported for reads, i.e. MATCH ... RETURN without CREATE / SET / REMOVE / DELETE.
+    fn check_where_list(&self) -> Result<()> {
+        if self.where_list.is_some()
+            && (!self.use_match
+                || self.use_create
+                || self.return_list.is_none()
+                || self.set_list.is_some()
+                || self.remove_list.is_some()
+                || self.delete_list.is_some()
+                || self.find_shortest_path)
+        {
+            return Err(anyhow::anyhow!(
+                "WHERE can only be used in a read query: {:?}",
+                self
+            ));
+        }
+        // checked once here, so a full scan and the bucket index reject the same values
+        if let Some(predicate) = self
+            .where_list
+            .iter()
+            .flatten()
+            .find(|x| x.bounds().is_none())
+        {
+            return Err(anyhow::anyhow!(
+                "The value of a predicate on {}.{} must be a number",
+                predicate.var_name(),
+                predicate.key()
+            ));
+        }
+        Ok(())
+    }
+
+    // Handles only refer to existing entities, so they can't be used on the entities
+    // a query creates.
+    fn check_handles(&self) -> Result<()> {
+        let creates_relation = self.use_create;
+        let creates_nodes = self.use_create && !self.use_match;
+        if (creates_relation && self.relation.as_ref().is_some_and(|x| x.handle.is_some()))
+            || (creates_nodes
+                && (self.node.as_ref().is_some_and(|x| x.handle.is_some())
+                    || self.next_node.as_ref().is_some_and(|x| x.handle.is_some())))
+        {
+            return Err(anyhow::anyhow!(
+                "Entity handles can't be used on created entities: {:?}",
+                self
+            ));
+        }
+        Ok(())
+    }
+
+    fn has_handle(&self) -> bool {
+        self.node.as_ref().is_some_and(|x| x.handle.is_some())
+            || self.relation.as_ref().is_some_and(|x| x.handle.is_some())
+            || self.next_node.as_ref().is_some_and(|x| x.handle.is_some())
+    }
+
+    fn to_where_query_string(&self) -> Result<String> {
+        if self.where_list.is_none() {
+            return Ok(String::new());
+        }
+
+        let s = self
+            .where_list
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(Predicate::to_query_string)
+            .collect::<Vec<String>>()
+            .join(" AND ");
+        if s.is_empty() {
+            return Err(anyhow::anyhow!(
+                "WHERE was used but no predicate was provided: {:?}",
+                self
+            ));
+        }
+        Ok(format!(" WHERE {}", s))
+    }
+
     fn to_return_query_string(&self) -> Result<String> {
         if self.return_list.is_none() {
             return Ok(String::new());
@@ -236,7 +404,7 @@ impl CypherQuery {
             .as_ref()
             .unwrap()
             .iter()
-            .map(|x| Item::to_query_string(x))
+            .map(Item::to_query_string)
             .collect::<Vec<String>>()
             .join(", ");
         if s.is_empty() {
@@ -258,7 +426,7 @@ impl CypherQuery {
             .as_ref()
             .unwrap()
             .iter()
-            .map(|x| Item::to_query_string(x))
+            .map(Item::to_query_string)
             .collect::<Vec<String>>()
             .join(", ");
         if s.is_empty() {
@@ -280,7 +448,7 @@ impl CypherQuery {
             .as_ref()
             .unwrap()
             .iter()
-            .map(|x| Item::to_query_string(x))
+            .map(Item::to_query_string)
             .collect::<Vec<String>>()
             .join(", ");
         if s.is_empty() {
@@ -303,7 +471,7 @@ impl CypherQuery {
             .unwrap()
             .0
             .iter()
-            .map(|x| Item::to_query_string(x))
+            .map(Item::to_query_string)
             .collect::<Vec<String>>()
             .join(", ");
         if s.is_empty() {
@@ -320,6 +488,12 @@ impl CypherQuery {
     }
 }
 
+impl Default for CypherQueryBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[allow(non_snake_case)]
 impl CypherQueryBuilder {
     pub fn new() -> Self {
@@ -333,7 +507,10 @@ impl CypherQueryBuilder {
             set_list: None,
             remove_list: None,
             delete_list: None,
+            where_list: None,
             find_shortest_path: false,
+            explain: false,
+            tenant: None,
         }
     }
 
@@ -388,11 +565,27 @@ impl CypherQueryBuilder {
         self
     }
 
+    pub fn WHERE(mut self, list: Vec<Predicate>) -> Self {
+        self.where_list = Some(list);
+        self
+    }
+
+    // Returns the encrypted execution plan instead of executing the query.
+    pub fn EXPLAIN(mut self) -> Self {
+        self.explain = true;
+        self
+    }
+
     pub fn find_shortest_path(mut self) -> Self {
         self.find_shortest_path = true;
         self
     }
 
+    pub fn tenant(mut self, tenant: impl Into<String>) -> Self {
+        self.tenant = Some(tenant.into());
+        self
+    }
+
     pub fn build(self) -> CypherQuery {
         CypherQuery {
             node: self.node,
@@ -404,7 +597,11 @@ impl CypherQueryBuilder {
             set_list: self.set_list,
             remove_list: self.remove_list,
             delete_list: self.delete_list,
+            where_list: self.where_list,
             find_shortest_path: self.find_shortest_path,
+            union: None,
+            explain: self.explain,
+            tenant: self.tenant,
         }
     }
 }
@@ -4,12 +4,14 @@ use serde::{Deserialize, Serialize};
 mod cypher;
 mod item;
 mod node;
+mod predicate;
 mod relation;
 mod rows;
 
 pub use self::cypher::{CRUDtype, CypherQuery, CypherQueryBuilder};
 pub use self::item::Item;
 pub use self::node::Node;
+pub use self::predicate::Predicate;
 pub use self::relation::Relation;
 pub use self::rows::{Inner, Row, Rows};
 
@@ -178,6 +180,256 @@ mod tests {
         Ok(())
     }
 
+    // MATCH (n:label1 {k1: 'v1'}) WHERE n.age > 30 AND n.age <= 40 RETURN n
+    #[test]
+    fn test_match_where() -> Result<()> {
+        let query = CypherQueryBuilder::new()
+            .MATCH()
+            .node(Node::new(Some("n"), vec!["label1"], vec![("k1", "v1")]))
+            .WHERE(vec![
+                Predicate::Gt(String::from("n"), String::from("age"), String::from("30")),
+                Predicate::Le(String::from("n"), String::from("age"), String::from("40")),
+            ])
+            .RETURN(vec![Item::Var(String::from("n"))])
+            .build();
+
+        let query_str = query.to_query_string()?;
+        assert_eq!(
+            query_str,
+            "MATCH (n:label1 {k1: 'v1'}) WHERE n.age > 30 AND n.age <= 40 RETURN n"
+        );
+
+        let serialized = query.serialize()?;
+        let deserilized = CypherQuery::deserialize(&serialized)?;
+        let query_str2 = deserilized.to_query_string()?;
+        assert_eq!(query_str, query_str2);
+
+        let predicates = deserilized.where_list.as_ref().unwrap();
+        assert!(predicates[0].eval("31") && predicates[1].eval("31"));
+        assert!(!predicates[0].eval("30") || !predicates[1].eval("30"));
+        assert!(!predicates[1].eval("40.5"));
+        assert!(!predicates[0].eval("thirty"));
+
+        // WHERE is only supported in reads
+        let query = CypherQueryBuilder::new()
+            .MATCH()
+            .node(Node::new(Some("n"), vec!["label1"], vec![("k1", "v1")]))
+            .WHERE(vec![Predicate::Gt(
+                String::from("n"),
+                String::from("age"),
+                String::from("30"),
+            )])
+            .DELETE(vec![Item::Var(String::from("n"))], true)
+            .build();
+        assert!(query.to_query_string().is_err());
+        assert!(query.get_type().is_err());
+
+        // the value of a predicate has to be a number
+        for value in ["thirty", "NaN", "inf"] {
+            let query = CypherQueryBuilder::new()
+                .MATCH()
+                .node(Node::new(Some("n"), vec!["label1"], vec![("k1", "v1")]))
+                .WHERE(vec![Predicate::Gt(
+                    String::from("n"),
+                    String::from("age"),
+                    String::from(value),
+                )])
+                .RETURN(vec![Item::Var(String::from("n"))])
+                .build();
+            assert!(query.to_query_string().is_err());
+            assert!(query.get_type().is_err());
+        }
+
+        Ok(())
+    }
+
+    // MATCH (n:label1) RETURN n UNION MATCH (n:label2)-[r]->(m) RETURN n UNION MATCH (n:label3) RETURN n
+    #[test]
+    fn test_union() -> Result<()> {
+        let query = |label: &str| {
+            CypherQueryBuilder::new()
+                .MATCH()
+                .node(Node::new(
+                    Some("n"),
+                    vec![label],
+                    Vec::<(String, String)>::new(),
+                ))
+                .RETURN(vec![Item::Var(String::from("n"))])
+                .build()
+        };
+        let path_query = CypherQueryBuilder::new()
+            .MATCH()
+            .node(Node::new(
+                Some("n"),
+                vec!["label2"],
+                Vec::<(String, String)>::new(),
+            ))
+            .relation(Relation::new_with_var("r"))
+            .next_node(Node::new_with_var("m"))
+            .RETURN(vec![Item::Var(String::from("n"))])
+            .build();
+
+        let union = query("label1").union(path_query).union(query("label3"));
+        let query_str = union.to_query_string()?;
+        assert_eq!(
+            query_str,
+            "MATCH (n:label1) RETURN n UNION MATCH (n:label2)-[r]->(m) RETURN n UNION MATCH (n:label3) RETURN n"
+        );
+        assert!(matches!(union.get_type()?, CRUDtype::Union));
+
+        let serialized = union.serialize()?;
+        let deserilized = CypherQuery::deserialize(&serialized)?;
+        let query_str2 = deserilized.to_query_string()?;
+        assert_eq!(query_str, query_str2);
+
+        let union_all = query("label1").union_all(query("label2"));
+        assert_eq!(
+            union_all.to_query_string()?,
+            "MATCH (n:label1) RETURN n UNION ALL MATCH (n:label2) RETURN n"
+        );
+
+        // UNION and UNION ALL can't be mixed, and only reads can be combined
+        let mixed = query("label1")
+            .union(query("label2"))
+            .union_all(query("label3"));
+        assert!(mixed.get_type().is_err());
+        let with_delete = query("label1").union(
+            CypherQueryBuilder::new()
+                .MATCH()
+                .node(Node::new_with_var("n"))
+                .DELETE(vec![Item::Var(String::from("n"))], true)
+                .build(),
+        );
+        assert!(with_delete.get_type().is_err());
+        assert!(with_delete.to_query_string().is_err());
+
+        Ok(())
+    }
+
+    // EXPLAIN MATCH (n:label1 {k1: 'v1'}) DETACH DELETE n
+    #[test]
+    fn test_explain() -> Result<()> {
+        let query = CypherQueryBuilder::new()
+            .MATCH()
+            .node(Node::new(Some("n"), vec!["label1"], vec![("k1", "v1")]))
+            .DELETE(vec![Item::Var(String::from("n"))], true)
+            .EXPLAIN()
+            .build();
+
+        let query_str = query.to_query_string()?;
+        assert_eq!(
+            query_str,
+            "EXPLAIN MATCH (n:label1 {k1: 'v1'}) DETACH DELETE n "
+        );
+        assert!(matches!(query.get_type()?, CRUDtype::Delete));
+
+        let serialized = query.serialize()?;
+        let deserilized = CypherQuery::deserialize(&serialized)?;
+        assert!(deserilized.explain);
+        let query_str2 = deserilized.to_query_string()?;
+        assert_eq!(query_str, query_str2);
+
+        // queries of older clients carry no explain flag
+        let mut value: serde_json::Value = serde_json::from_str(&serialized)?;
+        value.as_object_mut().unwrap().remove("explain");
+        assert!(!CypherQuery::deserialize(&value.to_string())?.explain);
+
+        // MATCH (n:label1) RETURN n UNION EXPLAIN MATCH (n:label2) RETURN n
+        let read = |label| {
+            CypherQueryBuilder::new()
+                .MATCH()
+                .node(Node::new(
+                    Some("n"),
+                    vec![label],
+                    Vec::<(String, String)>::new(),
+                ))
+                .RETURN(vec![Item::Var(String::from("n"))])
+        };
+        let union = read("label1")
+            .build()
+            .union(read("label2").EXPLAIN().build());
+        assert!(union.get_type().is_err());
+        assert!(union.to_query_string().is_err());
+        let union = read("label1")
+            .EXPLAIN()
+            .build()
+            .union(read("label2").build());
+        assert!(matches!(union.get_type()?, CRUDtype::Union));
+
+        Ok(())
+    }
+
+    // MATCH (n:label1) RETURN n, in the tenant team-a
+    #[test]
+    fn test_tenant() -> Result<()> {
+        let query = CypherQueryBuilder::new()
+            .MATCH()
+            .node(Node::new(
+                Some("n"),
+                vec!["label1"],
+                Vec::<(String, String)>::new(),
+            ))
+            .RETURN(vec![Item::Var(String::from("n"))])
+            .tenant("team-a")
+            .build();
+
+        let query_str = query.to_query_string()?;
+        assert_eq!(query_str, "MATCH (n:label1) RETURN n");
+
+        let serialized = query.serialize()?;
+        let deserilized = CypherQuery::deserialize(&serialized)?;
+        assert_eq!(deserilized.tenant.as_deref(), Some("team-a"));
+
+        // queries of clients that don't know about tenants
+        let mut value: serde_json::Value = serde_json::from_str(&serialized)?;
+        value.as_object_mut().unwrap().remove("tenant");
+        let deserilized = CypherQuery::deserialize(&value.to_string())?;
+        assert_eq!(deserilized.tenant, None);
+
+        Ok(())
+    }
+
+    // MATCH (n {handle}) SET n.k1 = 'v2' RETURN n
+    #[test]
+    fn test_by_handle() -> Result<()> {
+        let query = CypherQueryBuilder::new()
+            .MATCH()
+            .node(Node::by_handle("n", "h1"))
+            .SET(vec![Item::VarWithKeyValue(
+                String::from("n"),
+                String::from("k1"),
+                String::from("v2"),
+            )])
+            .RETURN(vec![Item::Var(String::from("n"))])
+            .build();
+
+        assert!(matches!(query.get_type()?, CRUDtype::Update));
+        // the handle has to be resolved by the enclave first
+        assert!(query.to_query_string().is_err());
+
+        let serialized = query.serialize()?;
+        let deserilized = CypherQuery::deserialize(&serialized)?;
+        assert_eq!(deserilized.node.unwrap().handle, Some(String::from("h1")));
+
+        let query = CypherQueryBuilder::new()
+            .CREATE()
+            .node(Node::by_handle("n", "h1"))
+            .build();
+        assert!(query.get_type().is_err());
+
+        let mut inner = Inner::new(vec![String::from("label1")], vec![]);
+        inner.set_handle(String::from("h1"));
+        assert_eq!(inner, Inner::new(vec![String::from("label1")], vec![]));
+        let rows = Rows::new(vec![Row::new(vec![inner])]);
+        let deserilized = Rows::deserialize(&rows.serialize()?)?;
+        assert_eq!(
+            deserilized.rows()[0].inners()[0].handle(),
+            Some(&String::from("h1"))
+        );
+
+        Ok(())
+    }
+
     // MATCH (n:label1 {k1: 'v1'}) SET n:label3, n.k1 = 'new_v1', n.k3 = 'v3' RETURN n
     #[test]
     fn test_set() -> Result<()> {
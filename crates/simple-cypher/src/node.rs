@@ -5,6 +5,10 @@ pub struct Node {
     pub var_name: Option<String>,
     pub labels: Vec<String>,
     pub properties: Vec<(String, String)>,
+    /// Opaque handle of an existing entity, as returned in `Inner::handle`.
+    /// It is resolved by the enclave and never sent to neo4j.
+    #[serde(default)]
+    pub handle: Option<String>,
 }
 
 impl Node {
@@ -20,6 +24,7 @@ impl Node {
                 .into_iter()
                 .map(|(k, v)| (k.into(), v.into()))
                 .collect(),
+            handle: None,
         }
     }
 
@@ -28,6 +33,17 @@ impl Node {
             var_name: Some(var_name.into()),
             labels: vec![],
             properties: vec![],
+            handle: None,
+        }
+    }
+
+    /// Matches exactly the entity the handle was issued for.
+    pub fn by_handle(var_name: impl Into<String>, handle: impl Into<String>) -> Self {
+        Self {
+            var_name: Some(var_name.into()),
+            labels: vec![],
+            properties: vec![],
+            handle: Some(handle.into()),
         }
     }
 
@@ -0,0 +1,92 @@
+use super::*;
+
+/// A range comparison on a numeric property, e.g. `n.age > 30`.
+///
+/// Each variant holds `(var_name, key, value)`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Predicate {
+    Gt(String, String, String),
+    Ge(String, String, String),
+    Lt(String, String, String),
+    Le(String, String, String),
+}
+
+impl Predicate {
+    pub fn var_name(&self) -> &String {
+        match self {
+            Predicate::Gt(var_name, _, _)
+            | Predicate::Ge(var_name, _, _)
+            | Predicate::Lt(var_name, _, _)
+            | Predicate::Le(var_name, _, _) => var_name,
+        }
+    }
+
+    pub fn var_name_mut(&mut self) -> &mut String {
+        match self {
+            Predicate::Gt(var_name, _, _)
+            | Predicate::Ge(var_name, _, _)
+            | Predicate::Lt(var_name, _, _)
+            | Predicate::Le(var_name, _, _) => var_name,
+        }
+    }
+
+    pub fn key(&self) -> &String {
+        match self {
+            Predicate::Gt(_, key, _)
+            | Predicate::Ge(_, key, _)
+            | Predicate::Lt(_, key, _)
+            | Predicate::Le(_, key, _) => key,
+        }
+    }
+
+    pub fn value(&self) -> &String {
+        match self {
+            Predicate::Gt(_, _, value)
+            | Predicate::Ge(_, _, value)
+            | Predicate::Lt(_, _, value)
+            | Predicate::Le(_, _, value) => value,
+        }
+    }
+
+    /// Returns the `(lower, upper)` bound this predicate puts on the property,
+    /// or `None` if the value of the predicate is not a finite number.
+    pub fn bounds(&self) -> Option<(f64, f64)> {
+        let value = self.value().parse::<f64>().ok().filter(|x| x.is_finite())?;
+        match self {
+            Predicate::Gt(..) | Predicate::Ge(..) => Some((value, f64::INFINITY)),
+            Predicate::Lt(..) | Predicate::Le(..) => Some((f64::NEG_INFINITY, value)),
+        }
+    }
+
+    /// Evaluates the predicate against a plaintext property value.
+    ///
+    /// Values that are not numbers never match.
+    pub fn eval(&self, property_value: &str) -> bool {
+        let (lhs, rhs) = match (property_value.parse::<f64>(), self.value().parse::<f64>()) {
+            (Ok(lhs), Ok(rhs)) => (lhs, rhs),
+            _ => return false,
+        };
+        match self {
+            Predicate::Gt(..) => lhs > rhs,
+            Predicate::Ge(..) => lhs >= rhs,
+            Predicate::Lt(..) => lhs < rhs,
+            Predicate::Le(..) => lhs <= rhs,
+        }
+    }
+
+    pub(crate) fn to_query_string(predicate: &Predicate) -> String {
+        let op = match predicate {
+            Predicate::Gt(..) => ">",
+            Predicate::Ge(..) => ">=",
+            Predicate::Lt(..) => "<",
+            Predicate::Le(..) => "<=",
+        };
+        format!(
+            "{}.{} {} {}",
+            predicate.var_name(),
+            predicate.key(),
+            op,
+            predicate.value()
+        )
+    }
+}
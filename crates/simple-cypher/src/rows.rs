@@ -5,6 +5,10 @@ use std::cmp::PartialEq;
 pub struct Inner {
     pub labels: Vec<String>,
     pub properties: Vec<(String, String)>,
+    /// Opaque handle of the entity, see `Node::by_handle` and `Relation::by_handle`.
+    /// It is not part of the entity, so it's ignored by `PartialEq`.
+    #[serde(default)]
+    pub handle: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -19,7 +23,19 @@ pub struct Rows {
 
 impl Inner {
     pub fn new(labels: Vec<String>, properties: Vec<(String, String)>) -> Self {
-        Self { labels, properties }
+        Self {
+            labels,
+            properties,
+            handle: None,
+        }
+    }
+
+    pub fn handle(&self) -> Option<&String> {
+        self.handle.as_ref()
+    }
+
+    pub fn set_handle(&mut self, handle: String) {
+        self.handle.replace(handle);
     }
 
     pub fn labels(&self) -> &Vec<String> {
@@ -0,0 +1,550 @@
+use aes_gcm_siv::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256GcmSiv, Nonce,
+};
+use anyhow::Result;
+use secure_channel::{from_hex, to_hex};
+use simple_cypher::*;
+
+use crate::crypto::{key_provider_from_env, Crypto, IntegrityError};
+use crate::policy::PermissionDenied;
+use crate::principal::Principal;
+use crate::tenant::{Tenant, DEFAULT_TENANT};
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SEALED_AUDIT_STATE_AAD: &[u8] = b"clique_task audit state v2";
+// marker of a started audit log in the sealed data keys
+const AUDIT_LOG_MARKER: &str = "audit-log";
+const AUDIT_CIPHER_CONTEXT: &str = "clique_task 2024 audit record key";
+const AUDIT_MAC_CONTEXT: &str = "clique_task 2024 audit chain key";
+const NONCE_LEN: usize = 12;
+
+/// How an audited query ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    Denied,
+    IntegrityError,
+    Failed,
+}
+
+impl Outcome {
+    pub fn of(result: &Result<Rows>) -> Self {
+        match result {
+            Ok(_) => Outcome::Ok,
+            Err(e) if e.is::<PermissionDenied>() => Outcome::Denied,
+            Err(e) if e.is::<IntegrityError>() => Outcome::IntegrityError,
+            Err(_) => Outcome::Failed,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Ok => "ok",
+            Outcome::Denied => "denied",
+            Outcome::IntegrityError => "integrity-error",
+            Outcome::Failed => "failed",
+        }
+    }
+
+    fn parse(outcome: &str) -> Result<Self> {
+        match outcome {
+            "ok" => Ok(Outcome::Ok),
+            "denied" => Ok(Outcome::Denied),
+            "integrity-error" => Ok(Outcome::IntegrityError),
+            "failed" => Ok(Outcome::Failed),
+            _ => Err(anyhow::anyhow!("Unknown audit outcome: {}", outcome)),
+        }
+    }
+}
+
+/// One query as it is recorded: who ran it, its shape without any property
+/// value, how many rows and entities it returned, and how it ended. The time
+/// comes from the untrusted host and is informational only.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub time: u64,
+    pub principal: String,
+    pub shape: String,
+    pub rows: usize,
+    pub entities: usize,
+    pub outcome: Outcome,
+}
+
+impl AuditRecord {
+    pub fn new(principal: &Principal, shape: String, result: &Result<Rows>) -> Self {
+        let (rows, entities) = match result {
+            Ok(rows) => (
+                rows.rows().len(),
+                rows.rows().iter().map(|x| x.inners().len()).sum(),
+            ),
+            Err(_) => (0, 0),
+        };
+        Self {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |x| x.as_secs()),
+            principal: principal.name.clone(),
+            shape,
+            rows,
+            entities,
+            outcome: Outcome::of(result),
+        }
+    }
+
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            self.time,
+            self.principal,
+            self.outcome.as_str(),
+            self.rows,
+            self.entities,
+            self.shape
+        )
+    }
+
+    fn from_line(line: &str) -> Result<Self> {
+        let fields: Vec<&str> = line.splitn(6, '\t').collect();
+        if fields.len() != 6 {
+            return Err(anyhow::anyhow!("Invalid audit record"));
+        }
+        Ok(Self {
+            time: fields[0].parse()?,
+            principal: fields[1].to_string(),
+            outcome: Outcome::parse(fields[2])?,
+            rows: fields[3].parse()?,
+            entities: fields[4].parse()?,
+            shape: fields[5].to_string(),
+        })
+    }
+}
+
+// Records are encrypted under one key and chained under another, both derived
+// from a random audit key that is only stored sealed next to the log.
+struct AuditKeys {
+    key: [u8; 32],
+    cipher: Aes256GcmSiv,
+    mac_key: [u8; 32],
+}
+
+impl AuditKeys {
+    fn new(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            cipher: Aes256GcmSiv::new_from_slice(&blake3::derive_key(AUDIT_CIPHER_CONTEXT, &key))
+                .unwrap(),
+            mac_key: blake3::derive_key(AUDIT_MAC_CONTEXT, &key),
+        }
+    }
+
+    // Links a record to the one before it: MAC(head || seq || ciphertext).
+    fn chain(&self, head: &blake3::Hash, seq: u64, sealed: &[u8]) -> blake3::Hash {
+        let mut hasher = blake3::Hasher::new_keyed(&self.mac_key);
+        hasher.update(head.as_bytes());
+        hasher.update(&seq.to_le_bytes());
+        hasher.update(sealed);
+        hasher.finalize()
+    }
+
+    fn seal(&self, seq: u64, record: &AuditRecord) -> Result<Vec<u8>> {
+        let nonce = Aes256GcmSiv::generate_nonce(&mut OsRng);
+        let encrypted = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: record.to_line().as_bytes(),
+                    aad: &seq.to_le_bytes(),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt an audit record"))?;
+        Ok([nonce.as_slice(), &encrypted].concat())
+    }
+
+    fn open(&self, seq: u64, sealed: &[u8]) -> Result<AuditRecord> {
+        if sealed.len() < NONCE_LEN {
+            return Err(anyhow::anyhow!("Invalid audit record {}", seq));
+        }
+        let (nonce, encrypted) = sealed.split_at(NONCE_LEN);
+        let plain = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: encrypted,
+                    aad: &seq.to_le_bytes(),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to decrypt audit record {}", seq))?;
+        AuditRecord::from_line(std::str::from_utf8(&plain)?)
+    }
+}
+
+fn state_path(log_path: &Path) -> PathBuf {
+    let mut path = log_path.to_path_buf().into_os_string();
+    path.push(".state");
+    PathBuf::from(path)
+}
+
+// The audit key and the head of the chain, sealed next to the log after every
+// record: `key || seq || MAC of record seq - 1`.
+struct SealedHead {
+    keys: AuditKeys,
+    seq: u64,
+    head: blake3::Hash,
+}
+
+impl SealedHead {
+    fn load(crypto: &Crypto, log_path: &Path) -> Result<Self> {
+        let plain = crypto.unseal_from_file(&state_path(log_path), SEALED_AUDIT_STATE_AAD)?;
+        if plain.len() != 32 + 8 + 32 {
+            return Err(anyhow::anyhow!(
+                "Invalid audit state of {}",
+                log_path.display()
+            ));
+        }
+        Ok(Self {
+            keys: AuditKeys::new(plain[..32].try_into().unwrap()),
+            seq: u64::from_le_bytes(plain[32..40].try_into().unwrap()),
+            head: blake3::Hash::from(<[u8; 32]>::try_from(&plain[40..]).unwrap()),
+        })
+    }
+
+    fn persist(
+        crypto: &Crypto,
+        log_path: &Path,
+        keys: &AuditKeys,
+        seq: u64,
+        head: &blake3::Hash,
+    ) -> Result<()> {
+        let plain = [&keys.key[..], &seq.to_le_bytes(), head.as_bytes()].concat();
+        crypto.seal_to_file(&state_path(log_path), &plain, SEALED_AUDIT_STATE_AAD)
+    }
+}
+
+// `<records>:<hex MAC of the last record>`, the MAC is all zero for an empty log.
+fn format_head(seq: u64, head: &blake3::Hash) -> String {
+    format!("{}:{}", seq, to_hex(head.as_bytes()))
+}
+
+struct AuditState {
+    keys: AuditKeys,
+    file: File,
+    seq: u64,
+    head: blake3::Hash,
+}
+
+/// Append-only log of every query, stored outside the enclave at
+/// `AUDIT_LOG_PATH`.
+///
+/// Each line is one record, `<seq> <hex nonce + ciphertext> <hex MAC>`, where
+/// the MAC covers the MAC of the record before it, so no record can be edited,
+/// removed or reordered without breaking the chain. The audit key and the head
+/// of the chain are sealed to `AUDIT_LOG_PATH` + `.state` after every record,
+/// so a log cut short of the sealed head is detected as truncated. Records
+/// past the sealed head are accepted: only the enclave can chain them.
+///
+/// The sealed data keys record that the log was started (see `Crypto::mark`),
+/// so removing the log with its state, or unsetting `AUDIT_LOG_PATH`, doesn't
+/// start it over. Like the version map, the state can't tell an older sealed
+/// copy put back together with an older log. Without `AUDIT_LOG_PATH` on an
+/// enclave that never started a log nothing is recorded.
+pub struct AuditLog {
+    path: Option<PathBuf>,
+    state: Mutex<Option<AuditState>>,
+}
+
+impl AuditLog {
+    pub fn new() -> Self {
+        Self {
+            path: None,
+            state: Mutex::new(None),
+        }
+    }
+
+    pub fn from_env(tenant: &Tenant) -> Self {
+        let mut audit = Self::new();
+        audit.path = tenant.path_var("AUDIT_LOG_PATH");
+        audit
+    }
+
+    /// Verifies the existing log and opens it for appending, or starts a new
+    /// one if this enclave never started a log.
+    pub fn load(&mut self, crypto: &Crypto) -> Result<()> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None if crypto.is_marked(AUDIT_LOG_MARKER) => {
+                return Err(anyhow::anyhow!(
+                    "AUDIT_LOG_PATH must be set, an audit log was started"
+                ))
+            }
+            None => {
+                log::warn!("AUDIT_LOG_PATH is not set, queries are not audited");
+                return Ok(());
+            }
+        };
+        if !state_path(path).exists() && !path.exists() {
+            if crypto.is_marked(AUDIT_LOG_MARKER) {
+                return Err(anyhow::anyhow!(
+                    "Audit log {} and its sealed state were removed",
+                    path.display()
+                ));
+            }
+            let mut key = [0u8; 32];
+            OsRng.fill_bytes(&mut key);
+            let keys = AuditKeys::new(key);
+            SealedHead::persist(crypto, path, &keys, 0, &blake3::Hash::from([0u8; 32]))?;
+            log::info!("start a new audit log at {}", path.display());
+        }
+
+        let (keys, records, head) = self.verify_with(crypto)?;
+        crypto.mark(AUDIT_LOG_MARKER)?;
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        log::info!(
+            "loaded audit log of {} records, head {}",
+            records.len(),
+            format_head(records.len() as u64, &head)
+        );
+        *self.state.get_mut().unwrap() = Some(AuditState {
+            keys,
+            file,
+            seq: records.len() as u64,
+            head,
+        });
+        Ok(())
+    }
+
+    /// Reads the whole log back, checking the chain and the sealed head.
+    pub fn verify(&self, crypto: &Crypto) -> Result<Vec<AuditRecord>> {
+        if self.path.is_none() {
+            return Err(anyhow::anyhow!("AUDIT_LOG_PATH is not set"));
+        }
+        Ok(self.verify_with(crypto)?.1)
+    }
+
+    fn verify_with(&self, crypto: &Crypto) -> Result<(AuditKeys, Vec<AuditRecord>, blake3::Hash)> {
+        let path = self.path.as_ref().unwrap();
+        if !state_path(path).exists() {
+            return Err(anyhow::anyhow!(
+                "Audit log {} has no sealed state",
+                path.display()
+            ));
+        }
+        let SealedHead {
+            keys,
+            seq: sealed_seq,
+            head: sealed_head,
+        } = SealedHead::load(crypto, path)?;
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut records = vec![];
+        let mut head = blake3::Hash::from([0u8; 32]);
+        // the head the log has at the sealed length
+        let mut at_sealed_seq = (sealed_seq == 0).then_some(head);
+        for (seq, line) in (0u64..).zip(text.lines()) {
+            let edited = || anyhow::anyhow!("Audit log was edited at record {}", seq);
+            let fields: Vec<&str> = line.split(' ').collect();
+            if fields.len() != 3 || fields[0] != seq.to_string() {
+                return Err(edited());
+            }
+            let sealed = from_hex(fields[1]).map_err(|_| edited())?;
+            let mac: [u8; 32] = from_hex(fields[2])
+                .ok()
+                .and_then(|x| x.try_into().ok())
+                .ok_or_else(edited)?;
+            let expected = keys.chain(&head, seq, &sealed);
+            // blake3::Hash compares in constant time
+            if expected != blake3::Hash::from(mac) {
+                return Err(edited());
+            }
+            records.push(keys.open(seq, &sealed)?);
+            head = expected;
+            if seq + 1 == sealed_seq {
+                at_sealed_seq = Some(head);
+            }
+        }
+
+        if at_sealed_seq != Some(sealed_head) {
+            return Err(anyhow::anyhow!(
+                "Audit log was truncated or rolled back: head {}, sealed {}",
+                format_head(records.len() as u64, &head),
+                format_head(sealed_seq, &sealed_head)
+            ));
+        }
+        Ok((keys, records, head))
+    }
+
+    /// Appends a record, and seals the new head.
+    pub fn append(&self, crypto: &Crypto, record: &AuditRecord) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let state = match state.as_mut() {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        let sealed = state.keys.seal(state.seq, record)?;
+        let head = state.keys.chain(&state.head, state.seq, &sealed);
+        writeln!(
+            state.file,
+            "{} {} {}",
+            state.seq,
+            to_hex(&sealed),
+            to_hex(head.as_bytes())
+        )?;
+        state.file.sync_data()?;
+        state.seq += 1;
+        state.head = head;
+        SealedHead::persist(
+            crypto,
+            self.path.as_ref().unwrap(),
+            &state.keys,
+            state.seq,
+            &state.head,
+        )
+    }
+}
+
+/// `tee_app verify-audit [tenant]`: checks the log of the tenant, the default
+/// one if not given, against its sealed head and prints its records.
+pub fn verify_from_env(tenant: Option<&str>) -> Result<()> {
+    let tenant = tenant.unwrap_or(DEFAULT_TENANT);
+    let tenant = Tenant::all_from_env()?
+        .into_iter()
+        .find(|x| x.id == tenant)
+        .ok_or_else(|| anyhow::anyhow!("Unknown tenant: {}", tenant))?;
+    let crypto = tenant.crypto(key_provider_from_env()?.into())?;
+    let records = AuditLog::from_env(&tenant).verify(&crypto)?;
+    for record in &records {
+        println!("{}", record.to_line());
+    }
+    println!("audit log is intact, {} records", records.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SoftwareKeyProvider;
+
+    fn crypto(dir: &Path) -> Result<Crypto> {
+        Crypto::new(
+            Box::new(SoftwareKeyProvider::new("secret", 0, 0)),
+            Some(dir.join("data_key.sealed")),
+        )
+    }
+
+    fn audit_log(dir: &Path) -> AuditLog {
+        let mut audit = AuditLog::new();
+        audit.path = Some(dir.join("audit.log"));
+        audit
+    }
+
+    // a log of three records, in a fresh directory
+    fn started_log() -> Result<(PathBuf, Crypto)> {
+        let dir = std::env::temp_dir().join(format!("tee_app-audit-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        let crypto = crypto(&dir)?;
+        let mut audit = audit_log(&dir);
+        audit.load(&crypto)?;
+        let principal = Principal::new("alice", ["reader"]);
+        for i in 0..3 {
+            let result = if i == 1 {
+                Err(PermissionDenied(String::from("Read on Node Student")).into())
+            } else {
+                Ok(Rows::new_empty())
+            };
+            let record = AuditRecord::new(&principal, format!("MATCH (n{}) RETURN n", i), &result);
+            audit.append(&crypto, &record)?;
+        }
+        Ok((dir, crypto))
+    }
+
+    fn error_of(res: Result<Vec<AuditRecord>>) -> String {
+        res.err().map(|e| e.to_string()).unwrap_or_default()
+    }
+
+    #[test]
+    fn test_audit_chain() -> Result<()> {
+        let (dir, crypto) = started_log()?;
+        let records = audit_log(&dir).verify(&crypto)?;
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].principal, "alice");
+        assert_eq!(records[1].outcome, Outcome::Denied);
+        assert_eq!(records[2].shape, "MATCH (n2) RETURN n");
+
+        // a restart keeps appending to the same chain
+        let mut audit = audit_log(&dir);
+        audit.load(&crypto)?;
+        let record = AuditRecord::new(
+            &Principal::new("bob", ["writer"]),
+            String::from("MATCH (n) RETURN n"),
+            &Ok(Rows::new_empty()),
+        );
+        audit.append(&crypto, &record)?;
+        assert_eq!(audit.verify(&crypto)?.len(), 4);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_audit_edited() -> Result<()> {
+        let (dir, crypto) = started_log()?;
+        let path = dir.join("audit.log");
+        let text = std::fs::read_to_string(&path)?;
+        let lines: Vec<&str> = text.lines().collect();
+
+        // a changed ciphertext, swapped records and a removed first record
+        let fields: Vec<&str> = lines[1].split(' ').collect();
+        let flipped = if fields[1].starts_with('0') { "1" } else { "0" };
+        let edited = [
+            format!(
+                "{}\n{} {}{} {}\n{}\n",
+                lines[0],
+                fields[0],
+                flipped,
+                &fields[1][1..],
+                fields[2],
+                lines[2]
+            ),
+            format!("{}\n{}\n{}\n", lines[0], lines[2], lines[1]),
+            format!("{}\n{}\n", lines[1], lines[2]),
+        ];
+        for text in edited {
+            std::fs::write(&path, text)?;
+            assert!(error_of(audit_log(&dir).verify(&crypto)).contains("edited"));
+            assert!(audit_log(&dir).load(&crypto).is_err());
+        }
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_audit_truncated() -> Result<()> {
+        let (dir, crypto) = started_log()?;
+        let path = dir.join("audit.log");
+        let text = std::fs::read_to_string(&path)?;
+        let lines: Vec<&str> = text.lines().collect();
+
+        std::fs::write(&path, format!("{}\n{}\n", lines[0], lines[1]))?;
+        assert!(error_of(audit_log(&dir).verify(&crypto)).contains("truncated"));
+        std::fs::write(&path, "")?;
+        assert!(error_of(audit_log(&dir).verify(&crypto)).contains("truncated"));
+
+        // the data keys remember the log, so it can't be started over
+        std::fs::remove_file(&path)?;
+        std::fs::remove_file(state_path(&path))?;
+        assert!(audit_log(&dir).load(&crypto).is_err());
+        assert!(AuditLog::new().load(&crypto).is_err());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+use aes_gcm_siv::aead::{rand_core::RngCore, OsRng};
+use anyhow::Result;
+
+use super::sealing::{self, SealedBlob};
+use super::KeyProvider;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+const SEALED_DATA_KEY_AAD: &[u8] = b"clique_task data key v1";
+const SEALED_DATA_KEYS_AAD: &[u8] = b"clique_task data keys v2";
+
+/// The versioned data-encryption keys all subkeys of `Crypto` are derived from.
+///
+/// The data keys are random and only stored sealed by the key provider at
+/// `path`, so a new enclave build only needs the small sealed file re-sealed
+/// instead of the whole database re-encrypted. Without a path, in the legacy
+/// mode of `DATA_KEY=raw`, the provider key itself is the only data key,
+/// version 0.
+pub struct DataKeys {
+    pub keys: BTreeMap<u32, [u8; 16]>,
+    /// Version entities are read and written under.
+    pub active: u32,
+    /// Version a key rotation is copying the entities to.
+    pub target: Option<u32>,
+    /// Other sealed state of the enclave that was started, see `Crypto::mark`.
+    pub markers: BTreeSet<String>,
+    path: Option<PathBuf>,
+}
+
+impl DataKeys {
+    pub fn load(key_provider: &dyn KeyProvider, path: Option<PathBuf>) -> Result<Self> {
+        match path {
+            Some(path) => load_or_create(key_provider, path),
+            None => {
+                log::warn!("DATA_KEY=raw, the data is encrypted under the provider key");
+                Ok(Self {
+                    keys: BTreeMap::from([(0, key_provider.get_current_key()?)]),
+                    active: 0,
+                    target: None,
+                    markers: BTreeSet::new(),
+                    path: None,
+                })
+            }
+        }
+    }
+
+    /// Whether the keys are sealed to a file, see `persist`.
+    pub fn is_sealed(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn persist(&self, key_provider: &dyn KeyProvider) -> Result<()> {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("DATA_KEY=raw has no sealed data keys to update"))?;
+        sealing::seal_to_file(key_provider, path, &self.to_bytes(), SEALED_DATA_KEYS_AAD)
+    }
+
+    /// Generates a key with the next version.
+    pub fn add_key(&mut self) -> u32 {
+        let version = self.keys.keys().next_back().map_or(0, |v| v + 1);
+        let mut key = [0u8; 16];
+        OsRng.fill_bytes(&mut key);
+        self.keys.insert(version, key);
+        version
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut plain = format!("active {}\n", self.active);
+        if let Some(target) = self.target {
+            plain.push_str(&format!("target {}\n", target));
+        }
+        for marker in &self.markers {
+            plain.push_str(&format!("mark {}\n", marker));
+        }
+        for (version, key) in &self.keys {
+            let hex: String = key.iter().map(|x| format!("{:02x}", x)).collect();
+            plain.push_str(&format!("{} {}\n", version, hex));
+        }
+        plain.into_bytes()
+    }
+
+    fn from_bytes(bytes: &[u8], path: PathBuf) -> Result<Self> {
+        let invalid = || anyhow::anyhow!("Invalid data keys in {}", path.display());
+        let mut keys = Self {
+            keys: BTreeMap::new(),
+            active: 0,
+            target: None,
+            markers: BTreeSet::new(),
+            path: None,
+        };
+        for line in std::str::from_utf8(bytes)?.lines() {
+            let (name, value) = line.split_once(' ').ok_or_else(invalid)?;
+            match name {
+                "active" => keys.active = value.parse()?,
+                "target" => keys.target = Some(value.parse()?),
+                "mark" => {
+                    keys.markers.insert(value.to_string());
+                }
+                _ => {
+                    let mut key = [0u8; 16];
+                    if value.len() != 2 * key.len() {
+                        return Err(invalid());
+                    }
+                    for (i, byte) in key.iter_mut().enumerate() {
+                        *byte = u8::from_str_radix(&value[2 * i..2 * i + 2], 16)
+                            .map_err(|_| invalid())?;
+                    }
+                    keys.keys.insert(name.parse()?, key);
+                }
+            }
+        }
+        let known = |v: &u32| keys.keys.contains_key(v);
+        if !known(&keys.active) || !keys.target.iter().all(known) {
+            return Err(invalid());
+        }
+        keys.path = Some(path);
+        Ok(keys)
+    }
+}
+
+/// Keys sealed under an older SVN are re-sealed under the current one right
+/// away, so they never move back to an older SVN. A single data key sealed by
+/// an older build becomes version 0.
+fn load_or_create(key_provider: &dyn KeyProvider, path: PathBuf) -> Result<DataKeys> {
+    let shown = path.display().to_string();
+    if !path.exists() {
+        let mut keys = DataKeys {
+            keys: BTreeMap::new(),
+            active: 0,
+            target: None,
+            markers: BTreeSet::new(),
+            path: Some(path),
+        };
+        keys.add_key();
+        keys.persist(key_provider)?;
+        log::info!("generated a new data key at {}", shown);
+        return Ok(keys);
+    }
+
+    let blob = SealedBlob::from_bytes(&std::fs::read(&path)?)?;
+    let plain = sealing::unseal(key_provider, &blob)?;
+    let keys = match blob.aad.as_slice() {
+        SEALED_DATA_KEYS_AAD => DataKeys::from_bytes(&plain, path)?,
+        SEALED_DATA_KEY_AAD => {
+            let key: [u8; 16] = plain
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Invalid data key in {}", path.display()))?;
+            DataKeys {
+                keys: BTreeMap::from([(0, key)]),
+                active: 0,
+                target: None,
+                markers: BTreeSet::new(),
+                path: Some(path),
+            }
+        }
+        _ => {
+            return Err(anyhow::anyhow!(
+                "{} doesn't hold the expected sealed data",
+                path.display()
+            ))
+        }
+    };
+    log::info!(
+        "loaded {} data keys of SVN {} from {}, active version {}",
+        keys.keys.len(),
+        blob.isv_svn,
+        shown,
+        keys.active
+    );
+
+    let current_svn = key_provider.current_svn()?;
+    if blob.isv_svn != current_svn || blob.aad != SEALED_DATA_KEYS_AAD {
+        keys.persist(key_provider)?;
+        log::info!("re-sealed the data keys under SVN {}", current_svn);
+    }
+    Ok(keys)
+}
@@ -0,0 +1,93 @@
+use std::fmt;
+
+/// An entity returned by neo4j failed verification in the enclave.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityError {
+    MissingUid,
+    MissingHash {
+        uid: String,
+    },
+    HashMismatch {
+        uid: String,
+    },
+    UnknownEntity {
+        uid: String,
+    },
+    StaleVersion {
+        uid: String,
+        expected: u64,
+        found: String,
+    },
+    UnexpectedRow {
+        uid: String,
+    },
+    OmittedRow {
+        uid: String,
+    },
+    WrongEndpoints {
+        uid: String,
+    },
+    DuplicateEntity {
+        uid: String,
+    },
+    UnexpectedKeyVersion {
+        version: u32,
+    },
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityError::MissingUid => write!(f, "Integrity check failed: entity without uid"),
+            IntegrityError::MissingHash { uid } => {
+                write!(f, "Integrity check failed: entity {} has no hash", uid)
+            }
+            IntegrityError::HashMismatch { uid } => {
+                write!(f, "Integrity check failed: hash mismatch of entity {}", uid)
+            }
+            IntegrityError::UnknownEntity { uid } => {
+                write!(
+                    f,
+                    "Integrity check failed: entity {} is not in the version map",
+                    uid
+                )
+            }
+            IntegrityError::StaleVersion {
+                uid,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Integrity check failed: entity {} has version {}, expected {}",
+                uid, found, expected
+            ),
+            IntegrityError::UnexpectedRow { uid } => write!(
+                f,
+                "Integrity check failed: entity {} doesn't match the query",
+                uid
+            ),
+            IntegrityError::OmittedRow { uid } => write!(
+                f,
+                "Integrity check failed: entity {} was omitted from the result",
+                uid
+            ),
+            IntegrityError::WrongEndpoints { uid } => write!(
+                f,
+                "Integrity check failed: relationship {} doesn't connect the nodes of its row",
+                uid
+            ),
+            IntegrityError::DuplicateEntity { uid } => write!(
+                f,
+                "Integrity check failed: entity {} was returned twice",
+                uid
+            ),
+            IntegrityError::UnexpectedKeyVersion { version } => write!(
+                f,
+                "Integrity check failed: entity of unexpected key version {}",
+                version
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IntegrityError {}
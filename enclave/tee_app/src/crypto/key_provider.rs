@@ -0,0 +1,256 @@
+use aes_gcm_siv::aead::{rand_core::RngCore, OsRng};
+use anyhow::Result;
+
+use super::seal_key::{self, KeyPolicy};
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const SOFTWARE_KEY_CONTEXT: &str = "clique_task 2024 software root key";
+const TENANT_KEY_CONTEXT: &str = "clique_task 2024 tenant root key";
+const SOFTWARE_SECRET_LEN: usize = 32;
+
+/// Source of the root key every other key of `Crypto` is derived from.
+///
+/// Keys are versioned by a security version number (SVN): data is always sealed
+/// under the key of `current_svn`, while keys of older SVNs down to `min_svn`
+/// can still be derived to unseal data of a previous build.
+pub trait KeyProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn current_svn(&self) -> Result<u16>;
+
+    fn min_svn(&self) -> u16;
+
+    /// `SGX_KEYPOLICY_*` of the keys, recorded in sealed blobs. Zero for keys not
+    /// derived by SGX.
+    fn key_policy(&self) -> u16 {
+        0
+    }
+
+    /// Derives the key of `svn` without checking it against the bounds.
+    fn derive_key(&self, svn: u16) -> Result<[u8; 16]>;
+
+    /// Key of `svn`, which must lie between `min_svn` and `current_svn`.
+    fn get_key(&self, svn: u16) -> Result<[u8; 16]> {
+        let current = self.current_svn()?;
+        if svn > current {
+            return Err(anyhow::anyhow!(
+                "Key of SVN {} is newer than this enclave (SVN {})",
+                svn,
+                current
+            ));
+        }
+        if svn < self.min_svn() {
+            return Err(anyhow::anyhow!(
+                "Key of SVN {} is older than the minimum SVN {}",
+                svn,
+                self.min_svn()
+            ));
+        }
+        self.derive_key(svn)
+    }
+
+    fn get_current_key(&self) -> Result<[u8; 16]> {
+        self.get_key(self.current_svn()?)
+    }
+}
+
+/// The SGX sealing key, fetched through Occlum's `/dev/sgx` ioctl.
+pub struct OcclumKeyProvider {
+    policy: KeyPolicy,
+    min_svn: u16,
+}
+
+impl OcclumKeyProvider {
+    pub fn new(policy: KeyPolicy, min_svn: u16) -> Self {
+        Self { policy, min_svn }
+    }
+
+    /// Reads `SEAL_KEY_POLICY` (`mrenclave` or `mrsigner`, default `mrenclave`)
+    /// and `SEAL_MIN_ISV_SVN`.
+    pub fn from_env() -> Result<Self> {
+        let policy = match std::env::var("SEAL_KEY_POLICY").as_deref() {
+            Err(_) | Ok("mrenclave") => KeyPolicy::MrEnclave,
+            Ok("mrsigner") => KeyPolicy::MrSigner,
+            Ok(policy) => return Err(anyhow::anyhow!("Unknown SEAL_KEY_POLICY: {}", policy)),
+        };
+        Ok(Self::new(policy, svn_from_env("SEAL_MIN_ISV_SVN")?))
+    }
+}
+
+impl KeyProvider for OcclumKeyProvider {
+    fn name(&self) -> &'static str {
+        match self.policy {
+            KeyPolicy::MrEnclave => "occlum (MRENCLAVE)",
+            KeyPolicy::MrSigner => "occlum (MRSIGNER)",
+        }
+    }
+
+    fn current_svn(&self) -> Result<u16> {
+        seal_key::current_isv_svn()
+    }
+
+    fn min_svn(&self) -> u16 {
+        self.min_svn
+    }
+
+    fn key_policy(&self) -> u16 {
+        self.policy.to_sgx()
+    }
+
+    fn derive_key(&self, svn: u16) -> Result<[u8; 16]> {
+        seal_key::get_key(self.policy, svn)
+    }
+}
+
+/// Derives the root key from a secret in `SOFTWARE_KEY_SECRET` or in the file
+/// at `SOFTWARE_KEY_PATH`, for running outside of SGX. Nothing protects the
+/// secret, so this is only meant for development and tests.
+pub struct SoftwareKeyProvider {
+    secret: Vec<u8>,
+    svn: u16,
+    min_svn: u16,
+}
+
+impl SoftwareKeyProvider {
+    pub fn new(secret: impl Into<Vec<u8>>, svn: u16, min_svn: u16) -> Self {
+        log::warn!("****************************************************************");
+        log::warn!("* Using the SOFTWARE key provider: keys are NOT protected by   *");
+        log::warn!("* SGX. Anyone who can read the secret can decrypt the graph.   *");
+        log::warn!("* Never use this in production.                                *");
+        log::warn!("****************************************************************");
+        Self {
+            secret: secret.into(),
+            svn,
+            min_svn,
+        }
+    }
+
+    /// Reads the secret from the environment. If `SOFTWARE_KEY_PATH` points to
+    /// a missing file, a random secret is generated and written there.
+    /// `SOFTWARE_KEY_SVN` and `SEAL_MIN_ISV_SVN` stand in for the ISV SVNs.
+    pub fn from_env() -> Result<Self> {
+        let svn = svn_from_env("SOFTWARE_KEY_SVN")?;
+        let min_svn = svn_from_env("SEAL_MIN_ISV_SVN")?;
+        if let Ok(secret) = std::env::var("SOFTWARE_KEY_SECRET") {
+            return Ok(Self::new(secret, svn, min_svn));
+        }
+
+        let path = std::env::var("SOFTWARE_KEY_PATH")
+            .map(PathBuf::from)
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "Software key provider needs SOFTWARE_KEY_SECRET or SOFTWARE_KEY_PATH"
+                )
+            })?;
+        if path.exists() {
+            return Ok(Self::new(std::fs::read(&path)?, svn, min_svn));
+        }
+
+        let mut secret = vec![0u8; SOFTWARE_SECRET_LEN];
+        OsRng.fill_bytes(&mut secret);
+        std::fs::write(&path, &secret)?;
+        log::info!("generated a new software key secret at {}", path.display());
+        Ok(Self::new(secret, svn, min_svn))
+    }
+}
+
+impl KeyProvider for SoftwareKeyProvider {
+    fn name(&self) -> &'static str {
+        "software"
+    }
+
+    fn current_svn(&self) -> Result<u16> {
+        Ok(self.svn)
+    }
+
+    fn min_svn(&self) -> u16 {
+        self.min_svn
+    }
+
+    fn derive_key(&self, svn: u16) -> Result<[u8; 16]> {
+        if self.secret.is_empty() {
+            return Err(anyhow::anyhow!("Software key secret is empty"));
+        }
+
+        let mut hasher = blake3::Hasher::new_derive_key(SOFTWARE_KEY_CONTEXT);
+        hasher.update(&svn.to_le_bytes());
+        hasher.update(&self.secret);
+        let mut key = [0u8; 16];
+        key.copy_from_slice(&hasher.finalize().as_bytes()[..16]);
+        Ok(key)
+    }
+}
+
+/// The root key of one tenant, derived from the root key of `inner` and the
+/// tenant id, so no two tenants share a key. Without a tenant id the root key
+/// is passed through, which keeps the data of the default tenant readable.
+pub struct TenantKeyProvider {
+    inner: Arc<dyn KeyProvider>,
+    tenant: Option<String>,
+}
+
+impl TenantKeyProvider {
+    pub fn new(inner: Arc<dyn KeyProvider>, tenant: Option<String>) -> Self {
+        Self { inner, tenant }
+    }
+}
+
+impl KeyProvider for TenantKeyProvider {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn current_svn(&self) -> Result<u16> {
+        self.inner.current_svn()
+    }
+
+    fn min_svn(&self) -> u16 {
+        self.inner.min_svn()
+    }
+
+    fn key_policy(&self) -> u16 {
+        self.inner.key_policy()
+    }
+
+    fn derive_key(&self, svn: u16) -> Result<[u8; 16]> {
+        let root = self.inner.derive_key(svn)?;
+        let tenant = match self.tenant.as_ref() {
+            Some(tenant) => tenant,
+            None => return Ok(root),
+        };
+        let mut hasher = blake3::Hasher::new_derive_key(TENANT_KEY_CONTEXT);
+        hasher.update(&(tenant.len() as u64).to_le_bytes());
+        hasher.update(tenant.as_bytes());
+        hasher.update(&root);
+        let mut key = [0u8; 16];
+        key.copy_from_slice(&hasher.finalize().as_bytes()[..16]);
+        Ok(key)
+    }
+}
+
+fn svn_from_env(name: &str) -> Result<u16> {
+    match std::env::var(name) {
+        Ok(svn) => svn
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid {}: {}", name, svn)),
+        Err(_) => Ok(0),
+    }
+}
+
+/// Picks the key provider from `KEY_PROVIDER` (`occlum` or `software`). Without
+/// it, the `software-keys` feature selects the software provider.
+pub fn key_provider_from_env() -> Result<Box<dyn KeyProvider>> {
+    let default = if cfg!(feature = "software-keys") {
+        "software"
+    } else {
+        "occlum"
+    };
+    let name = std::env::var("KEY_PROVIDER").unwrap_or_else(|_| default.to_string());
+    match name.as_str() {
+        "occlum" => Ok(Box::new(OcclumKeyProvider::from_env()?)),
+        "software" => Ok(Box::new(SoftwareKeyProvider::from_env()?)),
+        _ => Err(anyhow::anyhow!("Unknown KEY_PROVIDER: {}", name)),
+    }
+}
@@ -1,43 +1,280 @@
+mod data_key;
+mod integrity;
+mod key_provider;
+mod quote;
 mod seal_key;
+mod sealing;
 
+use aes_gcm_siv::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256GcmSiv, Nonce,
+};
 use anyhow::Result;
 use base64::{alphabet::Alphabet, engine::general_purpose, Engine as _};
 use simple_cypher::*;
 use soft_aes::aes::{aes_dec_ecb, aes_enc_ecb};
 
-use std::collections::HashMap;
+use crate::graph::{split_relation_uid, MAGIC_HASH_KEY, MAGIC_UID_KEY};
+use crate::index::is_bucket_key;
+use crate::label::MAGIC_LABELS_KEY;
+use crate::version::MAGIC_VERSION_KEY;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use self::data_key::DataKeys;
+pub use self::integrity::IntegrityError;
+#[cfg(test)]
+pub use self::key_provider::SoftwareKeyProvider;
+pub use self::key_provider::{key_provider_from_env, KeyProvider, TenantKeyProvider};
+pub use self::quote::quote_provider_from_env;
 
 const MAGIC_PREFIX: &str = "a";
+const MAGIC_TOKEN_PREFIX: &str = "t";
+const MAGIC_SEALED_PREFIX: &str = "s";
+const MAGIC_KEY_VERSION_PREFIX: &str = "v";
+const HANDLE_VERSION_SEPARATOR: char = '.';
+const NONCE_LEN: usize = 12;
+const BUCKET_TOKEN_CONTEXT: &str = "clique_task 2024 bucket token key";
+const HANDLE_CONTEXT: &str = "clique_task 2024 entity handle key";
+const MAC_CONTEXT: &str = "clique_task 2024 entity mac key";
+const MAC_DOMAIN: &[u8] = b"clique_task entity mac v1";
+const VALUE_CONTEXT: &str = "clique_task 2024 property value key";
+const VALUE_TOKEN_CONTEXT: &str = "clique_task 2024 property value token key";
+const REDACTION_CONTEXT: &str = "clique_task 2024 redaction salt key";
+const HANDLE_TAG_LEN: usize = 16;
 
-pub struct Crypto {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntityKind {
+    Node,
+    Relation,
+}
+
+/// All keys derived from one version of the data key.
+struct KeySet {
+    version: u32,
     key: [u8; 16],
+    token_key: [u8; 32],
+    handle_key: [u8; 32],
+    mac_key: [u8; 32],
+    value_token_key: [u8; 32],
+    redaction_key: [u8; 32],
+    value_cipher: Aes256GcmSiv,
+}
+
+impl KeySet {
+    fn new(version: u32, key: [u8; 16]) -> Self {
+        Self {
+            version,
+            key,
+            token_key: blake3::derive_key(BUCKET_TOKEN_CONTEXT, &key),
+            handle_key: blake3::derive_key(HANDLE_CONTEXT, &key),
+            mac_key: blake3::derive_key(MAC_CONTEXT, &key),
+            value_token_key: blake3::derive_key(VALUE_TOKEN_CONTEXT, &key),
+            redaction_key: blake3::derive_key(REDACTION_CONTEXT, &key),
+            value_cipher: Aes256GcmSiv::new_from_slice(&blake3::derive_key(VALUE_CONTEXT, &key))
+                .unwrap(),
+        }
+    }
+
+    // Version tag in front of every ciphertext. Version 0 has none, so data written
+    // before keys were versioned stays readable.
+    fn tag(&self) -> String {
+        if self.version == 0 {
+            String::new()
+        } else {
+            format!("{}{}", MAGIC_KEY_VERSION_PREFIX, self.version)
+        }
+    }
+}
+
+struct KeyRing {
+    data_keys: DataKeys,
+    sets: BTreeMap<u32, Arc<KeySet>>,
+}
+
+pub struct Crypto {
+    ring: RwLock<KeyRing>,
+    key_provider: Box<dyn KeyProvider>,
+    // keys whose values get a token neo4j can match on
+    matched_keys: HashSet<String>,
     padding: Option<String>,
     base64_engine: general_purpose::GeneralPurpose,
 }
 
 impl Crypto {
-    pub fn new() -> Self {
-        let key = seal_key::get_key();
+    /// `data_key_path` is where the data keys are sealed, see `DataKeys`.
+    pub fn new(key_provider: Box<dyn KeyProvider>, data_key_path: Option<PathBuf>) -> Result<Self> {
+        log::info!("root key from the {} key provider", key_provider.name());
+        let data_keys = DataKeys::load(key_provider.as_ref(), data_key_path)?;
+        let sets = data_keys
+            .keys
+            .iter()
+            .map(|(version, key)| (*version, Arc::new(KeySet::new(*version, *key))))
+            .collect();
         let padding = Some(String::from("PKCS7"));
         let base64_alphabet =
             Alphabet::new("ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789_$")
                 .unwrap();
         let base64_engine =
             general_purpose::GeneralPurpose::new(&base64_alphabet, general_purpose::NO_PAD);
-        Self {
-            key,
+        Ok(Self {
+            ring: RwLock::new(KeyRing { data_keys, sets }),
+            key_provider,
+            matched_keys: HashSet::new(),
             padding,
             base64_engine,
+        })
+    }
+
+    /// Lets neo4j match on the values of `keys`. Only these get a value token.
+    pub fn add_matched_keys(&mut self, keys: impl IntoIterator<Item = String>) {
+        self.matched_keys.extend(keys);
+    }
+
+    /// Whether neo4j can match a pattern on `key`. Other keys have to be matched
+    /// in the enclave.
+    pub fn is_matched_key(&self, key: &str) -> bool {
+        is_deterministic_key(key) || (self.matched_keys.contains(key) && !is_sealed_only_key(key))
+    }
+
+    /// Key version entities are read and written under.
+    pub fn active_version(&self) -> u32 {
+        self.ring.read().unwrap().data_keys.active
+    }
+
+    /// Key version an unfinished rotation copies the entities to.
+    pub fn target_version(&self) -> Option<u32> {
+        self.ring.read().unwrap().data_keys.target
+    }
+
+    /// Key versions that are neither active nor the rotation target, whose
+    /// entities are left over from a rotation.
+    pub fn retired_versions(&self) -> Vec<u32> {
+        let ring = self.ring.read().unwrap();
+        ring.sets
+            .keys()
+            .copied()
+            .filter(|v| *v != ring.data_keys.active && Some(*v) != ring.data_keys.target)
+            .collect()
+    }
+
+    /// Starts a key rotation by generating the target key, or returns the target
+    /// of the unfinished one.
+    pub fn begin_rotation(&self) -> Result<u32> {
+        let mut ring = self.ring.write().unwrap();
+        if let Some(target) = ring.data_keys.target {
+            return Ok(target);
+        }
+        let target = ring.data_keys.add_key();
+        ring.data_keys.target = Some(target);
+        if let Err(e) = ring.data_keys.persist(self.key_provider.as_ref()) {
+            ring.data_keys.target = None;
+            ring.data_keys.keys.remove(&target);
+            return Err(e);
+        }
+        let key = ring.data_keys.keys[&target];
+        ring.sets.insert(target, Arc::new(KeySet::new(target, key)));
+        log::info!("key rotation to version {} started", target);
+        Ok(target)
+    }
+
+    /// Makes the rotation target the active version, once every entity was copied.
+    pub fn activate_target(&self) -> Result<()> {
+        let mut ring = self.ring.write().unwrap();
+        let target = ring
+            .data_keys
+            .target
+            .ok_or_else(|| anyhow::anyhow!("No key rotation in progress"))?;
+        let active = ring.data_keys.active;
+        ring.data_keys.active = target;
+        ring.data_keys.target = None;
+        if let Err(e) = ring.data_keys.persist(self.key_provider.as_ref()) {
+            ring.data_keys.active = active;
+            ring.data_keys.target = Some(target);
+            return Err(e);
+        }
+        log::info!("key version {} is active", target);
+        Ok(())
+    }
+
+    /// Whether the data keys are sealed, and so can record markers. With
+    /// `DATA_KEY=raw` they can't.
+    pub fn seals_data_keys(&self) -> bool {
+        self.ring.read().unwrap().data_keys.is_sealed()
+    }
+
+    /// Whether the sealed state `name` was started, see `mark`.
+    pub fn is_marked(&self, name: &str) -> bool {
+        self.ring.read().unwrap().data_keys.markers.contains(name)
+    }
+
+    /// Records in the sealed data keys that the sealed state `name` was started,
+    /// so the host can't have it started over by deleting its files.
+    pub fn mark(&self, name: &str) -> Result<()> {
+        let mut ring = self.ring.write().unwrap();
+        if !ring.data_keys.markers.insert(name.to_string()) {
+            return Ok(());
         }
+        if let Err(e) = ring.data_keys.persist(self.key_provider.as_ref()) {
+            ring.data_keys.markers.remove(name);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Forgets a retired key version, once none of its entities are left.
+    pub fn drop_version(&self, version: u32) -> Result<()> {
+        let mut ring = self.ring.write().unwrap();
+        if version == ring.data_keys.active || Some(version) == ring.data_keys.target {
+            return Err(anyhow::anyhow!("Key version {} is still in use", version));
+        }
+        if let Some(key) = ring.data_keys.keys.remove(&version) {
+            if let Err(e) = ring.data_keys.persist(self.key_provider.as_ref()) {
+                ring.data_keys.keys.insert(version, key);
+                return Err(e);
+            }
+        }
+        ring.sets.remove(&version);
+        log::info!("key version {} dropped", version);
+        Ok(())
     }
 
-    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
-        aes_enc_ecb(plaintext, &self.key, self.padding.as_ref().map(|x| &**x))
+    fn keys(&self, version: u32) -> Result<Arc<KeySet>> {
+        self.ring
+            .read()
+            .unwrap()
+            .sets
+            .get(&version)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Unknown key version {}", version))
+    }
+
+    fn active_keys(&self) -> Arc<KeySet> {
+        let ring = self.ring.read().unwrap();
+        ring.sets[&ring.data_keys.active].clone()
+    }
+
+    /// Key version an encrypted entity was written under, read from the version tag
+    /// of its first property key or label.
+    pub fn key_version(&self, inner: &Inner) -> Result<u32> {
+        let first = inner
+            .properties
+            .first()
+            .map(|(k, _)| k)
+            .or_else(|| inner.labels.first())
+            .ok_or(IntegrityError::MissingUid)?;
+        Ok(split_version(first)?.0)
+    }
+
+    fn encrypt(&self, keys: &KeySet, plaintext: &[u8]) -> Result<Vec<u8>> {
+        aes_enc_ecb(plaintext, &keys.key, self.padding.as_ref().map(|x| &**x))
             .map_err(|_| anyhow::anyhow!("aes_enc_ecb failed"))
     }
 
-    pub fn decrypt(&self, encrypted: &[u8]) -> Result<Vec<u8>> {
-        aes_dec_ecb(encrypted, &self.key, self.padding.as_ref().map(|x| &**x))
+    fn decrypt(&self, keys: &KeySet, encrypted: &[u8]) -> Result<Vec<u8>> {
+        aes_dec_ecb(encrypted, &keys.key, self.padding.as_ref().map(|x| &**x))
             .map_err(|_| anyhow::anyhow!("aes_enc_ecb failed"))
     }
 
@@ -49,15 +286,131 @@ impl Crypto {
         Ok(self.base64_engine.decode(data)?)
     }
 
+    /// Keyed token of a range bucket, so neo4j can match buckets without
+    /// learning which range they stand for.
+    pub fn bucket_token(&self, version: u32, key: &str, bucket: u64) -> Result<String> {
+        let mut hasher = blake3::Hasher::new_keyed(&self.keys(version)?.token_key);
+        hasher.update(&(key.len() as u64).to_le_bytes());
+        hasher.update(key.as_bytes());
+        hasher.update(&bucket.to_le_bytes());
+        Ok(hasher.finalize().to_string())
+    }
+
+    /// Salted hash of a redacted property value. The salt is derived from the
+    /// data key of the tenant, so equal values only hash the same within a
+    /// tenant, until the key is rotated.
+    pub fn redaction_hash(&self, value: &str) -> String {
+        blake3::keyed_hash(&self.active_keys().redaction_key, value.as_bytes()).to_string()
+    }
+
+    /// Opaque handle of an entity: its uid encrypted, followed by a MAC over the
+    /// entity kind and the ciphertext. The same entity always gets the same handle
+    /// until the key is rotated.
+    pub fn seal_handle(&self, kind: EntityKind, uid: &str) -> Result<String> {
+        let keys = self.active_keys();
+        let mut sealed = self.encrypt(&keys, uid.as_bytes())?;
+        let tag = handle_tag(&keys, kind, &sealed);
+        sealed.extend_from_slice(&tag);
+        let handle = self.encode(&sealed)?;
+        if keys.version == 0 {
+            return Ok(handle);
+        }
+        Ok(format!(
+            "{}{}{}",
+            keys.version, HANDLE_VERSION_SEPARATOR, handle
+        ))
+    }
+
+    /// Returns the uid of the entity the handle was issued for.
+    pub fn open_handle(&self, kind: EntityKind, handle: &str) -> Result<String> {
+        let invalid = || anyhow::anyhow!("Invalid {:?} handle: {}", kind, handle);
+        let (version, sealed) = match handle.split_once(HANDLE_VERSION_SEPARATOR) {
+            Some((version, sealed)) => (version.parse().map_err(|_| invalid())?, sealed),
+            None => (0, handle),
+        };
+        let keys = self.keys(version).map_err(|_| invalid())?;
+        let sealed = self.decode(sealed.as_bytes()).map_err(|_| invalid())?;
+        if sealed.len() <= HANDLE_TAG_LEN {
+            return Err(invalid());
+        }
+        let (encrypted, tag) = sealed.split_at(sealed.len() - HANDLE_TAG_LEN);
+        if !ct_eq(&handle_tag(&keys, kind, encrypted), tag) {
+            return Err(invalid());
+        }
+        Ok(String::from_utf8(self.decrypt(&keys, encrypted)?)?)
+    }
+
+    /// Seals `data` under the key provider to a file on the hostfs, replacing the
+    /// old file atomically. See `SealedBlob` for the format.
+    pub fn seal_to_file(&self, path: &Path, data: &[u8], aad: &[u8]) -> Result<()> {
+        sealing::seal_to_file(self.key_provider.as_ref(), path, data, aad)
+    }
+
+    /// Unseals a file written by `seal_to_file` with the same `aad`.
+    pub fn unseal_from_file(&self, path: &Path, aad: &[u8]) -> Result<Vec<u8>> {
+        Ok(sealing::unseal_from_file(self.key_provider.as_ref(), path, aad)?.0)
+    }
+
+    /// Seals `data` under the key provider, like `seal_to_file` but to bytes.
+    pub fn seal(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        Ok(sealing::seal(self.key_provider.as_ref(), data, aad)?.to_bytes())
+    }
+
+    /// Unseals bytes written by `seal` with the same `aad`.
+    pub fn unseal(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let blob = sealing::SealedBlob::from_bytes(sealed)?;
+        if blob.aad != aad {
+            return Err(anyhow::anyhow!(
+                "Sealed data doesn't hold the expected data"
+            ));
+        }
+        sealing::unseal(self.key_provider.as_ref(), &blob)
+    }
+
+    /// Deterministic ciphertext of a label, key or uid under `version`, as stored
+    /// in neo4j.
+    pub fn enc_name(&self, version: u32, plain: &str) -> Result<String> {
+        self.enc_string(
+            &*self.keys(version)?,
+            &plain.to_string(),
+            &mut HashMap::new(),
+        )
+    }
+
     pub fn enc_query(&self, query: &mut CypherQuery) -> Result<()> {
+        self.enc_query_with(&self.active_keys(), query)
+    }
+
+    /// Encrypts the query under `version` instead of the active version.
+    pub fn enc_query_in(&self, version: u32, query: &mut CypherQuery) -> Result<()> {
+        self.enc_query_with(&*self.keys(version)?, query)
+    }
+
+    fn enc_query_with(&self, keys: &KeySet, query: &mut CypherQuery) -> Result<()> {
         let mut plain2enc = HashMap::new();
 
-        self.enc_node(query.node.as_mut(), &mut plain2enc)?;
-        self.enc_relationship(query.relation.as_mut(), &mut plain2enc)?;
-        self.enc_node(query.next_node.as_mut(), &mut plain2enc)?;
-        self.enc_items(query.return_list.as_mut(), &mut plain2enc)?;
-        self.enc_items(query.set_list.as_mut(), &mut plain2enc)?;
-        self.enc_items(query.remove_list.as_mut(), &mut plain2enc)?;
+        // Values are sealed under the uid of their entity, so every entity whose values
+        // are written has to carry its uid in the pattern.
+        let var2uid = get_var2uid(query);
+        let creates_relation = query.use_create;
+        let creates_nodes = query.use_create && !query.use_match;
+
+        self.enc_node(keys, query.node.as_mut(), creates_nodes, &mut plain2enc)?;
+        self.enc_relationship(
+            keys,
+            query.relation.as_mut(),
+            creates_relation,
+            &mut plain2enc,
+        )?;
+        self.enc_node(
+            keys,
+            query.next_node.as_mut(),
+            creates_nodes,
+            &mut plain2enc,
+        )?;
+        self.enc_items(keys, query.return_list.as_mut(), &var2uid, &mut plain2enc)?;
+        self.enc_items(keys, query.set_list.as_mut(), &var2uid, &mut plain2enc)?;
+        self.enc_items(keys, query.remove_list.as_mut(), &var2uid, &mut plain2enc)?;
 
         if let Some((list, _)) = query.delete_list.as_ref() {
             for item in list {
@@ -71,73 +424,184 @@ impl Crypto {
         Ok(())
     }
 
-    pub fn decrypt_and_verify(&self, mut enc_row: Row) -> Result<Row> {
+    /// `kinds` holds the kind of each inner of the row, in order. Each entity is
+    /// decrypted with the key version it was written under.
+    pub fn decrypt_and_verify(&self, mut enc_row: Row, kinds: &[EntityKind]) -> Result<Row> {
+        if kinds.len() != enc_row.inners().len() {
+            return Err(anyhow::anyhow!(
+                "Missing entity kinds of row: {:?}",
+                enc_row
+            ));
+        }
         let mut enc2plain = HashMap::new();
-        for inner in enc_row.inners_mut() {
-            self.dec_inner(inner, &mut enc2plain)?;
+        for (inner, kind) in enc_row.inners_mut().iter_mut().zip(kinds) {
+            let keys = self.keys(self.key_version(inner)?)?;
+            self.dec_inner(&keys, inner, &mut enc2plain)?;
+            verify_inner(&keys, *kind, inner)?;
         }
         Ok(enc_row)
     }
 
+    /// Keyed MAC of an entity under the active key, see `entity_mac_in`.
+    pub fn entity_mac(
+        &self,
+        kind: EntityKind,
+        labels: &[String],
+        properties: &[(String, String)],
+    ) -> Result<String> {
+        entity_mac(&self.active_keys(), kind, labels, properties)
+    }
+
+    /// Keyed MAC of an entity over a length-prefixed canonical encoding of its kind,
+    /// uid, endpoint uids for relationships, sorted labels and sorted properties.
+    /// The `hash` property that stores the MAC is skipped.
+    pub fn entity_mac_in(
+        &self,
+        version: u32,
+        kind: EntityKind,
+        labels: &[String],
+        properties: &[(String, String)],
+    ) -> Result<String> {
+        entity_mac(&*self.keys(version)?, kind, labels, properties)
+    }
+
     fn enc_node(
         &self,
+        keys: &KeySet,
         node: Option<&mut Node>,
+        is_created: bool,
         plain2enc: &mut HashMap<String, String>,
     ) -> Result<()> {
         if let Some(inner) = node {
             for i in 0..inner.labels.len() {
-                inner.labels[i] = self.enc_string(&inner.labels[i], plain2enc)?;
-            }
-            for i in 0..inner.properties.len() {
-                inner.properties[i].0 = self.enc_string(&inner.properties[i].0, plain2enc)?;
-                inner.properties[i].1 = self.enc_string(&inner.properties[i].1, plain2enc)?;
+                inner.labels[i] = self.enc_string(keys, &inner.labels[i], plain2enc)?;
             }
+            inner.properties =
+                self.enc_properties(keys, &inner.properties, is_created, plain2enc)?;
         }
         Ok(())
     }
 
     fn enc_relationship(
         &self,
+        keys: &KeySet,
         relation: Option<&mut Relation>,
+        is_created: bool,
         plain2enc: &mut HashMap<String, String>,
     ) -> Result<()> {
         if let Some(inner) = relation {
             for i in 0..inner.labels.len() {
-                inner.labels[i] = self.enc_string(&inner.labels[i], plain2enc)?;
-            }
-            for i in 0..inner.properties.len() {
-                inner.properties[i].0 = self.enc_string(&inner.properties[i].0, plain2enc)?;
-                inner.properties[i].1 = self.enc_string(&inner.properties[i].1, plain2enc)?;
+                inner.labels[i] = self.enc_string(keys, &inner.labels[i], plain2enc)?;
             }
+            inner.properties =
+                self.enc_properties(keys, &inner.properties, is_created, plain2enc)?;
         }
         Ok(())
     }
 
+    // A created entity stores every value sealed, next to the token of the value if
+    // its key is matched on. A matched entity is only matched by the tokens.
+    fn enc_properties(
+        &self,
+        keys: &KeySet,
+        properties: &[(String, String)],
+        is_created: bool,
+        plain2enc: &mut HashMap<String, String>,
+    ) -> Result<Vec<(String, String)>> {
+        let uid = properties
+            .iter()
+            .find(|(k, _)| k == MAGIC_UID_KEY)
+            .map(|(_, v)| v);
+
+        let mut res = vec![];
+        for (k, v) in properties {
+            if is_deterministic_key(k) {
+                res.push((
+                    self.enc_string(keys, k, plain2enc)?,
+                    self.enc_string(keys, v, plain2enc)?,
+                ));
+                continue;
+            }
+            if is_created {
+                let uid = uid.ok_or_else(|| anyhow::anyhow!("Missing uid to seal {}", k))?;
+                res.push((
+                    self.enc_string(keys, k, plain2enc)?,
+                    self.seal_value(keys, uid, k, v)?,
+                ));
+            }
+            if !self.is_matched_key(k) {
+                // a pattern can't be matched on it, so it has to be checked in the enclave
+                if !is_created {
+                    return Err(anyhow::anyhow!("Property {} can't be matched by neo4j", k));
+                }
+                continue;
+            }
+            res.push((
+                self.token_key_name(keys, k, plain2enc)?,
+                value_token(keys, k, v),
+            ));
+        }
+        Ok(res)
+    }
+
     fn enc_items(
         &self,
+        keys: &KeySet,
         items: Option<&mut Vec<Item>>,
+        var2uid: &HashMap<String, String>,
         plain2enc: &mut HashMap<String, String>,
     ) -> Result<()> {
         if let Some(items) = items {
-            for i in 0..items.len() {
-                match &items[i] {
+            let mut res = vec![];
+            for item in items.iter() {
+                match item {
                     Item::VarWithLabel(var, label) => {
-                        items[i] =
-                            Item::VarWithLabel(var.clone(), self.enc_string(&label, plain2enc)?);
+                        res.push(Item::VarWithLabel(
+                            var.clone(),
+                            self.enc_string(keys, label, plain2enc)?,
+                        ));
                     }
                     Item::VarWithKey(var, key) => {
-                        items[i] = Item::VarWithKey(var.clone(), self.enc_string(&key, plain2enc)?);
+                        res.push(Item::VarWithKey(
+                            var.clone(),
+                            self.enc_string(keys, key, plain2enc)?,
+                        ));
+                        if !is_deterministic_key(key) {
+                            res.push(Item::VarWithKey(
+                                var.clone(),
+                                self.token_key_name(keys, key, plain2enc)?,
+                            ));
+                        }
+                    }
+                    Item::VarWithKeyValue(var, key, value) if is_deterministic_key(key) => {
+                        res.push(Item::VarWithKeyValue(
+                            var.clone(),
+                            self.enc_string(keys, key, plain2enc)?,
+                            self.enc_string(keys, value, plain2enc)?,
+                        ));
                     }
                     Item::VarWithKeyValue(var, key, value) => {
-                        items[i] = Item::VarWithKeyValue(
+                        let uid = var2uid.get(var).ok_or_else(|| {
+                            anyhow::anyhow!("Missing uid of {} to seal {}", var, key)
+                        })?;
+                        res.push(Item::VarWithKeyValue(
+                            var.clone(),
+                            self.enc_string(keys, key, plain2enc)?,
+                            self.seal_value(keys, uid, key, value)?,
+                        ));
+                        if !self.is_matched_key(key) {
+                            continue;
+                        }
+                        res.push(Item::VarWithKeyValue(
                             var.clone(),
-                            self.enc_string(&key, plain2enc)?,
-                            self.enc_string(&value, plain2enc)?,
-                        );
+                            self.token_key_name(keys, key, plain2enc)?,
+                            value_token(keys, key, value),
+                        ));
                     }
-                    _ => {}
+                    Item::Var(_) => res.push(item.clone()),
                 }
             }
+            *items = res;
         }
 
         Ok(())
@@ -145,43 +609,308 @@ impl Crypto {
 
     fn enc_string(
         &self,
+        keys: &KeySet,
         plain: &String,
         plain2enc: &mut HashMap<String, String>,
     ) -> Result<String> {
         if let Some(enc) = plain2enc.get(plain) {
             Ok(enc.clone())
         } else {
-            let enc = add_prefix(&self.encode(&self.encrypt(plain.as_bytes())?)?);
+            let enc = format!(
+                "{}{}{}",
+                keys.tag(),
+                MAGIC_PREFIX,
+                self.encode(&self.encrypt(keys, plain.as_bytes())?)?
+            );
             plain2enc.insert(plain.clone(), enc.clone());
             Ok(enc)
         }
     }
 
-    fn dec_inner(&self, inner: &mut Inner, enc2plain: &mut HashMap<String, String>) -> Result<()> {
+    // Name of the property holding the token of `key`: the encrypted key with its
+    // own prefix, so it never collides with the sealed value.
+    fn token_key_name(
+        &self,
+        keys: &KeySet,
+        key: &String,
+        plain2enc: &mut HashMap<String, String>,
+    ) -> Result<String> {
+        let enc = self.enc_string(keys, key, plain2enc)?;
+        Ok(format!(
+            "{}{}{}",
+            keys.tag(),
+            MAGIC_TOKEN_PREFIX,
+            remove_prefix(split_version(&enc)?.1)?
+        ))
+    }
+
+    /// Seals a property value with AES-GCM-SIV under a random nonce. The uid of the
+    /// owning entity and the property key are the associated data, so a sealed value
+    /// can't be moved to another entity or key.
+    fn seal_value(&self, keys: &KeySet, uid: &str, key: &str, value: &str) -> Result<String> {
+        let nonce = Aes256GcmSiv::generate_nonce(&mut OsRng);
+        let ciphertext = keys
+            .value_cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: value.as_bytes(),
+                    aad: &value_aad(uid, key),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("aes_gcm_siv encrypt failed"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(format!(
+            "{}{}{}",
+            keys.tag(),
+            MAGIC_SEALED_PREFIX,
+            self.encode(&sealed)?
+        ))
+    }
+
+    fn open_value(&self, keys: &KeySet, uid: &str, key: &str, sealed: &str) -> Result<String> {
+        let invalid = || anyhow::anyhow!("Invalid sealed value of {}", key);
+        let sealed = versioned_body(keys, sealed)?
+            .strip_prefix(MAGIC_SEALED_PREFIX)
+            .ok_or_else(invalid)?;
+        let sealed = self.decode(sealed.as_bytes()).map_err(|_| invalid())?;
+        if sealed.len() < NONCE_LEN {
+            return Err(invalid());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plain = keys
+            .value_cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &value_aad(uid, key),
+                },
+            )
+            .map_err(|_| invalid())?;
+        Ok(String::from_utf8(plain)?)
+    }
+
+    fn dec_inner(
+        &self,
+        keys: &KeySet,
+        inner: &mut Inner,
+        enc2plain: &mut HashMap<String, String>,
+    ) -> Result<()> {
         for i in 0..inner.labels.len() {
-            inner.labels[i] = self.dec_string(&inner.labels[i], enc2plain)?;
+            inner.labels[i] = self.dec_string(keys, &inner.labels[i], enc2plain)?;
+        }
+
+        // tokens are only for matching, the sealed values carry the data
+        let mut properties = vec![];
+        for (k, v) in inner.properties.drain(..) {
+            if !versioned_body(keys, &k)?.starts_with(MAGIC_TOKEN_PREFIX) {
+                properties.push((k, v));
+            }
+        }
+        inner.properties = properties;
+        for i in 0..inner.properties.len() {
+            inner.properties[i].0 = self.dec_string(keys, &inner.properties[i].0, enc2plain)?;
         }
+
+        let uid = match inner.properties.iter().find(|(k, _)| k == MAGIC_UID_KEY) {
+            Some((_, v)) => self.dec_string(keys, v, enc2plain)?,
+            None => return Err(IntegrityError::MissingUid.into()),
+        };
         for i in 0..inner.properties.len() {
-            inner.properties[i].0 = self.dec_string(&inner.properties[i].0, enc2plain)?;
-            inner.properties[i].1 = self.dec_string(&inner.properties[i].1, enc2plain)?;
+            let (k, v) = &inner.properties[i];
+            // values written before they were sealed are still deterministic, and
+            // get sealed when their entity is rewritten, e.g. by a key rotation
+            inner.properties[i].1 =
+                if is_deterministic_key(k) || versioned_body(keys, v)?.starts_with(MAGIC_PREFIX) {
+                    self.dec_string(keys, v, enc2plain)?
+                } else {
+                    self.open_value(keys, &uid, k, v)?
+                };
         }
         Ok(())
     }
 
-    fn dec_string(&self, enc: &String, enc2plain: &mut HashMap<String, String>) -> Result<String> {
+    fn dec_string(
+        &self,
+        keys: &KeySet,
+        enc: &String,
+        enc2plain: &mut HashMap<String, String>,
+    ) -> Result<String> {
         if let Some(plain) = enc2plain.get(enc) {
             Ok(plain.clone())
         } else {
-            let plain =
-                String::from_utf8(self.decrypt(&self.decode(remove_prefix(enc)?.as_bytes())?)?)?;
+            let body = remove_prefix(versioned_body(keys, enc)?)?;
+            let plain = String::from_utf8(self.decrypt(keys, &self.decode(body.as_bytes())?)?)?;
             enc2plain.insert(enc.clone(), plain.clone());
             Ok(plain)
         }
     }
 }
 
-fn add_prefix(s: &str) -> String {
-    format!("{}{}", MAGIC_PREFIX, s)
+fn handle_tag(keys: &KeySet, kind: EntityKind, encrypted: &[u8]) -> [u8; HANDLE_TAG_LEN] {
+    let mut hasher = blake3::Hasher::new_keyed(&keys.handle_key);
+    hasher.update(&[kind as u8]);
+    hasher.update(encrypted);
+    let mut tag = [0u8; HANDLE_TAG_LEN];
+    tag.copy_from_slice(&hasher.finalize().as_bytes()[..HANDLE_TAG_LEN]);
+    tag
+}
+
+/// Deterministic keyed token of a property value, the only form of the value
+/// neo4j can match on.
+fn value_token(keys: &KeySet, key: &str, value: &str) -> String {
+    let mut hasher = blake3::Hasher::new_keyed(&keys.value_token_key);
+    hasher.update(&(key.len() as u64).to_le_bytes());
+    hasher.update(key.as_bytes());
+    hasher.update(value.as_bytes());
+    hasher.finalize().to_string()
+}
+
+fn entity_mac(
+    keys: &KeySet,
+    kind: EntityKind,
+    labels: &[String],
+    properties: &[(String, String)],
+) -> Result<String> {
+    let uid = properties
+        .iter()
+        .find(|(k, _)| k == MAGIC_UID_KEY)
+        .map(|(_, v)| v)
+        .ok_or(IntegrityError::MissingUid)?;
+
+    let mut labels: Vec<&String> = labels.iter().collect();
+    labels.sort();
+    let mut properties: Vec<&(String, String)> = properties
+        .iter()
+        .filter(|(k, _)| k != MAGIC_HASH_KEY)
+        .collect();
+    properties.sort();
+
+    let mut hasher = blake3::Hasher::new_keyed(&keys.mac_key);
+    update_field(&mut hasher, MAC_DOMAIN);
+    hasher.update(&[kind as u8]);
+    update_field(&mut hasher, uid.as_bytes());
+    if kind == EntityKind::Relation {
+        let (from_uid, to_uid) = split_relation_uid(uid)?;
+        update_field(&mut hasher, from_uid.as_bytes());
+        update_field(&mut hasher, to_uid.as_bytes());
+    }
+    hasher.update(&(labels.len() as u64).to_le_bytes());
+    for label in labels {
+        update_field(&mut hasher, label.as_bytes());
+    }
+    hasher.update(&(properties.len() as u64).to_le_bytes());
+    for (k, v) in properties {
+        update_field(&mut hasher, k.as_bytes());
+        update_field(&mut hasher, v.as_bytes());
+    }
+    Ok(hasher.finalize().to_string())
+}
+
+// Requires uid and hash, and recomputes the MAC over the decrypted entity.
+fn verify_inner(keys: &KeySet, kind: EntityKind, inner: &Inner) -> Result<()> {
+    let uid = inner.get(MAGIC_UID_KEY).ok_or(IntegrityError::MissingUid)?;
+    let stored = inner
+        .get(MAGIC_HASH_KEY)
+        .ok_or_else(|| IntegrityError::MissingHash { uid: uid.clone() })?;
+    let expected = entity_mac(keys, kind, &inner.labels, &inner.properties)
+        .map_err(|_| IntegrityError::HashMismatch { uid: uid.clone() })?;
+    if !ct_eq(expected.as_bytes(), stored.as_bytes()) {
+        return Err(IntegrityError::HashMismatch { uid: uid.clone() }.into());
+    }
+    Ok(())
+}
+
+fn update_field(hasher: &mut blake3::Hasher, field: &[u8]) {
+    hasher.update(&(field.len() as u64).to_le_bytes());
+    hasher.update(field);
+}
+
+// Constant-time comparison, so a forger can't learn a tag byte by byte.
+fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// uid, hash, version and bucket tokens are matched on directly, so they stay deterministic.
+fn is_deterministic_key(key: &str) -> bool {
+    key == MAGIC_UID_KEY || key == MAGIC_HASH_KEY || key == MAGIC_VERSION_KEY || is_bucket_key(key)
+}
+
+// A token of the hidden label set would tell neo4j which entities share their labels.
+fn is_sealed_only_key(key: &str) -> bool {
+    key == MAGIC_LABELS_KEY
+}
+
+/// Parses `MATCH_KEYS=name,email`, the keys neo4j can match patterns on besides
+/// the indexed ones.
+pub fn matched_keys_from_env() -> Vec<String> {
+    std::env::var("MATCH_KEYS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+fn get_var2uid(query: &CypherQuery) -> HashMap<String, String> {
+    let mut var2uid = HashMap::new();
+    for (var, properties) in [
+        query.node.as_ref().map(|x| (x.var_name(), &x.properties)),
+        query
+            .relation
+            .as_ref()
+            .map(|x| (x.var_name(), &x.properties)),
+        query
+            .next_node
+            .as_ref()
+            .map(|x| (x.var_name(), &x.properties)),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        if let (Some(var), Some((_, uid))) =
+            (var, properties.iter().find(|(k, _)| k == MAGIC_UID_KEY))
+        {
+            var2uid.insert(var.clone(), uid.clone());
+        }
+    }
+    var2uid
+}
+
+fn value_aad(uid: &str, key: &str) -> Vec<u8> {
+    let mut aad = vec![];
+    aad.extend_from_slice(&(uid.len() as u64).to_le_bytes());
+    aad.extend_from_slice(uid.as_bytes());
+    aad.extend_from_slice(&(key.len() as u64).to_le_bytes());
+    aad.extend_from_slice(key.as_bytes());
+    aad
+}
+
+// Splits the key version tag off a ciphertext. Untagged ciphertexts are version 0.
+fn split_version(s: &str) -> Result<(u32, &str)> {
+    if let Some(rest) = s.strip_prefix(MAGIC_KEY_VERSION_PREFIX) {
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        if digits > 0 {
+            return Ok((rest[..digits].parse()?, &rest[digits..]));
+        }
+    }
+    Ok((0, s))
+}
+
+// The ciphertext without its tag, which has to match the key version of its entity.
+fn versioned_body<'a>(keys: &KeySet, s: &'a str) -> Result<&'a str> {
+    let (version, body) = split_version(s)?;
+    if version != keys.version {
+        return Err(anyhow::anyhow!(
+            "Ciphertext of key version {} in an entity of version {}",
+            version,
+            keys.version
+        ));
+    }
+    Ok(body)
 }
 
 fn remove_prefix(s: &str) -> Result<String> {
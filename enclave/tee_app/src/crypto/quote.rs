@@ -0,0 +1,44 @@
+use anyhow::Result;
+use secure_channel::{to_hex, Evidence, MockQuoteProvider, QuoteProvider, REPORT_DATA_LEN};
+
+use super::seal_key;
+
+/// Quotes the enclave through Occlum's DCAP ioctls.
+pub struct OcclumQuoteProvider;
+
+impl OcclumQuoteProvider {
+    /// Logs the measurements of the running enclave, which clients pin in their
+    /// attestation policy.
+    pub fn new() -> Result<Self> {
+        let body = seal_key::report_body(&[0u8; REPORT_DATA_LEN])?;
+        log::info!("MRENCLAVE: {}", to_hex(&body.mr_enclave.m));
+        log::info!("MRSIGNER: {}", to_hex(&body.mr_signer.m));
+        log::info!("ISV SVN: {}", body.isv_svn);
+        Ok(Self)
+    }
+}
+
+impl QuoteProvider for OcclumQuoteProvider {
+    fn quote(&self, report_data: &[u8; REPORT_DATA_LEN]) -> Result<Evidence> {
+        let evidence = Evidence::SgxQuote(seal_key::dcap_quote(report_data)?);
+        if &evidence.report_body()?.report_data != report_data {
+            return Err(anyhow::anyhow!("The quote doesn't carry the report data"));
+        }
+        Ok(evidence)
+    }
+}
+
+/// Picks the quote provider from `QUOTE_PROVIDER` (`occlum` or `mock`, default
+/// `occlum`). The mock provider lets the attestation flow run without SGX, but
+/// proves nothing.
+pub fn quote_provider_from_env() -> Result<Box<dyn QuoteProvider>> {
+    let name = std::env::var("QUOTE_PROVIDER").unwrap_or_else(|_| String::from("occlum"));
+    match name.as_str() {
+        "occlum" => Ok(Box::new(OcclumQuoteProvider::new()?)),
+        "mock" => {
+            log::warn!("Using the MOCK quote provider: clients can't attest this enclave");
+            Ok(Box::new(MockQuoteProvider::default()))
+        }
+        _ => Err(anyhow::anyhow!("Unknown QUOTE_PROVIDER: {}", name)),
+    }
+}
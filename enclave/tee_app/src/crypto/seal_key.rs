@@ -1,23 +1,30 @@
 use anyhow::Result;
 use libc::{c_int, O_RDONLY};
 use sgx_types::{
-    sgx_attributes_t, sgx_key_128bit_t, sgx_key_id_t, sgx_key_request_t, sgx_report_data_t,
-    sgx_report_t, sgx_target_info_t, SGX_KEYID_SIZE, SGX_KEYPOLICY_MRENCLAVE, SGX_KEYSELECT_SEAL,
-    SGX_KEY_REQUEST_RESERVED2_BYTES, TSEAL_DEFAULT_FLAGSMASK, TSEAL_DEFAULT_MISCMASK,
+    sgx_attributes_t, sgx_key_128bit_t, sgx_key_id_t, sgx_key_request_t, sgx_report_body_t,
+    sgx_report_data_t, sgx_report_t, sgx_target_info_t, SGX_KEYID_SIZE, SGX_KEYPOLICY_MRENCLAVE,
+    SGX_KEYPOLICY_MRSIGNER, SGX_KEYSELECT_SEAL, SGX_KEY_REQUEST_RESERVED2_BYTES,
+    TSEAL_DEFAULT_FLAGSMASK, TSEAL_DEFAULT_MISCMASK,
 };
 
 use std::ffi::CString;
 
 const SGXIOC_GET_KEY: u64 = 0xc010730b; // #define SGXIOC_GET_KEY _IOWR('s', 11, sgxioc_get_key_arg_t)
 const SGXIOC_CREATE_REPORT: u64 = 0xc0187304; // #define SGXIOC_CREATE_REPORT _IOWR('s', 4, sgxioc_create_report_arg_t)
+const SGXIOC_GET_DCAP_QUOTE_SIZE: u64 = 0x80047307; // #define SGXIOC_GET_DCAP_QUOTE_SIZE _IOR('s', 7, uint32_t)
+const SGXIOC_GEN_DCAP_QUOTE: u64 = 0xc0187308; // #define SGXIOC_GEN_DCAP_QUOTE _IOWR('s', 8, sgxioc_gen_dcap_quote_arg_t)
 
 cfg_if::cfg_if! {
     if #[cfg(target_env = "musl")] {
         const IOCTL_GET_KEY: i32 = SGXIOC_GET_KEY as i32;
         const IOCTL_CREATE_REPORT: i32 = SGXIOC_CREATE_REPORT as i32;
+        const IOCTL_GET_DCAP_QUOTE_SIZE: i32 = SGXIOC_GET_DCAP_QUOTE_SIZE as i32;
+        const IOCTL_GEN_DCAP_QUOTE: i32 = SGXIOC_GEN_DCAP_QUOTE as i32;
     } else {
         const IOCTL_GET_KEY: u64 = SGXIOC_GET_KEY;
         const IOCTL_CREATE_REPORT: u64 = SGXIOC_CREATE_REPORT;
+        const IOCTL_GET_DCAP_QUOTE_SIZE: u64 = SGXIOC_GET_DCAP_QUOTE_SIZE;
+        const IOCTL_GEN_DCAP_QUOTE: u64 = SGXIOC_GEN_DCAP_QUOTE;
     }
 }
 
@@ -36,18 +43,26 @@ struct IoctlCreateReportArg {
     report: *mut sgx_report_t,             // Output
 }
 
+// Copy from occlum/src/libos/src/fs/dev_fs/dev_sgx/mod.rs
+#[repr(C)]
+struct IoctlGenDcapQuoteArg {
+    report_data: *const sgx_report_data_t, // Input
+    quote_len: *mut u32,                   // Input/output
+    quote_buf: *mut u8,                    // Output
+}
+
 pub struct GetKey {
     fd: c_int,
 }
 
 impl GetKey {
-    pub fn new() -> Self {
+    pub fn new() -> Result<Self> {
         let path = CString::new("/dev/sgx").unwrap();
         let fd = unsafe { libc::open(path.as_ptr(), O_RDONLY) };
         if fd > 0 {
-            Self { fd: fd }
+            Ok(Self { fd: fd })
         } else {
-            panic!("Open /dev/sgx failed")
+            Err(anyhow::anyhow!("Open /dev/sgx failed"))
         }
     }
 
@@ -67,10 +82,14 @@ impl GetKey {
         }
     }
 
-    pub fn create_report(&mut self, report: *mut sgx_report_t) -> Result<()> {
+    pub fn create_report(
+        &mut self,
+        report_data: *const sgx_report_data_t,
+        report: *mut sgx_report_t,
+    ) -> Result<()> {
         let report_args: IoctlCreateReportArg = IoctlCreateReportArg {
             target_info: std::ptr::null(),
-            report_data: std::ptr::null(),
+            report_data,
             report: report,
         };
 
@@ -80,13 +99,87 @@ impl GetKey {
         }
         Ok(())
     }
+
+    pub fn gen_dcap_quote(&mut self, report_data: *const sgx_report_data_t) -> Result<Vec<u8>> {
+        let mut quote_len: u32 = 0;
+        let ret = unsafe { libc::ioctl(self.fd, IOCTL_GET_DCAP_QUOTE_SIZE, &mut quote_len) };
+        if ret < 0 {
+            return Err(anyhow::anyhow!("IOCTRL IOCTL_GET_DCAP_QUOTE_SIZE failed"));
+        }
+
+        let mut quote = vec![0u8; quote_len as usize];
+        let quote_args: IoctlGenDcapQuoteArg = IoctlGenDcapQuoteArg {
+            report_data,
+            quote_len: &mut quote_len,
+            quote_buf: quote.as_mut_ptr(),
+        };
+        let ret = unsafe { libc::ioctl(self.fd, IOCTL_GEN_DCAP_QUOTE, &quote_args) };
+        if ret < 0 {
+            return Err(anyhow::anyhow!("IOCTRL IOCTL_GEN_DCAP_QUOTE failed"));
+        }
+        quote.truncate(quote_len as usize);
+        Ok(quote)
+    }
 }
 
-pub fn get_key() -> [u8; 16] {
-    let mut get_key = GetKey::new();
+impl Drop for GetKey {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
 
+/// Which enclave identity the seal key is bound to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyPolicy {
+    /// Only this exact enclave build can derive the key.
+    MrEnclave,
+    /// Any enclave signed by the same key can derive the key of its own or an older ISV SVN.
+    MrSigner,
+}
+
+impl KeyPolicy {
+    pub fn to_sgx(self) -> u16 {
+        match self {
+            KeyPolicy::MrEnclave => SGX_KEYPOLICY_MRENCLAVE,
+            KeyPolicy::MrSigner => SGX_KEYPOLICY_MRSIGNER,
+        }
+    }
+}
+
+fn create_report(get_key: &mut GetKey) -> Result<sgx_report_t> {
+    let mut report = unsafe { std::mem::zeroed::<sgx_report_t>() };
+    get_key.create_report(std::ptr::null(), &mut report)?;
+    Ok(report)
+}
+
+/// Report body of the running enclave with `report_data` in it.
+pub fn report_body(report_data: &[u8; 64]) -> Result<sgx_report_body_t> {
+    let mut get_key = GetKey::new()?;
+    let report_data = sgx_report_data_t { d: *report_data };
     let mut report = unsafe { std::mem::zeroed::<sgx_report_t>() };
-    get_key.create_report(&mut report).unwrap();
+    get_key.create_report(&report_data, &mut report)?;
+    Ok(report.body)
+}
+
+/// DCAP quote of the running enclave over `report_data`, signed by the quoting
+/// enclave so it can be verified remotely.
+pub fn dcap_quote(report_data: &[u8; 64]) -> Result<Vec<u8>> {
+    let mut get_key = GetKey::new()?;
+    let report_data = sgx_report_data_t { d: *report_data };
+    get_key.gen_dcap_quote(&report_data)
+}
+
+/// ISV SVN of the running enclave.
+pub fn current_isv_svn() -> Result<u16> {
+    let mut get_key = GetKey::new()?;
+    Ok(create_report(&mut get_key)?.body.isv_svn)
+}
+
+/// Seal key of `policy` for `isv_svn`. The hardware refuses an ISV SVN higher
+/// than the one of the running enclave, so an older build can't derive newer keys.
+pub fn get_key(policy: KeyPolicy, isv_svn: u16) -> Result<[u8; 16]> {
+    let mut get_key = GetKey::new()?;
+    let report = create_report(&mut get_key)?;
 
     let attribute_mask = sgx_attributes_t {
         flags: TSEAL_DEFAULT_FLAGSMASK,
@@ -95,20 +188,18 @@ pub fn get_key() -> [u8; 16] {
     let key_id = sgx_key_id_t {
         id: [0u8; SGX_KEYID_SIZE],
     };
-    let key_policy: u16 = SGX_KEYPOLICY_MRENCLAVE;
     let mut key_request = sgx_key_request_t {
         key_name: SGX_KEYSELECT_SEAL,
-        key_policy,
-        isv_svn: 0u16,
+        key_policy: policy.to_sgx(),
+        isv_svn,
         reserved1: 0u16,
         cpu_svn: report.body.cpu_svn,
         attribute_mask,
         key_id,
         misc_mask: TSEAL_DEFAULT_MISCMASK,
-        config_svn: 0u16,
+        config_svn: report.body.config_svn,
         reserved2: [0u8; SGX_KEY_REQUEST_RESERVED2_BYTES],
     };
 
-    let key = get_key.get_key(&mut key_request).unwrap();
-    key
+    get_key.get_key(&mut key_request)
 }
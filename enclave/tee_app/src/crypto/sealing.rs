@@ -0,0 +1,268 @@
+use aes_gcm_siv::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256GcmSiv, Nonce,
+};
+use anyhow::Result;
+
+use super::{KeyProvider, NONCE_LEN};
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const SEAL_CONTEXT: &str = "clique_task 2024 sealed blob key";
+const SEALED_BLOB_MAGIC: &[u8; 4] = b"CQSB";
+const SEALED_BLOB_VERSION: u8 = 1;
+const KEY_ID_LEN: usize = 16;
+// magic, version, key policy, isv svn, key id, aad length
+const HEADER_LEN: usize = 4 + 1 + 2 + 2 + KEY_ID_LEN + 4;
+
+/// Enclave state sealed under a key of the `KeyProvider`, laid out like
+/// `sgx_sealed_data_t`: the key policy, SVN and key id needed to derive the
+/// sealing key again, the additional authenticated data in the clear, and the
+/// encrypted payload. The whole header is authenticated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SealedBlob {
+    pub version: u8,
+    pub key_policy: u16,
+    pub isv_svn: u16,
+    pub key_id: [u8; KEY_ID_LEN],
+    pub aad: Vec<u8>,
+    pub nonce: [u8; NONCE_LEN],
+    pub ciphertext: Vec<u8>,
+}
+
+impl SealedBlob {
+    fn header(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LEN + self.aad.len());
+        header.extend_from_slice(SEALED_BLOB_MAGIC);
+        header.push(self.version);
+        header.extend_from_slice(&self.key_policy.to_le_bytes());
+        header.extend_from_slice(&self.isv_svn.to_le_bytes());
+        header.extend_from_slice(&self.key_id);
+        header.extend_from_slice(&(self.aad.len() as u32).to_le_bytes());
+        header.extend_from_slice(&self.aad);
+        header
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header();
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&self.ciphertext);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let invalid = || anyhow::anyhow!("Invalid sealed blob");
+        if bytes.len() < HEADER_LEN || &bytes[..4] != SEALED_BLOB_MAGIC {
+            return Err(invalid());
+        }
+        let version = bytes[4];
+        if version != SEALED_BLOB_VERSION {
+            return Err(anyhow::anyhow!(
+                "Unsupported sealed blob version {}",
+                version
+            ));
+        }
+        let key_policy = u16::from_le_bytes([bytes[5], bytes[6]]);
+        let isv_svn = u16::from_le_bytes([bytes[7], bytes[8]]);
+        let mut key_id = [0u8; KEY_ID_LEN];
+        key_id.copy_from_slice(&bytes[9..9 + KEY_ID_LEN]);
+        let aad_len = u32::from_le_bytes(bytes[HEADER_LEN - 4..HEADER_LEN].try_into().unwrap());
+
+        let rest = &bytes[HEADER_LEN..];
+        if rest.len() < aad_len as usize + NONCE_LEN {
+            return Err(invalid());
+        }
+        let (aad, rest) = rest.split_at(aad_len as usize);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        Ok(Self {
+            version,
+            key_policy,
+            isv_svn,
+            key_id,
+            aad: aad.to_vec(),
+            nonce: nonce.try_into().unwrap(),
+            ciphertext: ciphertext.to_vec(),
+        })
+    }
+}
+
+fn blob_cipher(key_provider: &dyn KeyProvider, svn: u16, key_id: &[u8]) -> Result<Aes256GcmSiv> {
+    let mut hasher = blake3::Hasher::new_derive_key(SEAL_CONTEXT);
+    hasher.update(&key_provider.get_key(svn)?);
+    hasher.update(key_id);
+    Ok(Aes256GcmSiv::new_from_slice(hasher.finalize().as_bytes()).unwrap())
+}
+
+/// Seals `data` under the current SVN of `key_provider`. `aad` is authenticated
+/// but stays readable in the blob.
+pub fn seal(key_provider: &dyn KeyProvider, data: &[u8], aad: &[u8]) -> Result<SealedBlob> {
+    let mut key_id = [0u8; KEY_ID_LEN];
+    OsRng.fill_bytes(&mut key_id);
+    let mut blob = SealedBlob {
+        version: SEALED_BLOB_VERSION,
+        key_policy: key_provider.key_policy(),
+        isv_svn: key_provider.current_svn()?,
+        key_id,
+        aad: aad.to_vec(),
+        nonce: Aes256GcmSiv::generate_nonce(&mut OsRng).into(),
+        ciphertext: vec![],
+    };
+    blob.ciphertext = blob_cipher(key_provider, blob.isv_svn, &blob.key_id)?
+        .encrypt(
+            Nonce::from_slice(&blob.nonce),
+            Payload {
+                msg: data,
+                aad: &blob.header(),
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("aes_gcm_siv encrypt failed"))?;
+    Ok(blob)
+}
+
+/// Opens a blob sealed under the same key policy and an SVN this enclave may
+/// still derive.
+pub fn unseal(key_provider: &dyn KeyProvider, blob: &SealedBlob) -> Result<Vec<u8>> {
+    if blob.key_policy != key_provider.key_policy() {
+        return Err(anyhow::anyhow!(
+            "Blob was sealed under key policy {:#06x}, but the provider uses {:#06x}",
+            blob.key_policy,
+            key_provider.key_policy()
+        ));
+    }
+    blob_cipher(key_provider, blob.isv_svn, &blob.key_id)?
+        .decrypt(
+            Nonce::from_slice(&blob.nonce),
+            Payload {
+                msg: &blob.ciphertext,
+                aad: &blob.header(),
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Invalid sealed blob"))
+}
+
+/// Seals `data` to `path`, replacing the old file atomically, so a crash never
+/// leaves a torn blob on the hostfs.
+pub fn seal_to_file(
+    key_provider: &dyn KeyProvider,
+    path: &Path,
+    data: &[u8],
+    aad: &[u8],
+) -> Result<()> {
+    let blob = seal(key_provider, data, aad)?;
+    // next to the full file name, tenant paths only differ in their extension
+    let mut tmp_path = path.as_os_str().to_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&blob.to_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Unseals the blob at `path` and checks it was sealed with `aad`. Returns the
+/// data and the SVN it was sealed under.
+pub fn unseal_from_file(
+    key_provider: &dyn KeyProvider,
+    path: &Path,
+    aad: &[u8],
+) -> Result<(Vec<u8>, u16)> {
+    let blob = SealedBlob::from_bytes(&std::fs::read(path)?)?;
+    if blob.aad != aad {
+        return Err(anyhow::anyhow!(
+            "{} doesn't hold the expected sealed data",
+            path.display()
+        ));
+    }
+    Ok((unseal(key_provider, &blob)?, blob.isv_svn))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SoftwareKeyProvider;
+
+    #[test]
+    fn test_seal_roundtrip() -> Result<()> {
+        let provider = SoftwareKeyProvider::new("secret", 2, 1);
+        let blob = seal(&provider, b"graph state", b"aad")?;
+        assert_eq!(blob.isv_svn, 2);
+        assert_eq!(blob.aad, b"aad");
+
+        let parsed = SealedBlob::from_bytes(&blob.to_bytes())?;
+        assert_eq!(parsed, blob);
+        assert_eq!(unseal(&provider, &parsed)?, b"graph state");
+
+        // a newer build still opens it, as long as SVN 2 is not below its minimum
+        let newer = SoftwareKeyProvider::new("secret", 3, 2);
+        assert_eq!(unseal(&newer, &parsed)?, b"graph state");
+        Ok(())
+    }
+
+    #[test]
+    fn test_seal_to_file() -> Result<()> {
+        let provider = SoftwareKeyProvider::new("secret", 2, 1);
+        let dir = std::env::temp_dir().join(format!("tee_app-seal-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        // paths of two tenants, which must not share a temp file
+        let team_a = dir.join("versions.sealed.team-a");
+        let team_b = dir.join("versions.sealed.team-b");
+        seal_to_file(&provider, &team_a, b"team a", b"aad")?;
+        seal_to_file(&provider, &team_b, b"team b", b"aad")?;
+        assert_eq!(
+            unseal_from_file(&provider, &team_a, b"aad")?,
+            (b"team a".to_vec(), 2)
+        );
+        assert_eq!(
+            unseal_from_file(&provider, &team_b, b"aad")?,
+            (b"team b".to_vec(), 2)
+        );
+        assert!(unseal_from_file(&provider, &team_a, b"other").is_err());
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 2);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_seal_tampering() -> Result<()> {
+        let provider = SoftwareKeyProvider::new("secret", 2, 1);
+        let blob = seal(&provider, b"graph state", b"aad")?;
+
+        let mut tampered = blob.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert!(unseal(&provider, &tampered).is_err());
+
+        let mut tampered = blob.clone();
+        tampered.aad = b"other".to_vec();
+        assert!(unseal(&provider, &tampered).is_err());
+
+        let mut tampered = blob.clone();
+        tampered.key_id[0] ^= 1;
+        assert!(unseal(&provider, &tampered).is_err());
+
+        // an older SVN derives another key, a newer one is refused
+        let mut tampered = blob.clone();
+        tampered.isv_svn = 1;
+        assert!(unseal(&provider, &tampered).is_err());
+        tampered.isv_svn = 3;
+        assert!(unseal(&provider, &tampered).is_err());
+
+        let mut tampered = blob.clone();
+        tampered.key_policy = 1;
+        assert!(unseal(&provider, &tampered).is_err());
+
+        // an old build may not open it, nor one past the minimum SVN
+        assert!(unseal(&SoftwareKeyProvider::new("secret", 1, 1), &blob).is_err());
+        assert!(unseal(&SoftwareKeyProvider::new("secret", 4, 3), &blob).is_err());
+        assert!(unseal(&SoftwareKeyProvider::new("other", 2, 1), &blob).is_err());
+
+        let mut bytes = blob.to_bytes();
+        bytes[0] ^= 1;
+        assert!(SealedBlob::from_bytes(&bytes).is_err());
+        let bytes = blob.to_bytes();
+        assert!(SealedBlob::from_bytes(&bytes[..HEADER_LEN + 2]).is_err());
+        Ok(())
+    }
+}
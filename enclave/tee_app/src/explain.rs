@@ -0,0 +1,247 @@
+use simple_cypher::*;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// The sub-queries a query sends to neo4j, recorded by running it as a dry run.
+///
+/// Reads are run for real, writes are recorded but not sent, and nothing is
+/// committed to the enclave state. Each sub-query is recorded as it is sent, with
+/// every ciphertext replaced by a placeholder, so two placeholders are equal
+/// exactly when neo4j sees equal ciphertexts.
+pub struct Plan {
+    state: Mutex<PlanState>,
+}
+
+#[derive(Default)]
+struct PlanState {
+    steps: Vec<PlanStep>,
+    redactor: Redactor,
+}
+
+struct PlanStep {
+    kind: &'static str,
+    query: String,
+    // the query with unnumbered placeholders, equal for repeats of a sub-query
+    shape: String,
+    repeat: usize,
+    in_neo4j: Vec<String>,
+    in_enclave: Vec<String>,
+}
+
+#[derive(Default)]
+struct Redactor {
+    labels: HashMap<String, String>,
+    keys: HashMap<String, String>,
+    values: HashMap<String, String>,
+}
+
+impl Plan {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(PlanState::default()),
+        }
+    }
+
+    /// Records an encrypted sub-query. A repeat of the previous sub-query with
+    /// other values only counts up its step.
+    pub fn record(&self, kind: &'static str, enc_query: &CypherQuery) {
+        let mut state = self.state.lock().unwrap();
+        let shape = redacted(enc_query, &mut |kind, _| format!("<{}>", kind));
+        let mut query = enc_query.clone();
+        redact_query(&mut query, &mut |kind, plain| {
+            state.redactor.placeholder(kind, plain)
+        });
+
+        if let Some(last) = state.steps.last_mut() {
+            if last.kind == kind && last.shape == shape {
+                last.repeat += 1;
+                return;
+            }
+        }
+        let step = PlanStep {
+            kind,
+            query: query_string(&query),
+            shape,
+            repeat: 1,
+            in_neo4j: pattern_predicates(&query),
+            in_enclave: vec![],
+        };
+        state.steps.push(step);
+    }
+
+    /// Notes what the enclave does with the last sub-query.
+    pub fn note(&self, note: impl Into<String>) {
+        let note = note.into();
+        let mut state = self.state.lock().unwrap();
+        match state.steps.last_mut() {
+            Some(step) => {
+                if !step.in_enclave.contains(&note) {
+                    step.in_enclave.push(note);
+                }
+            }
+            None => state.steps.push(PlanStep::enclave(note)),
+        }
+    }
+
+    /// Adds a step that only runs in the enclave.
+    pub fn push_enclave(&self, note: impl Into<String>) {
+        let mut state = self.state.lock().unwrap();
+        state.steps.push(PlanStep::enclave(note.into()));
+    }
+
+    /// One row per step, each holding a single `Inner` labeled with the kind of
+    /// the step.
+    pub fn into_rows(self) -> Rows {
+        let mut rows = Rows::new_empty();
+        let state = self.state.into_inner().unwrap();
+        for (i, step) in state.steps.into_iter().enumerate() {
+            rows.push(Row::new(vec![Inner::new(
+                vec![step.kind.to_string()],
+                vec![
+                    (String::from("step"), (i + 1).to_string()),
+                    (String::from("query"), step.query),
+                    (String::from("repeat"), step.repeat.to_string()),
+                    (String::from("neo4j"), step.in_neo4j.join("; ")),
+                    (String::from("enclave"), step.in_enclave.join("; ")),
+                ],
+            )]));
+        }
+        rows
+    }
+}
+
+impl PlanStep {
+    fn enclave(note: String) -> Self {
+        Self {
+            kind: "enclave",
+            query: String::new(),
+            shape: String::new(),
+            repeat: 1,
+            in_neo4j: vec![],
+            in_enclave: vec![note],
+        }
+    }
+}
+
+impl Redactor {
+    fn placeholder(&mut self, kind: &str, plain: &str) -> String {
+        let table = match kind {
+            "label" => &mut self.labels,
+            "key" => &mut self.keys,
+            _ => &mut self.values,
+        };
+        let next = table.len() + 1;
+        table
+            .entry(plain.to_string())
+            .or_insert_with(|| format!("<{}_{}>", kind, next))
+            .clone()
+    }
+}
+
+fn redacted(query: &CypherQuery, redact: &mut impl FnMut(&'static str, &str) -> String) -> String {
+    let mut query = query.clone();
+    redact_query(&mut query, redact);
+    query_string(&query)
+}
+
+fn query_string(query: &CypherQuery) -> String {
+    query
+        .to_query_string()
+        .unwrap_or_else(|e| format!("invalid query: {}", e))
+}
+
+// The labels and properties of the patterns, matched by neo4j.
+fn pattern_predicates(query: &CypherQuery) -> Vec<String> {
+    let mut in_neo4j = vec![];
+    for (var, labels, properties) in [
+        query
+            .node
+            .as_ref()
+            .map(|x| (x.var_name(), &x.labels, &x.properties)),
+        query
+            .relation
+            .as_ref()
+            .map(|x| (x.var_name(), &x.labels, &x.properties)),
+        query
+            .next_node
+            .as_ref()
+            .map(|x| (x.var_name(), &x.labels, &x.properties)),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        let var = var.map(|x| x.as_str()).unwrap_or("_");
+        for label in labels {
+            in_neo4j.push(format!("{}:{}", var, label));
+        }
+        for (k, v) in properties {
+            in_neo4j.push(format!("{}.{} = {}", var, k, v));
+        }
+    }
+    in_neo4j
+}
+
+fn redact_query(query: &mut CypherQuery, redact: &mut impl FnMut(&'static str, &str) -> String) {
+    for (labels, properties) in [
+        query
+            .node
+            .as_mut()
+            .map(|x| (&mut x.labels, &mut x.properties)),
+        query
+            .relation
+            .as_mut()
+            .map(|x| (&mut x.labels, &mut x.properties)),
+        query
+            .next_node
+            .as_mut()
+            .map(|x| (&mut x.labels, &mut x.properties)),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        for label in labels.iter_mut() {
+            *label = redact("label", label);
+        }
+        for (k, v) in properties.iter_mut() {
+            *k = redact("key", k);
+            *v = redact("value", v);
+        }
+    }
+    if let Some(list) = query.where_list.as_mut() {
+        for predicate in list.iter_mut() {
+            let (var, key, value) = (
+                predicate.var_name().clone(),
+                redact("key", predicate.key()),
+                redact("value", predicate.value()),
+            );
+            *predicate = match predicate {
+                Predicate::Gt(..) => Predicate::Gt(var, key, value),
+                Predicate::Ge(..) => Predicate::Ge(var, key, value),
+                Predicate::Lt(..) => Predicate::Lt(var, key, value),
+                Predicate::Le(..) => Predicate::Le(var, key, value),
+            };
+        }
+    }
+    for list in [
+        query.set_list.as_mut(),
+        query.remove_list.as_mut(),
+        query.return_list.as_mut(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        for item in list.iter_mut() {
+            *item = match item {
+                Item::Var(var) => Item::Var(var.clone()),
+                Item::VarWithLabel(var, label) => {
+                    Item::VarWithLabel(var.clone(), redact("label", label))
+                }
+                Item::VarWithKey(var, key) => Item::VarWithKey(var.clone(), redact("key", key)),
+                Item::VarWithKeyValue(var, key, value) => {
+                    Item::VarWithKeyValue(var.clone(), redact("key", key), redact("value", value))
+                }
+            };
+        }
+    }
+}
@@ -1,13 +1,30 @@
 use anyhow::Result;
 use simple_cypher::*;
 
-use crate::crypto::Crypto;
-
-use std::collections::{HashMap, VecDeque};
+use crate::audit::{AuditLog, AuditRecord};
+use crate::crypto::{Crypto, EntityKind, IntegrityError, KeyProvider};
+use crate::explain::Plan;
+use crate::index::{bucket_key, is_bucket_key, BucketIndex};
+use crate::label::{LabelHiding, MAGIC_LABELS_KEY};
+use crate::logger::{query_shape, Redacted};
+use crate::membership::MembershipIndex;
+use crate::policy::{Access, AccessPolicy, Action};
+use crate::principal::Principal;
+use crate::tenant::Tenant;
+use crate::version::{VersionChanges, VersionMap, MAGIC_VERSION_KEY};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::RwLock;
 
 pub const MAGIC_HASH_KEY: &str = "hash";
 pub const MAGIC_UID_KEY: &str = "uid";
 
+// length of a hyphenated uuid
+const NODE_UID_LEN: usize = 36;
+// a relationship uid is the uids of its endpoints followed by its own uuid
+const RELATION_UID_LEN: usize = 3 * NODE_UID_LEN;
+
 const NODE_VAR_NAME: &str = "n";
 const RELATION_VAR_NAME: &str = "r";
 const NEXT_NODE_VAR_NAME: &str = "m";
@@ -15,46 +32,667 @@ const NEXT_NODE_VAR_NAME: &str = "m";
 pub struct EncryptedGraph {
     database: neo4rs::Graph,
     crypto: Crypto,
+    bucket_index: BucketIndex,
+    label_hiding: LabelHiding,
+    versions: Mutex<VersionMap>,
+    membership: Mutex<MembershipIndex>,
+    policy: AccessPolicy,
+    audit: AuditLog,
+    // Taken for reading by write queries, and for writing by the rotation job while
+    // it copies the entities written during its copy again, so it can't miss a write.
+    writes: RwLock<()>,
+    // uids of the entities written while a key rotation copies the graph
+    rewritten: Mutex<Option<HashSet<String>>>,
+    // Taken for reading by every query, and for writing when the active key
+    // version changes, so a query never sees both versions as active.
+    rotation: RwLock<()>,
+}
+
+// Per query state threaded through the code executing it.
+struct QueryContext<'a> {
+    access: Access<'a>,
+    // set when the query is only explained, see `Plan`
+    plan: Option<Plan>,
+}
+
+impl QueryContext<'_> {
+    fn plan(&self) -> Option<&Plan> {
+        self.plan.as_ref()
+    }
+
+    fn note(&self, note: &str) {
+        if let Some(plan) = self.plan() {
+            plan.note(note);
+        }
+    }
 }
 
 impl EncryptedGraph {
+    /// The graph of `tenant`, in its neo4j database if it has one.
     pub async fn new(
         uri: impl Into<String>,
         user: impl Into<String>,
         password: impl Into<String>,
+        tenant: &Tenant,
+        root_key: Arc<dyn KeyProvider>,
     ) -> Result<Self> {
-        let database = neo4rs::Graph::new(uri, user, password).await?;
-        let crypto = Crypto::new();
-        Ok(Self { database, crypto })
+        let crypto = tenant.crypto(root_key)?;
+        let mut config = neo4rs::ConfigBuilder::default()
+            .uri(uri)
+            .user(user)
+            .password(password);
+        if let Some(db) = tenant.database.as_deref() {
+            config = config.db(db);
+        }
+        let database = neo4rs::Graph::connect(config.build()?).await?;
+        Ok(Self {
+            database,
+            crypto,
+            bucket_index: BucketIndex::new(),
+            label_hiding: LabelHiding::new(),
+            versions: Mutex::new(VersionMap::new()),
+            membership: Mutex::new(MembershipIndex::new()),
+            policy: AccessPolicy::new(),
+            audit: AuditLog::new(),
+            writes: RwLock::new(()),
+            rewritten: Mutex::new(None),
+            rotation: RwLock::new(()),
+        })
+    }
+
+    pub fn with_bucket_index(mut self, bucket_index: BucketIndex) -> Self {
+        self.crypto
+            .add_matched_keys(bucket_index.keys().cloned().collect::<Vec<_>>());
+        self.bucket_index = bucket_index;
+        self
+    }
+
+    /// Lets neo4j match patterns on the values of `keys`, see `Crypto::is_matched_key`.
+    /// The indexed keys are always matched on.
+    pub fn with_matched_keys(mut self, keys: Vec<String>) -> Self {
+        self.crypto.add_matched_keys(keys);
+        self
+    }
+
+    pub fn with_label_hiding(mut self, label_hiding: LabelHiding) -> Self {
+        self.label_hiding = label_hiding;
+        self
+    }
+
+    pub fn with_version_map(mut self, mut versions: VersionMap) -> Result<Self> {
+        versions.load(&self.crypto)?;
+        self.versions = Mutex::new(versions);
+        Ok(self)
+    }
+
+    pub fn with_membership_index(mut self, mut membership: MembershipIndex) -> Result<Self> {
+        membership.load(&self.crypto)?;
+        self.crypto
+            .add_matched_keys(membership.keys().iter().cloned().collect::<Vec<_>>());
+        self.membership = Mutex::new(membership);
+        Ok(self)
+    }
+
+    pub fn with_access_policy(mut self, mut policy: AccessPolicy) -> Result<Self> {
+        policy.load(&self.crypto)?;
+        self.policy = policy;
+        Ok(self)
+    }
+
+    pub fn with_audit_log(mut self, mut audit: AuditLog) -> Result<Self> {
+        audit.load(&self.crypto)?;
+        self.audit = audit;
+        Ok(self)
+    }
+
+    /// Runs `query` on behalf of `principal`, and records it in the audit log. A
+    /// read whose record can't be written fails, a write reports its result anyway.
+    pub async fn execute_query(&self, principal: &Principal, query: CypherQuery) -> Result<Rows> {
+        let shape = query_shape(&query);
+        let is_write = !query.explain
+            && matches!(
+                query.get_type(),
+                Ok(CRUDtype::Create | CRUDtype::Update | CRUDtype::Delete)
+            );
+        let res = self.execute(principal, query).await;
+        let record = AuditRecord::new(principal, shape, &res);
+        if let Err(e) = self.audit.append(&self.crypto, &record) {
+            // neo4j may have taken the write already, so its result still stands
+            if !is_write {
+                return Err(e);
+            }
+            log::error!("failed to audit a write: {:?}", e);
+        }
+        res
     }
 
-    pub async fn execute_query(&self, mut query: CypherQuery) -> Result<Rows> {
-        log::trace!("execute_query: {:?}", query);
+    async fn execute(&self, principal: &Principal, mut query: CypherQuery) -> Result<Rows> {
+        log::trace!("execute_query by {}: {}", principal.name, Redacted(&query));
+
+        let access = self.policy.access(principal);
+        access.check_query(&query)?;
+        check_reserved_names(&query)?;
 
-        confuse_var_name(&mut query);
+        let crud_type = query.get_type()?;
+        self.resolve_handles(&mut query)?;
+        if !matches!(crud_type, CRUDtype::Union) {
+            confuse_var_name(&mut query);
+        }
+
+        // an explained query runs as a dry run, which sends no writes
+        let ctx = QueryContext {
+            access,
+            plan: query.explain.then(Plan::new),
+        };
+        query.explain = false;
 
-        let mut res = match query.get_type()? {
-            CRUDtype::Create => self.create(query).await?,
-            CRUDtype::Read => self.read(query).await?,
-            CRUDtype::Update => self.update(query).await?,
-            CRUDtype::Delete => self.delete(query).await?,
-            CRUDtype::FindShortestPath => self.find_shortest_path(query).await?,
+        let is_write = ctx.plan.is_none()
+            && matches!(
+                crud_type,
+                CRUDtype::Create | CRUDtype::Update | CRUDtype::Delete
+            );
+        // always in this order, like the rotation job
+        let _writes = if is_write {
+            Some(self.writes.read().await)
+        } else {
+            None
+        };
+        let _rotation = self.rotation.read().await;
+
+        let res = match crud_type {
+            CRUDtype::Create => self.create(&ctx, query).await,
+            CRUDtype::Read => self.read(&ctx, query).await,
+            CRUDtype::Update => self.update(&ctx, query).await,
+            CRUDtype::Delete => self.delete(&ctx, query).await,
+            CRUDtype::FindShortestPath => self.find_shortest_path(&ctx, query).await,
+            CRUDtype::Union => self.union(&ctx, query).await,
         };
+        // a write that failed part way may have changed some nodes already
+        if is_write {
+            self.membership.lock().unwrap().commit(&self.crypto);
+        }
+        let mut res = res?;
+        if let Some(plan) = ctx.plan {
+            return Ok(plan.into_rows());
+        }
 
-        res.rows_mut().iter_mut().for_each(|row| {
+        // a union deduplicates on the rows it returns, so it finishes them itself
+        if !matches!(crud_type, CRUDtype::Union) {
+            self.finish_rows(&ctx.access, &mut res)?;
+        }
+
+        Ok(res)
+    }
+
+    // Turns decrypted rows into the ones returned to `access`: drops the rows it may
+    // not read, the magic keys and the stripped properties, and redacts the rest.
+    fn finish_rows(&self, access: &Access<'_>, rows: &mut Rows) -> Result<()> {
+        retain_readable(access, rows)?;
+        rows.rows_mut().iter_mut().for_each(|row| {
             row.inners_mut().iter_mut().for_each(|inner| {
                 inner.remove_property(MAGIC_HASH_KEY);
                 inner.remove_property(MAGIC_UID_KEY);
+                inner.remove_property(MAGIC_VERSION_KEY);
+                inner.properties.retain(|(k, _)| !is_bucket_key(k));
+                access.strip_properties(inner);
+                access.redact(inner, &self.crypto);
             })
         });
+        Ok(())
+    }
 
-        Ok(res)
+    /// Fails if entities of the active key version store their labels in the other
+    /// form than label hiding asks for, since neo4j couldn't match them on their
+    /// labels. A key rotation converts them, so it is only checked without one.
+    pub async fn check_label_hiding(&self) -> Result<()> {
+        let version = self.crypto.active_version();
+        let uid_key = self.crypto.enc_name(version, MAGIC_UID_KEY)?;
+        let labels_key = self.crypto.enc_name(version, MAGIC_LABELS_KEY)?;
+        let (found, hidden) = if self.label_hiding.is_enabled() {
+            ("IS NULL", "without")
+        } else {
+            ("IS NOT NULL", "with")
+        };
+        for (kind, pattern) in [
+            (EntityKind::Node, format!("({})", NODE_VAR_NAME)),
+            (
+                EntityKind::Relation,
+                format!("()-[{}]->()", RELATION_VAR_NAME),
+            ),
+        ] {
+            let query = neo4rs::query(&format!(
+                "MATCH {pattern} WHERE {var}.{uid} IS NOT NULL AND {var}.{labels} {found} RETURN count(*) AS found",
+                pattern = pattern,
+                var = kind_var(kind),
+                uid = uid_key,
+                labels = labels_key,
+                found = found
+            ));
+            let mut result = self.database.execute(query).await?;
+            let count = match result.next().await? {
+                Some(row) => row.get::<i64>("found")?,
+                None => 0,
+            };
+            if count > 0 {
+                return Err(anyhow::anyhow!(
+                    "{} entities are stored {} label hiding, start a key rotation with KEY_ROTATION=start to convert them",
+                    count,
+                    hidden
+                ));
+            }
+        }
+        Ok(())
     }
 
-    async fn create(&self, mut query: CypherQuery) -> Result<Rows> {
-        log::trace!("enter create with query: {:?}", query);
+    /// Whether a key rotation was started and is not finished yet.
+    pub fn is_rotating_key(&self) -> bool {
+        self.crypto.target_version().is_some()
+    }
+
+    /// Rotates the data key, or finishes a rotation a restart interrupted.
+    ///
+    /// Every entity is copied in batches of `batch_size`, re-encrypted and re-MACed
+    /// under the new key, while queries are still served from the old key version.
+    /// The entities written during the copy are copied again, with writes held
+    /// back only for that. Then the new version becomes active and the entities of
+    /// the old version are deleted.
+    pub async fn rotate_key(&self, batch_size: usize) -> Result<()> {
+        let old_version = self.crypto.active_version();
+        let new_version = self.crypto.begin_rotation()?;
+        log::info!(
+            "copying the graph from key version {} to {}",
+            old_version,
+            new_version
+        );
+        *self.rewritten.lock().unwrap() = Some(HashSet::new());
+        let res = self.copy_graph(old_version, new_version, batch_size).await;
+        *self.rewritten.lock().unwrap() = None;
+        res?;
+
+        for version in self.crypto.retired_versions() {
+            self.delete_key_version(version, batch_size).await?;
+            self.crypto.drop_version(version)?;
+        }
+        Ok(())
+    }
 
-        // TODO: Solve the problem where the uid name may conflict with the property name in the query
+    async fn copy_graph(
+        &self,
+        old_version: u32,
+        new_version: u32,
+        batch_size: usize,
+    ) -> Result<()> {
+        // leftovers of an interrupted copy
+        self.delete_key_version(new_version, batch_size).await?;
+        let nodes = self
+            .copy_entities(EntityKind::Node, old_version, new_version, batch_size)
+            .await?;
+        let relations = self
+            .copy_entities(EntityKind::Relation, old_version, new_version, batch_size)
+            .await?;
+        log::info!("copied {} nodes and {} relationships", nodes, relations);
+
+        let _writes = self.writes.write().await;
+        let rewritten = self.rewritten.lock().unwrap().take().unwrap_or_default();
+        log::info!(
+            "copying {} entities written during the copy",
+            rewritten.len()
+        );
+        self.copy_rewritten(rewritten, old_version, new_version)
+            .await?;
+
+        let _rotation = self.rotation.write().await;
+        self.crypto.activate_target()
+    }
+
+    // Pages through the entities of `old_version` by their encrypted uid, with the
+    // neo4j id breaking ties so no entity is skipped, and writes a copy of each
+    // under `new_version`. Returns the number of copied entities.
+    async fn copy_entities(
+        &self,
+        kind: EntityKind,
+        old_version: u32,
+        new_version: u32,
+        batch_size: usize,
+    ) -> Result<usize> {
+        let uid_key = self.crypto.enc_name(old_version, MAGIC_UID_KEY)?;
+        let pattern = match kind {
+            EntityKind::Node => format!("({})", NODE_VAR_NAME),
+            EntityKind::Relation => format!("()-[{}]->()", RELATION_VAR_NAME),
+        };
+        let var = kind_var(kind);
+
+        let mut copied = 0;
+        let mut last: Option<(String, i64)> = None;
+        let mut last_uid = None;
+        loop {
+            let condition = match last {
+                Some(_) => format!(
+                    "({var}.{key} > $last OR ({var}.{key} = $last AND id({var}) > $last_id))",
+                    var = var,
+                    key = uid_key
+                ),
+                None => format!("{}.{} IS NOT NULL", var, uid_key),
+            };
+            let (last_enc_uid, last_id) = last.clone().unwrap_or_default();
+            let query = neo4rs::query(&format!(
+                "MATCH {pattern} WHERE {condition} RETURN {var}, id({var}) AS id ORDER BY {var}.{key}, id({var}) LIMIT {limit}",
+                pattern = pattern,
+                condition = condition,
+                var = var,
+                key = uid_key,
+                limit = batch_size
+            ))
+            .param("last", last_enc_uid)
+            .param("last_id", last_id);
+            let mut result = self.database.execute(query).await?;
+
+            let mut batch = vec![];
+            while let Some(row) = result.next().await? {
+                let enc_inner = match kind {
+                    EntityKind::Node => build_inner_from_neo4rs_node(row.get(var)?),
+                    EntityKind::Relation => build_inner_from_neo4rs_relation(row.get(var)?),
+                };
+                batch.push((enc_inner, row.get::<i64>("id")?));
+            }
+            if batch.is_empty() {
+                return Ok(copied);
+            }
+            last = batch
+                .last()
+                .and_then(|(inner, id)| Some((inner.get(&uid_key)?.clone(), *id)));
+
+            for (enc_inner, _) in batch {
+                let inner = self.open_copy_source(kind, old_version, enc_inner)?;
+                let uid = inner
+                    .get(MAGIC_UID_KEY)
+                    .ok_or_else(|| anyhow::anyhow!("Data was attacked"))?
+                    .clone();
+                // uids are unique and their ciphertexts sort equal ones together
+                if last_uid.as_ref() == Some(&uid) {
+                    return Err(IntegrityError::DuplicateEntity { uid }.into());
+                }
+                let checked = self
+                    .versions
+                    .lock()
+                    .unwrap()
+                    .check(&uid, inner.get(MAGIC_VERSION_KEY));
+                match checked {
+                    Ok(()) => {
+                        self.write_copy(kind, new_version, &inner).await?;
+                        copied += 1;
+                    }
+                    // a write changed it since it was read, so it is copied again
+                    Err(e) => {
+                        let rewritten = self.rewritten.lock().unwrap();
+                        if !rewritten.as_ref().is_some_and(|x| x.contains(&uid)) {
+                            return Err(e.into());
+                        }
+                    }
+                }
+                last_uid = Some(uid);
+            }
+        }
+    }
+
+    // Copies the entities in `uids` again, which writes changed during the copy of
+    // the graph, and the relationships of the nodes among them, whose copies go
+    // with the old copy of their node.
+    async fn copy_rewritten(
+        &self,
+        uids: HashSet<String>,
+        old_version: u32,
+        new_version: u32,
+    ) -> Result<()> {
+        let (relation_uids, node_uids): (Vec<_>, Vec<_>) = uids
+            .into_iter()
+            .partition(|uid| split_relation_uid(uid).is_ok());
+
+        let mut relations = HashMap::new();
+        for uid in &node_uids {
+            self.delete_pinned(EntityKind::Node, new_version, node_pattern(uid))
+                .await?;
+            for enc_inner in self
+                .fetch_pinned(EntityKind::Node, old_version, node_pattern(uid))
+                .await?
+            {
+                let inner = self.open_copy_source(EntityKind::Node, old_version, enc_inner)?;
+                self.versions
+                    .lock()
+                    .unwrap()
+                    .check(uid, inner.get(MAGIC_VERSION_KEY))?;
+                self.write_copy(EntityKind::Node, new_version, &inner)
+                    .await?;
+            }
+            for pattern in [
+                relation_pattern(Some(uid), None, None),
+                relation_pattern(None, None, Some(uid)),
+            ] {
+                for enc_inner in self
+                    .fetch_pinned(EntityKind::Relation, old_version, pattern)
+                    .await?
+                {
+                    let inner =
+                        self.open_copy_source(EntityKind::Relation, old_version, enc_inner)?;
+                    let uid = inner
+                        .get(MAGIC_UID_KEY)
+                        .ok_or_else(|| anyhow::anyhow!("Data was attacked"))?;
+                    relations.insert(uid.clone(), inner);
+                }
+            }
+        }
+        for uid in &relation_uids {
+            let (from, to) = split_relation_uid(uid)?;
+            let pattern = relation_pattern(Some(from), Some(uid), Some(to));
+            self.delete_pinned(EntityKind::Relation, new_version, pattern.clone())
+                .await?;
+            for enc_inner in self
+                .fetch_pinned(EntityKind::Relation, old_version, pattern)
+                .await?
+            {
+                let inner = self.open_copy_source(EntityKind::Relation, old_version, enc_inner)?;
+                relations.insert(uid.clone(), inner);
+            }
+        }
+
+        for inner in relations.values() {
+            let uid = inner
+                .get(MAGIC_UID_KEY)
+                .ok_or_else(|| anyhow::anyhow!("Data was attacked"))?;
+            self.versions
+                .lock()
+                .unwrap()
+                .check(uid, inner.get(MAGIC_VERSION_KEY))?;
+            self.write_copy(EntityKind::Relation, new_version, inner)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Decrypts and verifies an entity of `version` read for a copy. Its version in
+    // the version map is left to the caller.
+    fn open_copy_source(&self, kind: EntityKind, version: u32, enc_inner: Inner) -> Result<Inner> {
+        if self.crypto.key_version(&enc_inner)? != version {
+            return Err(anyhow::anyhow!("Data was attacked"));
+        }
+        let mut row = self
+            .crypto
+            .decrypt_and_verify(Row::new(vec![enc_inner]), &[kind])?;
+        let mut inner = row.inners_mut().remove(0);
+        self.label_hiding.reveal(&mut inner)?;
+        Ok(inner)
+    }
+
+    async fn write_copy(&self, kind: EntityKind, version: u32, inner: &Inner) -> Result<()> {
+        let mut copy_query = self.build_copy_query(kind, version, inner)?;
+        self.crypto.enc_query_in(version, &mut copy_query)?;
+        self.database
+            .run(neo4rs::Query::from(copy_query.to_query_string()?))
+            .await?;
+        Ok(())
+    }
+
+    // The encrypted entities of `version` matched by `query`.
+    async fn fetch_pinned(
+        &self,
+        kind: EntityKind,
+        version: u32,
+        mut query: CypherQuery,
+    ) -> Result<Vec<Inner>> {
+        let var = kind_var(kind);
+        query.return_list = Some(vec![Item::Var(var.to_string())]);
+        self.crypto.enc_query_in(version, &mut query)?;
+        let mut result = self
+            .database
+            .execute(neo4rs::Query::from(query.to_query_string()?))
+            .await?;
+        let mut inners = vec![];
+        while let Some(row) = result.next().await? {
+            inners.push(match kind {
+                EntityKind::Node => build_inner_from_neo4rs_node(row.get(var)?),
+                EntityKind::Relation => build_inner_from_neo4rs_relation(row.get(var)?),
+            });
+        }
+        Ok(inners)
+    }
+
+    // Deletes the entities of `version` matched by `query`, nodes with their
+    // relationships.
+    async fn delete_pinned(
+        &self,
+        kind: EntityKind,
+        version: u32,
+        mut query: CypherQuery,
+    ) -> Result<()> {
+        let var = kind_var(kind);
+        query.delete_list = Some((
+            vec![Item::Var(var.to_string())],
+            matches!(kind, EntityKind::Node),
+        ));
+        self.crypto.enc_query_in(version, &mut query)?;
+        self.database
+            .run(neo4rs::Query::from(query.to_query_string()?))
+            .await?;
+        Ok(())
+    }
+
+    // CREATE (n:Label {...}), or MATCH (n {uid}), (m {uid}) CREATE (n)-[r:TYPE {...}]->(m)
+    // for a relationship, with the bucket tokens and hash of `version`. Labels are
+    // stored the way the current label hiding asks for, so a rotation also converts
+    // the entities written before it was turned on or off.
+    fn build_copy_query(
+        &self,
+        kind: EntityKind,
+        version: u32,
+        inner: &Inner,
+    ) -> Result<CypherQuery> {
+        let mut properties: Vec<(String, String)> = inner
+            .properties
+            .iter()
+            .filter(|(k, _)| k != MAGIC_HASH_KEY && !is_bucket_key(k))
+            .cloned()
+            .collect();
+        self.bucket_index
+            .refresh_properties(&self.crypto, version, &mut properties)?;
+        let mut labels = inner.labels.clone();
+        self.label_hiding.hide(kind, &mut labels, &mut properties)?;
+        let hash = self
+            .crypto
+            .entity_mac_in(version, kind, &labels, &properties)?;
+        properties.push((MAGIC_HASH_KEY.to_string(), hash));
+
+        match kind {
+            EntityKind::Node => Ok(CypherQueryBuilder::new()
+                .CREATE()
+                .node(Node::new(Some(NODE_VAR_NAME), labels, properties))
+                .build()),
+            EntityKind::Relation => {
+                let uid = inner
+                    .get(MAGIC_UID_KEY)
+                    .ok_or_else(|| anyhow::anyhow!("Data was attacked"))?;
+                let (from_uid, to_uid) = split_relation_uid(uid)?;
+                Ok(CypherQueryBuilder::new()
+                    .MATCH()
+                    .node(Node::new(
+                        Some(NODE_VAR_NAME),
+                        Vec::<String>::new(),
+                        vec![(MAGIC_UID_KEY, from_uid)],
+                    ))
+                    .next_node(Node::new(
+                        Some(NEXT_NODE_VAR_NAME),
+                        Vec::<String>::new(),
+                        vec![(MAGIC_UID_KEY, to_uid)],
+                    ))
+                    .CREATE()
+                    .relation(Relation::new(Some(RELATION_VAR_NAME), labels, properties))
+                    .build())
+            }
+        }
+    }
+
+    // Deletes the nodes of a key version in batches, with their relationships.
+    async fn delete_key_version(&self, version: u32, batch_size: usize) -> Result<()> {
+        let uid_key = self.crypto.enc_name(version, MAGIC_UID_KEY)?;
+        let mut deleted = 0;
+        loop {
+            let query = neo4rs::query(&format!(
+                "MATCH ({var}) WHERE {var}.{key} IS NOT NULL WITH {var} LIMIT {limit} DETACH DELETE {var} RETURN count(*) AS deleted",
+                var = NODE_VAR_NAME,
+                key = uid_key,
+                limit = batch_size
+            ));
+            let mut result = self.database.execute(query).await?;
+            let count = match result.next().await? {
+                Some(row) => row.get::<i64>("deleted")?,
+                None => 0,
+            };
+            if count == 0 {
+                break;
+            }
+            deleted += count;
+        }
+        if deleted > 0 {
+            log::info!("deleted {} nodes of key version {}", deleted, version);
+        }
+        Ok(())
+    }
+
+    // Replaces the handles in the patterns by the uid they were issued for, so the
+    // rest of the pipeline matches exactly that entity.
+    fn resolve_handles(&self, query: &mut CypherQuery) -> Result<()> {
+        for (kind, properties, handle) in [
+            query
+                .node
+                .as_mut()
+                .map(|x| (EntityKind::Node, &mut x.properties, &mut x.handle)),
+            query
+                .relation
+                .as_mut()
+                .map(|x| (EntityKind::Relation, &mut x.properties, &mut x.handle)),
+            query
+                .next_node
+                .as_mut()
+                .map(|x| (EntityKind::Node, &mut x.properties, &mut x.handle)),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if let Some(handle) = handle.take() {
+                let uid = self.crypto.open_handle(kind, &handle)?;
+                properties.retain(|(k, _)| k != MAGIC_UID_KEY);
+                properties.push((MAGIC_UID_KEY.to_string(), uid));
+            }
+        }
+        if let Some((next_query, _)) = query.union.as_mut() {
+            self.resolve_handles(next_query)?;
+        }
+        Ok(())
+    }
+
+    async fn create(&self, ctx: &QueryContext<'_>, mut query: CypherQuery) -> Result<Rows> {
+        log::trace!("enter create with query: {}", Redacted(&query));
+
+        let mut changes = VersionChanges::new();
         match (
             query.node.is_some(),
             query.relation.is_some(),
@@ -63,34 +701,44 @@ impl EncryptedGraph {
         ) {
             // case 1: CREATE (n:Label {name: $value})
             (true, false, false, false) => {
-                add_uid_to_node(query.node.as_mut().unwrap());
-                add_hash_to_node(query.node.as_mut().unwrap());
+                let uid = add_uid_to_node(query.node.as_mut().unwrap());
+                add_version(&mut query.node.as_mut().unwrap().properties, 1);
+                changes.set(uid, 1);
+                self.add_bucket_tokens(&mut query.node.as_mut().unwrap().properties)?;
+                self.hide_node_labels(query.node.as_mut().unwrap())?;
+                add_hash_to_node(&self.crypto, query.node.as_mut().unwrap())?;
             }
             // case 2: CREATE (n:Label)-[r:TYPE]->(m:Label)
             (true, true, true, false) => {
                 let from = add_uid_to_node(query.node.as_mut().unwrap());
                 let to = add_uid_to_node(query.next_node.as_mut().unwrap());
-                add_uid_to_relationship(query.relation.as_mut().unwrap(), &from, &to);
-
-                add_hash_to_node(query.node.as_mut().unwrap());
-                add_hash_to_node(query.next_node.as_mut().unwrap());
-                add_hash_to_relationship(query.relation.as_mut().unwrap());
+                let uid = add_uid_to_relationship(query.relation.as_mut().unwrap(), &from, &to);
+
+                add_version(&mut query.node.as_mut().unwrap().properties, 1);
+                add_version(&mut query.next_node.as_mut().unwrap().properties, 1);
+                add_version(&mut query.relation.as_mut().unwrap().properties, 1);
+                changes.set(from, 1);
+                changes.set(to, 1);
+                changes.set(uid, 1);
+
+                self.add_bucket_tokens(&mut query.node.as_mut().unwrap().properties)?;
+                self.add_bucket_tokens(&mut query.next_node.as_mut().unwrap().properties)?;
+                self.add_bucket_tokens(&mut query.relation.as_mut().unwrap().properties)?;
+
+                self.hide_node_labels(query.node.as_mut().unwrap())?;
+                self.hide_node_labels(query.next_node.as_mut().unwrap())?;
+                self.hide_relation_type(query.relation.as_mut().unwrap())?;
+
+                add_hash_to_node(&self.crypto, query.node.as_mut().unwrap())?;
+                add_hash_to_node(&self.crypto, query.next_node.as_mut().unwrap())?;
+                add_hash_to_relationship(&self.crypto, query.relation.as_mut().unwrap())?;
             }
             // case 3: MATCH (n:Label), (m:Label) CREATE (n)-[r:TYPE]->(m)
             (true, true, true, true) => {
                 // MATCH (n:Label), (m:Label) RETURN n, m
-                let read_query = {
-                    let mut read_query = query.clone();
-                    read_query.relation.take();
-                    read_query.use_create = false;
-                    read_query.return_list.replace(vec![
-                        Item::Var(NODE_VAR_NAME.to_string()),
-                        Item::Var(NEXT_NODE_VAR_NAME.to_string()),
-                    ]);
-                    read_query
-                };
+                let read_query = build_create_read_query(&query);
 
-                let plain_rows = self.read(read_query).await?;
+                let plain_rows = self.read(ctx, read_query).await?;
 
                 let mut res_rows = Rows::new_empty();
                 for plain_row in plain_rows.rows() {
@@ -104,7 +752,7 @@ impl EncryptedGraph {
                         .get(MAGIC_UID_KEY)
                         .ok_or_else(|| anyhow::anyhow!("Data was attacked"))?;
 
-                    let single_query = {
+                    let (single_query, uid) = {
                         let mut single_query = query.clone();
                         single_query
                             .node
@@ -116,40 +764,332 @@ impl EncryptedGraph {
                             .as_mut()
                             .unwrap()
                             .add_property(MAGIC_UID_KEY.to_string(), to_uid.clone());
-                        add_uid_to_relationship(
+                        let uid = add_uid_to_relationship(
                             single_query.relation.as_mut().unwrap(),
                             &from_uid,
                             &to_uid,
                         );
-                        add_hash_to_relationship(single_query.relation.as_mut().unwrap());
+                        self.take_pattern_labels(
+                            &mut single_query,
+                            &[NODE_VAR_NAME, NEXT_NODE_VAR_NAME],
+                        );
+                        self.take_unmatched_properties(
+                            &mut single_query,
+                            &[NODE_VAR_NAME, NEXT_NODE_VAR_NAME],
+                        );
+                        add_version(&mut single_query.relation.as_mut().unwrap().properties, 1);
+                        self.add_bucket_tokens(
+                            &mut single_query.relation.as_mut().unwrap().properties,
+                        )?;
+                        self.hide_relation_type(single_query.relation.as_mut().unwrap())?;
+                        add_hash_to_relationship(
+                            &self.crypto,
+                            single_query.relation.as_mut().unwrap(),
+                        )?;
 
                         self.encrypt_query(&mut single_query)?;
-                        single_query
+                        (single_query, uid)
                     };
 
-                    let result = self.execute_enc_query(single_query).await?;
+                    let mut changes = VersionChanges::new();
+                    changes.set(uid, 1);
+                    let result = self
+                        .execute_write(ctx.plan(), single_query, changes)
+                        .await?;
                     if !result.is_empty() {
                         res_rows.push(result.rows()[0].clone());
                     }
                 }
                 return Ok(res_rows);
             }
-            _ => return Err(anyhow::anyhow!("Invalid query: {:?}", query)),
+            _ => return Err(anyhow::anyhow!("Invalid query: {}", Redacted(&query))),
         }
 
+        let new_nodes: Vec<Node> = [query.node.clone(), query.next_node.clone()]
+            .into_iter()
+            .flatten()
+            .collect();
+
         self.encrypt_query(&mut query)?;
-        self.execute_enc_query(query).await
+        let res = self.execute_write(ctx.plan(), query, changes).await?;
+        self.update_membership(ctx, |membership| {
+            for node in new_nodes {
+                let mut inner = Inner::new(node.labels, node.properties);
+                self.label_hiding.reveal(&mut inner)?;
+                membership.insert_node(&inner.labels, &inner.properties)?;
+            }
+            Ok(())
+        })?;
+        Ok(res)
     }
 
-    async fn read(&self, mut query: CypherQuery) -> Result<Rows> {
-        log::trace!("enter read with query: {:?}", query);
+    // Entities the caller can't read are dropped with their rows, before update,
+    // delete and the traversal of find_shortest_path can see them.
+    async fn read(&self, ctx: &QueryContext<'_>, query: CypherQuery) -> Result<Rows> {
+        let mut res = if query.where_list.is_some() {
+            self.read_with_predicates(ctx.plan(), query).await?
+        } else {
+            self.read_pattern(ctx.plan(), query).await?
+        };
+        retain_readable(&ctx.access, &mut res)?;
+        Ok(res)
+    }
 
-        self.encrypt_query(&mut query)?;
-        self.execute_enc_query(query).await
+    async fn read_pattern(&self, plan: Option<&Plan>, query: CypherQuery) -> Result<Rows> {
+        log::trace!("enter read with query: {}", Redacted(&query));
+
+        // single node patterns can be checked against the membership index
+        let checked_node = match (&query.node, &query.relation, &query.next_node) {
+            (Some(node), None, None) => get_return_vars(&query)
+                .iter()
+                .position(|x| Some(x) == node.var_name())
+                .map(|i| (node.clone(), i)),
+            _ => None,
+        };
+
+        let res = self.execute_plain_query(plan, query).await?;
+
+        if let Some((node, i)) = checked_node {
+            self.check_membership(plan, &node, i, &res)?;
+        }
+        Ok(res)
+    }
+
+    // Checks the `i`th entity of each row against the membership index, as the
+    // nodes neo4j returned for `node`.
+    fn check_membership(
+        &self,
+        plan: Option<&Plan>,
+        node: &Node,
+        i: usize,
+        rows: &Rows,
+    ) -> Result<()> {
+        let membership = self.membership.lock().unwrap();
+        if !membership.is_enabled() {
+            return Ok(());
+        }
+        if let Some(plan) = plan {
+            plan.note("check the nodes against the membership index");
+        }
+        let inners = rows
+            .rows()
+            .iter()
+            .map(|row| {
+                row.inners()
+                    .get(i)
+                    .ok_or_else(|| anyhow::anyhow!("Data was attacked"))
+            })
+            .collect::<Result<Vec<&Inner>>>()?;
+        membership.check_nodes(node, &inners)?;
+        Ok(())
+    }
+
+    // neo4j can't compare ciphertexts, so range predicates are evaluated in the enclave.
+    // If a predicate is on a bucket-indexed property, only its candidate buckets are
+    // fetched from neo4j, otherwise it falls back to a full scan of the pattern. A
+    // single node pattern the membership index can check is always fully scanned, so
+    // the scanned nodes are checked for completeness before the predicates filter them.
+    async fn read_with_predicates(
+        &self,
+        plan: Option<&Plan>,
+        mut query: CypherQuery,
+    ) -> Result<Rows> {
+        log::trace!(
+            "enter read_with_predicates with query: {}",
+            Redacted(&query)
+        );
+
+        let predicates = query.where_list.take().unwrap();
+        let return_vars = get_return_vars(&query);
+        query.return_list.replace(
+            get_pattern_vars(&query)
+                .into_iter()
+                .map(Item::Var)
+                .collect(),
+        );
+        let pattern_vars = get_return_vars(&query);
+
+        let is_checkable = |node: &Node| self.membership.lock().unwrap().is_checkable(node);
+        let checked_node = match (&query.node, &query.relation, &query.next_node) {
+            (Some(node), None, None) if is_checkable(node) => pattern_vars
+                .iter()
+                .position(|x| Some(x) == node.var_name())
+                .map(|i| (node.clone(), i)),
+            _ => None,
+        };
+        let bucket = match checked_node {
+            Some(_) => None,
+            None => self.choose_bucket(&predicates, &pattern_vars)?,
+        };
+
+        let candidate_queries = match bucket {
+            Some((var, key, tokens)) => {
+                log::trace!("read {} buckets of {}.{}", tokens.len(), var, key);
+                let mut queries = vec![];
+                for token in tokens {
+                    let mut candidate_query = query.clone();
+                    add_property_to_pattern(&mut candidate_query, &var, bucket_key(&key), token)?;
+                    queries.push(candidate_query);
+                }
+                queries
+            }
+            None => {
+                log::trace!("read with full scan");
+                vec![query]
+            }
+        };
+
+        let mut res_rows = Rows::new_empty();
+        for candidate_query in candidate_queries {
+            let plain_rows = self.execute_plain_query(plan, candidate_query).await?;
+            if let Some((node, i)) = &checked_node {
+                self.check_membership(plan, node, *i, &plain_rows)?;
+            }
+            if let Some(plan) = plan {
+                plan.note(format!(
+                    "evaluate {} range predicates on the rows",
+                    predicates.len()
+                ));
+            }
+            for plain_row in plain_rows.rows() {
+                if plain_row.inners().len() != pattern_vars.len() {
+                    return Err(anyhow::anyhow!("Data was attacked"));
+                }
+
+                let matched = predicates.iter().all(|predicate| {
+                    let i = pattern_vars
+                        .iter()
+                        .position(|var| var == predicate.var_name())
+                        .unwrap();
+                    plain_row.inners()[i]
+                        .get(predicate.key())
+                        .is_some_and(|value| predicate.eval(value))
+                });
+                if !matched {
+                    continue;
+                }
+
+                let mut row = Row::new_empty();
+                for var in &return_vars {
+                    if let Some(i) = pattern_vars.iter().position(|x| x == var) {
+                        row.push(plain_row.inners()[i].clone());
+                    }
+                }
+                if !row.is_empty() {
+                    res_rows.push(row);
+                }
+            }
+        }
+        Ok(res_rows)
     }
 
-    async fn update(&self, query: CypherQuery) -> Result<Rows> {
-        log::trace!("enter update with query: {:?}", query);
+    // Picks the bucket-indexed predicate target `(var, key)` with the fewest candidate
+    // buckets, or `None` if no predicate can use the bucket index.
+    fn choose_bucket(
+        &self,
+        predicates: &[Predicate],
+        pattern_vars: &[String],
+    ) -> Result<Option<(String, String, Vec<String>)>> {
+        let mut groups: Vec<((&String, &String), Vec<&Predicate>)> = vec![];
+        for predicate in predicates {
+            if !pattern_vars.contains(predicate.var_name()) {
+                return Err(anyhow::anyhow!(
+                    "Invalid predicate: {}",
+                    Redacted(predicate)
+                ));
+            }
+            let target = (predicate.var_name(), predicate.key());
+            match groups.iter_mut().find(|(t, _)| *t == target) {
+                Some((_, group)) => group.push(predicate),
+                None => groups.push((target, vec![predicate])),
+            }
+        }
+
+        let mut best: Option<(String, String, Vec<String>)> = None;
+        for ((var, key), group) in &groups {
+            if let Some(tokens) = self
+                .bucket_index
+                .candidate_tokens(&self.crypto, key, group)?
+            {
+                if best.as_ref().is_none_or(|(_, _, t)| tokens.len() < t.len()) {
+                    best = Some((var.to_string(), key.to_string(), tokens));
+                }
+            }
+        }
+        Ok(best)
+    }
+
+    // Each side of the UNION is read and verified on its own. UNION removes duplicate rows,
+    // where two rows are the same if their entities have the same sorted labels and
+    // properties, as returned to the client after redaction.
+    async fn union(&self, ctx: &QueryContext<'_>, mut query: CypherQuery) -> Result<Rows> {
+        log::trace!("enter union with query: {}", Redacted(&query));
+
+        let mut is_all = false;
+        let mut parts = vec![];
+        loop {
+            let next = query.union.take();
+            parts.push(query);
+            match next {
+                Some((next_query, all)) => {
+                    is_all = all;
+                    query = *next_query;
+                }
+                None => break,
+            }
+        }
+
+        let columns = get_return_vars(&parts[0]);
+        for part in &parts[1..] {
+            if get_return_vars(part) != columns {
+                return Err(anyhow::anyhow!(
+                    "All sub queries in an UNION must have the same column names: {:?}, {:?}",
+                    columns,
+                    get_return_vars(part)
+                ));
+            }
+        }
+
+        let mut seen = HashSet::new();
+        let mut res_rows = Rows::new_empty();
+        for mut part in parts {
+            confuse_var_name(&mut part);
+            let mut plain_rows = self.read(ctx, part).await?;
+            self.finish_rows(&ctx.access, &mut plain_rows)?;
+            for plain_row in plain_rows.rows() {
+                if plain_row.inners().len() != columns.len() {
+                    return Err(anyhow::anyhow!("Data was attacked"));
+                }
+                // duplicates are rows the client can't tell apart, i.e. with the same
+                // labels and returned values
+                let values = plain_row
+                    .inners()
+                    .iter()
+                    .map(|inner| {
+                        let mut labels = inner.labels.clone();
+                        let mut properties = inner.properties.clone();
+                        labels.sort();
+                        properties.sort();
+                        (labels, properties)
+                    })
+                    .collect::<Vec<_>>();
+                if is_all || seen.insert(values) {
+                    res_rows.push(plain_row.clone());
+                }
+            }
+        }
+        if let Some(plan) = ctx.plan() {
+            plan.push_enclave(if is_all {
+                "concatenate the rows of all sub queries (UNION ALL)"
+            } else {
+                "concatenate the rows of all sub queries and remove duplicate values (UNION)"
+            });
+        }
+        Ok(res_rows)
+    }
+
+    async fn update(&self, ctx: &QueryContext<'_>, query: CypherQuery) -> Result<Rows> {
+        log::trace!("enter update with query: {}", Redacted(&query));
 
         match (
             query.node.is_some(),
@@ -158,17 +1098,16 @@ impl EncryptedGraph {
         ) {
             // case 1: MATCH (n:Label {name: $value}) REMOVE / SET
             (true, false, false) => {
-                let read_query = {
-                    let mut read_query = query.clone();
-                    read_query.set_list.take();
-                    read_query.remove_list.take();
-                    read_query
-                        .return_list
-                        .replace(vec![Item::Var(NODE_VAR_NAME.to_string())]);
-                    read_query
-                };
+                let read_query = build_update_read_query(&query);
 
-                let plain_rows = self.read(read_query).await?;
+                let plain_rows = self.read(ctx, read_query).await?;
+                check_targets(
+                    &ctx.access,
+                    &plain_rows,
+                    &[NODE_VAR_NAME.to_string()],
+                    &get_modified_vars(&query),
+                    Action::Update,
+                )?;
 
                 let mut res_rows = Rows::new_empty();
                 for plain_row in plain_rows.rows() {
@@ -182,31 +1121,49 @@ impl EncryptedGraph {
                         .ok_or_else(|| anyhow::anyhow!("Data was attacked"))?
                         .clone();
 
+                    let old_labels = inner.labels.clone();
                     let mut inners = vec![inner];
                     update_inners_by_remove(&mut inners, query.remove_list.as_ref())?;
                     update_inners_by_set(&mut inners, query.set_list.as_ref())?;
 
-                    let single_query = {
+                    let (single_query, changes) = {
                         let mut single_query = query.clone();
                         single_query
                             .node
                             .as_mut()
                             .unwrap()
                             .add_property(MAGIC_UID_KEY.to_string(), uid);
+                        self.take_pattern_labels(&mut single_query, &[NODE_VAR_NAME]);
+                        self.take_unmatched_properties(&mut single_query, &[NODE_VAR_NAME]);
+                        self.sync_bucket_tokens(&mut single_query, NODE_VAR_NAME, &mut inners[0])?;
+                        self.label_hiding.sync_items(
+                            &mut single_query,
+                            NODE_VAR_NAME,
+                            EntityKind::Node,
+                            &old_labels,
+                            &inners[0],
+                        )?;
+                        let mut changes = VersionChanges::new();
+                        let (uid, version) =
+                            bump_version(&mut single_query, NODE_VAR_NAME, &mut inners[0])?;
+                        changes.set(uid, version);
                         single_query
                             .set_list
                             .get_or_insert(vec![])
                             .push(Item::VarWithKeyValue(
                                 NODE_VAR_NAME.to_string(),
                                 MAGIC_HASH_KEY.to_string(),
-                                get_inner_hash(&mut inners[0]),
+                                self.stored_hash(EntityKind::Node, &inners[0])?,
                             ));
 
                         self.encrypt_query(&mut single_query)?;
-                        single_query
+                        (single_query, changes)
                     };
 
-                    let result = self.execute_enc_query(single_query).await?;
+                    let result = self
+                        .execute_write(ctx.plan(), single_query, changes)
+                        .await?;
+                    self.update_membership(ctx, |x| x.update_node(&inners[0]))?;
                     if !result.is_empty() {
                         res_rows.push(result.rows()[0].clone());
                     }
@@ -215,19 +1172,16 @@ impl EncryptedGraph {
             }
             // case 2: MATCH (n:Label {name: $value})-[r]->(m) REMOVE / SET
             (true, true, true) => {
-                let read_query = {
-                    let mut read_query = query.clone();
-                    read_query.set_list.take();
-                    read_query.remove_list.take();
-                    read_query.return_list.replace(vec![
-                        Item::Var(NODE_VAR_NAME.to_string()),
-                        Item::Var(RELATION_VAR_NAME.to_string()),
-                        Item::Var(NEXT_NODE_VAR_NAME.to_string()),
-                    ]);
-                    read_query
-                };
+                let read_query = build_update_read_query(&query);
 
-                let plain_rows = self.read(read_query).await?;
+                let plain_rows = self.read(ctx, read_query).await?;
+                check_targets(
+                    &ctx.access,
+                    &plain_rows,
+                    &[NODE_VAR_NAME, RELATION_VAR_NAME, NEXT_NODE_VAR_NAME].map(String::from),
+                    &get_modified_vars(&query),
+                    Action::Update,
+                )?;
 
                 let mut res_rows = Rows::new_empty();
                 for plain_row in plain_rows.rows() {
@@ -236,21 +1190,79 @@ impl EncryptedGraph {
                     }
 
                     let mut inners = plain_row.inners().clone();
-                    let r_uid = inners[1]
-                        .get(MAGIC_UID_KEY)
-                        .ok_or_else(|| anyhow::anyhow!("Data was attacked"))?
-                        .clone();
+                    let old_labels: Vec<Vec<String>> =
+                        inners.iter().map(|x| x.labels.clone()).collect();
+                    let mut uids = vec![];
+                    for inner in &inners {
+                        uids.push(
+                            inner
+                                .get(MAGIC_UID_KEY)
+                                .ok_or_else(|| anyhow::anyhow!("Data was attacked"))?
+                                .clone(),
+                        );
+                    }
 
                     update_inners_by_remove(&mut inners, query.remove_list.as_ref())?;
                     update_inners_by_set(&mut inners, query.set_list.as_ref())?;
 
-                    let single_query = {
+                    let (single_query, changes) = {
                         let mut single_query = query.clone();
-                        single_query
-                            .relation
-                            .as_mut()
-                            .unwrap()
-                            .add_property(MAGIC_UID_KEY.to_string(), r_uid);
+                        // the endpoints are pinned too, values set on them are sealed
+                        // under their uids
+                        for (var, uid) in [NODE_VAR_NAME, RELATION_VAR_NAME, NEXT_NODE_VAR_NAME]
+                            .into_iter()
+                            .zip(uids)
+                        {
+                            add_property_to_pattern(
+                                &mut single_query,
+                                var,
+                                MAGIC_UID_KEY.to_string(),
+                                uid,
+                            )?;
+                        }
+                        self.take_pattern_labels(
+                            &mut single_query,
+                            &[NODE_VAR_NAME, RELATION_VAR_NAME, NEXT_NODE_VAR_NAME],
+                        );
+                        self.take_unmatched_properties(
+                            &mut single_query,
+                            &[NODE_VAR_NAME, RELATION_VAR_NAME, NEXT_NODE_VAR_NAME],
+                        );
+                        for (i, (var, kind)) in [
+                            (NODE_VAR_NAME, EntityKind::Node),
+                            (RELATION_VAR_NAME, EntityKind::Relation),
+                            (NEXT_NODE_VAR_NAME, EntityKind::Node),
+                        ]
+                        .into_iter()
+                        .enumerate()
+                        {
+                            self.label_hiding.sync_items(
+                                &mut single_query,
+                                var,
+                                kind,
+                                &old_labels[i],
+                                &inners[i],
+                            )?;
+                        }
+                        self.sync_bucket_tokens(&mut single_query, NODE_VAR_NAME, &mut inners[0])?;
+                        self.sync_bucket_tokens(
+                            &mut single_query,
+                            RELATION_VAR_NAME,
+                            &mut inners[1],
+                        )?;
+                        self.sync_bucket_tokens(
+                            &mut single_query,
+                            NEXT_NODE_VAR_NAME,
+                            &mut inners[2],
+                        )?;
+                        let mut changes = VersionChanges::new();
+                        for (var, inner) in [NODE_VAR_NAME, RELATION_VAR_NAME, NEXT_NODE_VAR_NAME]
+                            .into_iter()
+                            .zip(inners.iter_mut())
+                        {
+                            let (uid, version) = bump_version(&mut single_query, var, inner)?;
+                            changes.set(uid, version);
+                        }
                         single_query
                             .set_list
                             .get_or_insert(vec![])
@@ -258,44 +1270,165 @@ impl EncryptedGraph {
                                 Item::VarWithKeyValue(
                                     NODE_VAR_NAME.to_string(),
                                     MAGIC_HASH_KEY.to_string(),
-                                    get_inner_hash(&mut inners[0]),
+                                    self.stored_hash(EntityKind::Node, &inners[0])?,
                                 ),
                                 Item::VarWithKeyValue(
                                     RELATION_VAR_NAME.to_string(),
                                     MAGIC_HASH_KEY.to_string(),
-                                    get_inner_hash(&mut inners[1]),
+                                    self.stored_hash(EntityKind::Relation, &inners[1])?,
                                 ),
                                 Item::VarWithKeyValue(
                                     NEXT_NODE_VAR_NAME.to_string(),
                                     MAGIC_HASH_KEY.to_string(),
-                                    get_inner_hash(&mut inners[2]),
+                                    self.stored_hash(EntityKind::Node, &inners[2])?,
                                 ),
                             ]);
 
                         self.encrypt_query(&mut single_query)?;
-                        single_query
+                        (single_query, changes)
                     };
 
-                    let result = self.execute_enc_query(single_query).await?;
+                    let result = self
+                        .execute_write(ctx.plan(), single_query, changes)
+                        .await?;
+                    self.update_membership(ctx, |membership| {
+                        membership.update_node(&inners[0])?;
+                        membership.update_node(&inners[2])
+                    })?;
                     if !result.is_empty() {
                         res_rows.push(result.rows()[0].clone());
                     }
                 }
                 return Ok(res_rows);
             }
-            _ => return Err(anyhow::anyhow!("Invalid query: {:?}", query)),
+            _ => return Err(anyhow::anyhow!("Invalid query: {}", Redacted(&query))),
         }
     }
 
-    async fn delete(&self, mut query: CypherQuery) -> Result<Rows> {
-        log::trace!("enter create with delete: {:?}", query);
+    async fn delete(&self, ctx: &QueryContext<'_>, mut query: CypherQuery) -> Result<Rows> {
+        log::trace!("enter delete with query: {}", Redacted(&query));
+
+        // read the entities first, so they can be dropped from the version map
+        let (delete_vars, detach) = match query.delete_list.as_ref() {
+            Some((list, detach)) => (
+                list.iter()
+                    .filter_map(|x| match x {
+                        Item::Var(var) => Some(var.clone()),
+                        _ => None,
+                    })
+                    .collect::<Vec<String>>(),
+                *detach,
+            ),
+            None => return Err(anyhow::anyhow!("Invalid query: {}", Redacted(&query))),
+        };
+        let pattern_vars = get_pattern_vars(&query);
+        let read_query = build_delete_read_query(&query, &pattern_vars);
+        let plain_rows = self.read(ctx, read_query).await?;
+        check_targets(
+            &ctx.access,
+            &plain_rows,
+            &pattern_vars,
+            &delete_vars,
+            Action::Delete,
+        )?;
+
+        for plain_row in plain_rows.rows() {
+            if plain_row.inners().len() != pattern_vars.len() {
+                return Err(anyhow::anyhow!("Data was attacked"));
+            }
+        }
+        // the entities of `rows` leave the version map once neo4j deleted them
+        let deleted = |rows: &[&Row]| -> Result<(VersionChanges, HashSet<String>)> {
+            let mut changes = VersionChanges::new();
+            let mut node_uids = HashSet::new();
+            for row in rows {
+                for (var, inner) in pattern_vars.iter().zip(row.inners()) {
+                    if !delete_vars.contains(var) {
+                        continue;
+                    }
+                    let uid = inner
+                        .get(MAGIC_UID_KEY)
+                        .ok_or_else(|| anyhow::anyhow!("Data was attacked"))?;
+                    if var != RELATION_VAR_NAME {
+                        // DETACH DELETE also removes the relationships of the node
+                        if detach {
+                            changes.detach(uid.clone());
+                        }
+                        node_uids.insert(uid.clone());
+                    }
+                    changes.remove(uid.clone());
+                }
+            }
+            Ok((changes, node_uids))
+        };
 
-        self.encrypt_query(&mut query)?;
-        self.execute_enc_query(query).await
+        let mut res = Rows::new_empty();
+        let mut node_uids = HashSet::new();
+        let mut pattern = query.clone();
+        let is_matched_in_enclave = !self
+            .take_unmatched_properties(
+                &mut pattern,
+                &[NODE_VAR_NAME, RELATION_VAR_NAME, NEXT_NODE_VAR_NAME],
+            )
+            .is_empty();
+        if ctx.access.is_restricted() || self.label_hiding.is_enabled() || is_matched_in_enclave {
+            // neo4j would also delete the matches the caller can't read, and can't
+            // match hidden labels or unmatched properties, so only the rows read
+            // above are deleted, each pinned by its uids
+            for plain_row in plain_rows.rows() {
+                let mut single_query = query.clone();
+                for (var, inner) in pattern_vars.iter().zip(plain_row.inners()) {
+                    let uid = inner
+                        .get(MAGIC_UID_KEY)
+                        .ok_or_else(|| anyhow::anyhow!("Data was attacked"))?;
+                    add_property_to_pattern(
+                        &mut single_query,
+                        var,
+                        MAGIC_UID_KEY.to_string(),
+                        uid.clone(),
+                    )?;
+                }
+                self.take_pattern_labels(
+                    &mut single_query,
+                    &[NODE_VAR_NAME, RELATION_VAR_NAME, NEXT_NODE_VAR_NAME],
+                );
+                self.take_unmatched_properties(
+                    &mut single_query,
+                    &[NODE_VAR_NAME, RELATION_VAR_NAME, NEXT_NODE_VAR_NAME],
+                );
+                self.encrypt_query(&mut single_query)?;
+                let (changes, uids) = deleted(&[plain_row])?;
+                for row in self
+                    .execute_write(ctx.plan(), single_query, changes)
+                    .await?
+                    .rows()
+                {
+                    res.push(row.clone());
+                }
+                node_uids.extend(uids);
+            }
+        } else {
+            self.encrypt_query(&mut query)?;
+            let (changes, uids) = deleted(&plain_rows.rows().iter().collect::<Vec<_>>())?;
+            res = self.execute_write(ctx.plan(), query, changes).await?;
+            node_uids = uids;
+        }
+
+        self.update_membership(ctx, |membership| {
+            for uid in &node_uids {
+                membership.remove_node(uid);
+            }
+            Ok(())
+        })?;
+        Ok(res)
     }
 
-    async fn find_shortest_path(&self, mut query: CypherQuery) -> Result<Rows> {
-        log::trace!("enter find_shortest_path with query: {:?}", query);
+    async fn find_shortest_path(
+        &self,
+        ctx: &QueryContext<'_>,
+        mut query: CypherQuery,
+    ) -> Result<Rows> {
+        log::trace!("enter find_shortest_path with query: {}", Redacted(&query));
 
         let mut src = query.node.take().unwrap();
         src.var_name.replace(NODE_VAR_NAME.to_string());
@@ -306,7 +1439,7 @@ impl EncryptedGraph {
         let mut queue: VecDeque<String> = VecDeque::new();
         let mut uid2node: HashMap<String, (Inner, String)> = HashMap::new();
 
-        let (_src_uid, dst_uid) = {
+        let dst_uid = {
             let read_query = CypherQueryBuilder::new()
                 .MATCH()
                 .node(src.clone())
@@ -317,7 +1450,7 @@ impl EncryptedGraph {
                 ])
                 .build();
 
-            let plain_rows = self.read(read_query).await?;
+            let plain_rows = self.read(ctx, read_query).await?;
 
             if plain_rows.rows().len() != 1 || plain_rows.rows()[0].inners().len() != 2 {
                 return Err(anyhow::anyhow!("Data was attacked"));
@@ -338,7 +1471,7 @@ impl EncryptedGraph {
                 (plain_rows.rows()[0].inners()[0].clone(), String::new()),
             );
 
-            (src_uid, dst_uid)
+            dst_uid
         };
 
         let mut res = Rows::new_empty();
@@ -363,22 +1496,9 @@ impl EncryptedGraph {
                 break;
             }
 
-            let read_query = CypherQueryBuilder::new()
-                .MATCH()
-                .node(Node::new(
-                    None::<String>,
-                    Vec::<String>::new(),
-                    vec![(MAGIC_UID_KEY, cur_uid.clone())],
-                ))
-                .relation(Relation::new_with_var(RELATION_VAR_NAME))
-                .next_node(Node::new_with_var(NEXT_NODE_VAR_NAME))
-                .RETURN(vec![
-                    Item::Var(RELATION_VAR_NAME.to_string()),
-                    Item::Var(NEXT_NODE_VAR_NAME.to_string()),
-                ])
-                .build();
+            let read_query = build_expand_query(cur_uid.clone());
 
-            let plain_rows = self.read(read_query).await?;
+            let plain_rows = self.read(ctx, read_query).await?;
 
             for plain_row in plain_rows.rows() {
                 if plain_row.inners().len() != 2 {
@@ -397,7 +1517,8 @@ impl EncryptedGraph {
                     .ok_or_else(|| anyhow::anyhow!("Data was attacked"))?
                     .clone();
 
-                if r_uid != cur_uid.clone() + &next_uid {
+                ctx.note("check that the relationship joins the node to the next one");
+                if split_relation_uid(&r_uid)? != (cur_uid.as_str(), next_uid.as_str()) {
                     return Err(anyhow::anyhow!("Data was attacked"));
                 }
 
@@ -411,14 +1532,318 @@ impl EncryptedGraph {
         Ok(res)
     }
 
+    fn add_bucket_tokens(&self, properties: &mut Vec<(String, String)>) -> Result<()> {
+        self.bucket_index.refresh_properties(
+            &self.crypto,
+            self.crypto.active_version(),
+            properties,
+        )?;
+        Ok(())
+    }
+
+    // Keeps the bucket tokens of an updated entity in sync with its indexed properties,
+    // and writes the changes back through the SET / REMOVE lists of the query.
+    fn sync_bucket_tokens(
+        &self,
+        query: &mut CypherQuery,
+        var: &str,
+        inner: &mut Inner,
+    ) -> Result<()> {
+        let removed = self.bucket_index.refresh_properties(
+            &self.crypto,
+            self.crypto.active_version(),
+            &mut inner.properties,
+        )?;
+        for (k, v) in inner.properties.iter().filter(|(k, _)| is_bucket_key(k)) {
+            query
+                .set_list
+                .get_or_insert(vec![])
+                .push(Item::VarWithKeyValue(var.to_string(), k.clone(), v.clone()));
+        }
+        for k in removed {
+            query
+                .remove_list
+                .get_or_insert(vec![])
+                .push(Item::VarWithKey(var.to_string(), k));
+        }
+        Ok(())
+    }
+
+    fn hide_node_labels(&self, node: &mut Node) -> Result<()> {
+        self.label_hiding
+            .hide(EntityKind::Node, &mut node.labels, &mut node.properties)
+    }
+
+    fn hide_relation_type(&self, relation: &mut Relation) -> Result<()> {
+        self.label_hiding.hide(
+            EntityKind::Relation,
+            &mut relation.labels,
+            &mut relation.properties,
+        )
+    }
+
+    // MAC of an entity in the form it is stored in, with its labels hidden.
+    fn stored_hash(&self, kind: EntityKind, inner: &Inner) -> Result<String> {
+        let (mut labels, mut properties) = (inner.labels.clone(), inner.properties.clone());
+        self.label_hiding.hide(kind, &mut labels, &mut properties)?;
+        self.crypto.entity_mac(kind, &labels, &properties)
+    }
+
+    // With label hiding, neo4j can't match the labels of a pattern. The labels of the
+    // patterns at the positions of `vars` are replaced by the generic label, and
+    // returned per var to be checked in the enclave. A labeled pattern without var
+    // gets the var of its position.
+    // Takes the properties of the patterns at `vars` neo4j can't match on, which are
+    // checked in the enclave instead, see `Crypto::is_matched_key`.
+    fn take_unmatched_properties(
+        &self,
+        query: &mut CypherQuery,
+        vars: &[&str],
+    ) -> Vec<(String, Vec<(String, String)>)> {
+        let mut taken = vec![];
+        for (position, var_name, properties) in [
+            query
+                .node
+                .as_mut()
+                .map(|x| (NODE_VAR_NAME, &mut x.var_name, &mut x.properties)),
+            query
+                .relation
+                .as_mut()
+                .map(|x| (RELATION_VAR_NAME, &mut x.var_name, &mut x.properties)),
+            query
+                .next_node
+                .as_mut()
+                .map(|x| (NEXT_NODE_VAR_NAME, &mut x.var_name, &mut x.properties)),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if !vars.contains(&position) {
+                continue;
+            }
+            let (matched, unmatched) = std::mem::take(properties)
+                .into_iter()
+                .partition(|(k, _)| self.crypto.is_matched_key(k));
+            *properties = matched;
+            if !unmatched.is_empty() {
+                let var = var_name.get_or_insert_with(|| position.to_string()).clone();
+                taken.push((var, unmatched));
+            }
+        }
+        taken
+    }
+
+    fn take_pattern_labels(
+        &self,
+        query: &mut CypherQuery,
+        vars: &[&str],
+    ) -> Vec<(String, Vec<String>)> {
+        let mut taken = vec![];
+        if !self.label_hiding.is_enabled() {
+            return taken;
+        }
+        for (position, kind, var_name, labels) in [
+            query.node.as_mut().map(|x| {
+                (
+                    NODE_VAR_NAME,
+                    EntityKind::Node,
+                    &mut x.var_name,
+                    &mut x.labels,
+                )
+            }),
+            query.relation.as_mut().map(|x| {
+                (
+                    RELATION_VAR_NAME,
+                    EntityKind::Relation,
+                    &mut x.var_name,
+                    &mut x.labels,
+                )
+            }),
+            query.next_node.as_mut().map(|x| {
+                (
+                    NEXT_NODE_VAR_NAME,
+                    EntityKind::Node,
+                    &mut x.var_name,
+                    &mut x.labels,
+                )
+            }),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if !vars.contains(&position) || labels.is_empty() {
+                continue;
+            }
+            let var = var_name.get_or_insert_with(|| position.to_string()).clone();
+            let generic = self.label_hiding.generic_labels(kind);
+            taken.push((var, std::mem::replace(labels, generic)));
+        }
+        taken
+    }
+
+    // Picks the label with salted tokens that has the fewest of them, to be matched
+    // by neo4j, or `None` if no label has tokens.
+    fn choose_label_tokens(
+        &self,
+        labels: &[(String, Vec<String>)],
+    ) -> Option<(String, String, Vec<String>)> {
+        let mut best: Option<(String, String, Vec<String>)> = None;
+        for (var, labels) in labels {
+            for label in labels {
+                if let Some(tokens) = self.label_hiding.tokens(label) {
+                    if best.as_ref().is_none_or(|(_, _, t)| tokens.len() < t.len()) {
+                        best = Some((var.clone(), label.clone(), tokens));
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    // Runs a plaintext read on neo4j. With label hiding, the labels of the patterns are
+    // checked in the enclave, and a label with salted tokens is matched by neo4j with
+    // one query per token.
+    async fn execute_plain_query(
+        &self,
+        plan: Option<&Plan>,
+        mut query: CypherQuery,
+    ) -> Result<Rows> {
+        let labels = self.take_pattern_labels(
+            &mut query,
+            &[NODE_VAR_NAME, RELATION_VAR_NAME, NEXT_NODE_VAR_NAME],
+        );
+        let properties = self.take_unmatched_properties(
+            &mut query,
+            &[NODE_VAR_NAME, RELATION_VAR_NAME, NEXT_NODE_VAR_NAME],
+        );
+        if labels.is_empty() && properties.is_empty() {
+            self.encrypt_query(&mut query)?;
+            return self.execute_enc_query(plan, query).await;
+        }
+
+        let return_vars = get_return_vars(&query);
+        query.return_list.replace(
+            get_pattern_vars(&query)
+                .into_iter()
+                .map(Item::Var)
+                .collect(),
+        );
+        let pattern_vars = get_return_vars(&query);
+
+        let candidate_queries = match self.choose_label_tokens(&labels) {
+            Some((var, _, tokens)) => {
+                log::trace!("read {} label tokens of {}", tokens.len(), var);
+                let mut queries = vec![];
+                for token in tokens {
+                    let mut candidate_query = query.clone();
+                    add_label_to_pattern(&mut candidate_query, &var, token)?;
+                    queries.push(candidate_query);
+                }
+                queries
+            }
+            None => vec![query],
+        };
+
+        let mut res_rows = Rows::new_empty();
+        for mut candidate_query in candidate_queries {
+            self.encrypt_query(&mut candidate_query)?;
+            let plain_rows = self.execute_enc_query(plan, candidate_query).await?;
+            if let Some(plan) = plan {
+                plan.note("check the hidden labels and unmatched properties of the rows");
+            }
+            for plain_row in plain_rows.rows() {
+                if plain_row.inners().len() != pattern_vars.len() {
+                    return Err(anyhow::anyhow!("Data was attacked"));
+                }
+
+                let matched = labels.iter().all(|(var, labels)| {
+                    let i = pattern_vars.iter().position(|x| x == var).unwrap();
+                    labels
+                        .iter()
+                        .all(|label| plain_row.inners()[i].labels.contains(label))
+                }) && properties.iter().all(|(var, properties)| {
+                    let i = pattern_vars.iter().position(|x| x == var).unwrap();
+                    properties
+                        .iter()
+                        .all(|(k, v)| plain_row.inners()[i].get(k) == Some(v))
+                });
+                if !matched {
+                    continue;
+                }
+
+                let mut row = Row::new_empty();
+                for var in &return_vars {
+                    if let Some(i) = pattern_vars.iter().position(|x| x == var) {
+                        row.push(plain_row.inners()[i].clone());
+                    }
+                }
+                if !row.is_empty() {
+                    res_rows.push(row);
+                }
+            }
+        }
+        Ok(res_rows)
+    }
+
+    // Runs a write on neo4j. Its new versions are accepted by reads while it runs,
+    // and committed to the version map only once neo4j took it, so a write neo4j
+    // drops later shows up as a stale version instead of going unnoticed.
+    // A dry run only records the write.
+    async fn execute_write(
+        &self,
+        plan: Option<&Plan>,
+        enc_query: CypherQuery,
+        changes: VersionChanges,
+    ) -> Result<Rows> {
+        if let Some(plan) = plan {
+            plan.record("write", &enc_query);
+            plan.note("not sent in a dry run");
+            return Ok(Rows::new_empty());
+        }
+        self.versions.lock().unwrap().begin(&changes);
+        let res = self.execute_enc_query(None, enc_query).await;
+        // before the commit, which a key rotation copying the entities may see
+        if let Some(rewritten) = self.rewritten.lock().unwrap().as_mut() {
+            rewritten.extend(changes.uids().cloned());
+        }
+        let mut versions = self.versions.lock().unwrap();
+        match res {
+            Ok(rows) => {
+                versions.commit(&self.crypto, changes);
+                Ok(rows)
+            }
+            Err(e) => {
+                versions.abort(&changes);
+                Err(e)
+            }
+        }
+    }
+
+    // Applies a write neo4j took to the membership index. A dry run sent no write,
+    // so it changes nothing.
+    fn update_membership(
+        &self,
+        ctx: &QueryContext<'_>,
+        update: impl FnOnce(&mut MembershipIndex) -> Result<()>,
+    ) -> Result<()> {
+        if ctx.plan.is_some() {
+            return Ok(());
+        }
+        update(&mut self.membership.lock().unwrap())
+    }
+
     fn encrypt_query(&self, query: &mut CypherQuery) -> Result<()> {
         log::trace!("enter encrypt_query");
 
         self.crypto.enc_query(query)
     }
 
-    async fn execute_enc_query(&self, enc_query: CypherQuery) -> Result<Rows> {
-        log::trace!("enter execute_enc_query: {:?}", enc_query);
+    async fn execute_enc_query(&self, plan: Option<&Plan>, enc_query: CypherQuery) -> Result<Rows> {
+        log::trace!("enter execute_enc_query: {}", Redacted(&enc_query));
+        if let Some(plan) = plan {
+            plan.record("read", &enc_query);
+            plan.note("decrypt and verify the returned entities");
+        }
 
         let mut result = self
             .database
@@ -426,21 +1851,68 @@ impl EncryptedGraph {
             .await?;
 
         let return_list = get_return_vars(&enc_query);
+        let active_version = self.crypto.active_version();
+        let mut skipped_versions = self.crypto.retired_versions();
+        skipped_versions.extend(self.crypto.target_version());
         let mut res_rows = Rows::new_empty();
-        while let Ok(Some(row)) = result.next().await {
-            // todo: verify result according to the query
+        while let Some(row) = result.next().await? {
             let mut res_enc_row = Row::new_empty();
+            let mut kinds = vec![];
+            let mut row_vars = vec![];
             for var in &return_list {
                 if let Ok(n) = row.get::<neo4rs::Node>(var) {
                     res_enc_row.push(build_inner_from_neo4rs_node(n));
+                    kinds.push(EntityKind::Node);
+                    row_vars.push(var);
                 }
                 if let Ok(r) = row.get::<neo4rs::Relation>(var) {
                     res_enc_row.push(build_inner_from_neo4rs_relation(r));
+                    kinds.push(EntityKind::Relation);
+                    row_vars.push(var);
                 }
             }
+            // each returned variable holds exactly one entity, the pattern and
+            // versions of which are checked below and by the callers
+            if row_vars.len() != return_list.len() {
+                return Err(anyhow::anyhow!("Data was attacked"));
+            }
+
+            // Rows of another key version are the copies of an unfinished key
+            // rotation, or the originals of a finished one that are not removed yet.
+            // A relationship connects nodes of its own version only.
+            let key_versions = res_enc_row
+                .inners()
+                .iter()
+                .map(|inner| self.crypto.key_version(inner))
+                .collect::<Result<Vec<_>>>()?;
+            if let Some(&version) = key_versions.iter().find(|v| **v != active_version) {
+                if skipped_versions.contains(&version) && key_versions.iter().all(|v| *v == version)
+                {
+                    continue;
+                }
+                return Err(IntegrityError::UnexpectedKeyVersion { version }.into());
+            }
 
             if !res_enc_row.is_empty() {
-                res_rows.push(self.crypto.decrypt_and_verify(res_enc_row)?);
+                let mut res_row = self.crypto.decrypt_and_verify(res_enc_row, &kinds)?;
+                check_endpoints(&enc_query, &row_vars, &res_row)?;
+                {
+                    let versions = self.versions.lock().unwrap();
+                    for inner in res_row.inners() {
+                        let uid = inner
+                            .get(MAGIC_UID_KEY)
+                            .ok_or_else(|| anyhow::anyhow!("Data was attacked"))?;
+                        versions.check(uid, inner.get(MAGIC_VERSION_KEY))?;
+                    }
+                }
+                for (inner, kind) in res_row.inners_mut().iter_mut().zip(kinds) {
+                    self.label_hiding.reveal(inner)?;
+                    if let Some(uid) = inner.get(MAGIC_UID_KEY) {
+                        let handle = self.crypto.seal_handle(kind, uid)?;
+                        inner.set_handle(handle);
+                    }
+                }
+                res_rows.push(res_row);
             }
         }
 
@@ -451,7 +1923,7 @@ impl EncryptedGraph {
 }
 
 fn confuse_var_name(query: &mut CypherQuery) {
-    log::trace!("enter confuse_var_name: {:?}", query);
+    log::trace!("enter confuse_var_name: {}", Redacted(query));
 
     let map_table = {
         let mut map_table = vec![];
@@ -531,59 +2003,30 @@ fn confuse_var_name(query: &mut CypherQuery) {
     if let Some((list, _)) = query.delete_list.as_mut() {
         update_var_name(list);
     }
+    if let Some(list) = query.where_list.as_mut() {
+        for predicate in list {
+            for (old_var, new_var) in &map_table {
+                if predicate.var_name() == old_var {
+                    *predicate.var_name_mut() = new_var.clone();
+                    break;
+                }
+            }
+        }
+    }
 
-    log::trace!("exit confuse_var_name: {:?}", query);
+    log::trace!("exit confuse_var_name: {}", Redacted(query));
 }
 
-fn add_hash_to_node(inner: &mut Node) {
-    inner.labels.sort();
-    inner.properties.sort();
-
-    let mut hasher = blake3::Hasher::new();
-    inner.labels.iter().for_each(|x| {
-        hasher.update(x.as_bytes());
-    });
-    inner.properties.iter().for_each(|(k, v)| {
-        hasher.update(k.as_bytes());
-        hasher.update(v.as_bytes());
-    });
-    inner
-        .properties
-        .push((MAGIC_HASH_KEY.to_string(), hasher.finalize().to_string()));
-}
-
-fn add_hash_to_relationship(inner: &mut Relation) {
-    inner.labels.sort();
-    inner.properties.sort();
-
-    let mut hasher = blake3::Hasher::new();
-    inner.labels.iter().for_each(|x| {
-        hasher.update(x.as_bytes());
-    });
-    inner.properties.iter().for_each(|(k, v)| {
-        hasher.update(k.as_bytes());
-        hasher.update(v.as_bytes());
-    });
-    inner
-        .properties
-        .push((MAGIC_HASH_KEY.to_string(), hasher.finalize().to_string()));
+fn add_hash_to_node(crypto: &Crypto, inner: &mut Node) -> Result<()> {
+    let hash = crypto.entity_mac(EntityKind::Node, &inner.labels, &inner.properties)?;
+    inner.properties.push((MAGIC_HASH_KEY.to_string(), hash));
+    Ok(())
 }
 
-fn get_inner_hash(inner: &mut Inner) -> String {
-    inner.labels.sort();
-    inner.properties.sort();
-
-    let mut hasher = blake3::Hasher::new();
-    inner.labels.iter().for_each(|x| {
-        hasher.update(x.as_bytes());
-    });
-    inner.properties.iter().for_each(|(k, v)| {
-        if k != MAGIC_HASH_KEY {
-            hasher.update(k.as_bytes());
-            hasher.update(v.as_bytes());
-        }
-    });
-    hasher.finalize().to_string()
+fn add_hash_to_relationship(crypto: &Crypto, inner: &mut Relation) -> Result<()> {
+    let hash = crypto.entity_mac(EntityKind::Relation, &inner.labels, &inner.properties)?;
+    inner.properties.push((MAGIC_HASH_KEY.to_string(), hash));
+    Ok(())
 }
 
 fn update_inners_by_set(inners: &mut Vec<Inner>, set_list: Option<&Vec<Item>>) -> Result<()> {
@@ -630,7 +2073,7 @@ fn update_inners_by_set(inners: &mut Vec<Inner>, set_list: Option<&Vec<Item>>) -
                         return Err(anyhow::anyhow!("Invalid var_name: {:?}", var));
                     }
                 }
-                _ => return Err(anyhow::anyhow!("Invalid set_list: {:?}", set_list)),
+                _ => return Err(anyhow::anyhow!("Invalid set_list: {}", Redacted(updates))),
             }
         }
     }
@@ -678,7 +2121,12 @@ fn update_inners_by_remove(inners: &mut Vec<Inner>, remove_list: Option<&Vec<Ite
                         return Err(anyhow::anyhow!("Invalid var_name: {:?}", var));
                     }
                 }
-                _ => return Err(anyhow::anyhow!("Invalid remove_list: {:?}", remove_list)),
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "Invalid remove_list: {}",
+                        Redacted(updates)
+                    ))
+                }
             }
         }
     }
@@ -692,10 +2140,166 @@ fn add_uid_to_node(node: &mut Node) -> String {
     uid
 }
 
-fn add_uid_to_relationship(relation: &mut Relation, from_uid: &String, to_uid: &String) {
+fn add_uid_to_relationship(relation: &mut Relation, from_uid: &String, to_uid: &String) -> String {
+    let uid = format!("{}{}{}", from_uid, to_uid, uuid::Uuid::new_v4());
     relation
         .properties
-        .push((MAGIC_UID_KEY.to_string(), format!("{}{}", from_uid, to_uid)));
+        .push((MAGIC_UID_KEY.to_string(), uid.clone()));
+    uid
+}
+
+fn add_version(properties: &mut Vec<(String, String)>, version: u64) {
+    properties.retain(|(k, _)| k != MAGIC_VERSION_KEY);
+    properties.push((MAGIC_VERSION_KEY.to_string(), version.to_string()));
+}
+
+// Increments the version of an updated entity and writes it back through the SET list.
+// Returns the `(uid, version)` to record in the version map.
+fn bump_version(query: &mut CypherQuery, var: &str, inner: &mut Inner) -> Result<(String, u64)> {
+    let uid = inner
+        .get(MAGIC_UID_KEY)
+        .ok_or_else(|| anyhow::anyhow!("Data was attacked"))?
+        .clone();
+    let version = inner
+        .get(MAGIC_VERSION_KEY)
+        .and_then(|x| x.parse::<u64>().ok())
+        .ok_or_else(|| anyhow::anyhow!("Data was attacked"))?
+        + 1;
+    inner.update_or_add_property(MAGIC_VERSION_KEY, version.to_string());
+    query
+        .set_list
+        .get_or_insert(vec![])
+        .push(Item::VarWithKeyValue(
+            var.to_string(),
+            MAGIC_VERSION_KEY.to_string(),
+            version.to_string(),
+        ));
+    Ok((uid, version))
+}
+
+fn kind_var(kind: EntityKind) -> &'static str {
+    match kind {
+        EntityKind::Node => NODE_VAR_NAME,
+        EntityKind::Relation => RELATION_VAR_NAME,
+    }
+}
+
+// MATCH (n {uid})
+fn node_pattern(uid: &str) -> CypherQuery {
+    CypherQueryBuilder::new()
+        .MATCH()
+        .node(Node::new(
+            Some(NODE_VAR_NAME),
+            Vec::<String>::new(),
+            vec![(MAGIC_UID_KEY, uid)],
+        ))
+        .build()
+}
+
+// MATCH (n)-[r]->(m), with the uids that are given
+fn relation_pattern(from: Option<&str>, uid: Option<&str>, to: Option<&str>) -> CypherQuery {
+    let pinned = |uid: Option<&str>| -> Vec<(String, String)> {
+        uid.map(|x| (MAGIC_UID_KEY.to_string(), x.to_string()))
+            .into_iter()
+            .collect()
+    };
+    CypherQueryBuilder::new()
+        .MATCH()
+        .node(Node::new(
+            Some(NODE_VAR_NAME),
+            Vec::<String>::new(),
+            pinned(from),
+        ))
+        .relation(Relation::new(
+            Some(RELATION_VAR_NAME),
+            Vec::<String>::new(),
+            pinned(uid),
+        ))
+        .next_node(Node::new(
+            Some(NEXT_NODE_VAR_NAME),
+            Vec::<String>::new(),
+            pinned(to),
+        ))
+        .build()
+}
+
+/// Splits the uid of a relationship back into the uids of its endpoints.
+pub fn split_relation_uid(uid: &str) -> Result<(&str, &str)> {
+    if uid.len() != RELATION_UID_LEN || !uid.is_ascii() {
+        return Err(anyhow::anyhow!("Invalid relationship uid: {}", uid));
+    }
+    Ok((&uid[..NODE_UID_LEN], &uid[NODE_UID_LEN..2 * NODE_UID_LEN]))
+}
+
+// The MAC binds a relationship to the endpoints in its uid, so neo4j can't pair it
+// with other nodes of the row than the ones it connects.
+fn check_endpoints(query: &CypherQuery, row_vars: &[&String], row: &Row) -> Result<()> {
+    let uid_of = |var: Option<&String>| -> Result<Option<&String>> {
+        match var.and_then(|var| row_vars.iter().position(|x| *x == var)) {
+            Some(i) => Ok(Some(
+                row.inners()[i]
+                    .get(MAGIC_UID_KEY)
+                    .ok_or(IntegrityError::MissingUid)?,
+            )),
+            None => Ok(None),
+        }
+    };
+    let r_uid = match uid_of(query.relation.as_ref().and_then(|x| x.var_name()))? {
+        Some(uid) => uid,
+        None => return Ok(()),
+    };
+    let (from, to) = split_relation_uid(r_uid)?;
+    let n_uid = uid_of(query.node.as_ref().and_then(|x| x.var_name()))?;
+    let m_uid = uid_of(query.next_node.as_ref().and_then(|x| x.var_name()))?;
+    if n_uid.is_some_and(|x| x != from) || m_uid.is_some_and(|x| x != to) {
+        return Err(IntegrityError::WrongEndpoints { uid: r_uid.clone() }.into());
+    }
+    Ok(())
+}
+
+// Keys and labels the enclave stores next to the client's own, so a client query
+// naming them would overwrite or forge them.
+fn is_reserved_name(name: &str) -> bool {
+    name == MAGIC_UID_KEY || name == MAGIC_HASH_KEY || name.contains('#')
+}
+
+fn check_reserved_names(query: &CypherQuery) -> Result<()> {
+    let mut names = vec![];
+    for (labels, properties) in [
+        query.node.as_ref().map(|x| (&x.labels, &x.properties)),
+        query.relation.as_ref().map(|x| (&x.labels, &x.properties)),
+        query.next_node.as_ref().map(|x| (&x.labels, &x.properties)),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        names.extend(labels);
+        names.extend(properties.iter().map(|(k, _)| k));
+    }
+    for item in [&query.set_list, &query.remove_list, &query.return_list]
+        .into_iter()
+        .flatten()
+        .flatten()
+    {
+        match item {
+            Item::Var(_) => {}
+            Item::VarWithLabel(_, name)
+            | Item::VarWithKey(_, name)
+            | Item::VarWithKeyValue(_, name, _) => names.push(name),
+        }
+    }
+    names.extend(query.where_list.iter().flatten().map(|x| x.key()));
+
+    if let Some(name) = names.into_iter().find(|x| is_reserved_name(x)) {
+        return Err(anyhow::anyhow!(
+            "{} is reserved and can't be used in a query",
+            name
+        ));
+    }
+    match &query.union {
+        Some((next_query, _)) => check_reserved_names(next_query),
+        None => Ok(()),
+    }
 }
 
 fn get_return_vars(query: &CypherQuery) -> Vec<String> {
@@ -714,6 +2318,172 @@ fn get_return_vars(query: &CypherQuery) -> Vec<String> {
     vars
 }
 
+// MATCH (n:Label), (m:Label) RETURN n, m
+fn build_create_read_query(query: &CypherQuery) -> CypherQuery {
+    let mut read_query = query.clone();
+    read_query.relation.take();
+    read_query.use_create = false;
+    read_query.return_list.replace(vec![
+        Item::Var(NODE_VAR_NAME.to_string()),
+        Item::Var(NEXT_NODE_VAR_NAME.to_string()),
+    ]);
+    read_query
+}
+
+// MATCH (n:Label) RETURN n, or MATCH (n:Label)-[r]->(m) RETURN n, r, m
+fn build_update_read_query(query: &CypherQuery) -> CypherQuery {
+    let mut read_query = query.clone();
+    read_query.set_list.take();
+    read_query.remove_list.take();
+    if query.relation.is_some() {
+        read_query.return_list.replace(vec![
+            Item::Var(NODE_VAR_NAME.to_string()),
+            Item::Var(RELATION_VAR_NAME.to_string()),
+            Item::Var(NEXT_NODE_VAR_NAME.to_string()),
+        ]);
+    } else {
+        read_query
+            .return_list
+            .replace(vec![Item::Var(NODE_VAR_NAME.to_string())]);
+    }
+    read_query
+}
+
+// MATCH (n:Label)-[r]->(m) RETURN n, r, m
+fn build_delete_read_query(query: &CypherQuery, pattern_vars: &[String]) -> CypherQuery {
+    let mut read_query = query.clone();
+    read_query.delete_list.take();
+    read_query
+        .return_list
+        .replace(pattern_vars.iter().cloned().map(Item::Var).collect());
+    read_query
+}
+
+// The vars a SET / REMOVE writes to.
+fn get_modified_vars(query: &CypherQuery) -> Vec<String> {
+    query
+        .set_list
+        .iter()
+        .chain(query.remove_list.iter())
+        .flatten()
+        .map(|item| match item {
+            Item::Var(var)
+            | Item::VarWithLabel(var, _)
+            | Item::VarWithKey(var, _)
+            | Item::VarWithKeyValue(var, _, _) => var.clone(),
+        })
+        .collect()
+}
+
+// Relationship uids start with the uids of both endpoints.
+fn entity_kind(inner: &Inner) -> Result<EntityKind> {
+    match inner.get(MAGIC_UID_KEY) {
+        Some(uid) if uid.len() == NODE_UID_LEN => Ok(EntityKind::Node),
+        Some(uid) if split_relation_uid(uid).is_ok() => Ok(EntityKind::Relation),
+        _ => Err(anyhow::anyhow!("Data was attacked")),
+    }
+}
+
+fn retain_readable(access: &Access<'_>, rows: &mut Rows) -> Result<()> {
+    if !access.is_restricted() {
+        return Ok(());
+    }
+    let mut readable = vec![];
+    for row in rows.rows() {
+        let mut is_readable = true;
+        for inner in row.inners() {
+            is_readable &= access.can_read(entity_kind(inner)?, inner);
+        }
+        readable.push(is_readable);
+    }
+    let mut readable = readable.into_iter();
+    rows.rows_mut().retain(|_| readable.next().unwrap());
+    Ok(())
+}
+
+// Checks `action` on the entities of `vars` in every row, before anything is written.
+fn check_targets(
+    access: &Access<'_>,
+    rows: &Rows,
+    row_vars: &[String],
+    vars: &[String],
+    action: Action,
+) -> Result<()> {
+    for row in rows.rows() {
+        for (var, inner) in row_vars.iter().zip(row.inners()) {
+            if vars.contains(var) {
+                access.check_entity(entity_kind(inner)?, inner, action)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// MATCH ({uid: $uid})-[r]->(m) RETURN r, m
+fn build_expand_query(uid: String) -> CypherQuery {
+    CypherQueryBuilder::new()
+        .MATCH()
+        .node(Node::new(
+            None::<String>,
+            Vec::<String>::new(),
+            vec![(MAGIC_UID_KEY.to_string(), uid)],
+        ))
+        .relation(Relation::new_with_var(RELATION_VAR_NAME))
+        .next_node(Node::new_with_var(NEXT_NODE_VAR_NAME))
+        .RETURN(vec![
+            Item::Var(RELATION_VAR_NAME.to_string()),
+            Item::Var(NEXT_NODE_VAR_NAME.to_string()),
+        ])
+        .build()
+}
+
+fn get_pattern_vars(query: &CypherQuery) -> Vec<String> {
+    [
+        query.node.as_ref().and_then(|x| x.var_name()),
+        query.relation.as_ref().and_then(|x| x.var_name()),
+        query.next_node.as_ref().and_then(|x| x.var_name()),
+    ]
+    .into_iter()
+    .flatten()
+    .cloned()
+    .collect()
+}
+
+fn add_property_to_pattern(
+    query: &mut CypherQuery,
+    var: &str,
+    key: String,
+    value: String,
+) -> Result<()> {
+    match (
+        var,
+        query.node.as_mut(),
+        query.relation.as_mut(),
+        query.next_node.as_mut(),
+    ) {
+        (NODE_VAR_NAME, Some(node), _, _) => node.add_property(key, value),
+        (RELATION_VAR_NAME, _, Some(relation), _) => relation.add_property(key, value),
+        (NEXT_NODE_VAR_NAME, _, _, Some(next_node)) => next_node.add_property(key, value),
+        _ => return Err(anyhow::anyhow!("Invalid var_name: {:?}", var)),
+    }
+    Ok(())
+}
+
+fn add_label_to_pattern(query: &mut CypherQuery, var: &str, label: String) -> Result<()> {
+    match (
+        var,
+        query.node.as_mut(),
+        query.relation.as_mut(),
+        query.next_node.as_mut(),
+    ) {
+        (NODE_VAR_NAME, Some(node), _, _) => node.labels.push(label),
+        (RELATION_VAR_NAME, _, Some(relation), _) => relation.labels.push(label),
+        (NEXT_NODE_VAR_NAME, _, _, Some(next_node)) => next_node.labels.push(label),
+        _ => return Err(anyhow::anyhow!("Invalid var_name: {:?}", var)),
+    }
+    Ok(())
+}
+
 fn build_inner_from_neo4rs_node(node: neo4rs::Node) -> Inner {
     let labels = node.labels().iter().map(|s| s.to_string()).collect();
     let mut properties = vec![];
@@ -731,3 +2501,106 @@ fn build_inner_from_neo4rs_relation(relation: neo4rs::Relation) -> Inner {
     }
     Inner::new(labels, properties)
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::crypto::key_provider_from_env;
+
+    // The tests wipe the graph, so they run as a principal only this policy lets do so.
+    const TEST_POLICY: &str = "
+        test-admin  label         *  read,create,update,delete
+        test-admin  relationship  *  read,create,update,delete
+        test-admin  property      *  read,write
+    ";
+
+    pub fn test_principal() -> Principal {
+        Principal::new("test", ["test-admin"])
+    }
+
+    /// The graph of the default tenant in the database of `DATABASE_URI`, with the
+    /// keys of the configured key provider and the grants of `test_principal`.
+    pub async fn test_graph() -> Result<EncryptedGraph> {
+        dotenv::dotenv().ok();
+        let var = |name| std::env::var(name).map_err(|_| anyhow::anyhow!("{} must be set", name));
+        let tenant = Tenant::all_from_env()?.remove(0);
+        let mut graph = EncryptedGraph::new(
+            var("DATABASE_URI")?,
+            var("DATABASE_USERNAME")?,
+            var("DATABASE_PASSWORD")?,
+            &tenant,
+            key_provider_from_env()?.into(),
+        )
+        .await?;
+        graph.policy = AccessPolicy::from_text(TEST_POLICY)?;
+        Ok(graph)
+    }
+
+    /// Deletes every node and relationship of the test graph.
+    pub async fn clear_graph(graph: &EncryptedGraph) -> Result<()> {
+        let query = CypherQueryBuilder::new()
+            .MATCH()
+            .node(Node::new(
+                Some("n"),
+                Vec::<String>::new(),
+                Vec::<(String, String)>::new(),
+            ))
+            .DELETE(vec![Item::Var(String::from("n"))], true)
+            .build();
+        graph.execute_query(&test_principal(), query).await?;
+        Ok(())
+    }
+
+    // (a:Person {name: 'a'})-[r:knows]->(b:Person {name: 'b'})
+    fn knows(use_create: bool) -> CypherQuery {
+        let builder = CypherQueryBuilder::new()
+            .MATCH()
+            .node(Node::new(Some("n"), vec!["Person"], vec![("name", "a")]))
+            .relation(Relation::new(
+                Some("r"),
+                vec!["knows"],
+                Vec::<(String, String)>::new(),
+            ))
+            .next_node(Node::new(Some("m"), vec!["Person"], vec![("name", "b")]));
+        if use_create {
+            builder.CREATE().build()
+        } else {
+            builder.RETURN(vec![Item::Var(String::from("r"))]).build()
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a neo4j database and sealed data keys"]
+    async fn test_key_rotation() -> Result<()> {
+        let graph = test_graph().await?;
+        clear_graph(&graph).await?;
+        let principal = test_principal();
+
+        // more parallel relationships a --> b than fit into one batch of the copy
+        const BATCH_SIZE: usize = 2;
+        const RELATIONS: usize = 2 * BATCH_SIZE + 1;
+        for name in ["a", "b"] {
+            let query = CypherQueryBuilder::new()
+                .CREATE()
+                .node(Node::new(
+                    None::<String>,
+                    vec!["Person"],
+                    vec![("name", name)],
+                ))
+                .build();
+            graph.execute_query(&principal, query).await?;
+        }
+        for _ in 0..RELATIONS {
+            graph.execute_query(&principal, knows(true)).await?;
+        }
+
+        let version = graph.crypto.active_version();
+        graph.rotate_key(BATCH_SIZE).await?;
+        assert_ne!(graph.crypto.active_version(), version);
+        assert!(!graph.is_rotating_key());
+
+        let result = graph.execute_query(&principal, knows(false)).await?;
+        assert_eq!(result.rows().len(), RELATIONS);
+        Ok(())
+    }
+}
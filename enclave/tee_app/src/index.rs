@@ -0,0 +1,166 @@
+use anyhow::Result;
+use simple_cypher::*;
+
+use crate::crypto::Crypto;
+use crate::logger::Redacted;
+
+use std::collections::HashMap;
+
+pub const MAGIC_BUCKET_KEY_PREFIX: &str = "bucket#";
+
+// Upper bound of sub-queries a single range predicate may expand to.
+const MAX_CANDIDATE_BUCKETS: u64 = 1024;
+
+/// Bucket layout of one indexed property. Values are clamped to `[min, max]`
+/// and split into buckets of `width`, so a wider bucket leaks less about the
+/// value but makes neo4j return more candidates for the enclave to filter.
+#[derive(Debug, Clone)]
+pub struct BucketSpec {
+    width: f64,
+    min: f64,
+    max: f64,
+}
+
+/// Optional per-property bucketized index for range predicates.
+///
+/// On write, every indexed numeric property `k` gets a companion property
+/// `bucket#k` holding a keyed token of its bucket. A range predicate on `k` is
+/// then pushed to neo4j as one equality match per candidate bucket, and the
+/// exact comparison is done in the enclave on the decrypted rows.
+#[derive(Debug, Clone, Default)]
+pub struct BucketIndex {
+    specs: HashMap<String, BucketSpec>,
+}
+
+impl BucketSpec {
+    pub fn new(width: f64, min: f64, max: f64) -> Result<Self> {
+        if !width.is_finite() || width <= 0.0 || min.is_nan() || max.is_nan() || min > max {
+            return Err(anyhow::anyhow!(
+                "Invalid bucket spec: width {}, min {}, max {}",
+                width,
+                min,
+                max
+            ));
+        }
+        Ok(Self { width, min, max })
+    }
+
+    fn bucket_of(&self, value: f64) -> u64 {
+        ((value.clamp(self.min, self.max) - self.min) / self.width).floor() as u64
+    }
+}
+
+impl BucketIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses the `BUCKET_INDEX` environment variable, e.g.
+    /// `BUCKET_INDEX=age:10:0:150;score:5:0:100` (`key:width:min:max`).
+    pub fn from_env() -> Result<Self> {
+        let mut index = Self::new();
+        let spec = match std::env::var("BUCKET_INDEX") {
+            Ok(spec) => spec,
+            Err(_) => return Ok(index),
+        };
+
+        for item in spec.split(';').filter(|x| !x.trim().is_empty()) {
+            let fields: Vec<&str> = item.trim().split(':').collect();
+            if fields.len() != 4 {
+                return Err(anyhow::anyhow!("Invalid BUCKET_INDEX item: {}", item));
+            }
+            let width = fields[1].parse::<f64>()?;
+            let min = fields[2].parse::<f64>()?;
+            let max = fields[3].parse::<f64>()?;
+            index.add(fields[0], BucketSpec::new(width, min, max)?);
+        }
+
+        log::info!("bucket index on {} properties", index.specs.len());
+        Ok(index)
+    }
+
+    pub fn add(&mut self, key: impl Into<String>, spec: BucketSpec) {
+        self.specs.insert(key.into(), spec);
+    }
+
+    /// The indexed property keys.
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.specs.keys()
+    }
+
+    /// Recomputes the bucket tokens of all indexed properties in `properties`
+    /// under key `version`.
+    ///
+    /// Returns the bucket keys that were removed because the indexed property is
+    /// gone or is no longer a number.
+    pub fn refresh_properties(
+        &self,
+        crypto: &Crypto,
+        version: u32,
+        properties: &mut Vec<(String, String)>,
+    ) -> Result<Vec<String>> {
+        let mut removed = vec![];
+        for (key, spec) in &self.specs {
+            let bucket_key = bucket_key(key);
+            let value = properties
+                .iter()
+                .find(|(k, _)| k == key)
+                .and_then(|(_, v)| v.parse::<f64>().ok());
+            properties.retain(|(k, _)| k != &bucket_key);
+            match value {
+                Some(value) => properties.push((
+                    bucket_key,
+                    crypto.bucket_token(version, key, spec.bucket_of(value))?,
+                )),
+                None => removed.push(bucket_key),
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Returns the bucket tokens that may contain values matching all
+    /// `predicates` on `key`, or `None` if `key` is not indexed and the
+    /// predicates have to be evaluated by a full scan.
+    pub fn candidate_tokens(
+        &self,
+        crypto: &Crypto,
+        key: &str,
+        predicates: &[&Predicate],
+    ) -> Result<Option<Vec<String>>> {
+        let spec = match self.specs.get(key) {
+            Some(spec) => spec,
+            None => return Ok(None),
+        };
+
+        let (mut lower, mut upper) = (f64::NEG_INFINITY, f64::INFINITY);
+        for predicate in predicates {
+            let (l, u) = predicate
+                .bounds()
+                .ok_or_else(|| anyhow::anyhow!("Invalid predicate: {}", Redacted(*predicate)))?;
+            lower = lower.max(l);
+            upper = upper.min(u);
+        }
+        if lower > upper {
+            return Ok(Some(vec![]));
+        }
+
+        let (first, last) = (spec.bucket_of(lower), spec.bucket_of(upper));
+        if last - first >= MAX_CANDIDATE_BUCKETS {
+            return Ok(None);
+        }
+        let version = crypto.active_version();
+        Ok(Some(
+            (first..=last)
+                .map(|bucket| crypto.bucket_token(version, key, bucket))
+                .collect::<Result<_>>()?,
+        ))
+    }
+}
+
+pub fn bucket_key(key: &str) -> String {
+    format!("{}{}", MAGIC_BUCKET_KEY_PREFIX, key)
+}
+
+pub fn is_bucket_key(key: &str) -> bool {
+    key.starts_with(MAGIC_BUCKET_KEY_PREFIX)
+}
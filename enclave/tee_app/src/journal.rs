@@ -0,0 +1,335 @@
+use anyhow::Result;
+
+use crate::crypto::Crypto;
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Records appended before the whole state is sealed into a new snapshot.
+const MAX_JOURNAL_RECORDS: u64 = 1024;
+const RECORD_LEN_BYTES: usize = 4;
+
+/// State loaded back from a `SealedJournal`.
+pub struct Loaded {
+    pub snapshot: Vec<u8>,
+    /// records appended since the snapshot, in order
+    pub records: Vec<Vec<u8>>,
+}
+
+/// Enclave state sealed to the hostfs as a snapshot plus an append-only journal
+/// of the changes since, so a write only seals its own change instead of the
+/// whole state.
+///
+/// The snapshot at `path` is sealed with its epoch, and the records of that epoch
+/// are appended to `<path>.<epoch>.journal`, each sealed on its own and bound to
+/// the epoch and its position. Records can't be edited, reordered, dropped from
+/// the middle or moved to another snapshot without failing to load.
+///
+/// After every change the epoch and the number of records are sealed to
+/// `<path>.head`, so a journal cut short of the head, or a snapshot older than
+/// it, fails to load. Records past the head are accepted, only the enclave can
+/// seal them. The sealed data keys record that the state was started (see
+/// `Crypto::mark`), so removing its files doesn't start it over. Without a
+/// trusted monotonic counter, putting back an older copy of all of these files,
+/// the data keys included, can't be told apart from an earlier state.
+pub struct SealedJournal {
+    path: PathBuf,
+    aad: &'static [u8],
+    // name of the state in the sealed data keys
+    marker: &'static str,
+    epoch: u64,
+    records: u64,
+    // a record failed to append, so the next change seals a snapshot instead
+    is_stale: bool,
+}
+
+impl SealedJournal {
+    pub fn new(path: PathBuf, aad: &'static [u8], marker: &'static str) -> Self {
+        Self {
+            path,
+            aad,
+            marker,
+            epoch: 0,
+            records: 0,
+            is_stale: false,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns `None` if nothing was sealed yet.
+    pub fn load(&mut self, crypto: &Crypto) -> Result<Option<Loaded>> {
+        let head = self.load_head(crypto)?;
+        if !self.path.exists() {
+            if head.is_some() || crypto.is_marked(self.marker) {
+                return Err(anyhow::anyhow!(
+                    "Sealed state {} was removed",
+                    self.path.display()
+                ));
+            }
+            return Ok(None);
+        }
+        let sealed = crypto.unseal_from_file(&self.path, self.aad)?;
+        if sealed.len() < 8 {
+            return Err(anyhow::anyhow!("Invalid snapshot {}", self.path.display()));
+        }
+        let (epoch, snapshot) = sealed.split_at(8);
+        self.epoch = u64::from_le_bytes(epoch.try_into().unwrap());
+        // records the head says the journal of this epoch holds at least; a newer
+        // snapshot than the head was sealed right before a crash
+        let sealed_records = match head {
+            Some((epoch, _)) if epoch > self.epoch => return Err(self.rolled_back()),
+            Some((epoch, records)) if epoch == self.epoch => records,
+            Some(_) => 0,
+            None if crypto.is_marked(self.marker) => {
+                return Err(anyhow::anyhow!(
+                    "Sealed state {} has no sealed head",
+                    self.path.display()
+                ))
+            }
+            None => 0,
+        };
+        // the journal of the previous epoch is left over if a crash came between
+        // sealing the snapshot and removing it
+        if self.epoch > 0 {
+            let _ = std::fs::remove_file(self.journal_path(self.epoch - 1));
+        }
+
+        let journal_path = self.journal_path(self.epoch);
+        let bytes = match std::fs::read(&journal_path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        let mut records = vec![];
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let len = bytes
+                .get(offset..offset + RECORD_LEN_BYTES)
+                .map(|x| u32::from_le_bytes(x.try_into().unwrap()) as usize);
+            let end = len.map(|len| offset + RECORD_LEN_BYTES + len);
+            let record = match end {
+                Some(end) if end <= bytes.len() => &bytes[offset + RECORD_LEN_BYTES..end],
+                // an append past the head was torn by a crash before the head was
+                // sealed, so its write was never acknowledged
+                _ if records.len() as u64 >= sealed_records => {
+                    log::warn!(
+                        "drop a torn record at the end of {}",
+                        journal_path.display()
+                    );
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&journal_path)?
+                        .set_len(offset as u64)?;
+                    break;
+                }
+                _ => return Err(self.rolled_back()),
+            };
+            records.push(crypto.unseal(record, &self.record_aad(records.len() as u64))?);
+            offset = end.unwrap();
+        }
+        if (records.len() as u64) < sealed_records {
+            return Err(self.rolled_back());
+        }
+        self.records = records.len() as u64;
+        self.persist_head(crypto)?;
+        Ok(Some(Loaded {
+            snapshot: snapshot.to_vec(),
+            records,
+        }))
+    }
+
+    /// Whether the next change should be sealed as a snapshot with `compact`
+    /// instead of appended.
+    pub fn is_full(&self) -> bool {
+        self.is_stale || self.records >= MAX_JOURNAL_RECORDS
+    }
+
+    /// Appends one record to the journal of the current snapshot.
+    pub fn append(&mut self, crypto: &Crypto, record: &[u8]) -> Result<()> {
+        let res = self.try_append(crypto, record);
+        if res.is_err() {
+            self.is_stale = true;
+        }
+        res
+    }
+
+    fn try_append(&mut self, crypto: &Crypto, record: &[u8]) -> Result<()> {
+        let sealed = crypto.seal(record, &self.record_aad(self.records))?;
+        let mut bytes = (sealed.len() as u32).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&sealed);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.journal_path(self.epoch))?;
+        file.write_all(&bytes)?;
+        file.sync_data()?;
+        self.records += 1;
+        self.persist_head(crypto)
+    }
+
+    /// Seals `snapshot` as the whole state under a new epoch, and starts an empty
+    /// journal for it.
+    pub fn compact(&mut self, crypto: &Crypto, snapshot: &[u8]) -> Result<()> {
+        let epoch = self.epoch + 1;
+        let mut sealed = epoch.to_le_bytes().to_vec();
+        sealed.extend_from_slice(snapshot);
+        crypto.seal_to_file(&self.path, &sealed, self.aad)?;
+        let _ = std::fs::remove_file(self.journal_path(self.epoch));
+        self.epoch = epoch;
+        self.records = 0;
+        self.persist_head(crypto)?;
+        self.is_stale = false;
+        log::info!(
+            "sealed a snapshot of {} at epoch {}",
+            self.path.display(),
+            epoch
+        );
+        Ok(())
+    }
+
+    // `epoch || records`, `None` if no head was sealed yet
+    fn load_head(&self, crypto: &Crypto) -> Result<Option<(u64, u64)>> {
+        let path = self.head_path();
+        if !path.exists() {
+            return Ok(None);
+        }
+        let plain = crypto.unseal_from_file(&path, &self.head_aad())?;
+        if plain.len() != 16 {
+            return Err(anyhow::anyhow!("Invalid sealed head {}", path.display()));
+        }
+        Ok(Some((
+            u64::from_le_bytes(plain[..8].try_into().unwrap()),
+            u64::from_le_bytes(plain[8..].try_into().unwrap()),
+        )))
+    }
+
+    fn persist_head(&self, crypto: &Crypto) -> Result<()> {
+        let plain = [self.epoch.to_le_bytes(), self.records.to_le_bytes()].concat();
+        crypto.seal_to_file(&self.head_path(), &plain, &self.head_aad())?;
+        if crypto.seals_data_keys() && !crypto.is_marked(self.marker) {
+            crypto.mark(self.marker)?;
+        }
+        Ok(())
+    }
+
+    fn rolled_back(&self) -> anyhow::Error {
+        anyhow::anyhow!(
+            "Sealed state {} was truncated or rolled back",
+            self.path.display()
+        )
+    }
+
+    fn head_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".head");
+        PathBuf::from(path)
+    }
+
+    fn head_aad(&self) -> Vec<u8> {
+        let mut aad = self.aad.to_vec();
+        aad.extend_from_slice(b" head");
+        aad
+    }
+
+    fn journal_path(&self, epoch: u64) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}.journal", epoch));
+        PathBuf::from(path)
+    }
+
+    fn record_aad(&self, seq: u64) -> Vec<u8> {
+        let mut aad = self.aad.to_vec();
+        aad.extend_from_slice(b" record");
+        aad.extend_from_slice(&self.epoch.to_le_bytes());
+        aad.extend_from_slice(&seq.to_le_bytes());
+        aad
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SoftwareKeyProvider;
+
+    fn crypto(dir: &Path) -> Result<Crypto> {
+        Crypto::new(
+            Box::new(SoftwareKeyProvider::new("secret", 0, 0)),
+            Some(dir.join("data_key.sealed")),
+        )
+    }
+
+    fn journal(dir: &Path) -> SealedJournal {
+        SealedJournal::new(dir.join("state.sealed"), b"state", "state")
+    }
+
+    // a snapshot and three records, in a fresh directory
+    fn started_journal() -> Result<(PathBuf, Crypto)> {
+        let dir = std::env::temp_dir().join(format!("tee_app-journal-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        let crypto = crypto(&dir)?;
+        let mut journal = journal(&dir);
+        assert!(journal.load(&crypto)?.is_none());
+        journal.compact(&crypto, b"snapshot")?;
+        for i in 0..3u8 {
+            journal.append(&crypto, &[i])?;
+        }
+        Ok((dir, crypto))
+    }
+
+    fn error_of(res: Result<Option<Loaded>>) -> String {
+        res.err().map(|e| e.to_string()).unwrap_or_default()
+    }
+
+    #[test]
+    fn test_journal_load() -> Result<()> {
+        let (dir, crypto) = started_journal()?;
+        let loaded = journal(&dir).load(&crypto)?.unwrap();
+        assert_eq!(loaded.snapshot, b"snapshot");
+        assert_eq!(loaded.records, vec![vec![0], vec![1], vec![2]]);
+
+        // a torn append past the head was never acknowledged and is dropped
+        let journal_path = dir.join("state.sealed.1.journal");
+        let mut file = OpenOptions::new().append(true).open(&journal_path)?;
+        file.write_all(&[200, 0, 0, 0, 1])?;
+        let loaded = journal(&dir).load(&crypto)?.unwrap();
+        assert_eq!(loaded.records.len(), 3);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_journal_rolled_back() -> Result<()> {
+        let (dir, crypto) = started_journal()?;
+        let journal_path = dir.join("state.sealed.1.journal");
+        let bytes = std::fs::read(&journal_path)?;
+
+        // the last record cut off whole, or torn
+        let record_len = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+        std::fs::write(&journal_path, &bytes[..2 * (RECORD_LEN_BYTES + record_len)])?;
+        assert!(error_of(journal(&dir).load(&crypto)).contains("rolled back"));
+        std::fs::write(&journal_path, &bytes[..bytes.len() - 1])?;
+        assert!(error_of(journal(&dir).load(&crypto)).contains("rolled back"));
+        std::fs::write(&journal_path, &bytes)?;
+
+        // an older head is kept, the journal is not
+        let head_path = dir.join("state.sealed.head");
+        let head = std::fs::read(&head_path)?;
+        let mut newer = journal(&dir);
+        newer.load(&crypto)?;
+        newer.compact(&crypto, b"newer")?;
+        std::fs::write(&head_path, &head)?;
+        assert_eq!(journal(&dir).load(&crypto)?.unwrap().snapshot, b"newer");
+
+        // the state removed, with or without its head
+        std::fs::remove_file(dir.join("state.sealed"))?;
+        assert!(error_of(journal(&dir).load(&crypto)).contains("removed"));
+        std::fs::remove_file(&head_path)?;
+        assert!(error_of(journal(&dir).load(&crypto)).contains("removed"));
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,321 @@
+use anyhow::Result;
+use simple_cypher::*;
+
+use crate::crypto::EntityKind;
+use crate::graph::MAGIC_UID_KEY;
+
+use std::collections::HashMap;
+
+pub const MAGIC_LABELS_KEY: &str = "label#set";
+
+const MAGIC_LABEL_PREFIX: &str = "label#";
+const GENERIC_NODE_LABEL: &str = "label#node";
+const GENERIC_RELATION_TYPE: &str = "label#relation";
+
+// Upper bound of tokens of a label, and so of sub-queries a match on it expands to.
+const MAX_LABEL_TOKENS: u32 = 1024;
+
+/// Optional hiding of labels and relationship types from neo4j.
+///
+/// Labels are encrypted deterministically, so neo4j sees how many entities
+/// share each of them. With hiding on, every node is stored under one generic
+/// label and every relationship under one generic type instead. The real
+/// labels are kept in the property `label#set`, which is sealed like any other
+/// value and covered by the entity MAC, and the labels of a pattern are checked
+/// in the enclave on the decrypted entities.
+///
+/// A label that has to stay indexable can be given `n` salted tokens. Each
+/// entity stores one of them, picked by a hash of its uid, next to the generic
+/// label (a relationship stores it as its type). neo4j then sees `n` labels of
+/// about equal size, and a match on the label becomes one sub-query per token.
+#[derive(Debug, Clone, Default)]
+pub struct LabelHiding {
+    enabled: bool,
+    tokens: HashMap<String, u32>,
+}
+
+impl LabelHiding {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `LABEL_HIDING=on` turns hiding on, and `LABEL_TOKENS` gives labels and
+    /// relationship types salted tokens, e.g. `LABEL_TOKENS=Student:8;KNOWS:4`
+    /// (`label:tokens`).
+    pub fn from_env() -> Result<Self> {
+        let mut hiding = Self::new();
+        hiding.enabled = match std::env::var("LABEL_HIDING").as_deref() {
+            Ok("on") => true,
+            Ok("off") | Err(_) => false,
+            Ok(mode) => return Err(anyhow::anyhow!("Unknown LABEL_HIDING: {}", mode)),
+        };
+        let spec = match std::env::var("LABEL_TOKENS") {
+            Ok(spec) => spec,
+            Err(_) => return Ok(hiding),
+        };
+        if !hiding.enabled {
+            return Err(anyhow::anyhow!("LABEL_TOKENS needs LABEL_HIDING=on"));
+        }
+
+        for item in spec.split(';').filter(|x| !x.trim().is_empty()) {
+            let (label, tokens) = item
+                .trim()
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("Invalid LABEL_TOKENS item: {}", item))?;
+            hiding.add_tokens(label, tokens.parse()?)?;
+        }
+
+        log::info!(
+            "label hiding on, {} labels with salted tokens",
+            hiding.tokens.len()
+        );
+        Ok(hiding)
+    }
+
+    pub fn add_tokens(&mut self, label: impl Into<String>, tokens: u32) -> Result<()> {
+        if tokens == 0 || tokens > MAX_LABEL_TOKENS {
+            return Err(anyhow::anyhow!(
+                "Invalid number of label tokens: {}",
+                tokens
+            ));
+        }
+        self.tokens.insert(label.into(), tokens);
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Labels a pattern of `kind` is matched on in neo4j instead of its own.
+    pub fn generic_labels(&self, kind: EntityKind) -> Vec<String> {
+        match kind {
+            EntityKind::Node => vec![GENERIC_NODE_LABEL.to_string()],
+            // a relationship has exactly one type, its token if it has one
+            EntityKind::Relation => vec![],
+        }
+    }
+
+    /// All tokens of `label`, or `None` if it has none and can only be checked
+    /// in the enclave.
+    pub fn tokens(&self, label: &str) -> Option<Vec<String>> {
+        let tokens = *self.tokens.get(label)?;
+        Some((0..tokens).map(|salt| label_token(label, salt)).collect())
+    }
+
+    /// Turns the labels of an entity into the ones stored in neo4j, and keeps
+    /// the real ones in `label#set`. The entity has to carry its uid.
+    pub fn hide(
+        &self,
+        kind: EntityKind,
+        labels: &mut Vec<String>,
+        properties: &mut Vec<(String, String)>,
+    ) -> Result<()> {
+        properties.retain(|(k, _)| k != MAGIC_LABELS_KEY);
+        if !self.enabled {
+            return Ok(());
+        }
+        let uid = properties
+            .iter()
+            .find(|(k, _)| k == MAGIC_UID_KEY)
+            .map(|(_, v)| v.clone())
+            .ok_or_else(|| anyhow::anyhow!("Missing uid to hide labels"))?;
+        properties.push((MAGIC_LABELS_KEY.to_string(), serde_json::to_string(labels)?));
+        *labels = self.stored_labels(kind, &uid, labels);
+        Ok(())
+    }
+
+    /// Restores the real labels of an entity read from neo4j. Entities written
+    /// without hiding keep their labels.
+    pub fn reveal(&self, inner: &mut Inner) -> Result<()> {
+        if let Some(labels) = inner.get(MAGIC_LABELS_KEY) {
+            inner.labels =
+                serde_json::from_str(labels).map_err(|_| anyhow::anyhow!("Invalid label set"))?;
+            inner.remove_property(MAGIC_LABELS_KEY);
+        }
+        Ok(())
+    }
+
+    /// Rewrites the label items of SET / REMOVE on `var` to the stored form: the
+    /// sealed label set of `inner`, and the tokens it gained and lost since it
+    /// had `old_labels`.
+    pub fn sync_items(
+        &self,
+        query: &mut CypherQuery,
+        var: &str,
+        kind: EntityKind,
+        old_labels: &[String],
+        inner: &Inner,
+    ) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        // the real labels never reach neo4j
+        for list in [query.set_list.as_mut(), query.remove_list.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            list.retain(|x| !matches!(x, Item::VarWithLabel(v, _) if v == var));
+        }
+        if old_labels == inner.labels {
+            return Ok(());
+        }
+
+        let uid = inner
+            .get(MAGIC_UID_KEY)
+            .ok_or_else(|| anyhow::anyhow!("Missing uid to hide labels"))?;
+        let old = self.stored_labels(kind, uid, old_labels);
+        let new = self.stored_labels(kind, uid, &inner.labels);
+        let set_list = query.set_list.get_or_insert(vec![]);
+        set_list.push(Item::VarWithKeyValue(
+            var.to_string(),
+            MAGIC_LABELS_KEY.to_string(),
+            serde_json::to_string(&inner.labels)?,
+        ));
+        for label in new.iter().filter(|x| !old.contains(x)) {
+            set_list.push(Item::VarWithLabel(var.to_string(), label.clone()));
+        }
+        for label in old.iter().filter(|x| !new.contains(x)) {
+            query
+                .remove_list
+                .get_or_insert(vec![])
+                .push(Item::VarWithLabel(var.to_string(), label.clone()));
+        }
+        Ok(())
+    }
+
+    fn stored_labels(&self, kind: EntityKind, uid: &str, labels: &[String]) -> Vec<String> {
+        let mut tokens = labels.iter().filter_map(|label| {
+            self.tokens
+                .get(label)
+                .map(|tokens| label_token(label, salt(uid, label, *tokens)))
+        });
+        match kind {
+            EntityKind::Node => std::iter::once(GENERIC_NODE_LABEL.to_string())
+                .chain(tokens)
+                .collect(),
+            EntityKind::Relation => {
+                vec![tokens
+                    .next()
+                    .unwrap_or_else(|| GENERIC_RELATION_TYPE.to_string())]
+            }
+        }
+    }
+}
+
+fn label_token(label: &str, salt: u32) -> String {
+    format!("{}{}#{}", MAGIC_LABEL_PREFIX, label, salt)
+}
+
+// The token of an entity depends only on its uid, which never leaves the enclave,
+// so updates and key rotation find it again without storing it.
+fn salt(uid: &str, label: &str, tokens: u32) -> u32 {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&(uid.len() as u64).to_le_bytes());
+    hasher.update(uid.as_bytes());
+    hasher.update(label.as_bytes());
+    let hash = hasher.finalize();
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&hash.as_bytes()[..8]);
+    (u64::from_le_bytes(bytes) % tokens as u64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entity() -> (Vec<String>, Vec<(String, String)>) {
+        (
+            vec![String::from("Student"), String::from("Person")],
+            vec![
+                (MAGIC_UID_KEY.to_string(), String::from("uid-1")),
+                (String::from("name"), String::from("Alice")),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_hide_reveal() -> Result<()> {
+        let mut hiding = LabelHiding::new();
+        hiding.enabled = true;
+        let (mut labels, mut properties) = entity();
+        hiding.hide(EntityKind::Node, &mut labels, &mut properties)?;
+        assert_eq!(labels, vec![GENERIC_NODE_LABEL.to_string()]);
+        let stored = serde_json::to_string(&["Student", "Person"])?;
+        assert!(properties.contains(&(MAGIC_LABELS_KEY.to_string(), stored)));
+
+        let mut inner = Inner::new(labels, properties);
+        hiding.reveal(&mut inner)?;
+        let (labels, properties) = entity();
+        assert_eq!(inner.labels, labels);
+        assert_eq!(inner.properties, properties);
+
+        // hiding again replaces a label set the entity already carried
+        let mut labels = inner.labels.clone();
+        let mut properties = inner.properties.clone();
+        hiding.hide(EntityKind::Node, &mut labels, &mut properties)?;
+        hiding.hide(EntityKind::Node, &mut labels, &mut properties)?;
+        let count = properties
+            .iter()
+            .filter(|(k, _)| k == MAGIC_LABELS_KEY)
+            .count();
+        assert_eq!(count, 1);
+
+        // the uid is needed, and a label set neo4j made up is refused
+        let mut labels = vec![String::from("Student")];
+        assert!(hiding
+            .hide(EntityKind::Node, &mut labels, &mut vec![])
+            .is_err());
+        let mut inner = Inner::new(
+            vec![],
+            vec![(MAGIC_LABELS_KEY.to_string(), String::from("Student"))],
+        );
+        assert!(hiding.reveal(&mut inner).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hide_tokens() -> Result<()> {
+        let mut hiding = LabelHiding::new();
+        hiding.enabled = true;
+        hiding.add_tokens("Student", 4)?;
+        hiding.add_tokens("KNOWS", 2)?;
+        assert!(hiding.add_tokens("Teacher", 0).is_err());
+        assert!(hiding.add_tokens("Teacher", MAX_LABEL_TOKENS + 1).is_err());
+
+        let (mut labels, mut properties) = entity();
+        hiding.hide(EntityKind::Node, &mut labels, &mut properties)?;
+        let tokens = hiding.tokens("Student").unwrap();
+        assert_eq!(tokens.len(), 4);
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels[0], GENERIC_NODE_LABEL);
+        assert!(tokens.contains(&labels[1]));
+        assert!(hiding.tokens("Person").is_none());
+
+        // the token depends only on the uid
+        let (mut again, mut properties) = entity();
+        hiding.hide(EntityKind::Node, &mut again, &mut properties)?;
+        assert_eq!(again, labels);
+
+        let mut types = vec![String::from("KNOWS")];
+        let mut properties = vec![(MAGIC_UID_KEY.to_string(), String::from("uid-1"))];
+        hiding.hide(EntityKind::Relation, &mut types, &mut properties)?;
+        assert!(hiding.tokens("KNOWS").unwrap().contains(&types[0]));
+        let mut types = vec![String::from("TEACHES")];
+        hiding.hide(EntityKind::Relation, &mut types, &mut properties)?;
+        assert_eq!(types, vec![GENERIC_RELATION_TYPE.to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hiding_off() -> Result<()> {
+        let hiding = LabelHiding::new();
+        let (mut labels, mut properties) = entity();
+        properties.push((MAGIC_LABELS_KEY.to_string(), String::from("[]")));
+        hiding.hide(EntityKind::Node, &mut labels, &mut properties)?;
+        let (expected_labels, expected_properties) = entity();
+        assert_eq!(labels, expected_labels);
+        assert_eq!(properties, expected_properties);
+        Ok(())
+    }
+}
@@ -0,0 +1,390 @@
+use aes_gcm_siv::aead::{rand_core::RngCore, OsRng};
+use anyhow::Result;
+use log::{LevelFilter, Metadata, Record};
+use simple_cypher::*;
+
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+const HIDDEN_VALUE: &str = "?";
+const PREDICATE_PLACEHOLDER: &str = "-0.31415926535897932e-307";
+const HASH_LEN: usize = 8;
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+static HASH_KEY: OnceLock<[u8; 32]> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+}
+
+/// Logger configured from the environment:
+///
+/// - `LOG_LEVEL`: a default level and levels of modules, by target prefix, e.g.
+///   `info,tee_app::graph=trace,rustls=warn`. The longest matching prefix wins.
+/// - `LOG_FORMAT`: `text` (default) or `json`, one object per line with the
+///   time in milliseconds, level, target and message.
+///
+/// Graph data must only be logged through `Redacted` or `Hashed`, so even at
+/// trace level no plaintext leaves the enclave.
+struct Logger {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+    format: Format,
+}
+
+impl Logger {
+    fn from_env() -> Result<Self> {
+        let mut logger = Self::parse(&std::env::var("LOG_LEVEL").unwrap_or_default())?;
+        logger.format = match std::env::var("LOG_FORMAT").as_deref() {
+            Ok("json") => Format::Json,
+            Ok("text") | Err(_) => Format::Text,
+            Ok(format) => return Err(anyhow::anyhow!("Unknown LOG_FORMAT: {}", format)),
+        };
+        Ok(logger)
+    }
+
+    fn parse(levels: &str) -> Result<Self> {
+        let mut logger = Self {
+            default: DEFAULT_LEVEL,
+            modules: vec![],
+            format: Format::Text,
+        };
+        for directive in levels.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            let invalid = || anyhow::anyhow!("Invalid LOG_LEVEL: {}", directive);
+            match directive.split_once('=') {
+                Some((module, level)) => logger.modules.push((
+                    module.to_string(),
+                    LevelFilter::from_str(level).map_err(|_| invalid())?,
+                )),
+                None => logger.default = LevelFilter::from_str(directive).map_err(|_| invalid())?,
+            }
+        }
+        // longest prefixes first, so the first match is the most specific one
+        logger.modules.sort_by_key(|x| std::cmp::Reverse(x.0.len()));
+        Ok(logger)
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| {
+                target == module
+                    || target
+                        .strip_prefix(module.as_str())
+                        .is_some_and(|x| x.starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, std::cmp::max)
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = match self.format {
+            Format::Text => format!("[{} {}] {}", record.level(), record.target(), record.args()),
+            Format::Json => serde_json::json!({
+                "time": SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |x| x.as_millis() as u64),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            })
+            .to_string(),
+        };
+        let _ = writeln!(std::io::stdout().lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+pub fn init() -> Result<()> {
+    let logger = LOGGER.get_or_init(|| {
+        Logger::from_env().unwrap_or_else(|e| {
+            eprintln!("{:#}, logging at {}", e, DEFAULT_LEVEL);
+            Logger::parse("").unwrap()
+        })
+    });
+    log::set_logger(logger).map_err(|e| anyhow::anyhow!("Failed to init the logger: {}", e))?;
+    log::set_max_level(logger.max_level());
+    Ok(())
+}
+
+/// What of a value may be logged: its structure without any plaintext value.
+pub trait Redact {
+    fn shape(&self) -> String;
+}
+
+/// Logs the shape of graph data instead of the data, with `{}` and `{:?}` alike.
+pub struct Redacted<'a, T: ?Sized>(pub &'a T);
+
+impl<T: Redact + ?Sized> fmt::Display for Redacted<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0.shape())
+    }
+}
+
+impl<T: Redact + ?Sized> fmt::Debug for Redacted<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0.shape())
+    }
+}
+
+/// Logs a short keyed hash of a value. The key is random per process, so equal
+/// values can be told apart within one run but not guessed from the log.
+pub struct Hashed<'a>(pub &'a [u8]);
+
+impl fmt::Display for Hashed<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let key = HASH_KEY.get_or_init(|| {
+            let mut key = [0u8; 32];
+            OsRng.fill_bytes(&mut key);
+            key
+        });
+        let hash = blake3::keyed_hash(key, self.0);
+        f.write_str(&hash.to_hex()[..HASH_LEN * 2])
+    }
+}
+
+/// The query with every property value, predicate value and handle replaced by
+/// `?`, e.g. `MATCH (n:Student {name: '?'}) WHERE n.age > ? RETURN n`. A
+/// pattern given by a handle shows it as a property, `(n {handle: '?'})`.
+pub fn query_shape(query: &CypherQuery) -> String {
+    let mut query = query.clone();
+    hide_values(&mut query);
+    match query.to_query_string() {
+        Ok(shape) => shape.replace(&format!(" {}", PREDICATE_PLACEHOLDER), " ?"),
+        Err(_) => HIDDEN_VALUE.to_string(),
+    }
+}
+
+fn hide_values(query: &mut CypherQuery) {
+    // a handle has to be resolved before the query is turned into a string
+    let hide = |properties: &mut Vec<(String, String)>, handle: &mut Option<String>| {
+        properties
+            .iter_mut()
+            .for_each(|(_, v)| *v = HIDDEN_VALUE.to_string());
+        if handle.take().is_some() {
+            properties.push((String::from("handle"), HIDDEN_VALUE.to_string()));
+        }
+    };
+    for node in [&mut query.node, &mut query.next_node]
+        .into_iter()
+        .flatten()
+    {
+        hide(&mut node.properties, &mut node.handle);
+    }
+    if let Some(relation) = query.relation.as_mut() {
+        hide(&mut relation.properties, &mut relation.handle);
+    }
+    for predicate in query.where_list.iter_mut().flatten() {
+        *predicate = hide_predicate(predicate);
+    }
+    for item in query.set_list.iter_mut().flatten() {
+        if let Item::VarWithKeyValue(_, _, value) = item {
+            *value = HIDDEN_VALUE.to_string();
+        }
+    }
+    if let Some((other, _)) = query.union.as_mut() {
+        hide_values(other);
+    }
+}
+
+// The query string only takes numbers in predicates, so a placeholder number
+// stands in for the value until the string is built.
+fn hide_predicate(predicate: &Predicate) -> Predicate {
+    let (var, key) = (predicate.var_name().clone(), predicate.key().clone());
+    let value = PREDICATE_PLACEHOLDER.to_string();
+    match predicate {
+        Predicate::Gt(..) => Predicate::Gt(var, key, value),
+        Predicate::Ge(..) => Predicate::Ge(var, key, value),
+        Predicate::Lt(..) => Predicate::Lt(var, key, value),
+        Predicate::Le(..) => Predicate::Le(var, key, value),
+    }
+}
+
+impl Redact for CypherQuery {
+    fn shape(&self) -> String {
+        query_shape(self)
+    }
+}
+
+impl Redact for Rows {
+    fn shape(&self) -> String {
+        let entities: usize = self.rows().iter().map(|x| x.inners().len()).sum();
+        let hash = match self.serialize() {
+            Ok(serialized) => Hashed(serialized.as_bytes()).to_string(),
+            Err(_) => HIDDEN_VALUE.to_string(),
+        };
+        format!(
+            "{} rows, {} entities, hash {}",
+            self.rows().len(),
+            entities,
+            hash
+        )
+    }
+}
+
+impl Redact for Predicate {
+    fn shape(&self) -> String {
+        let op = match self {
+            Predicate::Gt(..) => ">",
+            Predicate::Ge(..) => ">=",
+            Predicate::Lt(..) => "<",
+            Predicate::Le(..) => "<=",
+        };
+        format!("{}.{} {} {}", self.var_name(), self.key(), op, HIDDEN_VALUE)
+    }
+}
+
+impl Redact for Node {
+    fn shape(&self) -> String {
+        let keys: Vec<&str> = self.properties.iter().map(|(k, _)| k.as_str()).collect();
+        format!(
+            "({}{} {{{}}})",
+            self.var_name.as_deref().unwrap_or_default(),
+            self.labels
+                .iter()
+                .map(|x| format!(":{}", x))
+                .collect::<String>(),
+            keys.join(", ")
+        )
+    }
+}
+
+impl Redact for Item {
+    fn shape(&self) -> String {
+        match self {
+            Item::Var(var) => var.clone(),
+            Item::VarWithLabel(var, label) => format!("{}:{}", var, label),
+            Item::VarWithKey(var, key) => format!("{}.{}", var, key),
+            Item::VarWithKeyValue(var, key, _) => format!("{}.{} = {}", var, key, HIDDEN_VALUE),
+        }
+    }
+}
+
+impl<T: Redact> Redact for Vec<T> {
+    fn shape(&self) -> String {
+        let shapes: Vec<String> = self.iter().map(Redact::shape).collect();
+        format!("[{}]", shapes.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRETS: [&str; 5] = ["Alice", "18", "Bob", "Beijing", "handle-1"];
+
+    fn assert_hidden(shape: &str) {
+        for secret in SECRETS {
+            assert!(!shape.contains(secret), "{} in {}", secret, shape);
+        }
+    }
+
+    // MATCH (n:Student {name: 'Alice'}) WHERE n.age > 18 RETURN n
+    #[test]
+    fn test_query_shape() -> Result<()> {
+        let query = CypherQueryBuilder::new()
+            .MATCH()
+            .node(Node::new(
+                Some("n"),
+                vec!["Student"],
+                vec![("name", "Alice")],
+            ))
+            .WHERE(vec![Predicate::Gt(
+                String::from("n"),
+                String::from("age"),
+                String::from("18"),
+            )])
+            .RETURN(vec![Item::Var(String::from("n"))])
+            .build();
+        assert_eq!(
+            query_shape(&query),
+            "MATCH (n:Student {name: '?'}) WHERE n.age > ? RETURN n"
+        );
+        Ok(())
+    }
+
+    // MATCH (a {name: 'Alice'})-[r:KNOWS {city: 'Beijing'}]->(b) SET b.name = 'Bob' RETURN b
+    #[test]
+    fn test_query_shape_hides_set_values() -> Result<()> {
+        let query = CypherQueryBuilder::new()
+            .MATCH()
+            .node(Node::new(
+                Some("a"),
+                Vec::<String>::new(),
+                vec![("name", "Alice")],
+            ))
+            .relation(Relation::new(
+                Some("r"),
+                vec!["KNOWS"],
+                vec![("city", "Beijing")],
+            ))
+            .next_node(Node::new_with_var("b"))
+            .SET(vec![Item::VarWithKeyValue(
+                String::from("b"),
+                String::from("name"),
+                String::from("Bob"),
+            )])
+            .RETURN(vec![Item::Var(String::from("b"))])
+            .build();
+        let shape = query_shape(&query);
+        assert_hidden(&shape);
+        assert!(shape.contains("[r:KNOWS {city: '?'}]"), "{}", shape);
+        assert!(shape.contains("b.name = '?'"), "{}", shape);
+        Ok(())
+    }
+
+    // MATCH (n:Student {name: 'Alice'}) RETURN n UNION MATCH (a) WHERE a.age <= 18 RETURN a
+    #[test]
+    fn test_query_shape_hides_handles() -> Result<()> {
+        let other = CypherQueryBuilder::new()
+            .MATCH()
+            .node(Node::by_handle("a", "handle-1"))
+            .WHERE(vec![Predicate::Le(
+                String::from("a"),
+                String::from("age"),
+                String::from("18"),
+            )])
+            .RETURN(vec![Item::Var(String::from("a"))])
+            .build();
+        let query = CypherQueryBuilder::new()
+            .MATCH()
+            .node(Node::new(
+                Some("n"),
+                vec!["Student"],
+                vec![("name", "Alice")],
+            ))
+            .RETURN(vec![Item::Var(String::from("n"))])
+            .build()
+            .union(other);
+        assert_eq!(
+            query_shape(&query),
+            "MATCH (n:Student {name: '?'}) RETURN n UNION MATCH (a {handle: '?'}) WHERE a.age <= ? RETURN a"
+        );
+        assert_hidden(&Redacted(&query).to_string());
+        Ok(())
+    }
+}
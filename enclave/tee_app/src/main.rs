@@ -1,42 +1,30 @@
+mod audit;
 mod crypto;
+mod explain;
 mod graph;
+mod index;
+mod journal;
+mod label;
+mod logger;
+mod membership;
+mod policy;
+mod principal;
 mod server;
+mod tenant;
+mod version;
 
 use anyhow::Result;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
-    logger::init();
+    // LOG_LEVEL and LOG_FORMAT may come from .env too
+    dotenv::dotenv().ok();
+    logger::init()?;
 
-    server::start_server().await?;
-
-    Ok(())
-}
-
-mod logger {
-    use log::{Level, LevelFilter, Metadata, Record};
-
-    pub fn init() {
-        log::set_logger(&LOGGER)
-            .map(|()| log::set_max_level(LevelFilter::Trace))
-            .expect("failed to init the logger");
+    match std::env::args().nth(1).as_deref() {
+        Some("verify-audit") => audit::verify_from_env(std::env::args().nth(2).as_deref())?,
+        _ => server::start_server().await?,
     }
 
-    static LOGGER: SimpleLogger = SimpleLogger;
-
-    struct SimpleLogger;
-
-    impl log::Log for SimpleLogger {
-        fn enabled(&self, metadata: &Metadata) -> bool {
-            metadata.level() <= Level::Info
-        }
-
-        fn log(&self, record: &Record) {
-            if self.enabled(record.metadata()) {
-                println!("[{}] {}", record.level(), record.args());
-            }
-        }
-
-        fn flush(&self) {}
-    }
+    Ok(())
 }
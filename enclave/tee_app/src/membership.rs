@@ -0,0 +1,398 @@
+use anyhow::Result;
+use simple_cypher::*;
+
+use crate::crypto::{Crypto, IntegrityError};
+use crate::graph::MAGIC_UID_KEY;
+use crate::journal::{Loaded, SealedJournal};
+use crate::logger::Redacted;
+use crate::tenant::Tenant;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+const SEALED_MEMBERSHIP_AAD: &[u8] = b"clique_task membership index v2";
+// marker of a started membership index in the sealed data keys
+const MEMBERSHIP_INDEX_MARKER: &str = "membership-index";
+
+/// Optional authenticated index of the nodes carrying each label and each value
+/// of the indexed properties, enabled by `AUTHENTICATED_INDEX=key1,key2`.
+///
+/// A node pattern whose labels and properties are all covered by the index has
+/// an exactly known result set, so neo4j can neither add a node that doesn't
+/// match the pattern nor silently omit one. This holds for reads of a single
+/// node pattern, with or without a WHERE clause. Patterns with a relationship
+/// are not checked: the index holds nodes only, so neo4j can still omit a
+/// relationship, or a path through one, from their results. Like the version map, the index is
+/// sealed to `MEMBERSHIP_INDEX_PATH` as a snapshot plus a journal of the nodes
+/// each write changed, and has the same rollback limits.
+pub struct MembershipIndex {
+    enabled: bool,
+    keys: BTreeSet<String>,
+    // labels and indexed properties of every node, to remove it from its sets
+    nodes: BTreeMap<String, Entry>,
+    labels: BTreeMap<String, BTreeSet<String>>,
+    properties: BTreeMap<(String, String), BTreeSet<String>>,
+    journal: Option<SealedJournal>,
+    // changes applied since the last commit
+    record: String,
+}
+
+#[derive(Default)]
+struct Entry {
+    labels: Vec<String>,
+    properties: Vec<(String, String)>,
+}
+
+impl MembershipIndex {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            keys: BTreeSet::new(),
+            nodes: BTreeMap::new(),
+            labels: BTreeMap::new(),
+            properties: BTreeMap::new(),
+            journal: None,
+            record: String::new(),
+        }
+    }
+
+    /// An index kept in memory only would miss every existing node after a
+    /// restart, so `MEMBERSHIP_INDEX_PATH` is required with `AUTHENTICATED_INDEX`.
+    pub fn from_env(tenant: &Tenant) -> Result<Self> {
+        let mut index = Self::new();
+        let keys = match std::env::var("AUTHENTICATED_INDEX") {
+            Ok(keys) => keys,
+            Err(_) => return Ok(index),
+        };
+        index.enabled = true;
+        index.keys = keys
+            .split(',')
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty())
+            .collect();
+        let path = tenant.path_var("MEMBERSHIP_INDEX_PATH").ok_or_else(|| {
+            anyhow::anyhow!("MEMBERSHIP_INDEX_PATH must be set with AUTHENTICATED_INDEX")
+        })?;
+        index.journal = Some(SealedJournal::new(
+            path,
+            SEALED_MEMBERSHIP_AAD,
+            MEMBERSHIP_INDEX_MARKER,
+        ));
+        Ok(index)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// The indexed property keys.
+    pub fn keys(&self) -> &BTreeSet<String> {
+        &self.keys
+    }
+
+    /// Loads the sealed index, or seals an empty one on the first start.
+    pub fn load(&mut self, crypto: &Crypto) -> Result<()> {
+        let journal = match (self.enabled, self.journal.as_mut()) {
+            (true, Some(journal)) => journal,
+            _ => return Ok(()),
+        };
+        log::info!("membership index on labels and {:?}", self.keys);
+        let Loaded { snapshot, records } = match journal.load(crypto)? {
+            Some(loaded) => loaded,
+            None => {
+                log::info!(
+                    "start a new membership index at {}",
+                    journal.path().display()
+                );
+                let snapshot = self.snapshot();
+                return self
+                    .journal
+                    .as_mut()
+                    .unwrap()
+                    .compact(crypto, snapshot.as_bytes());
+            }
+        };
+
+        let mut keys = BTreeSet::new();
+        let count = records.len();
+        for line in [snapshot]
+            .into_iter()
+            .chain(records)
+            .map(String::from_utf8)
+            .collect::<Result<Vec<_>, _>>()?
+            .iter()
+            .flat_map(|x| x.lines())
+        {
+            let fields = line
+                .split(' ')
+                .map(from_hex)
+                .collect::<Option<Vec<String>>>()
+                .ok_or_else(|| anyhow::anyhow!("Invalid membership index"))?;
+            match fields.as_slice() {
+                [tag, key] if tag == "k" => {
+                    keys.insert(key.clone());
+                }
+                [tag, uid, entry @ ..] if tag == "n" && entry.len() % 2 == 0 => {
+                    let mut labels = vec![];
+                    let mut properties = vec![];
+                    for pair in entry.chunks(2) {
+                        match pair[0].strip_prefix('=') {
+                            Some(key) => properties.push((key.to_string(), pair[1].clone())),
+                            None => labels.push(pair[1].clone()),
+                        }
+                    }
+                    self.remove(uid);
+                    self.add(uid.clone(), Entry { labels, properties });
+                }
+                [tag, uid] if tag == "x" => self.remove(uid),
+                _ => return Err(anyhow::anyhow!("Invalid membership index")),
+            }
+        }
+
+        // the sets of a newly indexed key would miss every existing node
+        if keys != self.keys {
+            return Err(anyhow::anyhow!(
+                "AUTHENTICATED_INDEX changed from {:?} to {:?}",
+                keys,
+                self.keys
+            ));
+        }
+
+        log::info!(
+            "loaded membership index of {} nodes, {} journal records",
+            self.nodes.len(),
+            count
+        );
+        Ok(())
+    }
+
+    /// Seals the changes since the last commit. They were made by writes neo4j
+    /// already took, so a failure to seal them is logged, and the next commit
+    /// seals the whole index instead.
+    pub fn commit(&mut self, crypto: &Crypto) {
+        let journal = match self.journal.as_mut() {
+            Some(journal) if !self.record.is_empty() => journal,
+            _ => return,
+        };
+        let res = if journal.is_full() {
+            let snapshot = self.snapshot();
+            self.journal
+                .as_mut()
+                .unwrap()
+                .compact(crypto, snapshot.as_bytes())
+        } else {
+            journal.append(crypto, self.record.as_bytes())
+        };
+        if let Err(e) = res {
+            log::error!("failed to seal membership index changes: {:?}", e);
+        }
+        self.record.clear();
+    }
+
+    /// Adds a node with its labels and indexed properties.
+    pub fn insert_node(
+        &mut self,
+        labels: &[String],
+        properties: &[(String, String)],
+    ) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let uid = properties
+            .iter()
+            .find(|(k, _)| k == MAGIC_UID_KEY)
+            .map(|(_, v)| v.clone())
+            .ok_or(IntegrityError::MissingUid)?;
+        let entry = Entry {
+            labels: labels.to_vec(),
+            properties: properties
+                .iter()
+                .filter(|(k, _)| self.keys.contains(k))
+                .cloned()
+                .collect(),
+        };
+
+        self.remove(&uid);
+        self.record.push_str(&entry_line(&uid, &entry));
+        self.add(uid, entry);
+        Ok(())
+    }
+
+    /// Replaces the entries of an updated node.
+    pub fn update_node(&mut self, inner: &Inner) -> Result<()> {
+        self.insert_node(&inner.labels, &inner.properties)
+    }
+
+    pub fn remove_node(&mut self, uid: &str) {
+        if !self.enabled || !self.nodes.contains_key(uid) {
+            return;
+        }
+        self.remove(uid);
+        self.record
+            .push_str(&format!("{} {}\n", to_hex("x"), to_hex(uid)));
+    }
+
+    /// Whether the result set of `pattern` is exactly known, that is every property
+    /// of the pattern is the uid or an indexed key.
+    pub fn is_checkable(&self, pattern: &Node) -> bool {
+        self.enabled
+            && pattern
+                .properties
+                .iter()
+                .all(|(k, _)| k == MAGIC_UID_KEY || self.keys.contains(k))
+    }
+
+    /// Checks the nodes neo4j returned for `pattern`: each one has to match the
+    /// pattern, and if every constraint of the pattern is indexed, no node of the
+    /// indexed result set may be missing.
+    pub fn check_nodes(&self, pattern: &Node, inners: &[&Inner]) -> Result<(), IntegrityError> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        // the sets selected by the pattern, whose intersection is the result set
+        let empty = BTreeSet::new();
+        let mut sets = vec![];
+        let mut pinned_uid = None;
+        for label in &pattern.labels {
+            sets.push(self.labels.get(label).unwrap_or(&empty));
+        }
+        for (k, v) in &pattern.properties {
+            if k == MAGIC_UID_KEY {
+                pinned_uid = Some(v);
+            } else if self.keys.contains(k) {
+                sets.push(
+                    self.properties
+                        .get(&(k.clone(), v.clone()))
+                        .unwrap_or(&empty),
+                );
+            }
+        }
+        let is_expected = |uid: &String| {
+            pinned_uid.iter().all(|x| *x == uid)
+                && self.nodes.contains_key(uid)
+                && sets.iter().all(|x| x.contains(uid))
+        };
+
+        let mut returned = BTreeSet::new();
+        for inner in inners {
+            let uid = inner.get(MAGIC_UID_KEY).ok_or(IntegrityError::MissingUid)?;
+            if !is_expected(uid) || !matches_pattern(pattern, inner) {
+                return Err(IntegrityError::UnexpectedRow { uid: uid.clone() });
+            }
+            returned.insert(uid);
+        }
+
+        if !self.is_checkable(pattern) {
+            log::trace!("completeness of {} is not checkable", Redacted(pattern));
+            return Ok(());
+        }
+        // walk the smallest candidate set for a node that should have been returned
+        let omitted = match pinned_uid {
+            Some(uid) => is_expected(uid)
+                .then_some(uid)
+                .filter(|x| !returned.contains(x)),
+            None => match sets.iter().min_by_key(|x| x.len()) {
+                Some(smallest) => smallest
+                    .iter()
+                    .find(|x| !returned.contains(x) && is_expected(x)),
+                None => self.nodes.keys().find(|x| !returned.contains(x)),
+            },
+        };
+        match omitted {
+            Some(uid) => Err(IntegrityError::OmittedRow { uid: uid.clone() }),
+            None => Ok(()),
+        }
+    }
+
+    fn add(&mut self, uid: String, entry: Entry) {
+        for label in &entry.labels {
+            self.labels
+                .entry(label.clone())
+                .or_default()
+                .insert(uid.clone());
+        }
+        for property in &entry.properties {
+            self.properties
+                .entry(property.clone())
+                .or_default()
+                .insert(uid.clone());
+        }
+        self.nodes.insert(uid, entry);
+    }
+
+    fn remove(&mut self, uid: &str) {
+        let entry = match self.nodes.remove(uid) {
+            Some(entry) => entry,
+            None => return,
+        };
+        for label in entry.labels {
+            if let Some(uids) = self.labels.get_mut(&label) {
+                uids.remove(uid);
+                if uids.is_empty() {
+                    self.labels.remove(&label);
+                }
+            }
+        }
+        for property in entry.properties {
+            if let Some(uids) = self.properties.get_mut(&property) {
+                uids.remove(uid);
+                if uids.is_empty() {
+                    self.properties.remove(&property);
+                }
+            }
+        }
+    }
+
+    fn snapshot(&self) -> String {
+        let mut plain = String::new();
+        for key in &self.keys {
+            plain.push_str(&format!("{} {}\n", to_hex("k"), to_hex(key)));
+        }
+        for (uid, entry) in &self.nodes {
+            plain.push_str(&entry_line(uid, entry));
+        }
+        plain
+    }
+}
+
+// `n uid` followed by a pair per label and indexed property: a label is paired
+// with an empty field, a property with its key behind a `=`.
+fn entry_line(uid: &str, entry: &Entry) -> String {
+    let mut line = format!("{} {}", to_hex("n"), to_hex(uid));
+    for label in &entry.labels {
+        line.push_str(&format!(" {} {}", to_hex(""), to_hex(label)));
+    }
+    for (key, value) in &entry.properties {
+        line.push_str(&format!(
+            " {} {}",
+            to_hex(&format!("={}", key)),
+            to_hex(value)
+        ));
+    }
+    line.push('\n');
+    line
+}
+
+fn matches_pattern(pattern: &Node, inner: &Inner) -> bool {
+    pattern.labels.iter().all(|x| inner.labels.contains(x))
+        && pattern
+            .properties
+            .iter()
+            .all(|(k, v)| inner.get(k) == Some(v))
+}
+
+fn to_hex(s: &str) -> String {
+    s.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<String> {
+    let bytes = s
+        .as_bytes()
+        .chunks(2)
+        .map(|x| match x {
+            [hi, lo] => u8::from_str_radix(std::str::from_utf8(&[*hi, *lo]).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
@@ -0,0 +1,737 @@
+use anyhow::Result;
+use secure_channel::from_hex;
+use simple_cypher::*;
+
+use crate::crypto::{Crypto, EntityKind};
+use crate::principal::Principal;
+use crate::tenant::Tenant;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+const SEALED_POLICY_AAD: &[u8] = b"clique_task access policy v1";
+
+// Ed25519 key signed policies are verified with, in hex. It is taken from the
+// build environment, so it is part of the enclave measurement and the host
+// can't swap it for its own.
+const POLICY_PUBLIC_KEY: Option<&str> = option_env!("POLICY_PUBLIC_KEY");
+
+pub const ANY: &str = "*";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    Read,
+    Create,
+    Update,
+    Delete,
+    Write,
+}
+
+impl Action {
+    fn parse(action: &str) -> Result<Self> {
+        match action {
+            "read" => Ok(Action::Read),
+            "create" => Ok(Action::Create),
+            "update" => Ok(Action::Update),
+            "delete" => Ok(Action::Delete),
+            "write" => Ok(Action::Write),
+            _ => Err(anyhow::anyhow!("Unknown action: {}", action)),
+        }
+    }
+}
+
+/// A condition on a property of a decrypted entity, `key=value` or
+/// `key!=value`. A value `$attr` stands for the attribute `attr` of the
+/// principal, a principal without it never matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RowFilter {
+    key: String,
+    negated: bool,
+    value: String,
+}
+
+impl RowFilter {
+    fn parse(filter: &str) -> Result<Self> {
+        let (key, negated, value) = match filter.split_once("!=") {
+            Some((key, value)) => (key, true, value),
+            None => match filter.split_once('=') {
+                Some((key, value)) => (key, false, value),
+                None => return Err(anyhow::anyhow!("Invalid row filter: {}", filter)),
+            },
+        };
+        if key.is_empty() || value.is_empty() {
+            return Err(anyhow::anyhow!("Invalid row filter: {}", filter));
+        }
+        Ok(Self {
+            key: key.to_string(),
+            negated,
+            value: value.to_string(),
+        })
+    }
+
+    fn matches(&self, inner: &Inner, attributes: &BTreeMap<String, String>) -> bool {
+        let expected = match self.value.strip_prefix('$') {
+            Some(attr) => match attributes.get(attr) {
+                Some(value) => value,
+                None => return false,
+            },
+            None => &self.value,
+        };
+        (inner.get(&self.key) == Some(expected)) != self.negated
+    }
+}
+
+/// How a property is shown to a role, ordered from the least to the most
+/// revealing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Redaction {
+    /// The property is left out.
+    Drop,
+    /// The value is replaced by its hash, salted per tenant.
+    Hash,
+    /// All but the last N characters are replaced by `*`.
+    Mask(usize),
+}
+
+impl Redaction {
+    fn parse(rule: &str) -> Result<Self> {
+        match rule.split_once(':') {
+            Some(("mask", n)) => Ok(Redaction::Mask(n.parse()?)),
+            None if rule == "drop" => Ok(Redaction::Drop),
+            None if rule == "hash" => Ok(Redaction::Hash),
+            _ => Err(anyhow::anyhow!("Unknown redaction: {}", rule)),
+        }
+    }
+
+    fn apply(&self, value: &str, hash: impl FnOnce(&str) -> String) -> Option<String> {
+        match self {
+            Redaction::Drop => None,
+            Redaction::Hash => Some(hash(value)),
+            Redaction::Mask(n) => {
+                let len = value.chars().count();
+                Some(
+                    value
+                        .chars()
+                        .enumerate()
+                        .map(|(i, c)| if i + n < len { '*' } else { c })
+                        .collect(),
+                )
+            }
+        }
+    }
+}
+
+/// What one role may do, by label, relationship type and property name. `*`
+/// stands for every name without an entry of its own.
+#[derive(Debug, Clone, Default)]
+pub struct RoleGrants {
+    labels: HashMap<String, HashSet<Action>>,
+    relationship_types: HashMap<String, HashSet<Action>>,
+    properties: HashMap<String, HashSet<Action>>,
+    filters: HashMap<String, Vec<RowFilter>>,
+    redactions: HashMap<String, Redaction>,
+}
+
+impl RoleGrants {
+    // Whether this role shows `inner` under its label or relationship type `name`:
+    // it may read `name` and the entity passes the filters of `name` and of `*`.
+    fn shows(
+        &self,
+        kind: EntityKind,
+        name: &str,
+        inner: &Inner,
+        attributes: &BTreeMap<String, String>,
+    ) -> bool {
+        let grants = match kind {
+            EntityKind::Node => &self.labels,
+            EntityKind::Relation => &self.relationship_types,
+        };
+        allows(grants, name, Action::Read)
+            && [name, ANY]
+                .iter()
+                .filter_map(|x| self.filters.get(*x))
+                .flatten()
+                .all(|x| x.matches(inner, attributes))
+    }
+}
+
+fn allows(grants: &HashMap<String, HashSet<Action>>, name: &str, action: Action) -> bool {
+    grants
+        .get(name)
+        .or_else(|| grants.get(ANY))
+        .is_some_and(|x| x.contains(&action))
+}
+
+/// Role-based access control enforced in the enclave.
+///
+/// Read from `POLICY_PATH`, one grant per line:
+///
+/// ```text
+/// # role   kind          name     actions
+/// reader   label         Student  read
+/// reader   relationship  *        read
+/// reader   property      *        read
+/// reader   property      phone
+/// writer   label         Student  read,create,update,delete
+/// writer   property      *        read,write
+/// teacher  label         Student  read,update
+/// teacher  property      *        read,write
+/// teacher  filter        Student  school=$school
+/// reader   redact        id_card  mask:4
+/// reader   redact        email    hash
+/// ```
+///
+/// Labels and relationship types take `read`, `create`, `update` and `delete`,
+/// properties take `read` and `write`; a grant without actions denies the name
+/// even if `*` allows it. A principal may do what any of its roles may do.
+///
+/// A `filter` limits the rows a role sees of a label or relationship type (`*`
+/// for all) to the entities matching it, see `RowFilter`; filters of one role
+/// must all match. Rows no role shows are dropped before they are returned,
+/// updated or deleted.
+///
+/// A `redact` rule (`drop`, `hash` or `mask:N`, see `Redaction`) changes how a
+/// property (`*` for all) is returned to a role. A principal gets the most
+/// revealing form of its roles, a role without a rule shows the plain value.
+///
+/// The file is trusted either through an Ed25519 signature, if the enclave
+/// was built with `POLICY_PUBLIC_KEY` (hex, the signature is read as hex from
+/// `POLICY_PATH` + `.sig`), or because it is sealed by this enclave. A sealed
+/// policy is written from the plaintext at `POLICY_IMPORT_PATH` on startup.
+///
+/// Without `POLICY_PATH` the enclave refuses to start, unless `POLICY=allow-all`
+/// explicitly lets every principal run every query.
+#[derive(Debug, Default)]
+pub struct AccessPolicy {
+    path: Option<PathBuf>,
+    allow_all: bool,
+    public_key: Option<Vec<u8>>,
+    import_path: Option<PathBuf>,
+    roles: Option<HashMap<String, RoleGrants>>,
+}
+
+impl AccessPolicy {
+    /// A policy that lets every principal run every query, as `POLICY=allow-all`.
+    pub fn new() -> Self {
+        Self {
+            allow_all: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn from_env(tenant: &Tenant) -> Result<Self> {
+        let allow_all = match tenant.var("POLICY").as_deref() {
+            Some("allow-all") => true,
+            Some(policy) => return Err(anyhow::anyhow!("Unknown POLICY: {}", policy)),
+            None => false,
+        };
+        Ok(Self {
+            path: tenant.path_var("POLICY_PATH"),
+            allow_all,
+            public_key: POLICY_PUBLIC_KEY.map(from_hex).transpose()?,
+            import_path: tenant.path_var("POLICY_IMPORT_PATH"),
+            roles: None,
+        })
+    }
+
+    /// A policy of the grants in `text`, without a file to load.
+    #[cfg(test)]
+    pub fn from_text(text: &str) -> Result<Self> {
+        Ok(Self {
+            roles: Some(Self::parse(text)?),
+            ..Self::default()
+        })
+    }
+
+    pub fn load(&mut self, crypto: &Crypto) -> Result<()> {
+        let path = match (self.path.as_ref(), self.allow_all) {
+            (Some(path), false) => path,
+            (None, true) => {
+                log::warn!("POLICY=allow-all, every client may run every query");
+                return Ok(());
+            }
+            (Some(_), true) => {
+                return Err(anyhow::anyhow!("POLICY=allow-all contradicts POLICY_PATH"))
+            }
+            (None, false) => {
+                return Err(anyhow::anyhow!(
+                "POLICY_PATH must be set, or POLICY=allow-all to let every client run every query"
+            ))
+            }
+        };
+
+        let text = match self.public_key.as_ref() {
+            Some(public_key) => {
+                let text = std::fs::read(path)?;
+                let mut sig_path = path.clone().into_os_string();
+                sig_path.push(".sig");
+                let signature = from_hex(std::fs::read_to_string(&sig_path)?.trim())?;
+                ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key)
+                    .verify(&text, &signature)
+                    .map_err(|_| anyhow::anyhow!("Invalid signature of {}", path.display()))?;
+                String::from_utf8(text)?
+            }
+            None => {
+                if let Some(import_path) = self.import_path.as_ref() {
+                    import(crypto, import_path, path)?;
+                }
+                String::from_utf8(crypto.unseal_from_file(path, SEALED_POLICY_AAD)?)?
+            }
+        };
+
+        let roles = Self::parse(&text)?;
+        log::info!(
+            "access policy of {} roles from {}",
+            roles.len(),
+            path.display()
+        );
+        self.roles = Some(roles);
+        Ok(())
+    }
+
+    pub fn parse(text: &str) -> Result<HashMap<String, RoleGrants>> {
+        let mut roles: HashMap<String, RoleGrants> = HashMap::new();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 3 || fields.len() > 4 {
+                return Err(anyhow::anyhow!("Invalid policy line: {}", line));
+            }
+            if fields[1] == "filter" {
+                let filter = fields
+                    .get(3)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid policy line: {}", line))?;
+                roles
+                    .entry(fields[0].to_string())
+                    .or_default()
+                    .filters
+                    .entry(fields[2].to_string())
+                    .or_default()
+                    .push(RowFilter::parse(filter)?);
+                continue;
+            }
+            if fields[1] == "redact" {
+                let rule = fields
+                    .get(3)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid policy line: {}", line))?;
+                roles
+                    .entry(fields[0].to_string())
+                    .or_default()
+                    .redactions
+                    .insert(fields[2].to_string(), Redaction::parse(rule)?);
+                continue;
+            }
+            let actions = fields
+                .get(3)
+                .map_or(vec![], |x| x.split(',').collect())
+                .into_iter()
+                .map(Action::parse)
+                .collect::<Result<HashSet<Action>>>()?;
+
+            let grants = roles.entry(fields[0].to_string()).or_default();
+            let (map, allowed) = match fields[1] {
+                "label" => (&mut grants.labels, &ENTITY_ACTIONS[..]),
+                "relationship" => (&mut grants.relationship_types, &ENTITY_ACTIONS[..]),
+                "property" => (&mut grants.properties, &PROPERTY_ACTIONS[..]),
+                _ => return Err(anyhow::anyhow!("Unknown policy kind: {}", fields[1])),
+            };
+            if let Some(action) = actions.iter().find(|x| !allowed.contains(x)) {
+                return Err(anyhow::anyhow!(
+                    "{:?} doesn't apply to a {}: {}",
+                    action,
+                    fields[1],
+                    line
+                ));
+            }
+            map.entry(fields[2].to_string())
+                .or_default()
+                .extend(actions);
+        }
+        Ok(roles)
+    }
+
+    /// The grants of `principal`, from all its roles.
+    pub fn access<'a>(&'a self, principal: &'a Principal) -> Access<'a> {
+        Access {
+            grants: self.roles.as_ref().map(|roles| {
+                principal
+                    .roles
+                    .iter()
+                    .filter_map(|role| roles.get(role))
+                    .collect()
+            }),
+            attributes: &principal.attributes,
+        }
+    }
+}
+
+const ENTITY_ACTIONS: [Action; 4] = [Action::Read, Action::Create, Action::Update, Action::Delete];
+const PROPERTY_ACTIONS: [Action; 2] = [Action::Read, Action::Write];
+
+// Seals the plaintext policy at `import_path` to `path`, so later starts only
+// need the sealed copy.
+fn import(crypto: &Crypto, import_path: &Path, path: &Path) -> Result<()> {
+    let text = std::fs::read(import_path)?;
+    AccessPolicy::parse(std::str::from_utf8(&text)?)?;
+    crypto.seal_to_file(path, &text, SEALED_POLICY_AAD)?;
+    log::warn!(
+        "sealed the access policy {} to {}, remove POLICY_IMPORT_PATH now",
+        import_path.display(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// A query or an entity the principal may not access.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PermissionDenied(pub String);
+
+impl fmt::Display for PermissionDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Permission denied: {}", self.0)
+    }
+}
+
+impl std::error::Error for PermissionDenied {}
+
+/// What one principal may do. Without a policy it may do everything.
+pub struct Access<'a> {
+    grants: Option<Vec<&'a RoleGrants>>,
+    attributes: &'a BTreeMap<String, String>,
+}
+
+impl Access<'_> {
+    pub fn is_restricted(&self) -> bool {
+        self.grants.is_some()
+    }
+
+    fn allows(&self, kind: EntityKind, name: &str, action: Action) -> bool {
+        match &self.grants {
+            Some(grants) => grants.iter().any(|x| match kind {
+                EntityKind::Node => allows(&x.labels, name, action),
+                EntityKind::Relation => allows(&x.relationship_types, name, action),
+            }),
+            None => true,
+        }
+    }
+
+    fn allows_property(&self, key: &str, action: Action) -> bool {
+        match &self.grants {
+            Some(grants) => grants.iter().any(|x| allows(&x.properties, key, action)),
+            None => true,
+        }
+    }
+
+    fn check(&self, kind: EntityKind, labels: &[String], action: Action) -> Result<()> {
+        match labels.iter().find(|x| !self.allows(kind, x, action)) {
+            Some(label) => {
+                Err(PermissionDenied(format!("{:?} on {:?} {}", action, kind, label)).into())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn check_properties<'k>(
+        &self,
+        keys: impl IntoIterator<Item = &'k String>,
+        action: Action,
+    ) -> Result<()> {
+        for key in keys {
+            if !self.allows_property(key, action) {
+                return Err(PermissionDenied(format!("{:?} on property {}", action, key)).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the labels, relationship types and properties a plaintext query
+    /// names. The entities it matches are checked once they are decrypted.
+    pub fn check_query(&self, query: &CypherQuery) -> Result<()> {
+        if !self.is_restricted() {
+            return Ok(());
+        }
+
+        let crud_type = query.get_type()?;
+        let creates_nodes = matches!(crud_type, CRUDtype::Create) && !query.use_match;
+        let creates_relation = matches!(crud_type, CRUDtype::Create);
+        for node in [&query.node, &query.next_node].into_iter().flatten() {
+            if creates_nodes {
+                self.check(EntityKind::Node, &node.labels, Action::Create)?;
+                self.check_properties(node.properties.iter().map(|(k, _)| k), Action::Write)?;
+            } else {
+                self.check(EntityKind::Node, &node.labels, Action::Read)?;
+                self.check_properties(node.properties.iter().map(|(k, _)| k), Action::Read)?;
+            }
+        }
+        if let Some(relation) = &query.relation {
+            if creates_relation {
+                self.check(EntityKind::Relation, &relation.labels, Action::Create)?;
+                self.check_properties(relation.properties.iter().map(|(k, _)| k), Action::Write)?;
+            } else {
+                self.check(EntityKind::Relation, &relation.labels, Action::Read)?;
+                self.check_properties(relation.properties.iter().map(|(k, _)| k), Action::Read)?;
+            }
+        }
+
+        self.check_properties(
+            query.where_list.iter().flatten().map(|x| x.key()),
+            Action::Read,
+        )?;
+        for item in query.return_list.iter().flatten() {
+            match item {
+                Item::VarWithKey(_, key) => self.check_properties([key], Action::Read)?,
+                Item::VarWithLabel(_, label) => {
+                    self.check(EntityKind::Node, std::slice::from_ref(label), Action::Read)?
+                }
+                _ => {}
+            }
+        }
+        for item in query
+            .set_list
+            .iter()
+            .chain(query.remove_list.iter())
+            .flatten()
+        {
+            match item {
+                Item::VarWithKey(_, key) | Item::VarWithKeyValue(_, key, _) => {
+                    self.check_properties([key], Action::Write)?
+                }
+                Item::VarWithLabel(_, label) => self.check(
+                    EntityKind::Node,
+                    std::slice::from_ref(label),
+                    Action::Update,
+                )?,
+                Item::Var(_) => {}
+            }
+        }
+        // DETACH DELETE removes relationships of any type along with the nodes
+        if let Some((_, true)) = &query.delete_list {
+            self.check(EntityKind::Relation, &[ANY.to_string()], Action::Delete)?;
+        }
+
+        match &query.union {
+            Some((other, _)) => self.check_query(other),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether every label of a decrypted entity may be read by a role whose
+    /// row filters it passes. Unlabeled nodes fall under `*`.
+    pub fn can_read(&self, kind: EntityKind, inner: &Inner) -> bool {
+        let grants = match &self.grants {
+            Some(grants) => grants,
+            None => return true,
+        };
+        let any = [ANY.to_string()];
+        let labels = if inner.labels().is_empty() {
+            &any[..]
+        } else {
+            &inner.labels()[..]
+        };
+        labels.iter().all(|label| {
+            grants
+                .iter()
+                .any(|x| x.shows(kind, label, inner, self.attributes))
+        })
+    }
+
+    pub fn check_entity(&self, kind: EntityKind, inner: &Inner, action: Action) -> Result<()> {
+        if inner.labels().is_empty() {
+            self.check(kind, &[ANY.to_string()], action)
+        } else {
+            self.check(kind, inner.labels(), action)
+        }
+    }
+
+    /// Drops the properties of a decrypted entity that may not be read.
+    pub fn strip_properties(&self, inner: &mut Inner) {
+        if self.is_restricted() {
+            inner
+                .properties
+                .retain(|(k, _)| self.allows_property(k, Action::Read));
+        }
+    }
+
+    // The redaction of a property, `None` if some role shows it in plain.
+    fn redaction(&self, key: &str) -> Option<Redaction> {
+        let grants = self.grants.as_ref()?;
+        grants
+            .iter()
+            .map(|x| x.redactions.get(key).or_else(|| x.redactions.get(ANY)))
+            .try_fold(None, |most, x| x.map(|x| most.max(Some(*x))))
+            .flatten()
+    }
+
+    /// Applies the redaction rules of the principal's roles to the properties
+    /// of a result entity.
+    pub fn redact(&self, inner: &mut Inner, crypto: &Crypto) {
+        if !self.is_restricted() {
+            return;
+        }
+        let properties = std::mem::take(&mut inner.properties);
+        inner.properties = properties
+            .into_iter()
+            .filter_map(|(k, v)| match self.redaction(&k) {
+                Some(redaction) => redaction
+                    .apply(&v, |v| crypto.redaction_hash(v))
+                    .map(|v| (k, v)),
+                None => Some((k, v)),
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: &str = "
+        # role   kind          name     actions
+        reader   label         Student  read
+        reader   relationship  *        read
+        reader   property      *        read
+        reader   property      phone
+        writer   label         Student  read,create,update,delete
+        writer   property      *        read,write
+        teacher  filter        Student  school=$school
+        reader   redact        id_card  mask:4
+    ";
+
+    fn is_denied(res: Result<()>) -> bool {
+        res.is_err_and(|e| e.is::<PermissionDenied>())
+    }
+
+    // MATCH (n:Student {name: 'Alice'}) RETURN n
+    fn match_student(key: &str) -> CypherQuery {
+        CypherQueryBuilder::new()
+            .MATCH()
+            .node(Node::new(Some("n"), vec!["Student"], vec![(key, "Alice")]))
+            .RETURN(vec![Item::Var(String::from("n"))])
+            .build()
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let roles = AccessPolicy::parse(POLICY)?;
+        assert_eq!(roles.len(), 3);
+        let reader = &roles["reader"];
+        assert!(allows(&reader.labels, "Student", Action::Read));
+        assert!(!allows(&reader.labels, "Student", Action::Create));
+        assert!(!allows(&reader.labels, "Teacher", Action::Read));
+        assert!(allows(&reader.relationship_types, "KNOWS", Action::Read));
+        assert!(allows(&reader.properties, "name", Action::Read));
+        // a grant without actions overrides `*`
+        assert!(!allows(&reader.properties, "phone", Action::Read));
+        assert_eq!(reader.redactions["id_card"], Redaction::Mask(4));
+        assert_eq!(roles["teacher"].filters["Student"].len(), 1);
+
+        for line in [
+            "reader label",
+            "reader label Student read extra",
+            "reader node Student read",
+            "reader label Student write",
+            "reader property name create",
+            "reader label Student fly",
+            "reader filter Student",
+            "reader filter Student school",
+            "reader redact id_card",
+        ] {
+            assert!(AccessPolicy::parse(line).is_err(), "{}", line);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_query() -> Result<()> {
+        let policy = AccessPolicy::from_text(POLICY)?;
+        let reader = Principal::new("alice", ["reader"]);
+        let writer = Principal::new("bob", ["writer"]);
+        let nobody = Principal::new("eve", ["guest"]);
+
+        assert!(policy
+            .access(&reader)
+            .check_query(&match_student("name"))
+            .is_ok());
+        assert!(is_denied(
+            policy.access(&reader).check_query(&match_student("phone"))
+        ));
+        assert!(is_denied(
+            policy.access(&nobody).check_query(&match_student("name"))
+        ));
+
+        // CREATE (n:Student {name: 'Alice'}) RETURN n
+        let create = CypherQueryBuilder::new()
+            .CREATE()
+            .node(Node::new(
+                Some("n"),
+                vec!["Student"],
+                vec![("name", "Alice")],
+            ))
+            .RETURN(vec![Item::Var(String::from("n"))])
+            .build();
+        assert!(is_denied(policy.access(&reader).check_query(&create)));
+        assert!(policy.access(&writer).check_query(&create).is_ok());
+
+        // MATCH (n:Student {name: 'Alice'}) WHERE n.phone > 0 RETURN n
+        let filtered = CypherQueryBuilder::new()
+            .MATCH()
+            .node(Node::new(
+                Some("n"),
+                vec!["Student"],
+                vec![("name", "Alice")],
+            ))
+            .WHERE(vec![Predicate::Gt(
+                String::from("n"),
+                String::from("phone"),
+                String::from("0"),
+            )])
+            .RETURN(vec![Item::Var(String::from("n"))])
+            .build();
+        assert!(is_denied(policy.access(&reader).check_query(&filtered)));
+        assert!(policy.access(&writer).check_query(&filtered).is_ok());
+
+        // MATCH (n:Student {name: 'Alice'}) SET n.name = 'Bob' RETURN n
+        let update = CypherQueryBuilder::new()
+            .MATCH()
+            .node(Node::new(
+                Some("n"),
+                vec!["Student"],
+                vec![("name", "Alice")],
+            ))
+            .SET(vec![Item::VarWithKeyValue(
+                String::from("n"),
+                String::from("name"),
+                String::from("Bob"),
+            )])
+            .RETURN(vec![Item::Var(String::from("n"))])
+            .build();
+        assert!(is_denied(policy.access(&reader).check_query(&update)));
+        assert!(policy.access(&writer).check_query(&update).is_ok());
+
+        // the writer may not delete relationships along with the nodes
+        let delete = CypherQueryBuilder::new()
+            .MATCH()
+            .node(Node::new(
+                Some("n"),
+                vec!["Student"],
+                vec![("name", "Alice")],
+            ))
+            .DELETE(vec![Item::Var(String::from("n"))], true)
+            .build();
+        assert!(is_denied(policy.access(&writer).check_query(&delete)));
+
+        // a union is checked on both sides
+        let union = match_student("name").union(match_student("phone"));
+        assert!(is_denied(policy.access(&reader).check_query(&union)));
+
+        // without a policy everything is allowed
+        let allow_all = AccessPolicy::new();
+        assert!(!allow_all.access(&nobody).is_restricted());
+        assert!(allow_all.access(&nobody).check_query(&create).is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+use anyhow::Result;
+use secure_channel::{from_hex, PeerIdentity};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use crate::tenant::DEFAULT_TENANT;
+
+pub const ANONYMOUS_PRINCIPAL: &str = "anonymous";
+pub const TENANT_ATTRIBUTE: &str = "tenant";
+
+/// Who sends a query, the roles it acts in and its attributes, which row
+/// filters of the access policy compare against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    pub name: String,
+    pub roles: BTreeSet<String>,
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl Principal {
+    pub fn new(
+        name: impl Into<String>,
+        roles: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            roles: roles.into_iter().map(Into::into).collect(),
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    /// A connection without a client certificate, while no principal map is set.
+    pub fn anonymous() -> Self {
+        Self {
+            name: ANONYMOUS_PRINCIPAL.to_string(),
+            roles: BTreeSet::new(),
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    /// The tenant the principal belongs to, its `tenant` attribute.
+    pub fn tenant(&self) -> &str {
+        self.attributes
+            .get(TENANT_ATTRIBUTE)
+            .map_or(DEFAULT_TENANT, String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Matcher {
+    CommonName(String),
+    AltName(String),
+    Fingerprint([u8; 32]),
+    Anonymous,
+}
+
+impl Matcher {
+    fn matches(&self, identity: Option<&PeerIdentity>) -> bool {
+        match (self, identity) {
+            (Matcher::CommonName(cn), Some(identity)) => identity.common_name.as_ref() == Some(cn),
+            (Matcher::AltName(name), Some(identity)) => identity.alt_names.contains(name),
+            (Matcher::Fingerprint(fingerprint), Some(identity)) => {
+                &identity.fingerprint == fingerprint
+            }
+            (Matcher::Anonymous, None) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Maps the verified client certificate of a connection to a principal.
+///
+/// Read from the file at `PRINCIPALS_PATH`, one rule per line, the first
+/// matching rule wins:
+///
+/// ```text
+/// # identity                principal  roles          attributes
+/// san:alice@example.com     alice      reader,writer  school=MIT
+/// cn:backup-job             backup     admin
+/// fingerprint:ab01...       ops        admin
+/// anonymous                 guest      reader
+/// ```
+///
+/// `anonymous` matches connections without a client certificate. With a map
+/// set, a connection no rule matches is refused; without one every connection
+/// is `Principal::anonymous`.
+#[derive(Debug, Clone, Default)]
+pub struct PrincipalMap {
+    rules: Option<Vec<(Matcher, Principal)>>,
+}
+
+impl PrincipalMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("PRINCIPALS_PATH") {
+            Ok(path) => Self::load(Path::new(&path)),
+            Err(_) => {
+                log::warn!("PRINCIPALS_PATH is not set, every client is anonymous");
+                Ok(Self::new())
+            }
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let map = Self::parse(&std::fs::read_to_string(path)?)?;
+        log::info!(
+            "{} principal rules from {}",
+            map.rules.as_ref().map_or(0, Vec::len),
+            path.display()
+        );
+        Ok(map)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut rules = vec![];
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 2 || fields.len() > 4 {
+                return Err(anyhow::anyhow!("Invalid principal rule: {}", line));
+            }
+            let matcher = match fields[0].split_once(':') {
+                Some(("cn", cn)) => Matcher::CommonName(cn.to_string()),
+                Some(("san", name)) => Matcher::AltName(name.to_string()),
+                Some(("fingerprint", hex)) => Matcher::Fingerprint(
+                    from_hex(&hex.replace(':', ""))?
+                        .try_into()
+                        .map_err(|_| anyhow::anyhow!("Fingerprint {} isn't 32 bytes", hex))?,
+                ),
+                None if fields[0] == "anonymous" => Matcher::Anonymous,
+                _ => return Err(anyhow::anyhow!("Invalid principal identity: {}", fields[0])),
+            };
+            let roles = fields
+                .get(2)
+                .map_or(vec![], |x| x.split(',').filter(|x| !x.is_empty()).collect());
+            let attributes = fields
+                .get(3)
+                .map_or(vec![], |x| x.split(',').filter(|x| !x.is_empty()).collect())
+                .into_iter()
+                .map(|x| match x.split_once('=') {
+                    Some((k, v)) => Ok((k.to_string(), v.to_string())),
+                    None => Err(anyhow::anyhow!("Invalid principal attribute: {}", x)),
+                })
+                .collect::<Result<_>>()?;
+            rules.push((
+                matcher,
+                Principal {
+                    attributes,
+                    ..Principal::new(fields[1], roles)
+                },
+            ));
+        }
+        Ok(Self { rules: Some(rules) })
+    }
+
+    /// The principal of a connection, `identity` is `None` without a client certificate.
+    pub fn resolve(&self, identity: Option<&PeerIdentity>) -> Result<Principal> {
+        let rules = match &self.rules {
+            Some(rules) => rules,
+            None => return Ok(Principal::anonymous()),
+        };
+        rules
+            .iter()
+            .find(|(matcher, _)| matcher.matches(identity))
+            .map(|(_, principal)| principal.clone())
+            .ok_or_else(|| match identity {
+                Some(identity) => anyhow::anyhow!(
+                    "No principal for the client certificate CN {:?}, SAN {:?}",
+                    identity.common_name,
+                    identity.alt_names
+                ),
+                None => anyhow::anyhow!("No principal for clients without a certificate"),
+            })
+    }
+}
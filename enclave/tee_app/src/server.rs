@@ -1,25 +1,36 @@
+use crate::audit::AuditLog;
+use crate::crypto::{
+    key_provider_from_env, matched_keys_from_env, quote_provider_from_env, KeyProvider,
+};
 use crate::graph::EncryptedGraph;
+use crate::index::BucketIndex;
+use crate::label::LabelHiding;
+use crate::logger::Redacted;
+use crate::membership::MembershipIndex;
+use crate::policy::{AccessPolicy, PermissionDenied};
+use crate::principal::{Principal, PrincipalMap};
+use crate::tenant::Tenant;
+use crate::version::VersionMap;
 
 use anyhow::Result;
 use dotenv::dotenv;
-use rustls::client::danger::HandshakeSignatureValid;
-use rustls::pki_types::{CertificateDer, PrivateKeyDer, UnixTime};
-use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
-use rustls::server::WebPkiClientVerifier;
-use rustls::{DigitallySignedStruct, DistinguishedName, RootCertStore, SignatureScheme};
-use rustls_pemfile::{certs, read_all};
+use secure_channel::{
+    ra_tls_server_config, Channel, PeerIdentity, QuoteProvider, ServerTlsConfig, Transport,
+};
 use simple_cypher::*;
-use tokio::io::{copy, sink, split, AsyncReadExt, AsyncWriteExt};
+use tokio::io::{copy, sink, split};
 use tokio::net::TcpListener;
-use tokio_rustls::{rustls, TlsAcceptor};
+use tokio_rustls::TlsAcceptor;
 
+use std::collections::HashMap;
 use std::env;
-use std::fs::File;
-use std::io::{self, BufReader};
+use std::io;
 use std::net::ToSocketAddrs;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+const DEFAULT_KEY_ROTATION_BATCH_SIZE: usize = 1000;
+
 pub async fn start_server() -> Result<()> {
     dotenv().ok();
 
@@ -27,32 +38,70 @@ pub async fn start_server() -> Result<()> {
     let user = env::var("DATABASE_USERNAME").expect("DATABASE_USERNAME must be set");
     let pass = env::var("DATABASE_PASSWORD").expect("DATABASE_PASSWORD must be set");
 
-    let graph = Arc::new(EncryptedGraph::new(uri, user, pass).await?);
+    let key_provider: Arc<dyn KeyProvider> = key_provider_from_env()?.into();
+    let quote_provider: Arc<dyn QuoteProvider> = quote_provider_from_env()?.into();
+    let principals = Arc::new(PrincipalMap::from_env()?);
+
+    // one graph per tenant, each with its own keys and state files
+    let mut graphs = HashMap::new();
+    for tenant in Tenant::all_from_env()? {
+        log::info!("load tenant {}", tenant.id);
+        let graph = Arc::new(
+            EncryptedGraph::new(
+                uri.as_str(),
+                user.as_str(),
+                pass.as_str(),
+                &tenant,
+                key_provider.clone(),
+            )
+            .await?
+            .with_bucket_index(BucketIndex::from_env()?)
+            .with_matched_keys(matched_keys_from_env())
+            .with_label_hiding(LabelHiding::from_env()?)
+            .with_version_map(VersionMap::from_env(&tenant)?)?
+            .with_membership_index(MembershipIndex::from_env(&tenant)?)?
+            .with_access_policy(AccessPolicy::from_env(&tenant)?)?
+            .with_audit_log(AuditLog::from_env(&tenant))?,
+        );
 
-    // test_crud(&graph).await.unwrap();
-    // test_find_shortest_path(&graph).await.unwrap();
+        if tenant.var("KEY_ROTATION").as_deref() == Some("start") || graph.is_rotating_key() {
+            let batch_size = match env::var("KEY_ROTATION_BATCH_SIZE") {
+                Ok(size) => size.parse()?,
+                Err(_) => DEFAULT_KEY_ROTATION_BATCH_SIZE,
+            };
+            let graph = graph.clone();
+            let id = tenant.id.clone();
+            tokio::spawn(async move {
+                match graph.rotate_key(batch_size).await {
+                    Ok(()) => log::info!("key rotation of tenant {} finished", id),
+                    Err(e) => log::error!("key rotation of tenant {} failed: {:?}", id, e),
+                }
+            });
+        } else {
+            graph.check_label_hiding().await?;
+        }
+        graphs.insert(tenant.id, graph);
+    }
+    let graphs = Arc::new(graphs);
 
     let addr = "127.0.0.1:8080"
         .to_socket_addrs()?
         .next()
         .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
 
-    // let certs = load_certs("/server.crt")?;
-    // let key = load_keys("/server.key")?;
-    // let client_certs = load_certs("/rootCA.pem")?;
-
-    // let mut roots = RootCertStore::empty();
-    // for client_cert in client_certs {
-    //     roots.add(client_cert);
-    // }
-
-    // let inner = WebPkiClientVerifier::builder(roots.into()).build()?;
-    // let verifier = MyClientCertVerifier::new(inner).boxed();
-
-    // let server_config = rustls::ServerConfig::builder()
-    //     .with_client_cert_verifier(verifier)
-    //     .with_single_cert(certs, key)?;
-    // let acceptor = TlsAcceptor::from(Arc::new(server_config));
+    // RA-TLS: an ephemeral key and a self-signed certificate that carries evidence
+    // over the key, generated once per start. Client certificates are checked the
+    // same way with either TLS transport.
+    let tls_config = ServerTlsConfig::from_env()?;
+    let acceptor = match Transport::from_env()? {
+        Transport::RaTls => Some(ra_tls_server_config(
+            quote_provider.as_ref(),
+            tls_config.client_verifier()?,
+        )?),
+        Transport::Tls => Some(tls_config.server_config()?),
+        Transport::Attested => None,
+    }
+    .map(|config| TlsAcceptor::from(Arc::new(config)));
 
     log::info!("bind addr: {}", addr);
 
@@ -61,33 +110,45 @@ pub async fn start_server() -> Result<()> {
     log::info!("start listening...");
 
     loop {
-        let cloned_graph = graph.clone();
-        let (mut stream, _peer_addr) = listener.accept().await?;
+        let graphs = graphs.clone();
+        let quote_provider = quote_provider.clone();
+        let principals = principals.clone();
+        let acceptor = acceptor.clone();
+        let (stream, _peer_addr) = listener.accept().await?;
         log::info!("accept");
 
-        // let acceptor = acceptor.clone();
-
         let fut = async move {
-            // let mut stream = acceptor.accept(stream).await?;
+            // the client certificate, if any, was verified by the acceptor
+            let (mut channel, identity) = match acceptor {
+                Some(acceptor) => {
+                    let stream = acceptor.accept(stream).await?;
+                    let identity = match stream.get_ref().1.peer_certificates() {
+                        Some([cert, ..]) => Some(PeerIdentity::from_cert(cert)?),
+                        _ => None,
+                    };
+                    (Channel::plain(stream), identity)
+                }
+                None => (
+                    Channel::accept_attested(stream, quote_provider.as_ref()).await?,
+                    None,
+                ),
+            };
+            let principal = principals.resolve(identity.as_ref())?;
+            log::info!("handshake done, principal: {}", principal.name);
 
             loop {
-                let len = stream.read_u64().await? as usize;
-                log::trace!("read {}", len);
-                // todo: limit max len
-                let mut buf = vec![0u8; len];
-                stream.read_exact(&mut buf).await?;
-                log::trace!("read {} bytes", len);
+                let buf = channel.recv().await?;
+                log::trace!("read {} bytes", buf.len());
 
                 let serialized_query = String::from_utf8(buf)?;
                 let query = CypherQuery::deserialize(&serialized_query)?;
-                log::trace!("query: {:?}", query);
-                let result = cloned_graph.execute_query(query).await?;
-                log::trace!("execute result: {:?}", result);
+                log::trace!("query: {}", Redacted(&query));
+                let graph = tenant_graph(&graphs, &principal, query.tenant.as_deref())?;
+                let result = graph.execute_query(&principal, query).await?;
+                log::trace!("execute result: {}", Redacted(&result));
 
                 let text = result.serialize()?;
-                stream.write_u64(text.len() as u64).await?;
-                log::trace!("write {}", text.len());
-                stream.write_all(text.as_bytes()).await?;
+                channel.send(text.as_bytes()).await?;
                 log::trace!("write {} bytes", text.len());
             }
 
@@ -96,7 +157,7 @@ pub async fn start_server() -> Result<()> {
 
         tokio::spawn(async move {
             if let Err(err) = fut.await {
-                eprintln!("{:?}", err);
+                log::error!("connection closed: {:?}", err);
             }
         });
     }
@@ -104,654 +165,603 @@ pub async fn start_server() -> Result<()> {
     Ok(())
 }
 
-fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
-    certs(&mut BufReader::new(File::open(path)?)).collect()
-}
-
-fn load_keys(path: &str) -> Result<PrivateKeyDer<'static>> {
-    for item in read_all(&mut BufReader::new(File::open(path)?)) {
-        match item.unwrap() {
-            rustls_pemfile::Item::Pkcs1Key(key) => return Ok(key.into()),
-            rustls_pemfile::Item::Pkcs8Key(key) => return Ok(key.into()),
-            rustls_pemfile::Item::Sec1Key(key) => return Ok(key.into()),
-            _ => return Err(anyhow::anyhow!("invalid key")),
-        }
-    }
-    Err(anyhow::anyhow!("there is no key"))
-}
-
-#[derive(Debug)]
-struct MyClientCertVerifier {
-    inner: Arc<dyn ClientCertVerifier>,
-}
-
-impl MyClientCertVerifier {
-    pub fn new(inner: Arc<dyn ClientCertVerifier>) -> Self {
-        Self { inner }
-    }
-
-    /// Wrap this verifier in an [`Arc`] and coerce it to `dyn ClientCertVerifier`
-    #[inline(always)]
-    pub fn boxed(self) -> Arc<dyn ClientCertVerifier> {
-        // This function is needed to keep it functioning like the original verifier.
-        Arc::new(self)
-    }
-}
-
-impl ClientCertVerifier for MyClientCertVerifier {
-    fn root_hint_subjects(&self) -> &[DistinguishedName] {
-        self.inner.root_hint_subjects()
-    }
-
-    fn verify_client_cert(
-        &self,
-        end_entity: &CertificateDer<'_>,
-        intermediates: &[CertificateDer<'_>],
-        now: UnixTime,
-    ) -> Result<ClientCertVerified, rustls::Error> {
-        self.inner
-            .verify_client_cert(end_entity, intermediates, now)
-    }
-
-    fn verify_tls12_signature(
-        &self,
-        message: &[u8],
-        cert: &CertificateDer<'_>,
-        dss: &DigitallySignedStruct,
-    ) -> Result<HandshakeSignatureValid, rustls::Error> {
-        self.inner.verify_tls12_signature(message, cert, dss)
-    }
-
-    fn verify_tls13_signature(
-        &self,
-        message: &[u8],
-        cert: &CertificateDer<'_>,
-        dss: &DigitallySignedStruct,
-    ) -> Result<HandshakeSignatureValid, rustls::Error> {
-        self.inner.verify_tls13_signature(message, cert, dss)
-    }
-
-    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
-        self.inner.supported_verify_schemes()
+// The graph of the tenant a query asks for, which has to be the principal's own.
+fn tenant_graph<'a>(
+    graphs: &'a HashMap<String, Arc<EncryptedGraph>>,
+    principal: &Principal,
+    tenant: Option<&str>,
+) -> Result<&'a EncryptedGraph> {
+    let tenant = tenant.unwrap_or(principal.tenant());
+    if tenant != principal.tenant() {
+        return Err(PermissionDenied(format!("{} in tenant {}", principal.name, tenant)).into());
     }
+    graphs
+        .get(tenant)
+        .map(Arc::as_ref)
+        .ok_or_else(|| anyhow::anyhow!("Unknown tenant: {}", tenant))
 }
 
-async fn init_test(graph: &EncryptedGraph) -> Result<()> {
-    let query = CypherQueryBuilder::new()
-        .MATCH()
-        .node(Node::new(
-            Some("n"),
-            Vec::<String>::new(),
-            Vec::<(String, String)>::new(),
-        ))
-        .DELETE(vec![Item::Var(String::from("n"))], true)
-        .build();
-
-    graph.execute_query(query).await?;
-
-    Ok(())
-}
-
-async fn test_crud(graph: &EncryptedGraph) -> Result<()> {
-    init_test(graph).await?;
-
-    {
-        let query = CypherQueryBuilder::new()
-            .CREATE()
-            .node(Node::new(
-                Some("a"),
-                vec!["Student"],
-                vec![("name", "Alice"), ("age", "25")],
-            ))
-            .RETURN(vec![Item::Var(String::from("a"))])
-            .build();
-
-        println!("{}", query.to_query_string()?);
-        let result = graph.execute_query(query).await.unwrap();
-        println!("    {:?}", result);
-
-        assert_eq!(
-            result.rows()[0].inners()[0],
-            Inner::new(
-                vec!["Student".to_string()],
-                vec![
-                    ("name".to_string(), "Alice".to_string()),
-                    ("age".to_string(), "25".to_string())
-                ]
-            )
-        );
-    }
-    {
-        let query = CypherQueryBuilder::new()
-            .CREATE()
-            .node(Node::new(
-                Some("n"),
-                vec!["Student"],
-                vec![("name", "Bob"), ("age", "23"), ("home", "beijing")],
-            ))
-            .relation(Relation::new(
-                Some("r"),
-                vec!["Knows"],
-                vec![("time", "1year")],
-            ))
-            .next_node(Node::new(
-                Some("m"),
-                vec!["Student"],
-                vec![("name", "John"), ("age", "24"), ("home", "jiangxi")],
-            ))
-            .RETURN(vec![
-                Item::Var(String::from("n")),
-                Item::Var(String::from("r")),
-                Item::Var(String::from("m")),
-            ])
-            .build();
-
-        println!("{}", query.to_query_string()?);
-        let result = graph.execute_query(query).await.unwrap();
-        println!("    {:?}", result);
-
-        assert_eq!(
-            result.rows()[0].inners()[0],
-            Inner::new(
-                vec!["Student".to_string()],
-                vec![
-                    ("name".to_string(), "Bob".to_string()),
-                    ("age".to_string(), "23".to_string()),
-                    ("home".to_string(), "beijing".to_string())
-                ]
-            )
-        );
-        assert_eq!(
-            result.rows()[0].inners()[1],
-            Inner::new(
-                vec!["Knows".to_string()],
-                vec![("time".to_string(), "1year".to_string())]
-            )
-        );
-        assert_eq!(
-            result.rows()[0].inners()[2],
-            Inner::new(
-                vec!["Student".to_string()],
-                vec![
-                    ("name".to_string(), "John".to_string()),
-                    ("age".to_string(), "24".to_string()),
-                    ("home".to_string(), "jiangxi".to_string())
-                ]
-            )
-        );
-    }
-    {
-        let query = CypherQueryBuilder::new()
-            .MATCH()
-            .node(Node::new(
-                Some("n"),
-                vec!["Student"],
-                vec![("name", "Alice")],
-            ))
-            .relation(Relation::new(
-                Some("r"),
-                vec!["Like"],
-                vec![("time", "1month"), ("public", "yes")],
-            ))
-            .next_node(Node::new(
-                Some("m"),
-                vec!["Student"],
-                vec![("name", "John"), ("age", "24")],
-            ))
-            .CREATE()
-            .RETURN(vec![
-                Item::Var(String::from("n")),
-                Item::Var(String::from("r")),
-                Item::Var(String::from("m")),
-            ])
-            .build();
-
-        println!("{}", query.to_query_string()?);
-        let result = graph.execute_query(query).await.unwrap();
-        println!("    {:?}", result);
-
-        assert_eq!(
-            result.rows()[0].inners()[0],
-            Inner::new(
-                vec!["Student".to_string()],
-                vec![
-                    ("name".to_string(), "Alice".to_string()),
-                    ("age".to_string(), "25".to_string())
-                ]
-            )
-        );
-        assert_eq!(
-            result.rows()[0].inners()[1],
-            Inner::new(
-                vec!["Like".to_string()],
-                vec![
-                    ("time".to_string(), "1month".to_string()),
-                    ("public".to_string(), "yes".to_string())
-                ]
-            )
-        );
-        assert_eq!(
-            result.rows()[0].inners()[2],
-            Inner::new(
-                vec!["Student".to_string()],
-                vec![
-                    ("name".to_string(), "John".to_string()),
-                    ("age".to_string(), "24".to_string()),
-                    ("home".to_string(), "jiangxi".to_string())
-                ]
-            )
-        );
-    }
-    {
-        let query = CypherQueryBuilder::new()
-            .MATCH()
-            .node(Node::new(
-                Some("n"),
-                vec!["Student"],
-                Vec::<(String, String)>::new(),
-            ))
-            .RETURN(vec![Item::Var(String::from("n"))])
-            .build();
-
-        println!("{}", query.to_query_string()?);
-        let result = graph.execute_query(query).await.unwrap();
-        println!("    {:?}", result);
-
-        assert_eq!(result.rows().len(), 3);
-    }
-    {
-        let query = CypherQueryBuilder::new()
-            .MATCH()
-            .node(Node::new(Some("n"), vec!["Student"], vec![("age", "25")]))
-            .relation(Relation::new(
-                Some("r"),
-                vec!["Like"],
-                vec![("time", "1month")],
-            ))
-            .next_node(Node::new(
-                Some("m"),
-                vec!["Student"],
-                vec![("home", "jiangxi"), ("age", "24")],
-            ))
-            .RETURN(vec![
-                Item::Var(String::from("n")),
-                Item::Var(String::from("r")),
-                Item::Var(String::from("m")),
-            ])
-            .build();
-
-        println!("{}", query.to_query_string()?);
-        let result = graph.execute_query(query).await.unwrap();
-        println!("    {:?}", result);
-
-        assert_eq!(
-            result.rows()[0].inners()[0],
-            Inner::new(
-                vec!["Student".to_string()],
-                vec![
-                    ("name".to_string(), "Alice".to_string()),
-                    ("age".to_string(), "25".to_string())
-                ]
-            )
-        );
-        assert_eq!(
-            result.rows()[0].inners()[1],
-            Inner::new(
-                vec!["Like".to_string()],
-                vec![
-                    ("time".to_string(), "1month".to_string()),
-                    ("public".to_string(), "yes".to_string())
-                ]
-            )
-        );
-        assert_eq!(
-            result.rows()[0].inners()[2],
-            Inner::new(
-                vec!["Student".to_string()],
-                vec![
-                    ("name".to_string(), "John".to_string()),
-                    ("age".to_string(), "24".to_string()),
-                    ("home".to_string(), "jiangxi".to_string())
-                ]
-            )
-        );
-    }
-    {
-        let query = CypherQueryBuilder::new()
-            .MATCH()
-            .node(Node::new(
-                Some("n"),
-                vec!["Student"],
-                Vec::<(String, String)>::new(),
-            ))
-            .SET(vec![
-                Item::VarWithLabel(String::from("n"), String::from("Undergraduate")),
-                Item::VarWithKeyValue(
-                    String::from("n"),
-                    String::from("univ"),
-                    String::from("Nanjing univ"),
-                ),
-            ])
-            .RETURN(vec![Item::Var(String::from("n"))])
-            .build();
-
-        println!("{}", query.to_query_string()?);
-        let result = graph.execute_query(query).await.unwrap();
-        println!("    {:?}", result);
-
-        assert_eq!(result.rows().len(), 3);
-    }
-    {
-        let query = CypherQueryBuilder::new()
-            .MATCH()
-            .node(Node::new(
-                Some("x"),
-                vec!["Student"],
-                Vec::<(String, String)>::new(),
-            ))
-            .relation(Relation::new(
-                Some("xy"),
-                vec!["Knows"],
-                Vec::<(String, String)>::new(),
-            ))
-            .next_node(Node::new(
-                Some("y"),
-                vec!["Student"],
-                Vec::<(String, String)>::new(),
-            ))
-            .SET(vec![
-                Item::VarWithLabel(String::from("x"), String::from("Intern")),
-                Item::VarWithKeyValue(
-                    String::from("x"),
-                    String::from("location"),
-                    String::from("nanjing"),
-                ),
-                Item::VarWithKeyValue(
-                    String::from("y"),
-                    String::from("location"),
-                    String::from("nanjing"),
-                ),
-                Item::VarWithKeyValue(
-                    String::from("xy"),
-                    String::from("level"),
-                    String::from("mid"),
-                ),
-            ])
-            .RETURN(vec![
-                Item::Var(String::from("x")),
-                Item::Var(String::from("xy")),
-                Item::Var(String::from("y")),
-            ])
-            .build();
-
-        println!("{}", query.to_query_string()?);
-        let result = graph.execute_query(query).await.unwrap();
-        println!("    {:?}", result);
-
-        assert_eq!(
-            result.rows()[0].inners()[0],
-            Inner::new(
-                vec![
-                    "Student".to_string(),
-                    "Intern".to_string(),
-                    "Undergraduate".to_string()
-                ],
-                vec![
-                    ("name".to_string(), "Bob".to_string()),
-                    ("age".to_string(), "23".to_string()),
-                    ("home".to_string(), "beijing".to_string()),
-                    ("univ".to_string(), "Nanjing univ".to_string()),
-                    ("location".to_string(), "nanjing".to_string())
-                ]
-            )
-        );
-        assert_eq!(
-            result.rows()[0].inners()[1],
-            Inner::new(
-                vec!["Knows".to_string()],
-                vec![
-                    ("time".to_string(), "1year".to_string()),
-                    ("level".to_string(), "mid".to_string())
-                ]
-            )
-        );
-        assert_eq!(
-            result.rows()[0].inners()[2],
-            Inner::new(
-                vec!["Student".to_string(), "Undergraduate".to_string()],
-                vec![
-                    ("name".to_string(), "John".to_string()),
-                    ("age".to_string(), "24".to_string()),
-                    ("home".to_string(), "jiangxi".to_string()),
-                    ("univ".to_string(), "Nanjing univ".to_string()),
-                    ("location".to_string(), "nanjing".to_string())
-                ]
-            )
-        );
-    }
-    {
-        let query = CypherQueryBuilder::new()
-            .MATCH()
-            .node(Node::new(
-                Some("n"),
-                vec!["Intern"],
-                vec![("location", "nanjing")],
-            ))
-            .REMOVE(vec![
-                Item::VarWithLabel(String::from("n"), String::from("Intern")),
-                Item::VarWithKey(String::from("n"), String::from("location")),
-            ])
-            .RETURN(vec![Item::Var(String::from("n"))])
-            .build();
-
-        println!("{}", query.to_query_string()?);
-        let result = graph.execute_query(query).await.unwrap();
-        println!("    {:?}", result);
-
-        assert_eq!(
-            result.rows()[0].inners()[0],
-            Inner::new(
-                vec!["Student".to_string(), "Undergraduate".to_string()],
-                vec![
-                    ("name".to_string(), "Bob".to_string()),
-                    ("age".to_string(), "23".to_string()),
-                    ("home".to_string(), "beijing".to_string()),
-                    ("univ".to_string(), "Nanjing univ".to_string()),
-                ]
-            )
-        );
-    }
-    {
-        let query = CypherQueryBuilder::new()
-            .MATCH()
-            .node(Node::new(
-                Some("x"),
-                vec!["Student"],
-                Vec::<(String, String)>::new(),
-            ))
-            .relation(Relation::new(
-                Some("xy"),
-                vec!["Like"],
-                vec![("time", "1month")],
-            ))
-            .next_node(Node::new(
-                Some("y"),
-                vec!["Student"],
-                Vec::<(String, String)>::new(),
-            ))
-            .REMOVE(vec![
-                Item::VarWithLabel(String::from("x"), String::from("Undergraduate")),
-                Item::VarWithKey(String::from("x"), String::from("age")),
-                Item::VarWithKey(String::from("y"), String::from("age")),
-                Item::VarWithKey(String::from("xy"), String::from("public")),
-            ])
-            .RETURN(vec![
-                Item::Var(String::from("x")),
-                Item::Var(String::from("xy")),
-                Item::Var(String::from("y")),
-            ])
-            .build();
-
-        println!("{}", query.to_query_string()?);
-        let result = graph.execute_query(query).await.unwrap();
-        println!("    {:?}", result);
-
-        assert_eq!(
-            result.rows()[0].inners()[0],
-            Inner::new(
-                vec!["Student".to_string()],
-                vec![
-                    ("name".to_string(), "Alice".to_string()),
-                    ("univ".to_string(), "Nanjing univ".to_string())
-                ]
-            )
-        );
-        assert_eq!(
-            result.rows()[0].inners()[1],
-            Inner::new(
-                vec!["Like".to_string()],
-                vec![("time".to_string(), "1month".to_string())]
-            )
-        );
-        assert_eq!(
-            result.rows()[0].inners()[2],
-            Inner::new(
-                vec!["Student".to_string(), "Undergraduate".to_string()],
-                vec![
-                    ("name".to_string(), "John".to_string()),
-                    ("home".to_string(), "jiangxi".to_string()),
-                    ("univ".to_string(), "Nanjing univ".to_string()),
-                    ("location".to_string(), "nanjing".to_string())
-                ]
-            )
-        );
-    }
-    {
-        let query = CypherQueryBuilder::new()
-            .MATCH()
-            .node(Node::new(
-                Some("n"),
-                vec!["Student"],
-                vec![("name", "Alice")],
-            ))
-            .relation(Relation::new(
-                Some("r"),
-                Vec::<String>::new(),
-                Vec::<(String, String)>::new(),
-            ))
-            .next_node(Node::new(
-                None::<String>,
-                Vec::<String>::new(),
-                Vec::<(String, String)>::new(),
-            ))
-            .DELETE(
-                vec![Item::Var(String::from("n")), Item::Var(String::from("r"))],
-                false,
-            )
-            .build();
-
-        println!("{}", query.to_query_string()?);
-        let result = graph.execute_query(query).await.unwrap();
-        println!("    {:?}", result);
-    }
-    {
-        let query = CypherQueryBuilder::new()
-            .MATCH()
-            .node(Node::new(
-                Some("n"),
-                vec!["Student"],
-                Vec::<(String, String)>::new(),
-            ))
-            .DELETE(vec![Item::Var(String::from("n"))], true)
-            .build();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::tests::{clear_graph, test_graph, test_principal};
+
+    #[tokio::test]
+    #[ignore = "needs a neo4j database"]
+    async fn test_crud() -> Result<()> {
+        let graph = test_graph().await?;
+        clear_graph(&graph).await?;
+
+        {
+            let query = CypherQueryBuilder::new()
+                .CREATE()
+                .node(Node::new(
+                    Some("a"),
+                    vec!["Student"],
+                    vec![("name", "Alice"), ("age", "25")],
+                ))
+                .RETURN(vec![Item::Var(String::from("a"))])
+                .build();
+
+            println!("{}", query.to_query_string()?);
+            let result = graph.execute_query(&test_principal(), query).await.unwrap();
+            println!("    {:?}", result);
+
+            assert_eq!(
+                result.rows()[0].inners()[0],
+                Inner::new(
+                    vec!["Student".to_string()],
+                    vec![
+                        ("name".to_string(), "Alice".to_string()),
+                        ("age".to_string(), "25".to_string())
+                    ]
+                )
+            );
+        }
+        {
+            let query = CypherQueryBuilder::new()
+                .CREATE()
+                .node(Node::new(
+                    Some("n"),
+                    vec!["Student"],
+                    vec![("name", "Bob"), ("age", "23"), ("home", "beijing")],
+                ))
+                .relation(Relation::new(
+                    Some("r"),
+                    vec!["Knows"],
+                    vec![("time", "1year")],
+                ))
+                .next_node(Node::new(
+                    Some("m"),
+                    vec!["Student"],
+                    vec![("name", "John"), ("age", "24"), ("home", "jiangxi")],
+                ))
+                .RETURN(vec![
+                    Item::Var(String::from("n")),
+                    Item::Var(String::from("r")),
+                    Item::Var(String::from("m")),
+                ])
+                .build();
+
+            println!("{}", query.to_query_string()?);
+            let result = graph.execute_query(&test_principal(), query).await.unwrap();
+            println!("    {:?}", result);
+
+            assert_eq!(
+                result.rows()[0].inners()[0],
+                Inner::new(
+                    vec!["Student".to_string()],
+                    vec![
+                        ("name".to_string(), "Bob".to_string()),
+                        ("age".to_string(), "23".to_string()),
+                        ("home".to_string(), "beijing".to_string())
+                    ]
+                )
+            );
+            assert_eq!(
+                result.rows()[0].inners()[1],
+                Inner::new(
+                    vec!["Knows".to_string()],
+                    vec![("time".to_string(), "1year".to_string())]
+                )
+            );
+            assert_eq!(
+                result.rows()[0].inners()[2],
+                Inner::new(
+                    vec!["Student".to_string()],
+                    vec![
+                        ("name".to_string(), "John".to_string()),
+                        ("age".to_string(), "24".to_string()),
+                        ("home".to_string(), "jiangxi".to_string())
+                    ]
+                )
+            );
+        }
+        {
+            let query = CypherQueryBuilder::new()
+                .MATCH()
+                .node(Node::new(
+                    Some("n"),
+                    vec!["Student"],
+                    vec![("name", "Alice")],
+                ))
+                .relation(Relation::new(
+                    Some("r"),
+                    vec!["Like"],
+                    vec![("time", "1month"), ("public", "yes")],
+                ))
+                .next_node(Node::new(
+                    Some("m"),
+                    vec!["Student"],
+                    vec![("name", "John"), ("age", "24")],
+                ))
+                .CREATE()
+                .RETURN(vec![
+                    Item::Var(String::from("n")),
+                    Item::Var(String::from("r")),
+                    Item::Var(String::from("m")),
+                ])
+                .build();
+
+            println!("{}", query.to_query_string()?);
+            let result = graph.execute_query(&test_principal(), query).await.unwrap();
+            println!("    {:?}", result);
+
+            assert_eq!(
+                result.rows()[0].inners()[0],
+                Inner::new(
+                    vec!["Student".to_string()],
+                    vec![
+                        ("name".to_string(), "Alice".to_string()),
+                        ("age".to_string(), "25".to_string())
+                    ]
+                )
+            );
+            assert_eq!(
+                result.rows()[0].inners()[1],
+                Inner::new(
+                    vec!["Like".to_string()],
+                    vec![
+                        ("time".to_string(), "1month".to_string()),
+                        ("public".to_string(), "yes".to_string())
+                    ]
+                )
+            );
+            assert_eq!(
+                result.rows()[0].inners()[2],
+                Inner::new(
+                    vec!["Student".to_string()],
+                    vec![
+                        ("name".to_string(), "John".to_string()),
+                        ("age".to_string(), "24".to_string()),
+                        ("home".to_string(), "jiangxi".to_string())
+                    ]
+                )
+            );
+        }
+        {
+            let query = CypherQueryBuilder::new()
+                .MATCH()
+                .node(Node::new(
+                    Some("n"),
+                    vec!["Student"],
+                    Vec::<(String, String)>::new(),
+                ))
+                .RETURN(vec![Item::Var(String::from("n"))])
+                .build();
+
+            println!("{}", query.to_query_string()?);
+            let result = graph.execute_query(&test_principal(), query).await.unwrap();
+            println!("    {:?}", result);
+
+            assert_eq!(result.rows().len(), 3);
+        }
+        {
+            let query = CypherQueryBuilder::new()
+                .MATCH()
+                .node(Node::new(Some("n"), vec!["Student"], vec![("age", "25")]))
+                .relation(Relation::new(
+                    Some("r"),
+                    vec!["Like"],
+                    vec![("time", "1month")],
+                ))
+                .next_node(Node::new(
+                    Some("m"),
+                    vec!["Student"],
+                    vec![("home", "jiangxi"), ("age", "24")],
+                ))
+                .RETURN(vec![
+                    Item::Var(String::from("n")),
+                    Item::Var(String::from("r")),
+                    Item::Var(String::from("m")),
+                ])
+                .build();
+
+            println!("{}", query.to_query_string()?);
+            let result = graph.execute_query(&test_principal(), query).await.unwrap();
+            println!("    {:?}", result);
+
+            assert_eq!(
+                result.rows()[0].inners()[0],
+                Inner::new(
+                    vec!["Student".to_string()],
+                    vec![
+                        ("name".to_string(), "Alice".to_string()),
+                        ("age".to_string(), "25".to_string())
+                    ]
+                )
+            );
+            assert_eq!(
+                result.rows()[0].inners()[1],
+                Inner::new(
+                    vec!["Like".to_string()],
+                    vec![
+                        ("time".to_string(), "1month".to_string()),
+                        ("public".to_string(), "yes".to_string())
+                    ]
+                )
+            );
+            assert_eq!(
+                result.rows()[0].inners()[2],
+                Inner::new(
+                    vec!["Student".to_string()],
+                    vec![
+                        ("name".to_string(), "John".to_string()),
+                        ("age".to_string(), "24".to_string()),
+                        ("home".to_string(), "jiangxi".to_string())
+                    ]
+                )
+            );
+        }
+        {
+            let query = CypherQueryBuilder::new()
+                .MATCH()
+                .node(Node::new(
+                    Some("n"),
+                    vec!["Student"],
+                    Vec::<(String, String)>::new(),
+                ))
+                .SET(vec![
+                    Item::VarWithLabel(String::from("n"), String::from("Undergraduate")),
+                    Item::VarWithKeyValue(
+                        String::from("n"),
+                        String::from("univ"),
+                        String::from("Nanjing univ"),
+                    ),
+                ])
+                .RETURN(vec![Item::Var(String::from("n"))])
+                .build();
+
+            println!("{}", query.to_query_string()?);
+            let result = graph.execute_query(&test_principal(), query).await.unwrap();
+            println!("    {:?}", result);
+
+            assert_eq!(result.rows().len(), 3);
+        }
+        {
+            let query = CypherQueryBuilder::new()
+                .MATCH()
+                .node(Node::new(
+                    Some("x"),
+                    vec!["Student"],
+                    Vec::<(String, String)>::new(),
+                ))
+                .relation(Relation::new(
+                    Some("xy"),
+                    vec!["Knows"],
+                    Vec::<(String, String)>::new(),
+                ))
+                .next_node(Node::new(
+                    Some("y"),
+                    vec!["Student"],
+                    Vec::<(String, String)>::new(),
+                ))
+                .SET(vec![
+                    Item::VarWithLabel(String::from("x"), String::from("Intern")),
+                    Item::VarWithKeyValue(
+                        String::from("x"),
+                        String::from("location"),
+                        String::from("nanjing"),
+                    ),
+                    Item::VarWithKeyValue(
+                        String::from("y"),
+                        String::from("location"),
+                        String::from("nanjing"),
+                    ),
+                    Item::VarWithKeyValue(
+                        String::from("xy"),
+                        String::from("level"),
+                        String::from("mid"),
+                    ),
+                ])
+                .RETURN(vec![
+                    Item::Var(String::from("x")),
+                    Item::Var(String::from("xy")),
+                    Item::Var(String::from("y")),
+                ])
+                .build();
+
+            println!("{}", query.to_query_string()?);
+            let result = graph.execute_query(&test_principal(), query).await.unwrap();
+            println!("    {:?}", result);
+
+            assert_eq!(
+                result.rows()[0].inners()[0],
+                Inner::new(
+                    vec![
+                        "Student".to_string(),
+                        "Intern".to_string(),
+                        "Undergraduate".to_string()
+                    ],
+                    vec![
+                        ("name".to_string(), "Bob".to_string()),
+                        ("age".to_string(), "23".to_string()),
+                        ("home".to_string(), "beijing".to_string()),
+                        ("univ".to_string(), "Nanjing univ".to_string()),
+                        ("location".to_string(), "nanjing".to_string())
+                    ]
+                )
+            );
+            assert_eq!(
+                result.rows()[0].inners()[1],
+                Inner::new(
+                    vec!["Knows".to_string()],
+                    vec![
+                        ("time".to_string(), "1year".to_string()),
+                        ("level".to_string(), "mid".to_string())
+                    ]
+                )
+            );
+            assert_eq!(
+                result.rows()[0].inners()[2],
+                Inner::new(
+                    vec!["Student".to_string(), "Undergraduate".to_string()],
+                    vec![
+                        ("name".to_string(), "John".to_string()),
+                        ("age".to_string(), "24".to_string()),
+                        ("home".to_string(), "jiangxi".to_string()),
+                        ("univ".to_string(), "Nanjing univ".to_string()),
+                        ("location".to_string(), "nanjing".to_string())
+                    ]
+                )
+            );
+        }
+        {
+            let query = CypherQueryBuilder::new()
+                .MATCH()
+                .node(Node::new(
+                    Some("n"),
+                    vec!["Intern"],
+                    vec![("location", "nanjing")],
+                ))
+                .REMOVE(vec![
+                    Item::VarWithLabel(String::from("n"), String::from("Intern")),
+                    Item::VarWithKey(String::from("n"), String::from("location")),
+                ])
+                .RETURN(vec![Item::Var(String::from("n"))])
+                .build();
+
+            println!("{}", query.to_query_string()?);
+            let result = graph.execute_query(&test_principal(), query).await.unwrap();
+            println!("    {:?}", result);
+
+            assert_eq!(
+                result.rows()[0].inners()[0],
+                Inner::new(
+                    vec!["Student".to_string(), "Undergraduate".to_string()],
+                    vec![
+                        ("name".to_string(), "Bob".to_string()),
+                        ("age".to_string(), "23".to_string()),
+                        ("home".to_string(), "beijing".to_string()),
+                        ("univ".to_string(), "Nanjing univ".to_string()),
+                    ]
+                )
+            );
+        }
+        {
+            let query = CypherQueryBuilder::new()
+                .MATCH()
+                .node(Node::new(
+                    Some("x"),
+                    vec!["Student"],
+                    Vec::<(String, String)>::new(),
+                ))
+                .relation(Relation::new(
+                    Some("xy"),
+                    vec!["Like"],
+                    vec![("time", "1month")],
+                ))
+                .next_node(Node::new(
+                    Some("y"),
+                    vec!["Student"],
+                    Vec::<(String, String)>::new(),
+                ))
+                .REMOVE(vec![
+                    Item::VarWithLabel(String::from("x"), String::from("Undergraduate")),
+                    Item::VarWithKey(String::from("x"), String::from("age")),
+                    Item::VarWithKey(String::from("y"), String::from("age")),
+                    Item::VarWithKey(String::from("xy"), String::from("public")),
+                ])
+                .RETURN(vec![
+                    Item::Var(String::from("x")),
+                    Item::Var(String::from("xy")),
+                    Item::Var(String::from("y")),
+                ])
+                .build();
+
+            println!("{}", query.to_query_string()?);
+            let result = graph.execute_query(&test_principal(), query).await.unwrap();
+            println!("    {:?}", result);
+
+            assert_eq!(
+                result.rows()[0].inners()[0],
+                Inner::new(
+                    vec!["Student".to_string()],
+                    vec![
+                        ("name".to_string(), "Alice".to_string()),
+                        ("univ".to_string(), "Nanjing univ".to_string())
+                    ]
+                )
+            );
+            assert_eq!(
+                result.rows()[0].inners()[1],
+                Inner::new(
+                    vec!["Like".to_string()],
+                    vec![("time".to_string(), "1month".to_string())]
+                )
+            );
+            assert_eq!(
+                result.rows()[0].inners()[2],
+                Inner::new(
+                    vec!["Student".to_string(), "Undergraduate".to_string()],
+                    vec![
+                        ("name".to_string(), "John".to_string()),
+                        ("home".to_string(), "jiangxi".to_string()),
+                        ("univ".to_string(), "Nanjing univ".to_string()),
+                        ("location".to_string(), "nanjing".to_string())
+                    ]
+                )
+            );
+        }
+        {
+            let query = CypherQueryBuilder::new()
+                .MATCH()
+                .node(Node::new(
+                    Some("n"),
+                    vec!["Student"],
+                    vec![("name", "Alice")],
+                ))
+                .relation(Relation::new(
+                    Some("r"),
+                    Vec::<String>::new(),
+                    Vec::<(String, String)>::new(),
+                ))
+                .next_node(Node::new(
+                    None::<String>,
+                    Vec::<String>::new(),
+                    Vec::<(String, String)>::new(),
+                ))
+                .DELETE(
+                    vec![Item::Var(String::from("n")), Item::Var(String::from("r"))],
+                    false,
+                )
+                .build();
+
+            println!("{}", query.to_query_string()?);
+            let result = graph.execute_query(&test_principal(), query).await.unwrap();
+            println!("    {:?}", result);
+        }
+        {
+            let query = CypherQueryBuilder::new()
+                .MATCH()
+                .node(Node::new(
+                    Some("n"),
+                    vec!["Student"],
+                    Vec::<(String, String)>::new(),
+                ))
+                .DELETE(vec![Item::Var(String::from("n"))], true)
+                .build();
+
+            println!("{}", query.to_query_string()?);
+            let result = graph.execute_query(&test_principal(), query).await.unwrap();
+            println!("    {:?}", result);
+        }
 
-        println!("{}", query.to_query_string()?);
-        let result = graph.execute_query(query).await.unwrap();
-        println!("    {:?}", result);
+        Ok(())
     }
 
-    Ok(())
-}
+    #[tokio::test]
+    #[ignore = "needs a neo4j database"]
+    async fn test_find_shortest_path() -> Result<()> {
+        let graph = test_graph().await?;
+        clear_graph(&graph).await?;
+
+        //            c --> d
+        //            ⬆     ⬇
+        //      a --> b --> e --> f
+        //                  ⬇     ⬇
+        //                  h <-- g
+        for node_name in ["a", "b", "c", "d", "e", "f", "g", "h"] {
+            let query = CypherQueryBuilder::new()
+                .CREATE()
+                .node(Node::new(
+                    None::<String>,
+                    vec!["Person"],
+                    vec![("name", node_name)],
+                ))
+                .build();
+            let result = graph
+                .execute_query(
+                    &test_principal(),
+                    CypherQuery::deserialize(&query.serialize()?)?,
+                )
+                .await
+                .unwrap();
+            println!("{}\n{:?}", query.to_query_string()?, result);
+        }
 
-async fn test_find_shortest_path(graph: &EncryptedGraph) -> Result<()> {
-    init_test(graph).await?;
+        //            c --> d
+        //            ⬆     ⬇
+        //      a --> b --> e --> f
+        //                  ⬇     ⬇
+        //                  h <-- g
+        for (from, to) in [
+            ("a", "b"),
+            ("b", "c"),
+            ("b", "e"),
+            ("c", "d"),
+            ("d", "e"),
+            ("e", "f"),
+            ("f", "g"),
+            ("e", "h"),
+            ("g", "h"),
+        ] {
+            let query = CypherQueryBuilder::new()
+                .MATCH()
+                .node(Node::new(Some("n"), vec!["Person"], vec![("name", from)]))
+                .relation(Relation::new(
+                    Some("r"),
+                    vec!["knows"],
+                    Vec::<(String, String)>::new(),
+                ))
+                .next_node(Node::new(Some("m"), vec!["Person"], vec![("name", to)]))
+                .CREATE()
+                .build();
+            let result = graph
+                .execute_query(
+                    &test_principal(),
+                    CypherQuery::deserialize(&query.serialize()?)?,
+                )
+                .await
+                .unwrap();
+            println!("{}\n{:?}", query.to_query_string()?, result);
+        }
 
-    //            c --> d
-    //            ⬆     ⬇
-    //      a --> b --> e --> f
-    //                  ⬇     ⬇
-    //                  h <-- g
-    for node_name in ["a", "b", "c", "d", "e", "f", "g", "h"] {
         let query = CypherQueryBuilder::new()
-            .CREATE()
             .node(Node::new(
-                None::<String>,
+                Some("start"),
                 vec!["Person"],
-                vec![("name", node_name)],
+                vec![("name", "a")],
             ))
+            .next_node(Node::new(Some("dest"), vec!["Person"], vec![("name", "g")]))
+            .find_shortest_path()
             .build();
         let result = graph
-            .execute_query(CypherQuery::deserialize(&query.serialize()?)?)
+            .execute_query(
+                &test_principal(),
+                CypherQuery::deserialize(&query.serialize()?)?,
+            )
             .await
             .unwrap();
-        println!("{}\n{:?}", query.to_query_string()?, result);
-    }
 
-    //            c --> d
-    //            ⬆     ⬇
-    //      a --> b --> e --> f
-    //                  ⬇     ⬇
-    //                  h <-- g
-    for (from, to) in [
-        ("a", "b"),
-        ("b", "c"),
-        ("b", "e"),
-        ("c", "d"),
-        ("d", "e"),
-        ("e", "f"),
-        ("f", "g"),
-        ("e", "h"),
-        ("g", "h"),
-    ] {
-        let query = CypherQueryBuilder::new()
-            .MATCH()
-            .node(Node::new(Some("n"), vec!["Person"], vec![("name", from)]))
-            .relation(Relation::new(
-                Some("r"),
-                vec!["knows"],
-                Vec::<(String, String)>::new(),
-            ))
-            .next_node(Node::new(Some("m"), vec!["Person"], vec![("name", to)]))
-            .CREATE()
-            .build();
-        let result = graph
-            .execute_query(CypherQuery::deserialize(&query.serialize()?)?)
-            .await
-            .unwrap();
+        let path: Vec<&String> = result.rows()[0]
+            .inners()
+            .iter()
+            .map(|x| x.get("name").unwrap())
+            .collect();
+        assert_eq!(path, vec!["a", "b", "e", "f", "g"]);
         println!("{}\n{:?}", query.to_query_string()?, result);
-    }
-
-    let query = CypherQueryBuilder::new()
-        .node(Node::new(
-            Some("start"),
-            vec!["Person"],
-            vec![("name", "a")],
-        ))
-        .next_node(Node::new(Some("dest"), vec!["Person"], vec![("name", "g")]))
-        .find_shortest_path()
-        .build();
-    let result = graph
-        .execute_query(CypherQuery::deserialize(&query.serialize()?)?)
-        .await
-        .unwrap();
-
-    let path: Vec<&String> = result.rows()[0]
-        .inners()
-        .iter()
-        .map(|x| x.get("name").unwrap())
-        .collect();
-    assert_eq!(path, vec!["a", "b", "e", "f", "g"]);
-    println!("{}\n{:?}", query.to_query_string()?, result);
 
-    Ok(())
+        Ok(())
+    }
 }
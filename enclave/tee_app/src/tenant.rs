@@ -0,0 +1,126 @@
+use anyhow::Result;
+
+use crate::crypto::{Crypto, KeyProvider, TenantKeyProvider};
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+pub const DEFAULT_TENANT: &str = "default";
+
+/// Where the data keys are sealed without `DATA_KEY_PATH`, on the hostfs mount
+/// of Occlum.json.
+pub const DEFAULT_DATA_KEY_PATH: &str = "/host/data_key.sealed";
+
+/// A namespace on the shared enclave and neo4j.
+///
+/// Every tenant but the default one has a root key derived from the enclave's
+/// root key and its id (see `TenantKeyProvider`), and so its own data keys:
+/// equal plaintexts of two tenants encrypt to different ciphertexts, and the
+/// entities and handles of one tenant don't verify under the keys of another.
+/// Its sealed state lives in files of its own, see `path_var`, and it may be
+/// routed to a neo4j database of its own.
+///
+/// Tenants are listed in `TENANTS` as `id[:database]`, comma separated, e.g.
+/// `team-a:team_a,team-b`. The default tenant always exists and keeps the keys
+/// and files of a single-tenant enclave; `default:database` sets its database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tenant {
+    pub id: String,
+    pub database: Option<String>,
+}
+
+impl Tenant {
+    pub fn new(id: impl Into<String>, database: Option<String>) -> Self {
+        Self {
+            id: id.into(),
+            database,
+        }
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.id == DEFAULT_TENANT
+    }
+
+    /// The default tenant first, then the tenants of `TENANTS`.
+    pub fn all_from_env() -> Result<Vec<Self>> {
+        Self::parse(&std::env::var("TENANTS").unwrap_or_default())
+    }
+
+    pub fn parse(spec: &str) -> Result<Vec<Self>> {
+        let mut tenants = vec![Self::new(DEFAULT_TENANT, None)];
+        for entry in spec.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            let (id, database) = match entry.split_once(':') {
+                Some((id, database)) => (id, Some(database.to_string())),
+                None => (entry, None),
+            };
+            if id.is_empty()
+                || !id
+                    .chars()
+                    .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+            {
+                return Err(anyhow::anyhow!("Invalid tenant id: {}", id));
+            }
+            if id == DEFAULT_TENANT {
+                tenants[0].database = database;
+            } else if tenants.iter().any(|x| x.id == id) {
+                return Err(anyhow::anyhow!("Duplicate tenant: {}", id));
+            } else {
+                tenants.push(Self::new(id, database));
+            }
+        }
+        Ok(tenants)
+    }
+
+    /// Setting `name` of this tenant: `name` itself for the default tenant,
+    /// `name_ID` for the others, with the id in upper case and `-` as `_`.
+    pub fn var(&self, name: &str) -> Option<String> {
+        if self.is_default() {
+            return std::env::var(name).ok();
+        }
+        std::env::var(format!(
+            "{}_{}",
+            name,
+            self.id.to_uppercase().replace('-', "_")
+        ))
+        .ok()
+    }
+
+    /// Path setting `name` of this tenant: as `var`, but without `name_ID` the
+    /// path in `name` with `.id` appended, so one setting places the files of
+    /// all tenants side by side.
+    pub fn path_var(&self, name: &str) -> Option<PathBuf> {
+        if let Some(path) = self.var(name) {
+            return Some(PathBuf::from(path));
+        }
+        Some(self.tenant_path(std::env::var_os(name)?))
+    }
+
+    /// Keys of this tenant, derived from the enclave's `root` key provider, with
+    /// its data keys sealed at `DATA_KEY_PATH`, `DEFAULT_DATA_KEY_PATH` if unset.
+    /// `DATA_KEY=raw` keeps the legacy mode of older builds, which encrypt
+    /// under the provider key itself.
+    pub fn crypto(&self, root: Arc<dyn KeyProvider>) -> Result<Crypto> {
+        let tenant = (!self.is_default()).then(|| self.id.clone());
+        let data_key_path = match self.var("DATA_KEY").as_deref() {
+            Some("raw") => None,
+            Some("sealed") | None => Some(
+                self.path_var("DATA_KEY_PATH")
+                    .unwrap_or_else(|| self.tenant_path(DEFAULT_DATA_KEY_PATH.into())),
+            ),
+            Some(mode) => return Err(anyhow::anyhow!("Unknown DATA_KEY: {}", mode)),
+        };
+        Crypto::new(
+            Box::new(TenantKeyProvider::new(root, tenant)),
+            data_key_path,
+        )
+    }
+
+    // `path` of the default tenant, with `.id` appended for the others
+    fn tenant_path(&self, mut path: OsString) -> PathBuf {
+        if !self.is_default() {
+            path.push(format!(".{}", self.id));
+        }
+        PathBuf::from(path)
+    }
+}
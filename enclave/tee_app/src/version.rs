@@ -0,0 +1,230 @@
+use anyhow::Result;
+
+use crate::crypto::{Crypto, IntegrityError};
+use crate::graph::split_relation_uid;
+use crate::journal::{Loaded, SealedJournal};
+use crate::tenant::Tenant;
+
+use std::collections::{BTreeMap, HashMap};
+
+pub const MAGIC_VERSION_KEY: &str = "version#";
+
+const SEALED_VERSION_MAP_AAD: &[u8] = b"clique_task version map v2";
+// marker of a started version map in the sealed data keys
+const VERSION_MAP_MARKER: &str = "version-map";
+
+/// Authenticated uid -> version table of every entity in the graph.
+///
+/// Each entity carries a `version#` property covered by its MAC, and the enclave
+/// only accepts an entity whose version matches this table, so neo4j can't
+/// replay an old but internally consistent copy of it.
+///
+/// The table is sealed to `VERSION_MAP_PATH`, with the changes of every write
+/// appended to its journal, see `SealedJournal`. The sealed head of the journal
+/// keeps the host from cutting off the newest changes or putting back an older
+/// table on its own. An older copy of all sealed state, data keys included, put
+/// back together with a rollback of neo4j to the same point goes unnoticed:
+/// there is no trusted monotonic counter to compare it with.
+pub struct VersionMap {
+    versions: BTreeMap<String, u64>,
+    // versions of writes sent to neo4j but not committed yet, accepted next to
+    // the committed ones by concurrent reads
+    pending: HashMap<String, u64>,
+    journal: Option<SealedJournal>,
+}
+
+/// Changes one write makes to the version map. They are marked pending before
+/// the write is sent to neo4j, and committed only once neo4j took it.
+#[derive(Debug, Default)]
+pub struct VersionChanges {
+    set: Vec<(String, u64)>,
+    removed: Vec<String>,
+    // nodes whose relationships were deleted with them
+    detached: Vec<String>,
+}
+
+impl VersionChanges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, uid: impl Into<String>, version: u64) {
+        self.set.push((uid.into(), version));
+    }
+
+    pub fn remove(&mut self, uid: impl Into<String>) {
+        self.removed.push(uid.into());
+    }
+
+    /// Removes the relationships of a node deleted by DETACH DELETE.
+    pub fn detach(&mut self, node_uid: impl Into<String>) {
+        self.detached.push(node_uid.into());
+    }
+
+    /// The uids of the entities the write changes, without the relationships
+    /// deleted with their node.
+    pub fn uids(&self) -> impl Iterator<Item = &String> {
+        self.set
+            .iter()
+            .map(|(uid, _)| uid)
+            .chain(&self.removed)
+            .chain(&self.detached)
+    }
+}
+
+impl VersionMap {
+    /// A table kept in memory only.
+    pub fn new() -> Self {
+        Self {
+            versions: BTreeMap::new(),
+            pending: HashMap::new(),
+            journal: None,
+        }
+    }
+
+    /// Without a persisted table every entity is unknown after a restart, so
+    /// `VERSION_MAP_PATH` is required.
+    pub fn from_env(tenant: &Tenant) -> Result<Self> {
+        let path = tenant
+            .path_var("VERSION_MAP_PATH")
+            .ok_or_else(|| anyhow::anyhow!("VERSION_MAP_PATH must be set"))?;
+        let mut versions = Self::new();
+        versions.journal = Some(SealedJournal::new(
+            path,
+            SEALED_VERSION_MAP_AAD,
+            VERSION_MAP_MARKER,
+        ));
+        Ok(versions)
+    }
+
+    /// Loads the sealed table, or seals an empty one on the first start.
+    pub fn load(&mut self, crypto: &Crypto) -> Result<()> {
+        let journal = match self.journal.as_mut() {
+            Some(journal) => journal,
+            None => return Ok(()),
+        };
+        let Loaded { snapshot, records } = match journal.load(crypto)? {
+            Some(loaded) => loaded,
+            None => {
+                log::info!("start a new version map at {}", journal.path().display());
+                return journal.compact(crypto, &[]);
+            }
+        };
+
+        let invalid = || anyhow::anyhow!("Invalid version map");
+        for line in String::from_utf8(snapshot)?.lines() {
+            let (uid, version) = line.split_once(' ').ok_or_else(invalid)?;
+            self.versions.insert(uid.to_string(), version.parse()?);
+        }
+        let count = records.len();
+        for record in records {
+            for line in String::from_utf8(record)?.lines() {
+                match line.split(' ').collect::<Vec<_>>().as_slice() {
+                    ["s", uid, version] => {
+                        self.versions.insert(uid.to_string(), version.parse()?);
+                    }
+                    ["r", uid] => {
+                        self.versions.remove(*uid);
+                    }
+                    _ => return Err(invalid()),
+                }
+            }
+        }
+
+        log::info!(
+            "loaded version map of {} entities, {} journal records",
+            self.versions.len(),
+            count
+        );
+        Ok(())
+    }
+
+    /// Accepts the entity only if `version` is the latest version of `uid`, or
+    /// the version of a write that is still running.
+    pub fn check(&self, uid: &str, version: Option<&String>) -> Result<(), IntegrityError> {
+        let pending = self.pending.get(uid);
+        let expected =
+            self.versions
+                .get(uid)
+                .or(pending)
+                .ok_or_else(|| IntegrityError::UnknownEntity {
+                    uid: uid.to_string(),
+                })?;
+        match version.and_then(|x| x.parse::<u64>().ok()) {
+            Some(version) if version == *expected || Some(&version) == pending => Ok(()),
+            _ => Err(IntegrityError::StaleVersion {
+                uid: uid.to_string(),
+                expected: *expected,
+                found: version.cloned().unwrap_or_default(),
+            }),
+        }
+    }
+
+    /// Marks the versions set by a write that is about to be sent to neo4j.
+    pub fn begin(&mut self, changes: &VersionChanges) {
+        for (uid, version) in &changes.set {
+            self.pending.insert(uid.clone(), *version);
+        }
+    }
+
+    /// Drops the pending versions of a write neo4j didn't take.
+    pub fn abort(&mut self, changes: &VersionChanges) {
+        for (uid, version) in &changes.set {
+            if self.pending.get(uid) == Some(version) {
+                self.pending.remove(uid);
+            }
+        }
+    }
+
+    /// Applies the changes of a write neo4j took, and appends them to the
+    /// journal. The write already happened, so a failure to seal the changes is
+    /// logged, and the next write seals the whole table instead.
+    pub fn commit(&mut self, crypto: &Crypto, changes: VersionChanges) {
+        self.abort(&changes);
+
+        let mut record = String::new();
+        for (uid, version) in changes.set {
+            record.push_str(&format!("s {} {}\n", uid, version));
+            self.versions.insert(uid, version);
+        }
+        let mut removed = changes.removed;
+        if !changes.detached.is_empty() {
+            removed.extend(
+                self.versions
+                    .keys()
+                    .filter(|uid| {
+                        split_relation_uid(uid).is_ok_and(|(from, to)| {
+                            changes.detached.iter().any(|x| x == from || x == to)
+                        })
+                    })
+                    .cloned(),
+            );
+        }
+        for uid in removed {
+            if self.versions.remove(&uid).is_some() {
+                record.push_str(&format!("r {}\n", uid));
+            }
+        }
+
+        let journal = match self.journal.as_mut() {
+            Some(journal) if !record.is_empty() => journal,
+            _ => return,
+        };
+        let res = if journal.is_full() {
+            journal.compact(crypto, snapshot(&self.versions).as_bytes())
+        } else {
+            journal.append(crypto, record.as_bytes())
+        };
+        if let Err(e) = res {
+            log::error!("failed to seal version map changes: {:?}", e);
+        }
+    }
+}
+
+fn snapshot(versions: &BTreeMap<String, u64>) -> String {
+    let mut plain = String::new();
+    for (uid, version) in versions {
+        plain.push_str(&format!("{} {}\n", uid, version));
+    }
+    plain
+}